@@ -0,0 +1,142 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::clock;
+use crate::config::AUDIT_LOG_NAME;
+use crate::runid;
+
+// One operation that read backed up data, as a line of AUDIT_LOG_NAME
+#[derive(Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: String,
+    #[serde(default)]
+    pub run_id: String,
+    // Login name; under sudo the user who invoked it
+    pub user: String,
+    pub uid: u32,
+    pub operation: String,
+    // Checkpoint read from, or the bundle or stream file for restores that
+    // don't read the repository
+    pub from: String,
+    // Path prefixes selected; empty for everything
+    pub paths: Vec<String>,
+    // Bundle, stream or directory the data went to
+    pub target: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+// Account name of `uid` from /etc/passwd
+fn user_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.get(2)? == &uid.to_string()).then(|| fields[0].to_string())
+    })
+}
+
+// The uid's account name, except that a root process started through sudo is
+// recorded as the user who ran sudo
+fn current_user() -> (String, u32) {
+    let uid = unsafe { libc::getuid() };
+    let sudo_user = std::env::var("SUDO_USER").ok().filter(|name| uid == 0 && !name.is_empty());
+    let user = sudo_user
+        .or_else(|| user_name(uid))
+        .unwrap_or_else(|| format!("uid {}", uid));
+    (user, uid)
+}
+
+// Append a record of `operation` to the repository's audit log. A log that
+// can't be written is reported but doesn't undo the operation.
+pub fn record<E: Display>(
+    backup_dir: &Path,
+    operation: &str,
+    from: &str,
+    paths: &[String],
+    target: &str,
+    result: &Result<(), E>,
+) {
+    let (user, uid) = current_user();
+    let record = AuditRecord {
+        time: clock::local_now().to_rfc3339(),
+        run_id: runid::current(),
+        user,
+        uid,
+        operation: operation.to_string(),
+        from: from.to_string(),
+        paths: paths.to_vec(),
+        target: target.to_string(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    let path = backup_dir.join(AUDIT_LOG_NAME);
+    let written = serde_json::to_string(&record).map_err(io::Error::other).and_then(|line| {
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // A single write keeps lines whole when operations run concurrently
+        file.write_all(format!("{}\n", line).as_bytes())
+    });
+    if let Err(e) = written {
+        warn!("Failed to write audit log {:?}: {}", path, e);
+    }
+}
+
+// Filters for `audit show`; None matches everything
+pub struct AuditFilter<'a> {
+    pub user: Option<&'a str>,
+    pub from: Option<&'a str>,
+    pub path: Option<&'a str>,
+    // YYYY-MM-DD; operations from that day on match
+    pub since: Option<&'a str>,
+}
+
+impl AuditFilter<'_> {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.user.is_none_or(|user| record.user == user)
+            && self.from.is_none_or(|from| record.from == from)
+            && self.path.is_none_or(|path| {
+                record.paths.is_empty() || record.paths.iter().any(|p| path.starts_with(p.as_str()) || p.starts_with(path))
+            })
+            && self.since.is_none_or(|since| record.time.get(..10).is_some_and(|date| date >= since))
+    }
+}
+
+// Print the audit log, oldest first, one operation per line
+pub fn show(backup_dir: &Path, filter: &AuditFilter) -> io::Result<()> {
+    let path = backup_dir.join(AUDIT_LOG_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(record) = serde_json::from_str::<AuditRecord>(line) else {
+            warn!("Ignoring invalid audit log line: {}", line);
+            continue;
+        };
+        if !filter.matches(&record) {
+            continue;
+        }
+        let paths = if record.paths.is_empty() { "*".to_string() } else { record.paths.join(",") };
+        let outcome = match &record.error {
+            Some(error) => format!("failed: {}", error),
+            None if record.success => "ok".to_string(),
+            None => "failed".to_string(),
+        };
+        println!(
+            "{}\t{} ({})\t{}\t{}\t{}\t{}\t{}",
+            record.time,
+            record.user,
+            record.uid,
+            record.operation,
+            record.from,
+            paths,
+            record.target,
+            outcome
+        );
+    }
+    Ok(())
+}
@@ -1,16 +1,64 @@
-use crate::config::{IGNORE_DIRS, SRC_DIR};
+use crate::btime::birth_time;
+use crate::checkpoint::{checkpoint_ref, clear_metadata, ensure_unlocked, CHECKPOINT_INFO_NAME};
+use crate::churn;
+use crate::checksums::{mtime_ns, ChecksumDb};
+use crate::clock;
+use crate::concurrency::run_adaptive;
+use crate::config::{
+    COMPRESS_FILE_NAME, DUAL_PHASE, HARDLINK_UNCHANGED, HASH_BUFFER_SIZE, SCAN_HOOK, SCAN_HOOK_SKIP_FLAGGED,
+    SKIP_UNREADABLE_FILES, TOLERATE_VANISHED_FILES,
+};
+use crate::consistent::{strategy_for, Snapshot};
+use crate::error::{Error, Result};
+use crate::explain::CopyCause;
+use crate::filters::{self, FILTERED_FLAG};
+use crate::hashing::{hash_segments, ContentHasher, Scheme};
+use crate::history::RUN_REPORT_NAME;
+use crate::hooks::{scan_file, ScanVerdict};
+use crate::human::{format_count, format_duration, format_size};
+use crate::journal::JournalChanges;
+use crate::pack::{PackRef, PackWriter, PACK_DIR};
+use crate::pipeline::{self, Stage, Target, Timings};
+use crate::manifest::{has_manifest, read_entries, upsert_entries, ManifestEntry, ManifestWriter, MANIFEST_DIR};
+use crate::pattern::{may_match_below, path_matches};
+use crate::plan::{self, read_done, stored_intact, write_plan, Change, DiffSummary, DoneJournal, LastState};
+use crate::plugins::BACKEND_CHECKSUMS_NAME;
+use crate::prefetch::Prefetcher;
+use crate::reflink::copy_file;
+use crate::report::HTML_REPORT_NAME;
+use crate::priority::RunGuard;
+use crate::safety;
+use crate::seekable::{self, SeekIndex};
+use crate::settings::Config;
+use crate::status::Progress;
+use crate::timeout;
+use crate::zip_handler::{
+    existing_meta_path, is_legacy_dir, meta_name, meta_path, meta_zip, read_zip_metas, write_zip_metas, MetaEntry,
+    MetaExtractor, META_DIR,
+};
 use chrono::Timelike;
-use log::info;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use xxhash_rust::xxh3::Xxh3;
+use std::time::Duration;
+use walkdir::WalkDir;
 
 #[derive(Debug)]
-struct FileInfo {
-    size: u64,
-    hash: String,
-    time_stamp: chrono::DateTime<chrono::Utc>,
+pub(crate) struct FileInfo {
+    pub(crate) size: u64,
+    pub(crate) hash: String,
+    pub(crate) time_stamp: chrono::DateTime<chrono::Utc>,
+    // Checkpoint holding the data; absent in metas written before manifests existed
+    pub(crate) stored_in: Option<String>,
+    // Creation time of the source file in Unix seconds, where the filesystem records one
+    pub(crate) birth_time: Option<i64>,
+    // Place in a pack of the holding checkpoint, for small files stored packed
+    pub(crate) packed: Option<PackRef>,
+    // Frame layout, for large files stored compressed in the seekable format
+    pub(crate) seekable: Option<SeekIndex>,
 }
 
 impl PartialEq for FileInfo {
@@ -24,31 +72,85 @@ impl FileInfo {
         Self {
             size,
             hash,
-            time_stamp: time_stamp.unwrap_or(chrono::Utc::now().with_nanosecond(0).unwrap()),
+            time_stamp: time_stamp.unwrap_or(clock::now().with_nanosecond(0).unwrap()),
+            stored_in: None,
+            birth_time: None,
+            packed: None,
+            seekable: None,
         }
     }
 
     fn from_path(path: &Path) -> io::Result<Self> {
+        Self::with_data(path, path, &ChecksumDb::disabled())
+    }
+
+    // Size and hash of the data a compressed stored file holds
+    fn from_seekable(path: &Path, index: SeekIndex) -> io::Result<Self> {
+        let size = seekable::data_size(path)?;
+        let hash = hash_reader(&mut seekable::open(path)?, HASH_BUFFER_SIZE, Scheme::for_size(size))?;
+        let modified = fs::metadata(path)?
+            .modified()
+            .ok()
+            .map(|time| chrono::DateTime::<chrono::Utc>::from(time).with_nanosecond(0).unwrap());
+        let mut info = Self::new(size, hash, modified);
+        info.seekable = Some(index);
+        Ok(info)
+    }
+
+    // Size and hash of `data`, a snapshot of `path`, with the modification
+    // time of `path`. Hashes of source files read directly may come from `checksums`.
+    fn with_data(path: &Path, data: &Path, checksums: &ChecksumDb) -> io::Result<Self> {
         let metadata = fs::metadata(path)?;
-        let size = metadata.len();
-        let hash = compute_xxhash(path)?;
-        Ok(Self::new(size, hash, None))
+        let (size, hash) = if data == path {
+            (metadata.len(), checksums.hash(path, &metadata)?)
+        } else {
+            (fs::metadata(data)?.len(), compute_xxhash(data)?)
+        };
+        Ok(Self::described(&metadata, size, hash))
+    }
+
+    // Info of a file with `metadata` whose data has `size` and `hash`
+    fn described(metadata: &fs::Metadata, size: u64, hash: String) -> Self {
+        // The file's own modification time rather than the time of the run, so
+        // identical data always yields identical metadata
+        let modified = metadata
+            .modified()
+            .ok()
+            .map(|time| chrono::DateTime::<chrono::Utc>::from(time).with_nanosecond(0).unwrap());
+        let mut info = Self::new(size, hash, modified);
+        info.birth_time = birth_time(metadata);
+        info
     }
 
     fn write_to_file(&self, file: &mut File) -> io::Result<()> {
-        writeln!(
-            file,
-            "{}\n{}\n{}",
-            self.size,
-            self.hash,
-            self.time_stamp.timestamp()
-        )?;
-        Ok(())
+        file.write_all(self.to_meta_string().as_bytes())
+    }
+
+    pub(crate) fn to_meta_string(&self) -> String {
+        let mut meta = format!("{}\n{}\n{}\n", self.size, self.hash, self.time_stamp.timestamp());
+        // Pointer, birth time, pack reference and seek index follow, each
+        // possibly empty, up to the last one that is set
+        let optional = [
+            self.stored_in.clone().unwrap_or_default(),
+            self.birth_time.map(|bt| bt.to_string()).unwrap_or_default(),
+            self.packed.as_ref().map(PackRef::to_string).unwrap_or_default(),
+            self.seekable.as_ref().map(SeekIndex::to_string).unwrap_or_default(),
+        ];
+        let used = optional.iter().rposition(|line| !line.is_empty()).map_or(0, |last| last + 1);
+        for line in &optional[..used] {
+            meta.push_str(line);
+            meta.push('\n');
+        }
+        meta
     }
 
     fn read_from_meta(file: &mut File) -> io::Result<Self> {
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
+        Self::parse(&contents)
+    }
+
+    pub(crate) fn parse(contents: &str) -> io::Result<Self> {
         let mut lines = contents.lines();
 
         let size = lines
@@ -75,36 +177,89 @@ impl FileInfo {
                     })
             })?;
 
+        let stored_in = lines.next().map(|s| checkpoint_ref(s).to_string()).filter(|s| !s.is_empty());
+        let birth_time = lines
+            .next()
+            .filter(|bt| !bt.is_empty())
+            .map(|bt| bt.parse::<i64>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid birth time")))
+            .transpose()?;
+        let packed = lines.next().filter(|pack| !pack.is_empty()).map(PackRef::parse).transpose()?;
+        let seekable = lines.next().filter(|index| !index.is_empty()).map(SeekIndex::parse).transpose()?;
+
         Ok(Self {
             size,
             hash,
             time_stamp,
+            stored_in,
+            birth_time,
+            packed,
+            seekable,
         })
     }
 }
 
-fn compute_xxhash(file_path: &Path) -> io::Result<String> {
-    let mut file = File::open(file_path)?;
-    let mut hasher = Xxh3::new();
-    let mut buffer = [0u8; 4096];
+pub(crate) fn compute_xxhash(file_path: &Path) -> Result<String> {
+    compute_xxhash_with(file_path, HASH_BUFFER_SIZE)
+}
+
+pub(crate) fn compute_xxhash_with(file_path: &Path, buffer_size: usize) -> Result<String> {
+    let size = fs::metadata(file_path).map_err(|e| Error::hashing(file_path, e))?.len();
+    compute_xxhash_as(file_path, Scheme::for_size(size), buffer_size)
+}
+
+// Hash of a file computed the way `recorded` was, to compare it with
+pub(crate) fn compute_xxhash_like(file_path: &Path, recorded: &str) -> Result<String> {
+    compute_xxhash_as(file_path, Scheme::of(recorded), HASH_BUFFER_SIZE)
+}
+
+fn compute_xxhash_as(file_path: &Path, scheme: Scheme, buffer_size: usize) -> Result<String> {
+    let hash = match scheme {
+        Scheme::Flat => timeout::open(file_path).and_then(|mut file| hash_reader(&mut file, buffer_size, scheme)),
+        Scheme::Tree(segment) => fs::metadata(file_path)
+            .and_then(|metadata| hash_segments(file_path, metadata.len(), segment, buffer_size)),
+    };
+    hash.map_err(|e| Error::hashing(file_path, e))
+}
+
+// Hash of everything `reader` yields, e.g. a file packed with others
+pub(crate) fn hash_reader(reader: &mut impl Read, buffer_size: usize, scheme: Scheme) -> io::Result<String> {
+    let mut hasher = ContentHasher::new(scheme);
+    let mut buffer = vec![0u8; buffer_size];
 
     loop {
-        let bytes_read = file.read(&mut buffer)?;
+        let bytes_read = reader.read(&mut buffer)?;
         if bytes_read == 0 {
             break;
         }
         hasher.update(&buffer[..bytes_read]);
     }
 
-    let hash = hasher.digest();
-    Ok(format!("{:016x}", hash))
+    Ok(hasher.finish())
+}
+
+// Outcome of processing one source file
+struct ProcessedFile {
+    info: FileInfo,
+    // Bytes read and written, for throughput accounting
+    bytes: u64,
+    // Markers recorded in the manifest, e.g. "flagged" by the scan hook or
+    // why the data was copied
+    flags: Vec<String>,
 }
 
 fn dealing_with_file(
-    path: &Path,
-    last_checkpoint_meta: &Option<PathBuf>,
-    new_checkpoint_dir: &Path,
-) -> io::Result<()> {
+    job: &FileJob,
+    new_checkpoint: &Path,
+    checksums: &ChecksumDb,
+    packs: &PackWriter,
+    timings: &Timings,
+    run: &RunGuard,
+    use_reflink: bool,
+) -> io::Result<Option<ProcessedFile>> {
+    let (path, rel, new_checkpoint_dir) = (job.path.as_path(), job.rel.as_path(), job.dest.as_path());
+    let checkpoint_name = new_checkpoint.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let backup_dir = new_checkpoint.parent().unwrap_or(Path::new(""));
+    let last_checkpoint_meta = &job.last_checkpoint_meta;
     // Check if the file exists in the last checkpoint
     let last_file_info = if let Some(last_checkpoint_meta) = last_checkpoint_meta {
         if last_checkpoint_meta.exists() {
@@ -116,97 +271,804 @@ fn dealing_with_file(
     } else {
         None
     };
-    let current_file_info = FileInfo::from_path(path)?;
-    let new_meta_file = new_checkpoint_dir.with_extension("meta");
+    // Files applications keep open are captured through a snapshot, which is
+    // then hashed and stored in place of the live file
+    let snapshot = match strategy_for(rel)? {
+        Some(strategy) => Some(Snapshot::take(path, rel, backup_dir, strategy, use_reflink)?),
+        None => None,
+    };
+    // Content filters replace the data with their output in the same way
+    let filtered = filters::apply(rel, snapshot.as_ref().map_or(path, Snapshot::path), backup_dir)?;
+    let is_filtered = filtered.is_some();
+    let snapshot = filtered.or(snapshot);
+    let data = snapshot.as_ref().map_or(path, Snapshot::path);
+    let new_meta_file = meta_path(new_checkpoint_dir);
 
-    // Create the new checkpoint directory if it doesn't exist
-    if let Some(parent) = new_checkpoint_dir.parent() {
-        if !parent.exists() {
+    // A file whose size changed can't match the last checkpoint, so it is
+    // hashed while it is stored. Not with a scan hook, which needs the hash
+    // before anything is stored.
+    let metadata = fs::metadata(path)?;
+    if snapshot.is_none()
+        && SCAN_HOOK.is_empty()
+        && last_file_info.as_ref().is_none_or(|last| last.size != metadata.len())
+    {
+        if let Some(parent) = new_meta_file.parent() {
             fs::create_dir_all(parent)?;
         }
+        let target = if packs.accepts(metadata.len()) {
+            Target::Pack(packs)
+        } else if seekable::accepts(metadata.len()) {
+            Target::Seekable(new_checkpoint_dir)
+        } else {
+            Target::Plain(new_checkpoint_dir, use_reflink)
+        };
+        let stored = pipeline::store(path, target, timings, run)?;
+        checksums.record(path, &metadata, &stored.hash)?;
+        let mut info = FileInfo::described(&metadata, stored.size, stored.hash);
+        info.stored_in = Some(checkpoint_name.to_string());
+        info.packed = stored.packed;
+        info.seekable = stored.seekable;
+        let mut meta_file_handle = File::create(&new_meta_file)?;
+        info.write_to_file(&mut meta_file_handle)?;
+        info!("Stored {:?} -> {:?} in one pass", path, new_checkpoint_dir);
+        let bytes = info.size + stored.written;
+        let cause = if last_file_info.is_some() { CopyCause::SizeChanged } else { CopyCause::New };
+        return Ok(Some(ProcessedFile { info, bytes, flags: vec![cause.flag()] }));
     }
 
-    let mut meta_file_handle = File::create(&new_meta_file)?;
-    current_file_info.write_to_file(&mut meta_file_handle)?;
+    let mut current_file_info = timings.time(Stage::Hash, || FileInfo::with_data(path, data, checksums))?;
+
+    let mut flags = Vec::new();
+    if is_filtered {
+        flags.push(FILTERED_FLAG.to_string());
+    }
+    if scan_file(data, &current_file_info.hash)? == ScanVerdict::Flagged {
+        if SCAN_HOOK_SKIP_FLAGGED {
+            warn!("Skipping flagged file {:?}", path);
+            return Ok(None);
+        }
+        flags.push("flagged".to_string());
+    }
+
+    // Create the new checkpoint directory and its meta directory if they don't exist
+    if let Some(parent) = new_meta_file.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
 
     // Check if the file exists in the last checkpoint and if it has changed
     // If the file exists in the last checkpoint and hasn't changed, skip copying only creating the meta file
     if let Some(last_info) = &last_file_info {
         if last_info.eq(&current_file_info) {
-            // No changes, skip copying
+            if HARDLINK_UNCHANGED {
+                // Make the checkpoint a complete browsable tree by linking the stored copy
+                let stored = last_info
+                    .stored_in
+                    .as_ref()
+                    .map(|name| backup_dir.join(name).join(rel))
+                    .filter(|stored| stored.is_file());
+                // When appending to a checkpoint the stored copy may already be this one
+                let linked = stored
+                    .is_some_and(|stored| stored == new_checkpoint_dir || fs::hard_link(&stored, new_checkpoint_dir).is_ok());
+                if !linked {
+                    // Legacy meta without a data pointer, or a different filesystem
+                    copy_file(data, new_checkpoint_dir, use_reflink)?;
+                    flags.push(CopyCause::NotLinked.flag());
+                }
+                current_file_info.stored_in = Some(checkpoint_name.to_string());
+                let mut meta_file_handle = File::create(&new_meta_file)?;
+                current_file_info.write_to_file(&mut meta_file_handle)?;
+                info!("{} unchanged {:?}", if linked { "Linked" } else { "Copied" }, path);
+                let bytes = current_file_info.size;
+                return Ok(Some(ProcessedFile { info: current_file_info, bytes, flags }));
+            }
+
+            // No changes, skip copying; the data stays where the last checkpoint found it
+            current_file_info.stored_in = last_info.stored_in.clone();
+            current_file_info.packed = last_info.packed.clone();
+            current_file_info.seekable = last_info.seekable.clone();
+            let mut meta_file_handle = File::create(&new_meta_file)?;
+            current_file_info.write_to_file(&mut meta_file_handle)?;
             info!("No changes for {:?}", path);
-            return Ok(());
+            let bytes = current_file_info.size;
+            return Ok(Some(ProcessedFile { info: current_file_info, bytes, flags }));
         }
     }
 
     // If the file doesn't exist in the last checkpoint or has changed, copy it
     // Copy the file to the new checkpoint directory
+    let cause = match &last_file_info {
+        None => CopyCause::New,
+        Some(last) if last.size != current_file_info.size => CopyCause::SizeChanged,
+        Some(last) if last.time_stamp == current_file_info.time_stamp => CopyCause::ContentChangedInPlace,
+        Some(_) => CopyCause::ContentChanged,
+    };
+    flags.push(cause.flag());
+    current_file_info.stored_in = Some(checkpoint_name.to_string());
+    if packs.accepts(current_file_info.size) {
+        let (packed, copied) = timings.time(Stage::Write, || packs.add(data))?;
+        run.throttle(copied);
+        info!("Packed {:?} -> {}", path, packed);
+        current_file_info.packed = Some(packed);
+        let mut meta_file_handle = File::create(&new_meta_file)?;
+        current_file_info.write_to_file(&mut meta_file_handle)?;
+        let bytes = current_file_info.size + copied;
+        return Ok(Some(ProcessedFile { info: current_file_info, bytes, flags }));
+    }
+    if seekable::accepts(current_file_info.size) {
+        let (index, written) = timings.time(Stage::Compress, || seekable::compress(data, new_checkpoint_dir))?;
+        run.throttle(written);
+        info!("Compressed {:?} -> {:?} ({} frames)", path, new_checkpoint_dir, index.frames.len());
+        current_file_info.seekable = Some(index);
+        let mut meta_file_handle = File::create(&new_meta_file)?;
+        current_file_info.write_to_file(&mut meta_file_handle)?;
+        let bytes = current_file_info.size + written;
+        return Ok(Some(ProcessedFile { info: current_file_info, bytes, flags }));
+    }
+    let mut meta_file_handle = File::create(&new_meta_file)?;
+    current_file_info.write_to_file(&mut meta_file_handle)?;
     info!("Copied {:?} -> {:?}", path, new_checkpoint_dir);
-    fs::copy(path, new_checkpoint_dir)?;
+    let copied = timings.time(Stage::Write, || match snapshot {
+        Some(snapshot) => snapshot.persist(new_checkpoint_dir),
+        None => copy_file(path, new_checkpoint_dir, use_reflink),
+    })?;
+    run.throttle(copied);
 
-    Ok(())
+    let bytes = current_file_info.size + copied;
+    Ok(Some(ProcessedFile { info: current_file_info, bytes, flags }))
+}
+
+// Whether `e` just means `path` was deleted after it was listed
+fn vanished(e: &io::Error, path: &Path) -> bool {
+    TOLERATE_VANISHED_FILES
+        && e.kind() == io::ErrorKind::NotFound
+        && fs::symlink_metadata(path).is_err()
+        && safety::check_source().is_ok()
+}
+
+// Whether `e` means the run isn't permitted to read something it may skip
+fn unreadable(e: &io::Error) -> bool {
+    SKIP_UNREADABLE_FILES && e.kind() == io::ErrorKind::PermissionDenied
+}
+
+// Place of `rel` in `priority_paths` (PRIORITY_PATHS): the first pattern
+// matching it or a directory above it, or that may match below it; after all
+// of them if none does
+pub fn priority_rank(priority_paths: &[String], rel: &Path) -> usize {
+    priority_paths
+        .iter()
+        .position(|pattern| {
+            (pattern.contains('/') && may_match_below(pattern, rel))
+                || rel
+                    .ancestors()
+                    .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                    .any(|ancestor| path_matches(pattern, ancestor))
+        })
+        .unwrap_or(priority_paths.len())
+}
+
+// Ids of the accounts `names` in /etc/passwd or /etc/group
+fn account_ids(names: &[String], database: &str) -> Vec<u32> {
+    let entries = fs::read_to_string(database).unwrap_or_default();
+    names
+        .iter()
+        .filter_map(|name| {
+            let id = name.parse().ok().or_else(|| {
+                entries.lines().find_map(|line| {
+                    let fields: Vec<&str> = line.split(':').collect();
+                    (fields.first() == Some(&name.as_str())).then(|| fields.get(2)?.parse().ok()).flatten()
+                })
+            });
+            if id.is_none() {
+                warn!("Unknown account {:?} in {}, not ignoring it", name, database);
+            }
+            id
+        })
+        .collect()
+}
+
+// One walk of the source for a run: the settings deciding what is backed up,
+// with the numeric ids of IGNORE_OWNERS and IGNORE_GROUPS looked up once, and
+// the checkpoints and run the files are scheduled for
+struct Walk<'a> {
+    config: &'a Config,
+    ignored_owners: Vec<u32>,
+    ignored_groups: Vec<u32>,
+    last_checkpoint: &'a Path,
+    new_checkpoint: &'a Path,
+    run: &'a RunGuard,
+    progress: &'a Progress,
+}
+
+impl<'a> Walk<'a> {
+    fn new(
+        config: &'a Config,
+        last_checkpoint: &'a Path,
+        new_checkpoint: &'a Path,
+        run: &'a RunGuard,
+        progress: &'a Progress,
+    ) -> Self {
+        Self {
+            config,
+            ignored_owners: account_ids(&config.ignore_owners, "/etc/passwd"),
+            ignored_groups: account_ids(&config.ignore_groups, "/etc/group"),
+            last_checkpoint,
+            new_checkpoint,
+            run,
+            progress,
+        }
+    }
+
+    // Whether the file `rel` has to be backed up no matter what (CRITICAL_PATHS)
+    fn is_critical(&self, rel: &Path) -> bool {
+        self.config.critical_paths.iter().any(|pattern| path_matches(pattern, rel))
+    }
+
+    // Whether the directory `rel` may hold critical files
+    fn holds_critical(&self, rel: &Path) -> bool {
+        self.config.critical_paths.iter().any(|pattern| may_match_below(pattern, rel))
+    }
+
+    // Whether `rel` is backed up in allow-list mode (INCLUDE_ONLY): it or a
+    // directory above it is listed
+    fn included(&self, rel: &Path) -> bool {
+        let include_only = &self.config.include_only;
+        include_only.is_empty()
+            || rel
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| include_only.iter().any(|pattern| path_matches(pattern, ancestor)))
+    }
+
+    // Whether the walk has to look into the directory `rel` for listed paths
+    fn may_include(&self, rel: &Path) -> bool {
+        self.included(rel) || self.config.include_only.iter().any(|pattern| may_match_below(pattern, rel))
+    }
+
+    // Whether the directory at `path` is one of IGNORE_DIRS
+    fn ignored_dir(&self, path: &Path) -> bool {
+        let name = path.file_name().and_then(|name| name.to_str());
+        self.config.ignore_dirs.iter().any(|ignore| name == Some(ignore.as_str()))
+    }
+
+    // Whether the file's owner or group is ignored
+    fn ignored_owner(&self, metadata: &fs::Metadata) -> bool {
+        self.ignored_owners.contains(&metadata.uid()) || self.ignored_groups.contains(&metadata.gid())
+    }
+
+    // A file matching TEMP_FILE_PATTERNS that was modified too recently to
+    // have been abandoned, so is likely still being written
+    fn in_progress(&self, rel: &Path, metadata: &fs::Metadata) -> bool {
+        if !self.config.temp_file_patterns.iter().any(|pattern| path_matches(pattern, rel)) {
+            return false;
+        }
+        let age = metadata.modified().ok().and_then(|modified| modified.elapsed().ok()).unwrap_or_default();
+        if age >= Duration::from_secs(self.config.temp_file_min_age_mins * 60) {
+            return false;
+        }
+        info!("Skipping {:?}: temporary file modified {} ago, still in progress", rel, format_duration(age));
+        true
+    }
+}
+
+// Every critical pattern has to match a file of the finished checkpoint, so a
+// critical directory that is gone altogether doesn't go unnoticed
+fn check_critical_coverage(critical_paths: &[String], checkpoint: &Path) -> Result<()> {
+    let mut unmatched: Vec<&str> = critical_paths.iter().map(String::as_str).collect();
+    if unmatched.is_empty() || !has_manifest(checkpoint) {
+        return Ok(());
+    }
+    for entry in read_entries(checkpoint) {
+        let entry = entry?;
+        unmatched.retain(|pattern| !path_matches(pattern, &entry.path));
+        if unmatched.is_empty() {
+            return Ok(());
+        }
+    }
+    let missing = io::Error::new(io::ErrorKind::NotFound, "no file in the backup matches it");
+    Err(Error::critical(Path::new(unmatched[0]), missing))
 }
 
+// What a run reads from the source
+pub enum Scope<'a> {
+    // The whole tree
+    Full,
+    // The whole tree, merged into the last checkpoint; its metadata is only
+    // replaced once the scan is through and nothing stopped the run
+    Append,
+    // Only the paths a change journal recorded; the rest is carried over
+    Journal(&'a JournalChanges),
+    // Only these paths relative to SRC_DIR, a stage of a staged backup
+    Subtrees(&'a [PathBuf]),
+}
+
+// A source file waiting to be hashed and, if changed, copied
+struct FileJob {
+    path: PathBuf,
+    rel: PathBuf,
+    size: u64,
+    mtime_ns: i128,
+    last_checkpoint_meta: Option<PathBuf>,
+    dest: PathBuf,
+}
+
+// The manifest entry an interrupted attempt of this run recorded for `job`,
+// if the source is unchanged since and the data it stored is complete
+fn already_done(done: &HashMap<PathBuf, (i128, ManifestEntry)>, job: &FileJob, new_checkpoint: &Path) -> Option<ManifestEntry> {
+    let (mtime_ns, entry) = done.get(&job.rel)?;
+    (*mtime_ns == job.mtime_ns && entry.size == job.size && stored_intact(new_checkpoint, entry)).then(|| entry.clone())
+}
+
+// Back up SRC_DIR into `new_checkpoint`. Returns false when the run stopped
+// scheduling files because it reached its time limit.
 pub fn traverse_backup(
-    dir: &Path,
-    last_checkpoint: &Path,
+    config: &Config,
+    last: &MetaExtractor,
     new_checkpoint: &Path,
-) -> io::Result<()> {
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
+    scope: Scope,
+    checksums: &ChecksumDb,
+    run: &RunGuard,
+    progress: &Progress,
+) -> Result<bool> {
+    let manifest = ManifestWriter::create(new_checkpoint)?;
+    let packs = PackWriter::new(new_checkpoint);
+    let prefetcher = Prefetcher::start();
+    let last_checkpoint = last.root();
+    let walk = Walk::new(config, last_checkpoint, new_checkpoint, run, progress);
+    let mut stopped = false;
+    // Dual-phase runs keep what an interrupted attempt already finished
+    let (done, done_journal) = if DUAL_PHASE {
+        (read_done(new_checkpoint)?, Some(DoneJournal::open(new_checkpoint)?))
+    } else {
+        (HashMap::new(), None)
+    };
+    if !done.is_empty() {
+        info!("Resuming interrupted run, {} files already done", format_count(done.len() as u64));
+    }
+    let last_state = LastState::load(last_checkpoint)?;
+
+    let result = run_adaptive(
+        |emit| {
+            let scan = |emit: &mut dyn FnMut(FileJob)| match scope {
+                Scope::Full | Scope::Append => walk_backup(&walk, config.src_dir(), emit),
+                Scope::Journal(journal) => replay_journal(&walk, journal, &manifest, emit),
+                Scope::Subtrees(subtrees) => {
+                    let roots: Vec<&PathBuf> = subtrees.iter().collect();
+                    walk_roots(&walk, &roots, emit)
+                }
+            };
+            // List everything and diff it against the last checkpoint before
+            // any data is read
+            let mut jobs = Vec::new();
+            scan(&mut |job: FileJob| {
+                progress.add_total(job.size);
+                last.request(job.rel.parent().unwrap_or(Path::new("")));
+                jobs.push(job);
+            })?;
+            progress.scan_complete();
+            let full_scan = matches!(scope, Scope::Full | Scope::Append);
+            let (planned, summary) = schedule(&config.priority_paths, jobs, &last_state, full_scan);
+            let new_files = planned.iter().filter(|(change, _)| *change == Change::New).map(|(_, job)| job.rel.as_path());
+            churn::check(&config.plugin_dir, new_checkpoint, &last_state, new_files, &summary)?;
+            if matches!(scope, Scope::Append) {
+                clear_metadata(new_checkpoint)?;
+                fs::create_dir_all(new_checkpoint.join(MANIFEST_DIR))?;
+            }
+            if DUAL_PHASE {
+                // Recorded before any data is read, so a killed run can resume
+                write_plan(new_checkpoint, planned.iter().map(|(_, job)| (job.rel.as_path(), job.size, job.mtime_ns)))?;
+            }
+            info!("Planned {} files: {}", format_count(planned.len() as u64), summary);
+            for (_, job) in planned {
+                if run.out_of_time() {
+                    break;
+                }
+                prefetcher.hint(job.path.clone());
+                if !emit(job) {
+                    break;
+                }
+            }
+            stopped = run.out_of_time();
+            Ok(())
+        },
+        |job: FileJob| {
+            run.pause_if_needed();
+            let parent = job.rel.parent().unwrap_or(Path::new(""));
+            if let Some(entry) = already_done(&done, &job, new_checkpoint) {
+                write_entry_meta(new_checkpoint, &entry)?;
+                manifest.add(&entry)?;
+                progress.finish_file(job.size);
+                last.release(parent);
+                return Ok(0);
+            }
+            progress.begin_file(&job.path);
+            let result = last
+                .wait(parent)
+                .map_err(io::Error::from)
+                .and_then(|()| {
+                    let timings = progress.timings();
+                    dealing_with_file(&job, new_checkpoint, checksums, &packs, timings, run, config.use_reflink)
+                });
+            progress.finish_file(job.size);
+            last.release(parent);
+            let result = match result {
+                Err(e) if walk.is_critical(&job.rel) => return Err(Error::critical(&job.rel, e).into()),
+                Err(e) if vanished(&e, &job.path) || unreadable(&e) || timeout::timed_out(&e) => {
+                    if vanished(&e, &job.path) {
+                        warn!("Skipping {:?}: it vanished before it could be read", job.path);
+                        progress.skip_vanished();
+                    } else if timeout::timed_out(&e) {
+                        warn!("Skipping {:?}: {}", job.path, e);
+                        progress.skip_timed_out(&job.rel);
+                    } else {
+                        warn!("Skipping {:?}: {}", job.path, e);
+                        progress.skip_unreadable();
+                    }
+                    // Don't leave a .meta pointing at data that was never copied
+                    let _ = fs::remove_file(meta_path(&job.dest));
+                    let _ = fs::remove_file(&job.dest);
+                    return Ok(0);
+                }
+                result => result,
+            };
+            let Some(processed) = result? else {
+                return Ok(job.size);
+            };
+            let entry = ManifestEntry {
+                path: job.rel,
+                size: processed.info.size,
+                hash: processed.info.hash,
+                time_stamp: processed.info.time_stamp.timestamp(),
+                stored_in: processed.info.stored_in,
+                birth_time: processed.info.birth_time,
+                packed: processed.info.packed,
+                seekable: processed.info.seekable,
+                flags: processed.flags,
+            };
+            manifest.add(&entry)?;
+            if let Some(done_journal) = &done_journal {
+                done_journal.record(job.mtime_ns, &entry)?;
+            }
+            Ok(processed.bytes)
+        },
+    )
+    .and_then(|()| packs.finish())
+    .and_then(|()| manifest.finish());
+    // Finished or failed, the run won't be resumed
+    if DUAL_PHASE {
+        plan::finish(new_checkpoint)?;
+    }
+    result?;
+    // Only a run that saw the whole tree can tell a critical path is missing
+    if !stopped && !matches!(scope, Scope::Subtrees(_)) {
+        check_critical_coverage(&config.critical_paths, new_checkpoint)?;
+    }
+    Ok(!stopped)
+}
+
+// Diff the scanned files against the last checkpoint and order them for
+// processing: by PRIORITY_PATHS, then unchanged files before the ones to
+// copy, and otherwise in the order the scan found them, directory by
+// directory. Unchanged files aren't copied, but they are hashed in full
+// unless CHECKSUM_DB has their hash, so they mustn't hold up prioritized
+// ones in a time-limited run.
+fn schedule(
+    priority_paths: &[String],
+    jobs: Vec<FileJob>,
+    last_state: &LastState,
+    full_scan: bool,
+) -> (Vec<(Change, FileJob)>, DiffSummary) {
+    let mut summary = DiffSummary::default();
+    let mut planned: Vec<(Change, FileJob)> = jobs
+        .into_iter()
+        .map(|job| {
+            let change = last_state.classify(&job.rel, job.size, job.mtime_ns);
+            summary.record(change, job.size);
+            (change, job)
+        })
+        .collect();
+    planned.sort_by_cached_key(|(change, job)| (priority_rank(priority_paths, &job.rel), *change != Change::Unchanged));
+    if full_scan {
+        summary.removed = last_state.removed(summary.unchanged.0 + summary.modified.0);
+    }
+    (planned, summary)
+}
+
+// Show what a backup of SRC_DIR would do without touching the repository: the
+// same scan and diff as a real run, with the scan's directories created in a
+// scratch directory that is removed afterwards
+pub fn dry_run(config: &Config, last_checkpoint: &Path, run: &RunGuard) -> Result<()> {
+    let scratch = std::env::temp_dir().join(format!("nas-backup-dry-run-{}", std::process::id()));
+    fs::create_dir_all(&scratch)?;
+    let progress = Progress::start(&scratch, "dry-run");
+    let mut jobs = Vec::new();
+    let walk = Walk::new(config, last_checkpoint, &scratch, run, &progress);
+    let result = walk_backup(&walk, config.src_dir(), &mut |job| jobs.push(job));
+    fs::remove_dir_all(&scratch)?;
+    result?;
+    let (planned, summary) = schedule(&config.priority_paths, jobs, &LastState::load(last_checkpoint)?, true);
+    for (change, job) in &planned {
+        let change = match change {
+            Change::New => "new",
+            Change::Modified => "modified",
+            Change::Unchanged => continue,
+        };
+        println!("{:<9} {:>10}  {}", change, format_size(job.size), job.rel.display());
+    }
+    println!("{} files: {}", format_count(planned.len() as u64), summary);
+    Ok(())
+}
+
+// Walk `dir`, part of the source tree at SRC_DIR
+fn walk_backup(walk: &Walk, dir: &Path, emit: &mut dyn FnMut(FileJob)) -> io::Result<()> {
+    let root = walk.config.src_dir();
+    // Keeps the io::Error kind, so vanished directories are still recognised
+    let traversal = |e| io::Error::from(Error::traversal(dir, e));
+    let mut entries: Vec<fs::DirEntry> = match fs::read_dir(dir) {
+        Err(e) if dir.strip_prefix(root).is_ok_and(|rel| walk.holds_critical(rel)) => {
+            return Err(Error::critical(dir, e).into());
+        }
+        entries => entries.map_err(traversal)?.collect::<io::Result<_>>().map_err(traversal)?,
+    };
+    // Prioritized paths are scanned first, in case the run runs out of time
+    let priority_paths = &walk.config.priority_paths;
+    if !priority_paths.is_empty() {
+        entries.sort_by_cached_key(|entry| {
+            priority_rank(priority_paths, entry.path().strip_prefix(root).unwrap_or(Path::new("")))
+        });
+    }
+    for entry in entries {
+        // Out of time: schedule nothing more, not even directories
+        if walk.run.out_of_time() {
+            return Ok(());
+        }
         let path = entry.path();
-        let ft = entry.file_type()?;
         let rel = path
-            .strip_prefix(SRC_DIR)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let dest = new_checkpoint.join(rel);
+            .strip_prefix(root)
+            .map_err(io::Error::other)?;
+        let ft = match entry.file_type() {
+            Err(e) if vanished(&e, &path) && !walk.is_critical(rel) => {
+                warn!("Skipping {:?}: it vanished while listing", path);
+                walk.progress.skip_vanished();
+                continue;
+            }
+            ft => ft?,
+        };
+        let dest = walk.new_checkpoint.join(rel);
 
         if ft.is_dir() {
-            if IGNORE_DIRS.iter().any(|ignore| path.file_name().and_then(|name| name.to_str()) == Some(ignore)) {
+            if walk.ignored_dir(&path) {
                 info!("Ignoring directory {:?}", path);
                 continue;
             }
+            if path.file_name().is_some_and(|name| name == META_DIR) {
+                warn!("Skipping {:?}: {} is reserved for backup metadata", path, META_DIR);
+                continue;
+            }
+            if !walk.may_include(rel) {
+                continue;
+            }
             // ensure the folder exists, then recurse
             fs::create_dir_all(&dest)?;
-            traverse_backup(&path, last_checkpoint, new_checkpoint)?;
-        } else if ft.is_file() {
-            // ensure parent dirs exist, then copy
-            if let Some(parent) = dest.parent() {
-                fs::create_dir_all(parent)?;
+            match walk_backup(walk, &path, emit) {
+                Err(e) if vanished(&e, &path) && !walk.holds_critical(rel) => {
+                    warn!("Skipping directory {:?}: it vanished while listing", path);
+                    walk.progress.skip_vanished();
+                }
+                Err(e) if unreadable(&e) && !walk.holds_critical(rel) => {
+                    warn!("Skipping directory {:?}: {}", path, e);
+                    walk.progress.skip_unreadable();
+                }
+                result => result?,
             }
-            let last_checkpoint_meta = if !last_checkpoint.as_os_str().is_empty() {
-                Some(last_checkpoint.join(rel).with_extension("meta"))
-            } else {
-                None
+        } else if ft.is_file() {
+            let metadata = match entry.metadata() {
+                Err(e) if vanished(&e, &path) && !walk.is_critical(rel) => {
+                    warn!("Skipping {:?}: it vanished while listing", path);
+                    walk.progress.skip_vanished();
+                    continue;
+                }
+                metadata => metadata?,
             };
-            dealing_with_file(&path, &last_checkpoint_meta, &dest)?;
+            if !walk.included(rel)
+                || (!walk.is_critical(rel) && (walk.ignored_owner(&metadata) || walk.in_progress(rel, &metadata)))
+            {
+                continue;
+            }
+            emit(file_job(path.clone(), rel, &metadata, walk.last_checkpoint, dest)?);
         }
     }
     Ok(())
 }
 
-pub fn traverse_meta(checkpoint: &Path) -> io::Result<()> {
-    for entry in fs::read_dir(checkpoint)? {
+fn file_job(
+    path: PathBuf,
+    rel: &Path,
+    metadata: &fs::Metadata,
+    last_checkpoint: &Path,
+    dest: PathBuf,
+) -> io::Result<FileJob> {
+    // ensure parent dirs exist, then hand the file to the workers
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let last_checkpoint_meta = if !last_checkpoint.as_os_str().is_empty() {
+        Some(existing_meta_path(&last_checkpoint.join(rel)))
+    } else {
+        None
+    };
+    Ok(FileJob {
+        size: metadata.len(),
+        mtime_ns: mtime_ns(metadata),
+        rel: rel.to_path_buf(),
+        path,
+        last_checkpoint_meta,
+        dest,
+    })
+}
+
+// The .meta for a manifest entry taken over unchanged from another checkpoint
+fn write_entry_meta(new_checkpoint: &Path, entry: &ManifestEntry) -> io::Result<()> {
+    let meta_path = meta_path(&new_checkpoint.join(&entry.path));
+    if let Some(parent) = meta_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut info = FileInfo::new(entry.size, entry.hash.clone(), chrono::DateTime::from_timestamp(entry.time_stamp, 0));
+    info.stored_in = entry.stored_in.clone();
+    info.birth_time = entry.birth_time;
+    info.packed = entry.packed.clone();
+    info.seekable = entry.seekable.clone();
+    info.write_to_file(&mut File::create(&meta_path)?)
+}
+
+// After a run stopped at its time limit, take over the entries of the last
+// checkpoint it never reached, so the partial checkpoint is still a complete
+// tree: new data where the run got to, the previous state everywhere else.
+// Files since deleted from the source are left out, unless the whole source
+// went missing. Returns the number of entries carried over.
+pub fn carry_over_unreached(
+    src_dir: &Path,
+    last_checkpoint: &Path,
+    new_checkpoint: &Path,
+    source_lost: bool,
+) -> Result<usize> {
+    if last_checkpoint.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    if !has_manifest(last_checkpoint) {
+        warn!("{:?} has no manifest; files this run didn't reach are missing from it", last_checkpoint);
+        return Ok(0);
+    }
+    let reached = read_entries(new_checkpoint)
+        .map(|entry| entry.map(|entry| entry.path))
+        .collect::<io::Result<HashSet<PathBuf>>>()?;
+    let mut carried = Vec::new();
+    for entry in read_entries(last_checkpoint) {
         let entry = entry?;
+        let deleted = !source_lost && fs::symlink_metadata(src_dir.join(&entry.path)).is_err();
+        if reached.contains(&entry.path) || deleted {
+            continue;
+        }
+        write_entry_meta(new_checkpoint, &entry)?;
+        carried.push(entry);
+    }
+    let count = carried.len();
+    upsert_entries(new_checkpoint, carried)?;
+    Ok(count)
+}
+
+// Produce jobs from a change journal instead of walking the tree: entries of
+// the last checkpoint outside the changed paths are carried over as they are,
+// and only the changed paths themselves are hashed and copied.
+fn replay_journal(
+    walk: &Walk,
+    journal: &JournalChanges,
+    manifest: &ManifestWriter,
+    emit: &mut dyn FnMut(FileJob),
+) -> io::Result<()> {
+    let changed = |rel: &Path| rel.ancestors().any(|a| journal.paths.contains(a));
+
+    let mut carried = 0;
+    for entry in read_entries(&journal.baseline) {
+        let entry = entry?;
+        if changed(&entry.path) {
+            continue;
+        }
+        write_entry_meta(walk.new_checkpoint, &entry)?;
+        manifest.add(&entry)?;
+        carried += 1;
+    }
+    info!("Carried over {} unchanged entries from {:?}", carried, journal.baseline);
+
+    // Outermost changed paths only; anything below them is covered by the walk
+    let mut roots: Vec<&PathBuf> = journal
+        .paths
+        .iter()
+        .filter(|rel| !rel.ancestors().skip(1).any(|a| journal.paths.contains(a)))
+        .collect();
+    roots.sort();
+    walk_roots(walk, &roots, emit)
+}
+
+// Walk the given paths relative to SRC_DIR, files and directories alike
+fn walk_roots(walk: &Walk, roots: &[&PathBuf], emit: &mut dyn FnMut(FileJob)) -> io::Result<()> {
+    for rel in roots {
+        let path = walk.config.src_dir().join(rel);
+        let dest = walk.new_checkpoint.join(rel);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            // Deleted since the last backup, so it's simply not carried over
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            if !walk.may_include(rel) {
+                continue;
+            }
+            fs::create_dir_all(&dest)?;
+            walk_backup(walk, &path, emit)?;
+        } else if metadata.is_file()
+            && walk.included(rel)
+            && (walk.is_critical(rel) || !(walk.ignored_owner(&metadata) || walk.in_progress(rel, &metadata)))
+        {
+            emit(file_job(path, rel, &metadata, walk.last_checkpoint, dest)?);
+        }
+    }
+    Ok(())
+}
+
+// Write metadata for the files below `checkpoint` into the matching
+// directories below `out`, which is `checkpoint` itself unless the data is on
+// a read-only volume
+pub fn traverse_meta(
+    checkpoint: &Path,
+    out: &Path,
+    ignore_dirs: &[String],
+    checksums: &ChecksumDb,
+    run: &RunGuard,
+) -> Result<()> {
+    // Metadata generated by older versions sits between the data files
+    let legacy = is_legacy_dir(checkpoint);
+    if legacy {
+        warn!(
+            "{:?} has metadata in the legacy layout; its .meta files are taken for metadata, not data",
+            checkpoint
+        );
+    }
+    for entry in fs::read_dir(checkpoint).map_err(|e| Error::traversal(checkpoint, e))? {
+        let entry = entry.map_err(|e| Error::traversal(checkpoint, e))?;
         let path = entry.path();
         let ft = entry.file_type()?;
         if ft.is_dir() {
-            if IGNORE_DIRS.iter().any(|ignore| path.file_name().and_then(|name| name.to_str()) == Some(ignore)) {
+            if ignore_dirs.iter().any(|ignore| path.file_name().and_then(|name| name.to_str()) == Some(ignore.as_str())) {
                 info!("Ignoring directory {:?}", path);
                 continue;
             }
-            traverse_meta(&path)?;
+            if path.file_name().is_some_and(|name| name == MANIFEST_DIR || name == META_DIR || name == PACK_DIR) {
+                info!("Skipping metadata {:?}", path);
+                continue;
+            }
+            traverse_meta(&path, &out.join(entry.file_name()), ignore_dirs, checksums, run)?;
         } else if ft.is_file() {
-            if path.extension().and_then(|ext| ext.to_str()) == Some("meta") {
-                info!("Skipping meta file {:?}", path);
+            if legacy && path.extension().and_then(|ext| ext.to_str()) == Some("meta") {
+                info!("Skipping legacy meta file {:?}", path);
+                continue;
+            }
+            if legacy && path.file_name().is_some_and(|name| name == COMPRESS_FILE_NAME) {
+                info!("Skipping legacy {:?}", path);
                 continue;
             }
-            if path.file_name().and_then(|name| name.to_str()) == Some("meta_files.zip") {
-                info!("Skipping meta_files.zip {:?}", path);
+            let reserved = [CHECKPOINT_INFO_NAME, RUN_REPORT_NAME, HTML_REPORT_NAME, BACKEND_CHECKSUMS_NAME];
+            if path.file_name().is_some_and(|name| reserved.iter().any(|n| name == *n)) {
                 continue;
             }
-            let current_file_info = FileInfo::from_path(&path)?;
-            let new_meta_file = path.with_extension("meta");
+            run.pause_if_needed();
+            let current_file_info = FileInfo::with_data(&path, &path, checksums)?;
+            let new_meta_file = meta_path(&out.join(entry.file_name()));
+            if let Some(parent) = new_meta_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
 
             let mut meta_file_handle = File::create(&new_meta_file)?;
             current_file_info.write_to_file(&mut meta_file_handle)?;
@@ -215,3 +1077,106 @@ pub fn traverse_meta(checkpoint: &Path) -> io::Result<()> {
     }
     Ok(())
 }
+
+// Recompute metadata for the files stored under `subtree` of a checkpoint,
+// e.g. after fixing some by hand. Only metas whose content hash changed are
+// rewritten unless `force` is set. Returns the number of metas rewritten.
+pub fn regenerate_meta(
+    checkpoint: &Path,
+    subtree: &Path,
+    ignore_dirs: &[String],
+    force: bool,
+    run: &RunGuard,
+) -> Result<usize> {
+    let root = checkpoint.join(subtree);
+    if !root.is_dir() {
+        return Err(Error::traversal(
+            &root,
+            io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not a directory in {:?}", subtree, checkpoint)),
+        ));
+    }
+    ensure_unlocked(checkpoint)?;
+    let checkpoint_name = checkpoint
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut updated = Vec::new();
+    let dirs = WalkDir::new(&root).into_iter().filter_entry(|e| {
+        e.file_name() != MANIFEST_DIR
+            && e.file_name() != META_DIR
+            && e.file_name() != PACK_DIR
+            && !ignore_dirs.iter().any(|ignore| e.file_name().to_str() == Some(ignore.as_str()))
+    });
+    for dir in dirs.filter_map(|e| e.ok()).filter(|e| e.file_type().is_dir()) {
+        // Legacy directories keep their layout; everything else uses META_DIR
+        let legacy = is_legacy_dir(dir.path());
+        let zip = meta_zip(dir.path()).unwrap_or_else(|| dir.path().join(META_DIR).join(COMPRESS_FILE_NAME));
+        let mut metas = if zip.exists() { read_zip_metas(&zip)? } else { Vec::new() };
+        let mut changed = false;
+        let reserved = [CHECKPOINT_INFO_NAME, RUN_REPORT_NAME, HTML_REPORT_NAME, BACKEND_CHECKSUMS_NAME];
+
+        for entry in fs::read_dir(dir.path()).map_err(|e| Error::traversal(dir.path(), e))? {
+            let entry = entry.map_err(|e| Error::traversal(dir.path(), e))?;
+            let path = entry.path();
+            let name = entry.file_name();
+            if !entry.file_type()?.is_file()
+                || (legacy && (path.extension().is_some_and(|ext| ext == "meta") || name == COMPRESS_FILE_NAME))
+                || reserved.iter().any(|n| name == *n)
+            {
+                continue;
+            }
+            run.pause_if_needed();
+            let meta_name = meta_name(&name, legacy);
+            let existing = metas.iter().position(|meta| meta.name == meta_name);
+            let current = existing.map(|i| FileInfo::parse(&metas[i].content)).transpose()?;
+
+            let mut info = match current.as_ref().and_then(|current| current.seekable.clone()) {
+                Some(index) => FileInfo::from_seekable(&path, index)?,
+                None => FileInfo::from_path(&path)?,
+            };
+            if let Some(current) = current.as_ref().filter(|current| **current == info) {
+                if !force {
+                    continue;
+                }
+                info.time_stamp = current.time_stamp;
+            }
+            // The stored copy was created by the backup; only the source's
+            // creation time is worth keeping
+            info.birth_time = current.and_then(|current| current.birth_time);
+            // The data file is physically here, so this checkpoint holds it
+            info.stored_in = Some(checkpoint_name.clone());
+            let content = info.to_meta_string();
+            match existing {
+                Some(i) => metas[i].content = content,
+                None => metas.push(MetaEntry::new(meta_name, content)),
+            }
+            changed = true;
+            info!("Regenerated meta for {:?}", path);
+
+            updated.push(ManifestEntry {
+                path: path.strip_prefix(checkpoint).map_err(io::Error::other)?.to_path_buf(),
+                size: info.size,
+                hash: info.hash,
+                time_stamp: info.time_stamp.timestamp(),
+                stored_in: info.stored_in,
+                birth_time: info.birth_time,
+                packed: None,
+                seekable: info.seekable,
+                flags: Vec::new(),
+            });
+        }
+        if changed {
+            if let Some(parent) = zip.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_zip_metas(&zip, &metas)?;
+        }
+    }
+
+    let count = updated.len();
+    if has_manifest(checkpoint) && !updated.is_empty() {
+        upsert_entries(checkpoint, updated)?;
+    }
+    Ok(count)
+}
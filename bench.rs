@@ -0,0 +1,175 @@
+use log::info;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+use crate::backup_utils::compute_xxhash_with;
+use crate::config::{HASH_BUFFER_SIZE, MAX_JOBS};
+use crate::human::{format_count, format_duration, format_rate, format_size};
+use crate::settings::Config;
+
+// How much source data to sample, and how many directory entries to stat
+const SAMPLE_BYTES: u64 = 256 * 1024 * 1024;
+const SAMPLE_ENTRIES: usize = 20_000;
+// Size of the file written to and read back from the target
+const WRITE_BYTES: usize = 64 * 1024 * 1024;
+// Small files created, stat'ed and deleted on the target
+const METADATA_FILES: usize = 500;
+const BUFFER_SIZES: [usize; 4] = [4 * 1024, 64 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+const WORKER_COUNTS: [usize; 5] = [1, 2, 4, 8, 16];
+
+struct Sample {
+    files: Vec<PathBuf>,
+    bytes: u64,
+    entries: usize,
+    walk_time: Duration,
+}
+
+// Walk the source (read-only) collecting files to hash, timing the metadata ops
+fn sample_source(src: &Path, ignore_dirs: &[String]) -> io::Result<Sample> {
+    let started = Instant::now();
+    let mut sample = Sample { files: Vec::new(), bytes: 0, entries: 0, walk_time: Duration::ZERO };
+    let walker = WalkDir::new(src)
+        .into_iter()
+        .filter_entry(|e| !ignore_dirs.iter().any(|ignore| e.file_name().to_str() == Some(ignore.as_str())));
+    for entry in walker.filter_map(|e| e.ok()) {
+        let metadata = entry.metadata().map_err(io::Error::other)?;
+        sample.entries += 1;
+        if metadata.is_file() && sample.bytes < SAMPLE_BYTES {
+            sample.bytes += metadata.len();
+            sample.files.push(entry.into_path());
+        }
+        if sample.entries >= SAMPLE_ENTRIES {
+            break;
+        }
+    }
+    sample.walk_time = started.elapsed();
+    Ok(sample)
+}
+
+// Hash the sampled files with `workers` threads, returning the elapsed time
+fn hash_sample(files: &[PathBuf], workers: usize, buffer_size: usize) -> io::Result<Duration> {
+    let next = AtomicUsize::new(0);
+    let started = Instant::now();
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        compute_xxhash_with(file, buffer_size)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|h| h.join().unwrap_or_else(|_| Err(io::Error::other("Bench worker panicked"))))
+    })?;
+    Ok(started.elapsed())
+}
+
+fn ops_rate(ops: usize, elapsed: Duration) -> String {
+    format!("{}/s", format_count((ops as f64 / elapsed.as_secs_f64().max(1e-9)) as u64))
+}
+
+// Measure the throughput of the stages a backup is made of and print a report
+// with suggested settings.
+pub fn run_bench(config: &Config) -> io::Result<()> {
+    let (src, backup_dir) = (config.src_dir(), config.backup_dir());
+    info!("Benchmarking source {:?} and target {:?}", src, backup_dir);
+    let sample = sample_source(src, &config.ignore_dirs)?;
+    if sample.files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No files to sample in {:?}", src)));
+    }
+    println!("Source sample: {} files, {}", format_count(sample.files.len() as u64), format_size(sample.bytes));
+    println!(
+        "  metadata:   {} entries in {} ({})",
+        format_count(sample.entries as u64),
+        format_duration(sample.walk_time),
+        ops_rate(sample.entries, sample.walk_time)
+    );
+
+    // The first pass reads from disk; later passes mostly from the page cache
+    let cold = hash_sample(&sample.files, 1, HASH_BUFFER_SIZE)?;
+    println!("  hash (cold): {}", format_rate(sample.bytes, cold));
+
+    let mut best_buffer = (HASH_BUFFER_SIZE, Duration::MAX);
+    for size in BUFFER_SIZES {
+        let elapsed = hash_sample(&sample.files, 1, size)?;
+        println!("  hash, {} buffer: {}", format_size(size as u64), format_rate(sample.bytes, elapsed));
+        if elapsed < best_buffer.1 {
+            best_buffer = (size, elapsed);
+        }
+    }
+
+    let mut best_workers = (1, Duration::MAX);
+    for workers in WORKER_COUNTS {
+        let elapsed = hash_sample(&sample.files, workers, best_buffer.0)?;
+        println!("  hash, {} workers: {}", workers, format_rate(sample.bytes, elapsed));
+        // Only count more workers as better when they gain at least 10%
+        if elapsed.as_secs_f64() < best_workers.1.as_secs_f64() * 0.9 {
+            best_workers = (workers, elapsed);
+        }
+    }
+
+    let scratch = backup_dir.join(format!(".bench-{}", std::process::id()));
+    fs::create_dir_all(&scratch)?;
+    let result = bench_target(&scratch);
+    fs::remove_dir_all(&scratch)?;
+    let write = result?;
+
+    println!("Suggested settings:");
+    println!("  HASH_BUFFER_SIZE = {}", best_buffer.0);
+    println!("  MAX_JOBS = {} (currently {})", best_workers.0, MAX_JOBS);
+    let cold_rate = sample.bytes as f64 / cold.as_secs_f64();
+    let write_rate = WRITE_BYTES as f64 / write.as_secs_f64();
+    if write_rate < cold_rate {
+        println!("  The target writes slower than the source reads; it limits backups of changed data");
+    }
+    if sample.entries as f64 / sample.walk_time.as_secs_f64() < 2000.0 {
+        println!("  Source metadata is slow; raise PREFETCH_AHEAD or use USE_CHANGE_JOURNAL with `watch`");
+    }
+    Ok(())
+}
+
+// Sequential write (with fsync), read-back and small-file metadata ops on the
+// target. Returns the write time.
+fn bench_target(scratch: &Path) -> io::Result<Duration> {
+    println!("Target:");
+    let path = scratch.join("data");
+    let chunk = vec![0x5au8; 1024 * 1024];
+    let started = Instant::now();
+    let mut file = File::create(&path)?;
+    for _ in 0..WRITE_BYTES / chunk.len() {
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+    let write = started.elapsed();
+    println!("  write: {}", format_rate(WRITE_BYTES as u64, write));
+
+    let started = Instant::now();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut file = File::open(&path)?;
+    while file.read(&mut buffer)? > 0 {}
+    let read = started.elapsed();
+    println!("  read:  {} (likely cached)", format_rate(WRITE_BYTES as u64, read));
+
+    let started = Instant::now();
+    for i in 0..METADATA_FILES {
+        fs::write(scratch.join(format!("small-{}", i)), b"x")?;
+    }
+    for i in 0..METADATA_FILES {
+        fs::metadata(scratch.join(format!("small-{}", i)))?;
+    }
+    for i in 0..METADATA_FILES {
+        fs::remove_file(scratch.join(format!("small-{}", i)))?;
+    }
+    let elapsed = started.elapsed();
+    println!("  metadata: {} create/stat/delete", ops_rate(METADATA_FILES * 3, elapsed));
+    Ok(write)
+}
@@ -0,0 +1,442 @@
+use log::info;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::{list_checkpoints, write_atomic};
+use crate::manifest::{has_manifest, read_entries};
+use crate::pattern::path_matches;
+
+// Browsing index for the shell and searches across checkpoints, so they
+// don't read the manifests of every checkpoint. All checkpoints share one
+// trie of path names, NODES_FILE: records of parent node (u32), kind (0
+// directory, 1 file), name length (u16) and name, little endian, numbered
+// from 1 in file order; node 0 is the root. Nodes are only ever appended,
+// so their numbers stay valid. Each checkpoint then has a zstd-compressed
+// "<checkpoint>.bits": the node count, a bitmap of the nodes it contains
+// (files and their directories) and the sizes of those nodes in order as
+// LEB128, where a directory's size is everything below it.
+const INDEX_DIR: &str = ".browse";
+const NODES_FILE: &str = "nodes.bin";
+const BITS_EXT: &str = "bits";
+const ROOT: u32 = 0;
+const RECORD_HEADER: usize = 7;
+
+pub struct Tree {
+    parents: Vec<u32>,
+    files: Vec<bool>,
+    // Names of all nodes back to back; node n ends at name_ends[n]
+    names: Vec<u8>,
+    name_ends: Vec<usize>,
+    // Children of node n, sorted by name and kind: children[child_starts[n]..child_starts[n + 1]]
+    child_starts: Vec<usize>,
+    children: Vec<u32>,
+}
+
+impl Tree {
+    fn empty() -> Self {
+        Self {
+            parents: vec![ROOT],
+            files: vec![false],
+            names: Vec::new(),
+            name_ends: vec![0],
+            child_starts: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    // The tree and the length of NODES_FILE it was read from. A record cut
+    // short by an interrupted update is left out.
+    fn load(backup_dir: &Path) -> io::Result<(Self, u64)> {
+        let mut tree = Self::empty();
+        let data = match fs::read(backup_dir.join(INDEX_DIR).join(NODES_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut pos = 0;
+        while pos + RECORD_HEADER <= data.len() {
+            let parent = u32::from_le_bytes(data[pos..pos + 4].try_into().expect("4 bytes"));
+            let file = data[pos + 4] == 1;
+            let len = u16::from_le_bytes(data[pos + 5..pos + 7].try_into().expect("2 bytes")) as usize;
+            let name = pos + RECORD_HEADER;
+            if name + len > data.len() {
+                break;
+            }
+            if parent as usize >= tree.parents.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Browsing index is corrupt; run `index build`"));
+            }
+            tree.push(parent, file, &data[name..name + len]);
+            pos = name + len;
+        }
+        tree.link();
+        Ok((tree, pos as u64))
+    }
+
+    fn push(&mut self, parent: u32, file: bool, name: &[u8]) -> u32 {
+        self.parents.push(parent);
+        self.files.push(file);
+        self.names.extend_from_slice(name);
+        self.name_ends.push(self.names.len());
+        (self.parents.len() - 1) as u32
+    }
+
+    // Group the nodes by parent
+    fn link(&mut self) {
+        let count = self.parents.len();
+        let mut starts = vec![0; count + 1];
+        for &parent in &self.parents[1..] {
+            starts[parent as usize + 1] += 1;
+        }
+        for n in 0..count {
+            starts[n + 1] += starts[n];
+        }
+        let mut next = starts.clone();
+        let mut children = vec![ROOT; count - 1];
+        for id in 1..count as u32 {
+            let parent = self.parents[id as usize] as usize;
+            children[next[parent]] = id;
+            next[parent] += 1;
+        }
+        for n in 0..count {
+            children[starts[n]..starts[n + 1]].sort_by(|&a, &b| self.key(a).cmp(&self.key(b)));
+        }
+        self.child_starts = starts;
+        self.children = children;
+    }
+
+    fn key(&self, id: u32) -> (&[u8], bool) {
+        (self.name(id), self.files[id as usize])
+    }
+
+    pub fn name(&self, id: u32) -> &[u8] {
+        let id = id as usize;
+        let start = if id == 0 { 0 } else { self.name_ends[id - 1] };
+        &self.names[start..self.name_ends[id]]
+    }
+
+    pub fn is_file(&self, id: u32) -> bool {
+        self.files[id as usize]
+    }
+
+    // Nodes added since the tree was loaded have none yet
+    pub fn children(&self, id: u32) -> &[u32] {
+        match self.child_starts.get(id as usize..id as usize + 2) {
+            Some(&[start, end]) => &self.children[start..end],
+            _ => &[],
+        }
+    }
+
+    fn child(&self, parent: u32, name: &[u8], file: bool) -> Option<u32> {
+        let children = self.children(parent);
+        children.binary_search_by(|&id| self.key(id).cmp(&(name, file))).ok().map(|i| children[i])
+    }
+
+    // The node of a directory or file path relative to the checkpoint
+    pub fn find(&self, rel: &Path, file: bool) -> Option<u32> {
+        let names: Vec<&OsStr> = rel.iter().collect();
+        if file && names.is_empty() {
+            return None;
+        }
+        let mut id = ROOT;
+        for (i, name) in names.iter().enumerate() {
+            id = self.child(id, name.as_bytes(), file && i == names.len() - 1)?;
+        }
+        Some(id)
+    }
+
+    pub fn path(&self, mut id: u32) -> PathBuf {
+        let mut names = Vec::new();
+        while id != ROOT {
+            names.push(OsStr::from_bytes(self.name(id)));
+            id = self.parents[id as usize];
+        }
+        names.iter().rev().collect()
+    }
+}
+
+// The nodes of one checkpoint
+pub struct View {
+    bits: Vec<u64>,
+    // Set bits in the words before each word
+    ranks: Vec<u32>,
+    sizes: Vec<u64>,
+}
+
+impl View {
+    pub fn contains(&self, id: u32) -> bool {
+        let id = id as usize;
+        self.bits.get(id / 64).is_some_and(|word| word & (1 << (id % 64)) != 0)
+    }
+
+    pub fn size(&self, id: u32) -> Option<u64> {
+        if !self.contains(id) {
+            return None;
+        }
+        let id = id as usize;
+        let below = self.bits[id / 64] & ((1 << (id % 64)) - 1);
+        self.sizes.get(self.ranks[id / 64] as usize + below.count_ones() as usize).copied()
+    }
+}
+
+fn bits_path(backup_dir: &Path, checkpoint: &str) -> PathBuf {
+    backup_dir.join(INDEX_DIR).join(format!("{}.{}", checkpoint, BITS_EXT))
+}
+
+fn invalid_bits(checkpoint: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Browsing index of {} is corrupt; run `index build`", checkpoint))
+}
+
+fn write_view(backup_dir: &Path, checkpoint: &str, present: &[bool], sizes: &[u64]) -> io::Result<()> {
+    let mut data = (present.len() as u32).to_le_bytes().to_vec();
+    let mut bitmap = vec![0u8; present.len().div_ceil(8)];
+    for (id, _) in present.iter().enumerate().filter(|(_, &present)| present) {
+        bitmap[id / 8] |= 1 << (id % 8);
+    }
+    data.extend_from_slice(&bitmap);
+    for (id, _) in present.iter().enumerate().filter(|(_, &present)| present) {
+        let mut size = sizes[id];
+        loop {
+            let byte = (size & 0x7f) as u8;
+            size >>= 7;
+            if size == 0 {
+                data.push(byte);
+                break;
+            }
+            data.push(byte | 0x80);
+        }
+    }
+    write_atomic(&bits_path(backup_dir, checkpoint), &zstd::bulk::compress(&data, 3)?)
+}
+
+// The view of a checkpoint, or None if it isn't indexed
+fn read_view(backup_dir: &Path, checkpoint: &str) -> io::Result<Option<View>> {
+    let compressed = match fs::read(bits_path(backup_dir, checkpoint)) {
+        Ok(compressed) => compressed,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let data = zstd::stream::decode_all(compressed.as_slice())?;
+    let count = u32::from_le_bytes(data.get(..4).ok_or_else(|| invalid_bits(checkpoint))?.try_into().expect("4 bytes"));
+    let bitmap = data.get(4..4 + (count as usize).div_ceil(8)).ok_or_else(|| invalid_bits(checkpoint))?;
+    let bits: Vec<u64> = bitmap
+        .chunks(8)
+        .map(|chunk| chunk.iter().rev().fold(0, |word, &byte| word << 8 | byte as u64))
+        .collect();
+    let mut ranks = Vec::with_capacity(bits.len());
+    let mut set = 0;
+    for word in &bits {
+        ranks.push(set);
+        set += word.count_ones();
+    }
+    let mut sizes = Vec::with_capacity(set as usize);
+    let (mut size, mut shift) = (0u64, 0);
+    for &byte in &data[4 + bitmap.len()..] {
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            sizes.push(size);
+            (size, shift) = (0, 0);
+        }
+    }
+    if sizes.len() != set as usize {
+        return Err(invalid_bits(checkpoint));
+    }
+    Ok(Some(View { bits, ranks, sizes }))
+}
+
+// Add a checkpoint to the index, from its manifest. Paths new to the
+// repository are appended to the trie; checkpoints without a manifest (from
+// before manifests existed) are left out.
+pub fn update(backup_dir: &Path, checkpoint: &Path) -> io::Result<()> {
+    if !has_manifest(checkpoint) {
+        return Ok(());
+    }
+    let name = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    fs::create_dir_all(backup_dir.join(INDEX_DIR))?;
+    // Held until the end, so concurrent updates don't number nodes twice
+    let mut nodes_file =
+        OpenOptions::new().create(true).append(true).open(backup_dir.join(INDEX_DIR).join(NODES_FILE))?;
+    if unsafe { libc::flock(nodes_file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut tree, valid) = Tree::load(backup_dir)?;
+    if nodes_file.metadata()?.len() > valid {
+        nodes_file.set_len(valid)?;
+    }
+
+    let mut added: HashMap<(u32, bool, Vec<u8>), u32> = HashMap::new();
+    let mut records = Vec::new();
+    let mut present = vec![false; tree.parents.len()];
+    let mut sizes = vec![0u64; tree.parents.len()];
+    for entry in read_entries(checkpoint) {
+        let entry = entry?;
+        let names: Vec<&OsStr> = entry.path.iter().collect();
+        let mut id = ROOT;
+        present[0] = true;
+        sizes[0] += entry.size;
+        for (i, name) in names.iter().enumerate() {
+            let (name, file) = (name.as_bytes(), i == names.len() - 1);
+            id = match tree.child(id, name, file) {
+                Some(child) => child,
+                None => *added.entry((id, file, name.to_vec())).or_insert_with(|| {
+                    records.extend_from_slice(&id.to_le_bytes());
+                    records.push(file as u8);
+                    records.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                    records.extend_from_slice(name);
+                    present.push(false);
+                    sizes.push(0);
+                    tree.push(id, file, name)
+                }),
+            };
+            present[id as usize] = true;
+            sizes[id as usize] += entry.size;
+        }
+    }
+    nodes_file.write_all(&records)?;
+    write_view(backup_dir, &name, &present, &sizes)?;
+    info!("Indexed {} for browsing ({} new paths)", name, added.len());
+    Ok(())
+}
+
+// Index every checkpoint that isn't yet and drop those deleted since
+pub fn build(backup_dir: &Path) -> io::Result<()> {
+    let checkpoints = list_checkpoints(backup_dir)?;
+    let names: Vec<String> = checkpoints
+        .iter()
+        .map(|checkpoint| checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string())
+        .collect();
+    for (checkpoint, name) in checkpoints.iter().zip(&names) {
+        if !bits_path(backup_dir, name).exists() {
+            update(backup_dir, checkpoint)?;
+        }
+    }
+    if let Ok(entries) = fs::read_dir(backup_dir.join(INDEX_DIR)) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let stale = path.extension().is_some_and(|ext| ext == BITS_EXT)
+                && path.file_stem().is_some_and(|stem| !names.iter().any(|name| stem == name.as_str()));
+            if stale {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Keep the index in step with a checkpoint being renamed or deleted
+pub fn rename(backup_dir: &Path, from: &str, to: &str) -> io::Result<()> {
+    match fs::rename(bits_path(backup_dir, from), bits_path(backup_dir, to)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn forget(backup_dir: &Path, checkpoint: &str) -> io::Result<()> {
+    match fs::remove_file(bits_path(backup_dir, checkpoint)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// The trie with the views of checkpoints loaded as they are asked for
+pub struct Index {
+    backup_dir: PathBuf,
+    tree: Tree,
+    views: HashMap<String, Option<View>>,
+}
+
+impl Index {
+    // None if nothing was indexed yet
+    pub fn open(backup_dir: &Path) -> io::Result<Option<Self>> {
+        if !backup_dir.join(INDEX_DIR).join(NODES_FILE).exists() {
+            return Ok(None);
+        }
+        let (tree, _) = Tree::load(backup_dir)?;
+        Ok(Some(Self { backup_dir: backup_dir.to_path_buf(), tree, views: HashMap::new() }))
+    }
+
+    // The trie with the view of a checkpoint, if it is indexed
+    pub fn view(&mut self, checkpoint: &str) -> io::Result<Option<(&Tree, &View)>> {
+        if !self.views.contains_key(checkpoint) {
+            let view = read_view(&self.backup_dir, checkpoint)?;
+            self.views.insert(checkpoint.to_string(), view);
+        }
+        Ok(self.views[checkpoint].as_ref().map(|view| (&self.tree, view)))
+    }
+
+    // The files of a checkpoint at or below `prefix`, or None if the
+    // checkpoint isn't indexed
+    pub fn files_below(&mut self, checkpoint: &str, prefix: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+        let Some((tree, view)) = self.view(checkpoint)? else {
+            return Ok(None);
+        };
+        let mut files = Vec::new();
+        let mut pending: Vec<u32> = [tree.find(prefix, true), tree.find(prefix, false)]
+            .into_iter()
+            .flatten()
+            .filter(|&id| id == ROOT || view.contains(id))
+            .collect();
+        while let Some(id) = pending.pop() {
+            if tree.is_file(id) {
+                files.push(tree.path(id));
+            } else {
+                pending.extend(tree.children(id).iter().filter(|&&child| view.contains(child)));
+            }
+        }
+        Ok(Some(files))
+    }
+
+    // Files matching `pattern` (see pattern.rs) in any indexed checkpoint,
+    // with the checkpoints holding them, oldest first
+    pub fn search(&mut self, pattern: &str) -> io::Result<Vec<(PathBuf, Vec<String>)>> {
+        let mut checkpoints = Vec::new();
+        for checkpoint in list_checkpoints(&self.backup_dir)? {
+            let name = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if self.view(&name)?.is_some() {
+                checkpoints.push(name);
+            }
+        }
+        let views: Vec<(&String, &View)> =
+            checkpoints.iter().filter_map(|name| Some((name, self.views[name].as_ref()?))).collect();
+        let mut found = Vec::new();
+        for id in 1..self.tree.parents.len() as u32 {
+            if !self.tree.is_file(id) {
+                continue;
+            }
+            // Only path patterns need the whole path
+            let path = if pattern.contains('/') {
+                self.tree.path(id)
+            } else {
+                PathBuf::from(OsStr::from_bytes(self.tree.name(id)))
+            };
+            if !path_matches(pattern, &path) {
+                continue;
+            }
+            let holding: Vec<String> =
+                views.iter().filter(|(_, view)| view.contains(id)).map(|(name, _)| name.to_string()).collect();
+            if !holding.is_empty() {
+                found.push((self.tree.path(id), holding));
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+}
+
+// Print the files matching `pattern` across checkpoints: path, number of
+// checkpoints, first and last of them
+pub fn print_search(index: &mut Index, pattern: &str) -> io::Result<()> {
+    let found = index.search(pattern)?;
+    for (path, checkpoints) in &found {
+        let first = checkpoints.first().map_or("", String::as_str);
+        let last = checkpoints.last().map_or("", String::as_str);
+        println!("{}\t{} checkpoints\t{} .. {}", path.display(), checkpoints.len(), first, last);
+    }
+    info!("{} files match {:?}", found.len(), pattern);
+    Ok(())
+}
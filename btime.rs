@@ -0,0 +1,34 @@
+use std::fs::{self, File};
+use std::io;
+use std::time::UNIX_EPOCH;
+
+// Creation (birth) time of a file in Unix seconds. std reads it through statx
+// on Linux and st_birthtime on macOS and the BSDs; None where the filesystem
+// doesn't record one (ext3, most network filesystems) or the kernel is too old.
+pub fn birth_time(metadata: &fs::Metadata) -> Option<i64> {
+    let created = metadata.created().ok()?;
+    match created.duration_since(UNIX_EPOCH) {
+        Ok(after) => i64::try_from(after.as_secs()).ok(),
+        Err(before) => i64::try_from(before.duration().as_secs()).ok().map(|secs| -secs),
+    }
+}
+
+// Set the creation time of an open file. Only macOS lets it be set; Linux has
+// no call for it, so there Ok(false) tells the caller it was left alone.
+#[cfg(target_os = "macos")]
+pub fn restore_birth_time(file: &File, secs: i64) -> io::Result<bool> {
+    use std::os::macos::fs::FileTimesExt;
+    use std::time::Duration;
+    let time = if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    };
+    file.set_times(fs::FileTimes::new().set_created(time))?;
+    Ok(true)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn restore_birth_time(_file: &File, _secs: i64) -> io::Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,149 @@
+use log::{info, warn};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use zip::write::{FileOptions, ZipWriter};
+use zip::ZipArchive;
+
+use crate::checkpoint::{resolve_checkpoint, resolve_files};
+use crate::manifest::{escape_path, name_string, unescape_path};
+use crate::priority::RunGuard;
+use crate::settings::Config;
+use crate::status::Progress;
+use crate::verify::{already_restored, restore_verified};
+
+// Bundle layout: the selected files at their checkpoint-relative paths (so a
+// plain `unzip` restores them) plus one manifest entry describing each file.
+// Zip entry names have to be UTF-8, so the manifest holds the exact paths,
+// escaped as in checkpoint manifests; bundles from before that lack the
+// ESCAPED_PATHS line and name their files by entry name.
+pub const BUNDLE_MANIFEST_NAME: &str = ".nbk-manifest";
+const ESCAPED_PATHS: &str = "paths\tescaped";
+
+// Zip entry name of the file at `rel`
+fn entry_name(rel: &Path) -> String {
+    rel.iter().map(name_string).collect::<Vec<_>>().join("/")
+}
+
+pub fn create_bundle(
+    backup_dir: &Path,
+    checkpoint_name: &str,
+    paths: &[String],
+    out: &Path,
+    run: &RunGuard,
+    progress: &Progress,
+) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, checkpoint_name)?;
+    let files = resolve_files(backup_dir, &checkpoint)?;
+    let checkpoint_name = checkpoint
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut zip = ZipWriter::new(File::create(out)?);
+    let options = FileOptions::<()>::default().large_file(true);
+    let mut manifest = format!("checkpoint\t{}\n{}\n", checkpoint_name, ESCAPED_PATHS);
+    let mut count = 0;
+
+    let selected: Vec<_> = files
+        .iter()
+        .filter(|(rel, _)| paths.is_empty() || paths.iter().any(|p| rel.starts_with(p.trim_end_matches('/'))))
+        .collect();
+    for (_, file) in &selected {
+        progress.add_total(file.info.size);
+    }
+    progress.scan_complete();
+
+    for (rel, file) in selected {
+        run.pause_if_needed();
+        progress.begin_file(rel);
+        zip.start_file(entry_name(rel), options)?;
+        io::copy(&mut run.throttled(file.open()?), &mut zip)?;
+        manifest.push_str(&format!("{}\t{}\t{}\n", file.info.hash, file.info.size, escape_path(rel)));
+        progress.finish_file(file.info.size);
+        count += 1;
+    }
+
+    if count == 0 {
+        warn!("No files in {:?} matched {:?}", checkpoint, paths);
+    }
+
+    zip.start_file(BUNDLE_MANIFEST_NAME, options)?;
+    zip.write_all(manifest.as_bytes())?;
+    zip.finish()?;
+    info!("Bundled {} files from {:?} into {:?}", count, checkpoint, out);
+    Ok(())
+}
+
+// Files an earlier, interrupted extraction restored already are kept
+pub fn extract_bundle(
+    config: &Config,
+    bundle: &Path,
+    dest: &Path,
+    run: &RunGuard,
+    progress: &Progress,
+) -> io::Result<()> {
+    let mut archive = ZipArchive::new(File::open(bundle)?)?;
+
+    let mut manifest = String::new();
+    archive
+        .by_name(BUNDLE_MANIFEST_NAME)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Missing bundle manifest"))?
+        .read_to_string(&mut manifest)?;
+
+    let mut entries = Vec::new();
+    let mut escaped = false;
+    for line in manifest.lines() {
+        if line == ESCAPED_PATHS {
+            escaped = true;
+            continue;
+        }
+        let mut fields = line.splitn(3, '\t');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("checkpoint"), Some(name), None) => info!("Bundle from checkpoint {}", name),
+            (Some(hash), Some(size), Some(name)) => {
+                progress.add_total(size.parse().unwrap_or(0));
+                entries.push((hash.to_string(), size.parse().unwrap_or(0), name.to_string()));
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid bundle manifest")),
+        }
+    }
+
+    progress.scan_complete();
+
+    let (mut corrupted, mut kept) = (0, 0);
+    for (hash, size, name) in &entries {
+        run.pause_if_needed();
+        let unsafe_path = || io::Error::new(io::ErrorKind::InvalidData, format!("Unsafe path in bundle: {}", name));
+        let (zip_file, rel) = if escaped {
+            let rel = unescape_path(name);
+            if rel.as_os_str().is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(unsafe_path());
+            }
+            (archive.by_name(&entry_name(&rel))?, rel)
+        } else {
+            let zip_file = archive.by_name(name)?;
+            let rel: PathBuf = zip_file.enclosed_name().ok_or_else(unsafe_path)?;
+            (zip_file, rel)
+        };
+        let out_path = dest.join(rel);
+        progress.begin_file(&out_path);
+        if already_restored(&out_path, *size, hash) {
+            kept += 1;
+        } else if restore_verified(config, &mut run.throttled(zip_file), &out_path, hash, |_| Ok(()))? {
+            info!("Extracted: {}", out_path.display());
+        } else {
+            corrupted += 1;
+        }
+        progress.finish_file(*size);
+    }
+
+    if corrupted > 0 {
+        warn!("{} files failed verification", corrupted);
+    }
+    if kept > 0 {
+        info!("{} files were already extracted", kept);
+    }
+    info!("Extracted {} files into '{}'", entries.len(), dest.display());
+    Ok(())
+}
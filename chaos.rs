@@ -0,0 +1,92 @@
+use log::{error, warn};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Hidden testing mode (`--chaos <rate>` with any command, `--chaos-seed <n>`
+// to replay a run): every write into the repository fails, stalls, or is cut
+// short followed by a crash with probability <rate>, so tests can check that
+// interrupted and failed runs leave the repository consistent. Off unless
+// enabled; without it the hooks below cost one atomic load.
+static RATE_PPM: AtomicU64 = AtomicU64::new(0);
+static RNG: AtomicU64 = AtomicU64::new(0);
+
+const MAX_DELAY_MS: u64 = 500;
+
+enum Fault {
+    Fail,
+    Delay,
+    Crash,
+}
+
+pub fn enable(rate: f64, seed: u64) {
+    RATE_PPM.store((rate.clamp(0.0, 1.0) * 1_000_000.0) as u64, Ordering::Relaxed);
+    RNG.store(seed, Ordering::Relaxed);
+    warn!("Chaos mode: injecting storage faults at rate {} (seed {})", rate, seed);
+}
+
+// splitmix64 over a shared counter
+fn next_random() -> u64 {
+    let mut z = RNG.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn roll() -> Option<Fault> {
+    let rate = RATE_PPM.load(Ordering::Relaxed);
+    if rate == 0 || next_random() % 1_000_000 >= rate {
+        return None;
+    }
+    Some(match next_random() % 3 {
+        0 => Fault::Fail,
+        1 => Fault::Delay,
+        _ => Fault::Crash,
+    })
+}
+
+fn crash(op: &str) -> ! {
+    error!("Chaos: crashing during {}", op);
+    std::process::abort()
+}
+
+// Before a step that changes the repository (a rename, a copy): fail it,
+// stall it or crash instead
+pub fn point(op: &str) -> io::Result<()> {
+    match roll() {
+        None => Ok(()),
+        Some(Fault::Fail) => Err(io::Error::other(format!("Chaos: injected failure in {}", op))),
+        Some(Fault::Delay) => {
+            thread::sleep(Duration::from_millis(next_random() % MAX_DELAY_MS));
+            Ok(())
+        }
+        Some(Fault::Crash) => crash(op),
+    }
+}
+
+// Write all of `buf`, unless chaos fails or stalls the write, or writes only
+// part of it and crashes
+pub fn write_all(writer: &mut impl Write, buf: &[u8], op: &str) -> io::Result<()> {
+    match roll() {
+        Some(Fault::Crash) => {
+            let cut = next_random() as usize % (buf.len() + 1);
+            let _ = writer.write_all(&buf[..cut]).and_then(|_| writer.flush());
+            crash(op)
+        }
+        Some(Fault::Fail) => Err(io::Error::other(format!("Chaos: injected failure in {}", op))),
+        Some(Fault::Delay) => {
+            thread::sleep(Duration::from_millis(next_random() % MAX_DELAY_MS));
+            writer.write_all(buf)
+        }
+        None => writer.write_all(buf),
+    }
+}
+
+// fs::write with the faults of write_all
+pub fn write_file(path: &Path, contents: &[u8], op: &str) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    write_all(&mut file, contents, op)
+}
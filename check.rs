@@ -0,0 +1,211 @@
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backup_utils::{compute_xxhash_like, hash_reader};
+use crate::checkpoint::read_chain_entries;
+use crate::manifest::{has_manifest, name_string, read_entries, root_hash, saved_shard_hashes, shard_hashes};
+use crate::config::HASH_BUFFER_SIZE;
+use crate::hashing::Scheme;
+use crate::pack::open_packed;
+use crate::plugins;
+use crate::seekable;
+use crate::priority::RunGuard;
+use crate::settings::Config;
+use crate::zip_handler::{is_legacy_dir, meta_name, meta_zips};
+
+// Compare each checkpoint's manifest against the root hash recorded for it in
+// the chain. Only manifests are read, never metas or data. Returns the number
+// of problems found.
+fn check_roots(backup_dir: &Path) -> io::Result<usize> {
+    let mut problems = 0;
+    for (name, root) in read_chain_entries(backup_dir)? {
+        let checkpoint = backup_dir.join(&name);
+        if !checkpoint.is_dir() {
+            error!("Checkpoint {} is in the chain but missing", name);
+            problems += 1;
+            continue;
+        }
+        let Some(root) = root else {
+            if has_manifest(&checkpoint) {
+                warn!("No root hash recorded for {}, skipping", name);
+            }
+            continue;
+        };
+        if !has_manifest(&checkpoint) {
+            error!("Manifest of {} is missing", name);
+            problems += 1;
+            continue;
+        }
+        let shards = shard_hashes(&checkpoint)?;
+        if root_hash(&shards) == root {
+            continue;
+        }
+        problems += 1;
+        // The saved shard hashes narrow the damage down, as long as they
+        // themselves still match the root
+        match saved_shard_hashes(&checkpoint)? {
+            Some(saved) if root_hash(&saved) == root => {
+                for (shard, (current, saved)) in shards.iter().zip(&saved).enumerate() {
+                    if current != saved {
+                        error!("Manifest shard {:02x} of {} does not match its recorded hash", shard, name);
+                    }
+                }
+            }
+            _ => error!("Manifest of {} does not match the root hash in the chain", name),
+        }
+    }
+    Ok(problems)
+}
+
+// Re-hash the data each checkpoint stores itself and compare with its manifest
+fn check_data(backup_dir: &Path, run: &RunGuard) -> io::Result<usize> {
+    let mut problems = 0;
+    for (name, _) in read_chain_entries(backup_dir)? {
+        let checkpoint = backup_dir.join(&name);
+        if !has_manifest(&checkpoint) {
+            continue;
+        }
+        for entry in read_entries(&checkpoint) {
+            let entry = entry?;
+            if entry.stored_in.as_deref() != Some(name.as_str()) {
+                continue;
+            }
+            run.pause_if_needed();
+            let hash = match (&entry.packed, &entry.seekable) {
+                (Some(packed), _) => open_packed(&packed.path(&checkpoint), packed.offset, entry.size)
+                    .and_then(|mut data| hash_reader(&mut data, HASH_BUFFER_SIZE, Scheme::of(&entry.hash))),
+                (None, Some(_)) => seekable::open(&checkpoint.join(&entry.path))
+                    .and_then(|mut data| hash_reader(&mut data, HASH_BUFFER_SIZE, Scheme::of(&entry.hash))),
+                (None, None) => compute_xxhash_like(&checkpoint.join(&entry.path), &entry.hash).map_err(io::Error::from),
+            };
+            match hash {
+                Ok(hash) if hash == entry.hash => {}
+                Ok(_) => {
+                    error!("Hash mismatch for {:?} in {}", entry.path, name);
+                    problems += 1;
+                }
+                Err(e) => {
+                    error!("Cannot read {:?} in {}: {}", entry.path, name, e);
+                    problems += 1;
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+// Directories still in the legacy layout keep "<stem>.meta" next to the data,
+// so data files named *.meta and files sharing a stem (a.txt, a.pdf) get
+// mixed up with, or share, a meta. Such directories are only reported, as
+// checkpoints written since use the sidecar layout. Returns the number of
+// directories affected.
+fn check_legacy_layout(backup_dir: &Path) -> io::Result<usize> {
+    let mut affected = 0;
+    for (name, _) in read_chain_entries(backup_dir)? {
+        let checkpoint = backup_dir.join(&name);
+        for zip in meta_zips(&checkpoint) {
+            let Some(dir) = zip.parent().filter(|dir| is_legacy_dir(dir)) else {
+                continue;
+            };
+            let mut by_meta: HashMap<String, Vec<String>> = HashMap::new();
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() || entry.path() == zip {
+                    continue;
+                }
+                let file_name = entry.file_name();
+                by_meta.entry(meta_name(&file_name, true)).or_default().push(name_string(&file_name));
+            }
+            let mut collisions: Vec<String> = by_meta
+                .into_iter()
+                .filter(|(meta, files)| files.len() > 1 || files.contains(meta))
+                .map(|(meta, mut files)| {
+                    files.sort();
+                    format!("{} ({})", meta, files.join(", "))
+                })
+                .collect();
+            if collisions.is_empty() {
+                continue;
+            }
+            collisions.sort();
+            warn!(
+                "{:?} uses the legacy meta layout and these metas are ambiguous: {}",
+                dir,
+                collisions.join("; ")
+            );
+            affected += 1;
+        }
+    }
+    Ok(affected)
+}
+
+// Validate the repository. `quick` limits the check to the manifest root
+// hashes in the chain; otherwise stored data is re-hashed as well.
+// Compare the checksums backends attest to for their copies with the ones
+// they reported when storing them. Nothing is downloaded or hashed locally.
+// Returns the number of problems found.
+fn check_backends(backup_dir: &Path, plugin_dir: &Path) -> io::Result<usize> {
+    let backends = plugins::attesting_backends(plugin_dir)?;
+    if backends.is_empty() {
+        warn!("No backend plugin can attest to its copies");
+        return Ok(0);
+    }
+    let (mut problems, mut attested) = (0, 0);
+    for (name, _) in read_chain_entries(backup_dir)? {
+        let recorded = plugins::recorded_checksums(&backup_dir.join(&name))?;
+        for plugin in &backends {
+            let Some(expected) = recorded.get(&plugin.name) else {
+                continue;
+            };
+            let current = match plugin.attest(&name) {
+                Ok(current) => current,
+                Err(e) => {
+                    error!("{}", e);
+                    problems += 1;
+                    continue;
+                }
+            };
+            for (path, checksum) in expected {
+                match current.get(path) {
+                    Some(current) if current == checksum => attested += 1,
+                    Some(current) => {
+                        error!("{}: {} now has {} for {:?}, stored {}", name, plugin.name, current, path, checksum);
+                        problems += 1;
+                    }
+                    None => {
+                        error!("{}: {:?} is missing from {}", name, path, plugin.name);
+                        problems += 1;
+                    }
+                }
+            }
+        }
+    }
+    info!("Backends attested to {} stored objects", attested);
+    Ok(problems)
+}
+
+pub fn check_repository(config: &Config, quick: bool, backends: bool, run: &RunGuard) -> io::Result<()> {
+    let backup_dir = config.backup_dir();
+    let mut problems = check_roots(backup_dir)?;
+    let legacy = check_legacy_layout(backup_dir)?;
+    if legacy > 0 {
+        warn!("{} directories in the legacy layout have ambiguous metas", legacy);
+    }
+    if !quick {
+        problems += check_data(backup_dir, run)?;
+    }
+    if backends {
+        problems += check_backends(backup_dir, &config.plugin_dir)?;
+    }
+    if problems > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Check of {:?} found {} problems", backup_dir, problems),
+        ));
+    }
+    info!("Check of {:?} passed", backup_dir);
+    Ok(())
+}
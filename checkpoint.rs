@@ -0,0 +1,892 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::backup_utils::FileInfo;
+use crate::browse;
+use crate::chaos;
+use crate::clock;
+use crate::config::{CHECKPOINT_NAME, COMPRESS_FILE_NAME};
+use crate::history::{RunReport, RUN_REPORT_NAME};
+use crate::manifest::{
+    has_manifest, lookup, lookup_all, read_entries, rewrite_manifest_stored_in, root_hash, save_shard_hashes, shard_hashes,
+    ManifestEntry, MANIFEST_DIR,
+};
+use crate::pack::{open_packed, PACK_DIR};
+use crate::plan::{PLAN_DONE_NAME, PLAN_NAME};
+use crate::plugins::BACKEND_CHECKSUMS_NAME;
+use crate::report::HTML_REPORT_NAME;
+use crate::seekable;
+use crate::zip_handler::{meta_zips, read_zip_metas, rewrite_zip_stored_in, META_DIR};
+
+// Creation order of checkpoints, one name per line. Needed once checkpoints
+// can be renamed, since names then no longer sort chronologically.
+const CHAIN_FILE: &str = ".chain";
+// Per-checkpoint details such as annotations, stored inside the checkpoint
+pub const CHECKPOINT_INFO_NAME: &str = ".checkpoint.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CheckpointInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<String>,
+    // Retention lock (YYYY-MM-DD): the checkpoint may not be deleted or
+    // rewritten before this date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<String>,
+    // Binary version and repository format the checkpoint was written with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<u32>,
+    // Written by a run that stopped at its time limit or lost its source;
+    // files it didn't reach keep their state from the checkpoint before
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    // The run that picked up where this partial one stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continued_by: Option<String>,
+    // Snapshot of another backup tool the checkpoint was converted from, as
+    // "<tool>:<id>"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported_from: Option<String>,
+    // Mount point the source was on, so the next run notices it missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_mount: Option<String>,
+}
+
+impl CheckpointInfo {
+    pub fn lock_date(&self) -> io::Result<Option<chrono::NaiveDate>> {
+        self.locked_until
+            .as_deref()
+            .map(parse_lock_date)
+            .transpose()
+    }
+
+    pub fn load(checkpoint: &Path) -> io::Result<Self> {
+        match fs::read(checkpoint.join(CHECKPOINT_INFO_NAME)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, checkpoint: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        write_atomic(&checkpoint.join(CHECKPOINT_INFO_NAME), &json)
+    }
+}
+
+// Replace a file via a temporary sibling and rename, so readers see either
+// the old or the new contents but never a partial write.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    chaos::write_file(Path::new(&tmp), contents, "write_atomic")?;
+    chaos::point("write_atomic rename")?;
+    fs::rename(&tmp, path)
+}
+
+// Checkpoint references (latest, chain, data pointers) are stored as bare names
+// so a repository keeps working after being moved to another disk or mount
+// point. Older or hand-edited files may hold absolute paths instead; only their
+// last component is meaningful.
+pub fn checkpoint_ref(reference: &str) -> &str {
+    let path = Path::new(reference);
+    if path.is_absolute() {
+        path.file_name().and_then(|name| name.to_str()).unwrap_or(reference)
+    } else {
+        reference
+    }
+}
+
+// Each chain line is a checkpoint name, optionally followed by a tab and the
+// root hash of its manifest (see `record_root`).
+pub fn read_chain_entries(backup_dir: &Path) -> io::Result<Vec<(String, Option<String>)>> {
+    match fs::read_to_string(backup_dir.join(CHAIN_FILE)) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(|line| match line.split_once('\t') {
+                Some((name, root)) => (name, Some(root.to_string()).filter(|r| !r.is_empty())),
+                None => (line, None),
+            })
+            .map(|(name, root)| (checkpoint_ref(name).to_string(), root))
+            .filter(|(name, _)| !name.is_empty())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_chain(backup_dir: &Path) -> io::Result<Vec<String>> {
+    Ok(read_chain_entries(backup_dir)?.into_iter().map(|(name, _)| name).collect())
+}
+
+fn write_chain(backup_dir: &Path, chain: &[(String, Option<String>)]) -> io::Result<()> {
+    let mut content = String::new();
+    for (name, root) in chain {
+        content.push_str(name);
+        if let Some(root) = root {
+            content.push('\t');
+            content.push_str(root);
+        }
+        content.push('\n');
+    }
+    write_atomic(&backup_dir.join(CHAIN_FILE), content.as_bytes())
+}
+
+// Record a newly completed checkpoint as the newest link of the chain
+pub fn append_to_chain(backup_dir: &Path, name: &str) -> io::Result<()> {
+    let mut chain = read_chain_entries(backup_dir)?;
+    chain.retain(|(existing, _)| existing != name);
+    chain.push((name.to_string(), None));
+    write_chain(backup_dir, &chain)?;
+    record_root(backup_dir, &backup_dir.join(name))
+}
+
+// Drop a deleted checkpoint from the chain
+pub fn remove_from_chain(backup_dir: &Path, name: &str) -> io::Result<()> {
+    let mut chain = read_chain_entries(backup_dir)?;
+    chain.retain(|(existing, _)| existing != name);
+    write_chain(backup_dir, &chain)
+}
+
+// Store the root hash of a checkpoint's manifest in its chain entry, with the
+// shard hashes it was computed from kept in the manifest directory. Anything
+// that rewrites a manifest calls this afterwards, so a root that no longer
+// matches means the manifest was changed or damaged behind our back.
+pub fn record_root(backup_dir: &Path, checkpoint: &Path) -> io::Result<()> {
+    if !has_manifest(checkpoint) {
+        return Ok(());
+    }
+    let name = checkpoint.file_name().unwrap_or_default().to_string_lossy();
+    let mut chain = read_chain_entries(backup_dir)?;
+    let Some(entry) = chain.iter_mut().find(|(existing, _)| *existing == name) else {
+        return Ok(());
+    };
+    let shards = shard_hashes(checkpoint)?;
+    save_shard_hashes(checkpoint, &shards)?;
+    entry.1 = Some(root_hash(&shards));
+    write_chain(backup_dir, &chain)
+}
+
+// All checkpoint directories under the backup root, oldest first.
+// Checkpoints predating the chain file are ordered by their timestamp names
+// and come before the ones recorded in the chain.
+pub fn list_checkpoints(backup_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let chain = read_chain(backup_dir)?;
+    let mut checkpoints: Vec<PathBuf> = fs::read_dir(backup_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .collect();
+    checkpoints.sort_by_cached_key(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        (chain.iter().position(|c| *c == name), name)
+    });
+    Ok(checkpoints)
+}
+
+// The chain from the oldest checkpoint up to and including `checkpoint`
+pub fn checkpoints_up_to(backup_dir: &Path, checkpoint: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut checkpoints = list_checkpoints(backup_dir)?;
+    if let Some(pos) = checkpoints.iter().position(|c| c.file_name() == checkpoint.file_name()) {
+        checkpoints.truncate(pos + 1);
+    }
+    Ok(checkpoints)
+}
+
+const MAX_NAME_SUFFIX: u32 = 1000;
+
+// Atomically create the directory for a new checkpoint. If `proposed` is
+// taken (two runs in the same second, or the clock jumped back onto an old
+// name) a counter suffix is appended; an existing checkpoint is never reused.
+pub fn claim_checkpoint_name(backup_dir: &Path, proposed: &str) -> io::Result<String> {
+    if let Some(newest) = list_checkpoints(backup_dir)?.last().and_then(|c| c.file_name()) {
+        let newest = newest.to_string_lossy();
+        if let (Some(proposed_at), Some(newest_at)) = (created_at(proposed), created_at(&newest)) {
+            if proposed_at < newest_at {
+                warn!("Clock appears to have gone backwards: {} is older than newest checkpoint {}", proposed, newest);
+            }
+        }
+    }
+
+    for suffix in 0..MAX_NAME_SUFFIX {
+        let name = if suffix == 0 {
+            proposed.to_string()
+        } else {
+            format!("{}_{}", proposed, suffix)
+        };
+        match fs::create_dir(backup_dir.join(&name)) {
+            Ok(()) => return Ok(name),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!("Checkpoint {} already exists, trying another name", name);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free checkpoint name for {}", proposed),
+    ))
+}
+
+fn parse_lock_date(date: &str) -> io::Result<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid date {:?}, expected YYYY-MM-DD", date))
+    })
+}
+
+// Guard for every code path that deletes or rewrites a checkpoint
+pub fn ensure_unlocked(checkpoint: &Path) -> io::Result<()> {
+    if let Some(until) = CheckpointInfo::load(checkpoint)?.lock_date()? {
+        if clock::local_now().date_naive() < until {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Checkpoint {:?} is retention-locked until {}", checkpoint, until),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Creation time encoded in a generated checkpoint name ("%Y-%m-%d_%H-%M_%S",
+// UTC, possibly with a "_N" suffix); None for renamed checkpoints.
+fn created_at(name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let (stamp, suffix) = name.split_at_checked(19)?;
+    // The counter of a name claimed when the plain one was taken
+    if !suffix.is_empty() && suffix.strip_prefix('_').is_none_or(|n| n.parse::<u32>().is_err()) {
+        return None;
+    }
+    chrono::NaiveDateTime::parse_from_str(stamp, "%Y-%m-%d_%H-%M_%S")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+// When the state a checkpoint holds was taken: the start of the last run
+// that wrote it, which for a checkpoint later runs were merged into is the
+// newest of them, else the time in its name
+pub fn taken_at(checkpoint: &Path) -> Option<chrono::DateTime<chrono::Utc>> {
+    let report = fs::read(checkpoint.join(RUN_REPORT_NAME))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<RunReport>(&json).ok());
+    report
+        .and_then(|report| chrono::DateTime::parse_from_rfc3339(&report.started_at).ok())
+        .map(|time| time.to_utc())
+        .or_else(|| created_at(&checkpoint.file_name()?.to_string_lossy()))
+}
+
+// A point in time given on the command line: RFC 3339, or local time as
+// "YYYY-MM-DD HH:MM[:SS]" (or with a "T"), or a date, meaning the end of
+// that day
+pub fn parse_point_in_time(text: &str) -> io::Result<chrono::DateTime<chrono::Utc>> {
+    use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Ok(time.to_utc());
+    }
+    let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(23, 59, 59));
+    local
+        .and_then(|time| Local.from_local_datetime(&time).latest())
+        .map(|time| time.to_utc())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid time {:?}, expected e.g. 2024-01-15, \"2024-01-15 14:30\" or RFC 3339", text),
+            )
+        })
+}
+
+// The newest checkpoint taken at or before `at`, i.e. the tree as it was then.
+// Every checkpoint of the chain is looked at, as times needn't rise along it
+// when a clock was set back between runs.
+pub fn checkpoint_at(backup_dir: &Path, at: chrono::DateTime<chrono::Utc>) -> io::Result<PathBuf> {
+    let mut found: Option<(chrono::DateTime<chrono::Utc>, PathBuf)> = None;
+    for name in read_chain(backup_dir)? {
+        let checkpoint = backup_dir.join(name);
+        if !checkpoint.is_dir() {
+            continue;
+        }
+        match taken_at(&checkpoint) {
+            Some(taken) if taken <= at && found.as_ref().is_none_or(|(newest, _)| taken >= *newest) => {
+                found = Some((taken, checkpoint))
+            }
+            Some(_) => {}
+            None => warn!("Can't tell when {:?} was taken; skipping it", checkpoint),
+        }
+    }
+    let found = found.map(|(_, checkpoint)| checkpoint);
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No checkpoint was taken at or before {}", at.with_timezone(&chrono::Local)),
+        )
+    })
+}
+
+// The checkpoint a new run should be merged into instead of creating another
+// one, per APPEND_WITHIN: the last checkpoint if it was created recently
+// enough and isn't retention-locked.
+pub fn append_target(last_checkpoint: &Path, append_within: &str) -> io::Result<Option<String>> {
+    if append_within.is_empty() || !last_checkpoint.is_dir() {
+        return Ok(None);
+    }
+    let Some(name) = last_checkpoint.file_name().map(|name| name.to_string_lossy().to_string()) else {
+        return Ok(None);
+    };
+    let Some(created) = created_at(&name) else {
+        return Ok(None);
+    };
+    let recent = match append_within {
+        "day" => created.with_timezone(&chrono::Local).date_naive() == clock::local_now().date_naive(),
+        hours => {
+            let hours: i64 = hours.strip_suffix('h').and_then(|h| h.parse().ok()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid APPEND_WITHIN {:?}, expected \"day\" or e.g. \"6h\"", append_within),
+                )
+            })?;
+            clock::now() - created < chrono::Duration::hours(hours)
+        }
+    };
+    if !recent {
+        return Ok(None);
+    }
+    if let Err(e) = ensure_unlocked(last_checkpoint) {
+        info!("Starting a new checkpoint: {}", e);
+        return Ok(None);
+    }
+    Ok(Some(name))
+}
+
+// Drop the metadata (meta zips and manifest) of a checkpoint that is about to
+// be rewritten by an appending run. Its previous state has been extracted as
+// the run's baseline.
+pub fn clear_metadata(checkpoint: &Path) -> io::Result<()> {
+    for zip in meta_zips(checkpoint) {
+        fs::remove_file(zip)?;
+    }
+    let manifest_dir = checkpoint.join(MANIFEST_DIR);
+    if manifest_dir.exists() {
+        fs::remove_dir_all(manifest_dir)?;
+    }
+    Ok(())
+}
+
+// Drop the loose metas of a checkpoint whose interrupted dual-phase run is
+// resumed. The run writes them again for every file it still finds.
+pub fn clear_loose_metas(checkpoint: &Path) -> io::Result<()> {
+    let meta_dirs: Vec<PathBuf> = WalkDir::new(checkpoint)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir() && e.file_name() == META_DIR)
+        .map(|e| e.into_path())
+        .collect();
+    for dir in meta_dirs {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+// After an appending or resumed run: remove data the checkpoint still holds for files
+// that are no longer part of it (deleted from the source since the last run).
+pub fn prune_unreferenced(checkpoint: &Path) -> io::Result<usize> {
+    let name = checkpoint.file_name().map(|name| name.to_string_lossy().to_string());
+    let mut referenced = HashSet::new();
+    for entry in read_entries(checkpoint) {
+        let entry = entry?;
+        if entry.stored_in == name {
+            referenced.insert(entry.path);
+        }
+    }
+    let stale: Vec<PathBuf> = stored_files(checkpoint).filter(|rel| !referenced.contains(rel)).collect();
+    for rel in &stale {
+        fs::remove_file(checkpoint.join(rel))?;
+        info!("Removed {:?}, no longer in the source", rel);
+    }
+    Ok(stale.len())
+}
+
+// Set or extend a retention lock. Locks can only be extended, never shortened
+// or removed, so a compromised account cannot unlock and then delete.
+pub fn lock_checkpoint(backup_dir: &Path, name: &str, until: &str) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, name)?;
+    let until = parse_lock_date(until)?;
+    let mut checkpoint_info = CheckpointInfo::load(&checkpoint)?;
+    if let Some(current) = checkpoint_info.lock_date()? {
+        if until < current {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Checkpoint {:?} is already locked until {}; locks cannot be shortened", checkpoint, current),
+            ));
+        }
+    }
+    checkpoint_info.locked_until = Some(until.format("%Y-%m-%d").to_string());
+    checkpoint_info.save(&checkpoint)?;
+    info!("Locked {:?} until {}", checkpoint, until);
+    Ok(())
+}
+
+fn validate_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "latest" || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid checkpoint name: {:?}", name),
+        ));
+    }
+    Ok(())
+}
+
+// Rename a checkpoint and every reference to it: data pointers in later
+// manifests and metas, the chain file and the latest-checkpoint pointer.
+pub fn rename_checkpoint(backup_dir: &Path, old: &str, new: &str) -> io::Result<()> {
+    validate_name(new)?;
+    let checkpoint = resolve_checkpoint(backup_dir, old)?;
+    ensure_unlocked(&checkpoint)?;
+    let old = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let target = backup_dir.join(new);
+    if target.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Checkpoint already exists: {:?}", target),
+        ));
+    }
+
+    // Later checkpoints point at this one for unchanged files, so they get
+    // rewritten too and must not be locked either
+    let checkpoints = list_checkpoints(backup_dir)?;
+    for later in &checkpoints {
+        ensure_unlocked(later)?;
+    }
+    for later in checkpoints {
+        if has_manifest(&later) {
+            rewrite_manifest_stored_in(&later, &old, new)?;
+        }
+        for zip in meta_zips(&later) {
+            rewrite_zip_stored_in(&zip, &old, new)?;
+        }
+    }
+
+    // Capture the full current order first; the new name may not sort like the old one
+    let chain: Vec<(String, Option<String>)> = list_checkpoints(backup_dir)?
+        .iter()
+        .filter_map(|c| c.file_name().map(|n| n.to_string_lossy().to_string()))
+        .map(|name| if name == old { new.to_string() } else { name })
+        .map(|name| (name, None))
+        .collect();
+
+    fs::rename(&checkpoint, &target)?;
+    write_chain(backup_dir, &chain)?;
+    browse::rename(backup_dir, &old, new)?;
+    // Rewritten data pointers changed the manifests, and with them the roots
+    for checkpoint in list_checkpoints(backup_dir)? {
+        record_root(backup_dir, &checkpoint)?;
+    }
+
+    let latest = backup_dir.join(CHECKPOINT_NAME);
+    if fs::read_to_string(&latest).is_ok_and(|content| checkpoint_ref(content.trim()) == old) {
+        write_atomic(&latest, new.as_bytes())?;
+    }
+    let previous = latest_backup(backup_dir);
+    if fs::read_to_string(&previous).is_ok_and(|content| checkpoint_ref(content.trim()) == old) {
+        write_atomic(&previous, new.as_bytes())?;
+    }
+
+    info!("Renamed checkpoint {} -> {}", old, new);
+    Ok(())
+}
+
+// Rewrite latest and the chain so they hold bare checkpoint names. Returns the
+// number of references that were changed.
+pub fn normalize_references(backup_dir: &Path) -> io::Result<usize> {
+    let mut changed = 0;
+    let latest = backup_dir.join(CHECKPOINT_NAME);
+    if let Ok(content) = fs::read_to_string(&latest) {
+        let name = checkpoint_ref(content.trim());
+        if name != content.trim() {
+            write_atomic(&latest, name.as_bytes())?;
+            changed += 1;
+        }
+    }
+    if let Ok(content) = fs::read_to_string(backup_dir.join(CHAIN_FILE)) {
+        let absolute = content.lines().filter(|line| Path::new(line).is_absolute()).count();
+        if absolute > 0 {
+            write_chain(backup_dir, &read_chain_entries(backup_dir)?)?;
+            changed += absolute;
+        }
+    }
+    Ok(changed)
+}
+
+pub fn annotate_checkpoint(backup_dir: &Path, name: &str, annotation: Option<&str>) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, name)?;
+    let mut checkpoint_info = CheckpointInfo::load(&checkpoint)?;
+    checkpoint_info.annotation = annotation.map(str::to_string).filter(|a| !a.is_empty());
+    checkpoint_info.save(&checkpoint)?;
+    match &checkpoint_info.annotation {
+        Some(annotation) => info!("Annotated {:?}: {}", checkpoint, annotation),
+        None => info!("Cleared annotation of {:?}", checkpoint),
+    }
+    Ok(())
+}
+
+// CHECKPOINT_NAME is rotated rather than just overwritten: its previous value
+// is kept in a .bak copy, which readers fall back to should the pointer be
+// damaged or name a checkpoint that doesn't exist.
+fn latest_backup(backup_dir: &Path) -> PathBuf {
+    backup_dir.join(format!("{}.bak", CHECKPOINT_NAME))
+}
+
+// The checkpoint a pointer file names, if it is readable and the checkpoint exists
+fn pointer_target(backup_dir: &Path, pointer: &Path) -> Option<String> {
+    let content = fs::read_to_string(pointer).ok()?;
+    let name = checkpoint_ref(content.trim());
+    (!name.is_empty() && !name.contains(['/', '\\', '\n']) && backup_dir.join(name).is_dir()).then(|| name.to_string())
+}
+
+// Point CHECKPOINT_NAME at `name`, keeping the checkpoint it named before in
+// the .bak copy
+pub fn set_latest(backup_dir: &Path, name: &str) -> io::Result<()> {
+    let latest = backup_dir.join(CHECKPOINT_NAME);
+    if let Some(previous) = pointer_target(backup_dir, &latest).filter(|previous| previous != name) {
+        write_atomic(&latest_backup(backup_dir), previous.as_bytes())?;
+    }
+    write_atomic(&latest, name.as_bytes())
+}
+
+// Name of the latest checkpoint, or None for a repository without backups.
+// A damaged pointer falls back to the .bak copy with a warning, so a single
+// bad write doesn't make the next backup start over with a full one.
+pub fn read_latest(backup_dir: &Path) -> Option<String> {
+    let latest = backup_dir.join(CHECKPOINT_NAME);
+    if let Some(name) = pointer_target(backup_dir, &latest) {
+        return Some(name);
+    }
+    let fallback = pointer_target(backup_dir, &latest_backup(backup_dir));
+    if latest.exists() {
+        match &fallback {
+            Some(name) => warn!("{:?} is damaged or names a missing checkpoint; using the previous one, {}", latest, name),
+            None => warn!("{:?} is damaged or names a missing checkpoint, and there is no usable backup copy", latest),
+        }
+    } else if let Some(name) = &fallback {
+        warn!("{:?} is missing; using the previous latest checkpoint, {}", latest, name);
+    }
+    fallback
+}
+
+// Resolve a checkpoint given by name, accepting "latest" for the checkpoint
+// recorded in CHECKPOINT_NAME.
+pub fn resolve_checkpoint(backup_dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let name = if name == "latest" {
+        read_latest(backup_dir).unwrap_or_default()
+    } else {
+        name.trim_end_matches('/').to_string()
+    };
+    let checkpoint = backup_dir.join(&name);
+    if name.is_empty() || !checkpoint.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Checkpoint not found: {:?}", checkpoint),
+        ));
+    }
+    Ok(checkpoint)
+}
+
+// Read every .meta record of a checkpoint, both loose files and the ones
+// packed into COMPRESS_FILE_NAME, keyed by the meta path relative to the checkpoint.
+pub fn read_checkpoint_meta(checkpoint: &Path) -> io::Result<HashMap<PathBuf, FileInfo>> {
+    let mut metas = HashMap::new();
+    for entry in WalkDir::new(checkpoint).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel_dir = path
+            .parent()
+            .and_then(|p| p.strip_prefix(checkpoint).ok())
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+
+        if path.file_name().is_some_and(|name| name == COMPRESS_FILE_NAME) {
+            for meta in read_zip_metas(path)? {
+                if meta.name.ends_with(".meta") {
+                    metas.insert(rel_dir.join(&meta.name), FileInfo::parse(&meta.content)?);
+                }
+            }
+        } else if path.extension().is_some_and(|ext| ext == "meta") {
+            let contents = fs::read_to_string(path)?;
+            let rel = path.strip_prefix(checkpoint).map_err(io::Error::other)?;
+            metas.insert(rel.to_path_buf(), FileInfo::parse(&contents)?);
+        }
+    }
+    Ok(metas)
+}
+
+// Data files physically stored in a checkpoint (everything except metadata).
+fn stored_files(checkpoint: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    WalkDir::new(checkpoint)
+        .into_iter()
+        .filter_entry(|e| ![MANIFEST_DIR, META_DIR, PACK_DIR].iter().any(|n| e.file_name() == *n))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.path().extension().is_none_or(|ext| ext != "meta"))
+        .filter(|e| {
+            let reserved = [
+                COMPRESS_FILE_NAME,
+                CHECKPOINT_INFO_NAME,
+                RUN_REPORT_NAME,
+                HTML_REPORT_NAME,
+                PLAN_NAME,
+                PLAN_DONE_NAME,
+                BACKEND_CHECKSUMS_NAME,
+            ];
+            !reserved.iter().any(|n| e.file_name() == *n)
+        })
+        .filter_map(move |e| e.path().strip_prefix(checkpoint).ok().map(Path::to_path_buf))
+}
+
+pub struct ResolvedFile {
+    // The data file, or the pack holding the data of a packed file
+    pub stored_at: PathBuf,
+    pub info: FileInfo,
+}
+
+impl ResolvedFile {
+    // Reader over the file's data, wherever and however it is stored
+    pub fn open(&self) -> io::Result<Box<dyn Read>> {
+        match (&self.info.packed, &self.info.seekable) {
+            (Some(packed), _) => Ok(Box::new(open_packed(&self.stored_at, packed.offset, self.info.size)?)),
+            (None, Some(_)) => seekable::open(&self.stored_at),
+            (None, None) => Ok(Box::new(fs::File::open(&self.stored_at)?)),
+        }
+    }
+
+    // Reader over `length` bytes of the file's data from `offset` on, reading
+    // only what that range needs
+    pub fn open_range(&self, offset: u64, length: u64) -> io::Result<Box<dyn Read>> {
+        let offset = offset.min(self.info.size);
+        let length = length.min(self.info.size - offset);
+        match (&self.info.packed, &self.info.seekable) {
+            (Some(packed), _) => Ok(Box::new(open_packed(&self.stored_at, packed.offset + offset, length)?)),
+            (None, Some(index)) => seekable::open_range(&self.stored_at, index, offset, length),
+            (None, None) => {
+                let mut file = fs::File::open(&self.stored_at)?;
+                file.seek(io::SeekFrom::Start(offset))?;
+                Ok(Box::new(file.take(length)))
+            }
+        }
+    }
+}
+
+// Reconstruct the full file listing of a checkpoint. Unchanged files are only
+// recorded as .meta in an incremental checkpoint, so their data is taken from
+// the newest earlier checkpoint that stored a copy.
+pub fn resolve_files(
+    backup_dir: &Path,
+    checkpoint: &Path,
+) -> io::Result<BTreeMap<PathBuf, ResolvedFile>> {
+    if has_manifest(checkpoint) {
+        return resolve_from_manifest(backup_dir, checkpoint);
+    }
+
+    let mut metas = read_checkpoint_meta(checkpoint)?;
+    let mut resolved = BTreeMap::new();
+
+    for candidate in checkpoints_up_to(backup_dir, checkpoint)?.iter().rev() {
+        for rel in stored_files(candidate) {
+            if resolved.contains_key(&rel) {
+                continue;
+            }
+            if let Some(info) = metas.remove(&rel.with_extension("meta")) {
+                let stored_at = candidate.join(&rel);
+                resolved.insert(rel, ResolvedFile { stored_at, info });
+            }
+        }
+        if metas.is_empty() {
+            break;
+        }
+    }
+
+    for meta in metas.keys() {
+        warn!("No stored data found for {:?}", meta);
+    }
+    Ok(resolved)
+}
+
+// Manifest entries name the checkpoint holding their data; only entries
+// carried over from pre-manifest checkpoints need the chain searched.
+fn resolve_entry(backup_dir: &Path, checkpoints: &[PathBuf], entry: ManifestEntry) -> Option<ResolvedFile> {
+    let stored_at = match (&entry.stored_in, &entry.packed) {
+        (Some(name), Some(packed)) => Some(packed.path(&backup_dir.join(name))),
+        (Some(name), None) => Some(backup_dir.join(name).join(&entry.path)),
+        (None, _) => checkpoints
+            .iter()
+            .rev()
+            .map(|candidate| candidate.join(&entry.path))
+            .find(|path| path.is_file()),
+    };
+    let Some(stored_at) = stored_at else {
+        warn!("No stored data found for {:?}", entry.path);
+        return None;
+    };
+    let info = FileInfo {
+        size: entry.size,
+        hash: entry.hash,
+        time_stamp: chrono::DateTime::from_timestamp(entry.time_stamp, 0).unwrap_or_default(),
+        stored_in: entry.stored_in,
+        birth_time: entry.birth_time,
+        packed: entry.packed,
+        seekable: entry.seekable,
+    };
+    Some(ResolvedFile { stored_at, info })
+}
+
+fn resolve_from_manifest(
+    backup_dir: &Path,
+    checkpoint: &Path,
+) -> io::Result<BTreeMap<PathBuf, ResolvedFile>> {
+    let checkpoints = checkpoints_up_to(backup_dir, checkpoint)?;
+    let mut resolved = BTreeMap::new();
+
+    for entry in read_entries(checkpoint) {
+        let entry = entry?;
+        let path = entry.path.clone();
+        if let Some(file) = resolve_entry(backup_dir, &checkpoints, entry) {
+            resolved.insert(path, file);
+        }
+    }
+    Ok(resolved)
+}
+
+// Resolve the files of a checkpoint at or below the path prefixes `paths`
+// (all of them when empty). With a manifest and a browsing index, the index
+// tells which files those are, so only the manifest shards holding them are
+// read; otherwise the whole checkpoint is resolved and filtered.
+pub fn resolve_paths(
+    backup_dir: &Path,
+    checkpoint: &Path,
+    paths: &[String],
+) -> io::Result<BTreeMap<PathBuf, ResolvedFile>> {
+    let name = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut rels = Vec::new();
+    let mut index = match browse::Index::open(backup_dir)? {
+        Some(index) if !paths.is_empty() && has_manifest(checkpoint) => index,
+        _ => return resolve_all_below(backup_dir, checkpoint, paths),
+    };
+    for prefix in paths {
+        match index.files_below(&name, Path::new(prefix.trim_matches('/')))? {
+            Some(files) => rels.extend(files),
+            None => return resolve_all_below(backup_dir, checkpoint, paths),
+        }
+    }
+    let checkpoints = checkpoints_up_to(backup_dir, checkpoint)?;
+    let mut resolved = BTreeMap::new();
+    for entry in lookup_all(checkpoint, &rels)? {
+        let path = entry.path.clone();
+        if let Some(file) = resolve_entry(backup_dir, &checkpoints, entry) {
+            resolved.insert(path, file);
+        }
+    }
+    Ok(resolved)
+}
+
+fn resolve_all_below(
+    backup_dir: &Path,
+    checkpoint: &Path,
+    paths: &[String],
+) -> io::Result<BTreeMap<PathBuf, ResolvedFile>> {
+    let mut files = resolve_files(backup_dir, checkpoint)?;
+    files.retain(|rel, _| paths.is_empty() || paths.iter().any(|p| rel.starts_with(p.trim_matches('/'))));
+    Ok(files)
+}
+
+// Resolve a single file of a checkpoint; with a manifest only the shard that
+// can hold it is read
+pub fn resolve_file(backup_dir: &Path, checkpoint: &Path, rel: &Path) -> io::Result<Option<ResolvedFile>> {
+    if !has_manifest(checkpoint) {
+        return Ok(resolve_files(backup_dir, checkpoint)?.remove(rel));
+    }
+    let Some(entry) = lookup(checkpoint, rel)? else {
+        return Ok(None);
+    };
+    let checkpoints = if entry.stored_in.is_none() { checkpoints_up_to(backup_dir, checkpoint)? } else { Vec::new() };
+    Ok(resolve_entry(backup_dir, &checkpoints, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nas-backup-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn created_at_parses_plain_and_suffixed_names() {
+        let expected = chrono::NaiveDate::from_ymd_opt(2024, 1, 10).unwrap().and_hms_opt(10, 0, 5).unwrap().and_utc();
+        assert_eq!(created_at("2024-01-10_10-00_05"), Some(expected));
+        assert_eq!(created_at("2024-01-10_10-00_05_1"), Some(expected));
+        assert_eq!(created_at("2024-01-10_10-00_05_999"), Some(expected));
+        assert_eq!(created_at("2024-01-10_10-00_05_"), None);
+        assert_eq!(created_at("2024-01-10_10-00_05-old"), None);
+        assert_eq!(created_at("before-upgrade"), None);
+    }
+
+    #[test]
+    fn claimed_names_keep_their_time() {
+        let backup_dir = scratch("claim");
+        let first = claim_checkpoint_name(&backup_dir, "2024-01-10_10-00_05").unwrap();
+        let second = claim_checkpoint_name(&backup_dir, "2024-01-10_10-00_05").unwrap();
+        assert_eq!(first, "2024-01-10_10-00_05");
+        assert_eq!(second, "2024-01-10_10-00_05_1");
+        assert_eq!(taken_at(&backup_dir.join(&second)), created_at(&first));
+        fs::remove_dir_all(&backup_dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_at_takes_the_newest_chained_one_by_then() {
+        let now = clock::pinned();
+        let name = |days: i64| (now - chrono::Duration::days(days)).format("%Y-%m-%d_%H-%M_%S").to_string();
+        let backup_dir = scratch("at");
+        // The second run had its clock set back; the last directory was
+        // claimed by a run that never finished
+        let chain: Vec<(String, Option<String>)> = [5, 10, 2].iter().map(|days| (name(*days), None)).collect();
+        for (checkpoint, _) in &chain {
+            fs::create_dir(backup_dir.join(checkpoint)).unwrap();
+        }
+        fs::create_dir(backup_dir.join(name(1))).unwrap();
+        write_chain(&backup_dir, &chain).unwrap();
+
+        let at = |days: i64| {
+            checkpoint_at(&backup_dir, now - chrono::Duration::days(days)).map(|c| c.file_name().unwrap().to_owned())
+        };
+        assert_eq!(at(0).unwrap(), *name(2));
+        assert_eq!(at(3).unwrap(), *name(5));
+        assert_eq!(at(6).unwrap(), *name(10));
+        assert_eq!(at(11).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&backup_dir).unwrap();
+    }
+
+    #[test]
+    fn retention_locks_end_on_their_date() {
+        let today = clock::pinned().with_timezone(&chrono::Local).date_naive();
+        let checkpoint = scratch("lock");
+        let dates = [(today.succ_opt().unwrap(), true), (today, false), (today.pred_opt().unwrap(), false)];
+        for (until, locked) in dates {
+            let info = CheckpointInfo { locked_until: Some(until.to_string()), ..Default::default() };
+            info.save(&checkpoint).unwrap();
+            assert_eq!(ensure_unlocked(&checkpoint).is_err(), locked, "locked until {}", until);
+        }
+        fs::remove_dir_all(&checkpoint).unwrap();
+    }
+}
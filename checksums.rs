@@ -0,0 +1,144 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::backup_utils::compute_xxhash;
+use crate::error::Result;
+use crate::manifest::{escape_path, unescape_path};
+
+struct Cached {
+    size: u64,
+    mtime_ns: i128,
+    inode: u64,
+    hash: String,
+    // Looked up or added during this run
+    seen: bool,
+}
+
+pub(crate) fn mtime_ns(metadata: &fs::Metadata) -> i128 {
+    metadata.mtime() as i128 * 1_000_000_000 + metadata.mtime_nsec() as i128
+}
+
+// Content hashes of source files keyed by absolute path, trusted for as long
+// as size, modification time and inode stay the same. Kept at checksum_db and
+// shared by every run that reads source files (backups of any profile and meta
+// generation), so a file is only hashed again once it actually changed.
+pub struct ChecksumDb {
+    path: Option<PathBuf>,
+    // Tree this run walks; entries below it that weren't seen are stale
+    root: PathBuf,
+    entries: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+impl ChecksumDb {
+    // A database that always hashes, for callers that must not trust a cache
+    pub fn disabled() -> Self {
+        Self { path: None, root: PathBuf::new(), entries: Mutex::new(HashMap::new()) }
+    }
+
+    // The database at `path` ("" for none), for a run walking `root`
+    pub fn open(path: &Path, root: &Path) -> io::Result<Self> {
+        if path.as_os_str().is_empty() {
+            return Ok(Self::disabled());
+        }
+        let path = path.to_path_buf();
+        let mut entries = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    let [file, size, mtime_ns, inode, hash] = fields[..] else {
+                        warn!("Ignoring invalid checksum database line: {}", line);
+                        continue;
+                    };
+                    let (Ok(size), Ok(mtime_ns), Ok(inode)) = (size.parse(), mtime_ns.parse(), inode.parse()) else {
+                        warn!("Ignoring invalid checksum database line: {}", line);
+                        continue;
+                    };
+                    let cached = Cached { size, mtime_ns, inode, hash: hash.to_string(), seen: false };
+                    entries.insert(unescape_path(file), cached);
+                }
+                info!("Loaded {} cached checksums from {:?}", entries.len(), path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            path: Some(path),
+            root: std::path::absolute(root)?,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    // Hash of `file`, from the database if its size, mtime and inode match
+    pub fn hash(&self, file: &Path, metadata: &fs::Metadata) -> Result<String> {
+        if self.path.is_none() {
+            return compute_xxhash(file);
+        }
+        let key = std::path::absolute(file)?;
+        let (size, mtime_ns, inode) = (metadata.len(), mtime_ns(metadata), metadata.ino());
+        if let Some(cached) = self.entries.lock().unwrap().get_mut(&key) {
+            if (cached.size, cached.mtime_ns, cached.inode) == (size, mtime_ns, inode) {
+                cached.seen = true;
+                return Ok(cached.hash.clone());
+            }
+        }
+        let hash = compute_xxhash(file)?;
+        let cached = Cached { size, mtime_ns, inode, hash: hash.clone(), seen: true };
+        self.entries.lock().unwrap().insert(key, cached);
+        Ok(hash)
+    }
+
+    // Remember the hash of `file` computed elsewhere, e.g. while storing it
+    pub fn record(&self, file: &Path, metadata: &fs::Metadata, hash: &str) -> io::Result<()> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        let key = std::path::absolute(file)?;
+        let (size, mtime_ns, inode) = (metadata.len(), mtime_ns(metadata), metadata.ino());
+        let cached = Cached { size, mtime_ns, inode, hash: hash.to_string(), seen: true };
+        self.entries.lock().unwrap().insert(key, cached);
+        Ok(())
+    }
+
+    // Write the database back. After a complete walk of the root, entries
+    // below it that weren't seen belong to deleted or excluded files and are
+    // dropped; entries for other trees are kept for their own runs.
+    pub fn save(&self, complete_walk: bool) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let entries = self.entries.lock().unwrap();
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut dropped = 0;
+        for (file, cached) in entries.iter() {
+            if complete_walk && !cached.seen && file.starts_with(&self.root) {
+                dropped += 1;
+                continue;
+            }
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                escape_path(file),
+                cached.size,
+                cached.mtime_ns,
+                cached.inode,
+                cached.hash
+            )?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp, path)?;
+        info!("Saved {} cached checksums to {:?} ({} stale dropped)", entries.len() - dropped, path, dropped);
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checkpoint::write_atomic;
+use crate::clock;
+use crate::config::{MASS_CHANGE_MIN_FILES, MASS_CHANGE_PERCENT, SUSPICIOUS_EXTENSIONS};
+use crate::error::{Error, Result};
+use crate::human::format_count;
+use crate::plan::{DiffSummary, LastState};
+use crate::plugins;
+use crate::runid;
+
+// Guard against encrypting malware: a run whose scan looks like the tree was
+// rewritten wholesale stops before it stores anything, so the checkpoints
+// before it stay the latest good state. The suspicion is kept in HOLD_FILE
+// and announced to the notification plugins; every run finding the same
+// churn stops again until someone runs `mass-change accept`. A run that no
+// longer finds it (the files were restored) drops the hold by itself.
+const HOLD_FILE: &str = ".mass-change.json";
+
+#[derive(Serialize, Deserialize)]
+struct Hold {
+    reason: String,
+    // RFC 3339
+    detected_at: String,
+    run_id: String,
+    checkpoint: String,
+    accepted: bool,
+}
+
+fn load(backup_dir: &Path) -> io::Result<Option<Hold>> {
+    let path = backup_dir.join(HOLD_FILE);
+    match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(backup_dir: &Path, hold: &Hold) -> io::Result<()> {
+    write_atomic(&backup_dir.join(HOLD_FILE), &serde_json::to_vec_pretty(hold).map_err(io::Error::other)?)
+}
+
+fn remove(backup_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(backup_dir.join(HOLD_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// What looks like malware at work among the scan's results, if anything
+fn suspicion<'a>(last: &LastState, new_files: impl Iterator<Item = &'a Path>, summary: &DiffSummary) -> Option<String> {
+    let known = last.file_count().filter(|&count| count > 0)?;
+    // Deleted files are only known after a full scan
+    let changed = summary.modified.0 + summary.removed.unwrap_or(0);
+    if known >= MASS_CHANGE_MIN_FILES && changed * 100 > known * MASS_CHANGE_PERCENT {
+        return Some(format!(
+            "{} of the {} files of the last checkpoint were modified or deleted ({}%)",
+            format_count(changed),
+            format_count(known),
+            changed * 100 / known
+        ));
+    }
+
+    // New files by extension, where it is a known ransom extension or was
+    // added to the name of a file that existed before
+    let mut renamed: HashMap<String, u64> = HashMap::new();
+    for rel in new_files {
+        let Some(extension) = rel.extension().map(|ext| ext.to_string_lossy().to_lowercase()) else {
+            continue;
+        };
+        if SUSPICIOUS_EXTENSIONS.contains(&extension.as_str()) || last.contains(&rel.with_extension("")) {
+            *renamed.entry(extension).or_default() += 1;
+        }
+    }
+    let (extension, count) = renamed.into_iter().max_by_key(|(_, count)| *count)?;
+    (count >= MASS_CHANGE_MIN_FILES.max(1)).then(|| format!("{} new files named *.{}", format_count(count), extension))
+}
+
+// Stop a run whose scan suggests mass encryption, unless that was accepted.
+// `new_files` are the files the scan found that the last checkpoint lacks.
+pub fn check<'a>(
+    plugin_dir: &Path,
+    new_checkpoint: &Path,
+    last: &LastState,
+    new_files: impl Iterator<Item = &'a Path>,
+    summary: &DiffSummary,
+) -> Result<()> {
+    if MASS_CHANGE_PERCENT == 0 {
+        return Ok(());
+    }
+    let backup_dir = new_checkpoint.parent().unwrap_or(Path::new(""));
+    let hold = load(backup_dir)?;
+    let Some(reason) = suspicion(last, new_files, summary) else {
+        if hold.is_some() {
+            info!("The mass change seen earlier is gone; lifting the hold");
+            remove(backup_dir)?;
+        }
+        return Ok(());
+    };
+    if hold.as_ref().is_some_and(|hold| hold.accepted) {
+        warn!("Suspected mass change accepted, proceeding: {}", reason);
+        remove(backup_dir)?;
+        return Ok(());
+    }
+    let checkpoint = new_checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    save(
+        backup_dir,
+        &Hold {
+            reason: reason.clone(),
+            detected_at: clock::now().to_rfc3339(),
+            run_id: runid::current(),
+            checkpoint: checkpoint.clone(),
+            accepted: false,
+        },
+    )?;
+    let details = serde_json::json!({ "checkpoint": checkpoint, "reason": reason });
+    plugins::notify(plugin_dir, "mass_change_detected", details);
+    Err(Error::MassChange(reason))
+}
+
+pub fn status(backup_dir: &Path) -> io::Result<()> {
+    match load(backup_dir)? {
+        None => println!("No suspected mass change"),
+        Some(hold) => {
+            println!("Suspected mass change: {}", hold.reason);
+            println!("Detected:  {} (run {}, checkpoint {})", hold.detected_at, hold.run_id, hold.checkpoint);
+            if hold.accepted {
+                println!("Accepted; the next run proceeds");
+            } else {
+                println!("Runs stop until `mass-change accept`");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Let the next run store the suspected mass change
+pub fn accept(backup_dir: &Path) -> io::Result<()> {
+    let Some(mut hold) = load(backup_dir)? else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No suspected mass change to accept"));
+    };
+    hold.accepted = true;
+    save(backup_dir, &hold)?;
+    warn!("Accepted suspected mass change ({}); the next run stores it", hold.reason);
+    Ok(())
+}
@@ -0,0 +1,623 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::human;
+
+// The command line; main's run_cli_command carries out the parsed command
+#[derive(Parser)]
+#[command(
+    name = "nas-backup-utils",
+    version,
+    about = "Incremental backups of a NAS share"
+)]
+pub struct Cli {
+    #[arg(long, global = true, value_name = "FILE", help = "Settings file to use instead of ./nas-backup.toml or /etc/nas-backup.toml")]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "DIR", help = "Directory to back up, overriding the settings")]
+    pub src: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "DIR", help = "Backup target, overriding the settings")]
+    pub backup_dir: Option<PathBuf>,
+    #[arg(short = 'y', long, global = true, help = "Don't ask for confirmation")]
+    pub yes: bool,
+    // Testing aids: a fixed time, and injected I/O failures (see chaos.rs)
+    #[arg(long, global = true, hide = true)]
+    pub clock: Option<String>,
+    #[arg(long, global = true, hide = true)]
+    pub chaos: Option<String>,
+    #[arg(long, global = true, hide = true)]
+    pub chaos_seed: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Back up the source into a new checkpoint")]
+    Backup(BackupArgs),
+    #[command(about = "Regenerate the metas of a checkpoint, or generate them for a directory")]
+    Meta(MetaArgs),
+    #[command(about = "Restore a checkpoint, or the tree as of a point in time, into a directory")]
+    Restore(RestoreArgs),
+    #[command(about = "Attach a note to a checkpoint")]
+    Annotate(AnnotateArgs),
+    #[command(subcommand, about = "Show who read backed up data")]
+    Audit(AuditAction),
+    #[command(about = "Time hashing the source and writing to the target")]
+    Bench,
+    #[command(about = "Write files of a checkpoint into a single bundle file")]
+    Bundle(BundleArgs),
+    #[command(about = "Print a file of a checkpoint")]
+    Cat(CatArgs),
+    #[command(about = "Check the repository for missing or damaged data")]
+    Check(CheckArgs),
+    #[command(subcommand, about = "Write a settings file with the current settings")]
+    Config(ConfigAction),
+    #[command(about = "Back up once in every BACKUP_WINDOW")]
+    Daemon,
+    #[command(about = "Delete a checkpoint")]
+    Delete(DeleteArgs),
+    #[command(about = "Export a checkpoint as a plain directory tree")]
+    Export(ExportArgs),
+    #[command(about = "Export the files of all checkpoints as CSV")]
+    ExportIndex(ExportIndexArgs),
+    #[command(about = "Write a checkpoint as a stream, e.g. to tape")]
+    ExportStream(ExportStreamArgs),
+    #[command(about = "Extract the files of a bundle")]
+    ExtractBundle(ExtractBundleArgs),
+    #[command(about = "Find files by name in a checkpoint")]
+    Find(FindArgs),
+    #[command(subcommand, about = "Require deletions to be confirmed")]
+    Guard(GuardAction),
+    #[command(about = "Import snapshots of a restic or borg repository")]
+    Import(ImportArgs),
+    #[command(subcommand, about = "Build the index `search` uses")]
+    Index(IndexAction),
+    #[command(about = "Fail unless a backup succeeded recently")]
+    LastSuccess(LastSuccessArgs),
+    #[command(about = "Protect a checkpoint from deletion until a date")]
+    Lock(LockArgs),
+    #[command(subcommand, about = "Show the log of a run")]
+    Logs(LogsAction),
+    #[command(subcommand, about = "Show or accept a suspected mass change")]
+    MassChange(MassChangeAction),
+    #[command(about = "Convert checkpoints to the current layout")]
+    Migrate(MigrateArgs),
+    #[command(about = "Mirror a checkpoint into MIRROR_DIR")]
+    Mirror(MirrorArgs),
+    #[command(about = "List the notification plugins")]
+    Plugins,
+    #[command(about = "Show the start or end of a file of a checkpoint")]
+    Preview(PreviewArgs),
+    #[command(about = "Delete the checkpoints the retention policy doesn't keep")]
+    Prune(PruneArgs),
+    #[command(about = "Rename a checkpoint")]
+    Rename(RenameArgs),
+    #[command(about = "Pack, compress or deduplicate stored data per the policies")]
+    Repack(RepackArgs),
+    #[command(subcommand, about = "Compare a replica with the repository")]
+    Replica(ReplicaAction),
+    #[command(subcommand, about = "Move, upgrade or describe the repository")]
+    Repo(RepoAction),
+    #[command(about = "Write the HTML run report of a checkpoint")]
+    Report(ReportArgs),
+    #[command(about = "Restore files from a stream")]
+    RestoreStream(RestoreStreamArgs),
+    #[command(about = "Search the index for files")]
+    Search(SearchArgs),
+    #[command(about = "Share files of a checkpoint through expiring links")]
+    Share(ShareArgs),
+    #[command(about = "Explore the repository interactively")]
+    Shell,
+    #[command(about = "Show what deleting checkpoints would free, or sizes by directory")]
+    Stats(StatsArgs),
+    #[command(about = "Show the state of the repository and its runs")]
+    Status,
+    #[command(about = "Journal changes to the source for the next backup")]
+    Watch,
+    #[command(about = "Explain why a file's data was stored")]
+    WhyCopied(WhyCopiedArgs),
+}
+
+impl Command {
+    // Name as given on the command line, for the metrics of the run
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Backup(_) => "backup",
+            Self::Meta(_) => "meta",
+            Self::Restore(_) => "restore",
+            Self::Annotate(_) => "annotate",
+            Self::Audit(_) => "audit",
+            Self::Bench => "bench",
+            Self::Bundle(_) => "bundle",
+            Self::Cat(_) => "cat",
+            Self::Check(_) => "check",
+            Self::Config(_) => "config",
+            Self::Daemon => "daemon",
+            Self::Delete(_) => "delete",
+            Self::Export(_) => "export",
+            Self::ExportIndex(_) => "export-index",
+            Self::ExportStream(_) => "export-stream",
+            Self::ExtractBundle(_) => "extract-bundle",
+            Self::Find(_) => "find",
+            Self::Guard(_) => "guard",
+            Self::Import(_) => "import",
+            Self::Index(_) => "index",
+            Self::LastSuccess(_) => "last-success",
+            Self::Lock(_) => "lock",
+            Self::Logs(_) => "logs",
+            Self::MassChange(_) => "mass-change",
+            Self::Migrate(_) => "migrate",
+            Self::Mirror(_) => "mirror",
+            Self::Plugins => "plugins",
+            Self::Preview(_) => "preview",
+            Self::Prune(_) => "prune",
+            Self::Rename(_) => "rename",
+            Self::Repack(_) => "repack",
+            Self::Replica(_) => "replica",
+            Self::Repo(_) => "repo",
+            Self::Report(_) => "report",
+            Self::RestoreStream(_) => "restore-stream",
+            Self::Search(_) => "search",
+            Self::Share(_) => "share",
+            Self::Shell => "shell",
+            Self::Stats(_) => "stats",
+            Self::Status => "status",
+            Self::Watch => "watch",
+            Self::WhyCopied(_) => "why-copied",
+        }
+    }
+}
+
+// Transfer shaping shared by the commands that read or write a lot
+#[derive(Args)]
+pub struct Shaping {
+    #[arg(long, value_name = "SIZE", value_parser = human::parse_size, help = "Limit transfers to this many bytes per second")]
+    pub limit_rate: Option<u64>,
+    #[arg(long, value_name = "HH:MM-HH:MM", help = "Pause outside this daily window")]
+    pub window: Option<String>,
+}
+
+#[derive(Args)]
+pub struct BackupArgs {
+    #[arg(long, help = "Only show what would be stored")]
+    pub dry_run: bool,
+    #[arg(long, value_name = "DURATION", value_parser = human::parse_duration, help = "Stop storing after this long, e.g. 2h")]
+    pub max_duration: Option<Duration>,
+    #[arg(long, value_name = "SIZE", value_parser = human::parse_size, help = "Store a first backup in stages of this size")]
+    pub stage_per_run: Option<u64>,
+    #[arg(long, value_name = "SIZE", value_parser = human::parse_size, help = "Limit writes to this many bytes per second")]
+    pub limit_rate: Option<u64>,
+}
+
+#[derive(Args)]
+pub struct MetaArgs {
+    #[arg(required_unless_present = "dir", help = "Checkpoint whose metas to regenerate")]
+    pub checkpoint: Option<String>,
+    #[arg(long, value_name = "SUBTREE", conflicts_with = "dir", help = "Only regenerate below this path")]
+    pub path: Option<PathBuf>,
+    #[arg(long, conflicts_with = "dir", help = "Regenerate metas that look current too")]
+    pub force: bool,
+    #[arg(long, value_name = "DIR", conflicts_with = "checkpoint", help = "Generate metas for this directory")]
+    pub dir: Option<PathBuf>,
+    #[arg(long, value_name = "DIR", requires = "dir", help = "Write them here instead, e.g. for a read-only snapshot")]
+    pub out: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RestoreArgs {
+    #[arg(required_unless_present = "at", conflicts_with = "at", help = "Checkpoint to restore, e.g. latest")]
+    pub checkpoint: Option<String>,
+    #[arg(long, value_name = "TIME", help = "Restore the newest checkpoint taken by then, e.g. 2024-01-15")]
+    pub at: Option<String>,
+    #[arg(long, value_name = "DIR", help = "Directory to restore into")]
+    pub to: PathBuf,
+    #[arg(long, alias = "path", value_name = "PREFIX", value_delimiter = ',', help = "Only restore files below these paths")]
+    pub paths: Vec<String>,
+    #[arg(long, help = "Overwrite files that differ from the checkpoint")]
+    pub force: bool,
+    #[arg(long, help = "Restore into a staging directory, validate it and then swap it in")]
+    pub sandbox: bool,
+    #[arg(long, value_name = "N", help = "Priority against other runs on the repository")]
+    pub priority: Option<u8>,
+    #[command(flatten)]
+    pub shaping: Shaping,
+}
+
+#[derive(Args)]
+pub struct AnnotateArgs {
+    #[arg(help = "Checkpoint to annotate")]
+    pub checkpoint: String,
+    #[arg(help = "The note; without one, the current note is shown")]
+    pub text: Vec<String>,
+    #[arg(long, conflicts_with = "text", help = "Remove the note")]
+    pub clear: bool,
+}
+
+#[derive(Subcommand)]
+pub enum AuditAction {
+    #[command(about = "Show the recorded reads, oldest first")]
+    Show {
+        #[arg(long, value_name = "NAME", help = "Only reads by this user")]
+        user: Option<String>,
+        #[arg(long, value_name = "CHECKPOINT|FILE", help = "Only reads from this checkpoint, bundle or stream")]
+        from: Option<String>,
+        #[arg(long, value_name = "PREFIX", help = "Only reads of files below this path")]
+        path: Option<String>,
+        #[arg(long, value_name = "YYYY-MM-DD", help = "Only reads on or after this day")]
+        since: Option<chrono::NaiveDate>,
+    },
+}
+
+#[derive(Args)]
+pub struct BundleArgs {
+    #[arg(help = "Checkpoint to bundle, e.g. latest")]
+    pub checkpoint: String,
+    #[arg(long, value_name = "PREFIX", value_delimiter = ',', help = "Only bundle files below these paths")]
+    pub paths: Vec<String>,
+    #[arg(long, value_name = "FILE", help = "Bundle file to write")]
+    pub out: PathBuf,
+    #[arg(long, value_name = "N", help = "Priority against other runs on the repository")]
+    pub priority: Option<u8>,
+    #[command(flatten)]
+    pub shaping: Shaping,
+}
+
+#[derive(Args)]
+pub struct CatArgs {
+    #[arg(help = "Checkpoint holding the file, e.g. latest")]
+    pub checkpoint: String,
+    #[arg(help = "Path of the file within the checkpoint")]
+    pub path: String,
+    #[arg(long, value_name = "BYTES", default_value_t = 0, help = "Start this far into the file")]
+    pub offset: u64,
+    #[arg(long, value_name = "BYTES", help = "Print no more than this")]
+    pub length: Option<u64>,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    #[arg(long, help = "Only check the root hashes in the chain, without re-hashing stored data")]
+    pub quick: bool,
+    #[arg(long, help = "Also compare the checksums storage plugins attest to")]
+    pub backends: bool,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    #[command(about = "Write the settings file, asking for the paths")]
+    Init {
+        #[arg(long, value_name = "FILE", help = "File to write instead of ./nas-backup.toml")]
+        out: Option<PathBuf>,
+        #[arg(long, help = "Overwrite an existing file")]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct DeleteArgs {
+    #[arg(help = "Checkpoint to delete")]
+    pub checkpoint: String,
+    #[arg(long, help = "Move data later checkpoints still use into the next one")]
+    pub rehome: bool,
+    #[arg(long, value_name = "TOKEN", help = "Confirm a deletion the guard asked about")]
+    pub confirm: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    #[arg(help = "Checkpoint to export, e.g. latest")]
+    pub checkpoint: String,
+    #[arg(long, value_parser = ["plain-tree"], help = "Layout of the export")]
+    pub format: String,
+    #[arg(long, value_name = "DIR", help = "Directory to export into")]
+    pub out: PathBuf,
+    #[arg(long, value_name = "PREFIX", value_delimiter = ',', help = "Only export files below these paths")]
+    pub paths: Vec<String>,
+    #[arg(long, value_name = "N", help = "Priority against other runs on the repository")]
+    pub priority: Option<u8>,
+    #[command(flatten)]
+    pub shaping: Shaping,
+}
+
+#[derive(Args)]
+pub struct ExportIndexArgs {
+    #[arg(long, help = "Output format, csv")]
+    pub format: String,
+    #[arg(long, value_name = "FILE|-", help = "File to write, or - for stdout")]
+    pub out: String,
+}
+
+#[derive(Args)]
+pub struct ExportStreamArgs {
+    #[arg(help = "Checkpoint to write, e.g. latest")]
+    pub checkpoint: String,
+    #[arg(long, value_name = "FILE|DEVICE|-", help = "Where to write the stream, or - for stdout")]
+    pub out: String,
+    #[arg(long, value_name = "FILE", help = "Index of the stream to write, for restore-stream")]
+    pub index: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ExtractBundleArgs {
+    #[arg(help = "Bundle file to extract")]
+    pub bundle: PathBuf,
+    #[arg(long, value_name = "DIR", default_value = ".", help = "Directory to extract into")]
+    pub dest: PathBuf,
+    #[arg(long, help = "Extract into a staging directory, validate it and then swap it in")]
+    pub sandbox: bool,
+    #[command(flatten)]
+    pub shaping: Shaping,
+}
+
+#[derive(Args)]
+pub struct FindArgs {
+    #[arg(help = "Name or path to look for; * and ? match any characters")]
+    pub pattern: String,
+    #[arg(default_value = "latest", help = "Checkpoint to look in")]
+    pub checkpoint: String,
+}
+
+#[derive(Subcommand)]
+pub enum GuardAction {
+    #[command(about = "Show the guard and pending deletions")]
+    Status,
+    #[command(about = "Turn the guard on or change it")]
+    Set {
+        #[arg(long, help = "Ask for a passphrase to confirm")]
+        passphrase: bool,
+        #[arg(long, value_name = "DURATION", value_parser = human::parse_duration, help = "Only accept confirmations after this long")]
+        delay: Option<Duration>,
+        #[arg(long, value_name = "TOKEN", help = "Confirm the change")]
+        confirm: Option<String>,
+    },
+    #[command(about = "Turn the guard off")]
+    Off {
+        #[arg(long, value_name = "TOKEN", help = "Confirm turning it off")]
+        confirm: Option<String>,
+    },
+    #[command(about = "Cancel a pending deletion")]
+    Cancel {
+        #[arg(help = "Token of the deletion")]
+        token: String,
+    },
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    #[arg(value_parser = ["restic", "borg"], help = "Tool that wrote the repository")]
+    pub tool: String,
+    #[arg(help = "Repository to import from")]
+    pub repository: String,
+    #[arg(long, value_name = "ID", value_delimiter = ',', help = "Only import these snapshots")]
+    pub snapshots: Vec<String>,
+    #[arg(long, value_name = "DIR", help = "Only import this directory of the snapshots")]
+    pub path: Option<String>,
+    #[arg(long, value_name = "FILE", help = "File holding the repository password")]
+    pub password_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum IndexAction {
+    #[command(about = "Index every checkpoint")]
+    Build,
+}
+
+#[derive(Args)]
+pub struct LastSuccessArgs {
+    #[arg(long, value_name = "DURATION", value_parser = human::parse_duration, help = "Fail if it is older, e.g. 26h")]
+    pub max_age: Duration,
+    #[arg(long, value_name = "DIR", help = "Only look at backups into this target")]
+    pub target: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct LockArgs {
+    #[arg(help = "Checkpoint to lock")]
+    pub checkpoint: String,
+    #[arg(long, value_name = "YYYY-MM-DD", help = "Keep it until this day")]
+    pub until: String,
+}
+
+#[derive(Subcommand)]
+pub enum LogsAction {
+    #[command(about = "List the recent runs, or show the log of one")]
+    Show {
+        #[arg(long, value_name = "ID", help = "Run whose log to show")]
+        run: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MassChangeAction {
+    #[command(about = "Show the suspected mass change, if any")]
+    Status,
+    #[command(about = "Accept it so backups go on as usual")]
+    Accept,
+}
+
+#[derive(Args)]
+pub struct MigrateArgs {
+    #[arg(help = "Only convert this checkpoint")]
+    pub checkpoint: Option<String>,
+    #[arg(long, help = "Check the converted data against the original")]
+    pub verify: bool,
+    #[arg(long, value_name = "DIR", help = "Copy the repository here and convert the copy")]
+    pub to: Option<PathBuf>,
+    #[arg(long, requires = "checkpoint", help = "Convert the checkpoint back to the old layout")]
+    pub revert: bool,
+}
+
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct MirrorArgs {
+    #[command(subcommand)]
+    pub action: Option<MirrorAction>,
+    #[arg(help = "Checkpoint to mirror; the latest if not given")]
+    pub checkpoint: Option<String>,
+    #[arg(long, value_name = "DIR", help = "Mirror here instead of MIRROR_DIR")]
+    pub to: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum MirrorAction {
+    #[command(about = "Show which checkpoint the mirror holds")]
+    Status {
+        #[arg(long, value_name = "DIR", help = "Mirror to look at instead of MIRROR_DIR")]
+        to: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+pub struct PreviewArgs {
+    #[arg(help = "Checkpoint holding the file, e.g. latest")]
+    pub checkpoint: String,
+    #[arg(help = "Path of the file within the checkpoint")]
+    pub path: String,
+    #[arg(long, value_name = "N", default_value_t = 50, help = "Number of lines to show")]
+    pub lines: usize,
+    #[arg(long, help = "Show the end of the file instead")]
+    pub tail: bool,
+}
+
+#[derive(Args)]
+pub struct PruneArgs {
+    #[arg(long, value_name = "N", help = "Keep this many newest checkpoints")]
+    pub keep_last: Option<u32>,
+    #[arg(long, value_name = "DAYS", help = "Keep the last checkpoint of this many days")]
+    pub keep_daily: Option<u32>,
+    #[arg(long, value_name = "WEEKS", help = "Keep the last checkpoint of this many weeks")]
+    pub keep_weekly: Option<u32>,
+    #[arg(long, value_name = "MONTHS", help = "Keep the last checkpoint of this many months")]
+    pub keep_monthly: Option<u32>,
+    #[arg(long, help = "Only show what would be deleted")]
+    pub dry_run: bool,
+    #[arg(long, value_name = "TOKEN", help = "Confirm a prune the guard asked about")]
+    pub confirm: Option<String>,
+}
+
+#[derive(Args)]
+pub struct RenameArgs {
+    #[arg(help = "Checkpoint to rename")]
+    pub checkpoint: String,
+    #[arg(help = "Its new name")]
+    pub new_name: String,
+}
+
+#[derive(Args)]
+pub struct RepackArgs {
+    #[arg(long, required = true, help = "Apply the pack, compression and deduplication policies")]
+    pub apply_policies: bool,
+    #[arg(long, value_name = "SIZE", value_parser = human::parse_size, help = "Stop after rewriting this much data")]
+    pub max_bytes: Option<u64>,
+    #[arg(long, value_name = "DURATION", value_parser = human::parse_duration, help = "Stop after this long, e.g. 2h")]
+    pub max_duration: Option<Duration>,
+    #[arg(long, value_name = "N", help = "Priority against other runs on the repository")]
+    pub priority: Option<u8>,
+    #[command(flatten)]
+    pub shaping: Shaping,
+}
+
+#[derive(Subcommand)]
+pub enum ReplicaAction {
+    #[command(about = "Check that the replica holds the same data")]
+    Verify {
+        #[arg(help = "Backup directory of the replica")]
+        replica: PathBuf,
+        #[arg(long, value_name = "DIR", help = "Compare with this repository instead of the configured one")]
+        primary: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RepoAction {
+    #[command(about = "Fix up the repository after it moved to a new disk or mount point")]
+    Relocate {
+        #[arg(help = "Where the repository is now; the configured backup directory if not given")]
+        dir: Option<PathBuf>,
+    },
+    #[command(about = "Upgrade the repository to the current format")]
+    Upgrade,
+    #[command(about = "Describe the repository")]
+    Info,
+}
+
+#[derive(Args)]
+pub struct ReportArgs {
+    #[arg(default_value = "latest", help = "Checkpoint whose run to report")]
+    pub checkpoint: String,
+    #[arg(long, value_name = "FILE", help = "File to write instead of stdout")]
+    pub out: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RestoreStreamArgs {
+    #[arg(help = "Stream to read, e.g. a tape device")]
+    pub stream: PathBuf,
+    #[arg(long, value_name = "FILE", help = "Index written with the stream")]
+    pub index: PathBuf,
+    #[arg(long, value_name = "PREFIX", value_delimiter = ',', help = "Only restore files below these paths")]
+    pub paths: Vec<String>,
+    #[arg(long, value_name = "DIR", default_value = ".", help = "Directory to restore into")]
+    pub dest: PathBuf,
+    #[arg(long, help = "Restore into a staging directory, validate it and then swap it in")]
+    pub sandbox: bool,
+    #[command(flatten)]
+    pub shaping: Shaping,
+}
+
+#[derive(Args)]
+pub struct SearchArgs {
+    #[arg(help = "Name or path to look for; * and ? match any characters")]
+    pub pattern: String,
+}
+
+// `share <checkpoint> <path>` creates a link; the actions manage them
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct ShareArgs {
+    #[command(subcommand)]
+    pub action: Option<ShareAction>,
+    #[arg(required = true, help = "Checkpoint holding the file, e.g. latest")]
+    pub checkpoint: Option<String>,
+    #[arg(required = true, help = "Path of the file within the checkpoint")]
+    pub path: Option<String>,
+    #[arg(long, value_name = "DURATION", value_parser = human::parse_duration, default_value = "24h", help = "Link lifetime")]
+    pub expires: Duration,
+}
+
+#[derive(Subcommand)]
+pub enum ShareAction {
+    #[command(about = "List the links that still work")]
+    List,
+    #[command(about = "Make a link stop working")]
+    Revoke {
+        #[arg(help = "Token of the link")]
+        token: String,
+    },
+    #[command(about = "Serve the links until stopped")]
+    Serve,
+}
+
+#[derive(Args)]
+#[command(group(clap::ArgGroup::new("mode").required(true).args(["dedup", "dirs"])))]
+pub struct StatsArgs {
+    #[arg(long, help = "Show what deleting checkpoints would free")]
+    pub dedup: bool,
+    #[arg(long, value_name = "CHECKPOINT", value_delimiter = ',', requires = "dedup", help = "Checkpoints to delete")]
+    pub prune: Vec<String>,
+    #[arg(long, help = "Show the sizes of the directories of a checkpoint")]
+    pub dirs: bool,
+    #[arg(requires = "dirs", help = "Checkpoint to size; the latest if not given")]
+    pub checkpoint: Option<String>,
+    #[arg(long, value_name = "DIR", requires = "dirs", help = "Only size below this directory")]
+    pub path: Option<String>,
+}
+
+#[derive(Args)]
+pub struct WhyCopiedArgs {
+    #[arg(help = "Checkpoint holding the file, e.g. latest")]
+    pub checkpoint: String,
+    #[arg(help = "Path of the file within the checkpoint")]
+    pub path: String,
+}
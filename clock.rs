@@ -0,0 +1,66 @@
+use chrono::{DateTime, Local, Utc};
+use log::warn;
+use std::sync::OnceLock;
+
+// Source of "now" for everything that names checkpoints, stamps records or
+// judges retention and append windows. Runs use the system clock unless
+// another one is installed before anything asks for the time: the hidden
+// `--clock <RFC 3339 time>` option pins a run to a fixed instant, so history
+// spanning months can be simulated one backup at a time. Scheduling (backup
+// windows, timeouts) and log lines stay on the wall clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// Deterministic clock: always the time it was set to
+pub struct TestClock {
+    time: DateTime<Utc>,
+}
+
+impl TestClock {
+    pub fn at(time: DateTime<Utc>) -> Self {
+        Self { time }
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+static CLOCK: OnceLock<&'static dyn Clock> = OnceLock::new();
+
+// Use `clock` for the rest of the process. Only the first call counts.
+pub fn install(clock: &'static dyn Clock) {
+    if CLOCK.set(clock).is_err() {
+        warn!("A clock is already installed; keeping it");
+    }
+}
+
+pub fn now() -> DateTime<Utc> {
+    CLOCK.get().copied().unwrap_or(&SystemClock).now()
+}
+
+pub fn local_now() -> DateTime<Local> {
+    now().with_timezone(&Local)
+}
+
+// Pin the clock of the test process to 2026-10-15 12:00 UTC, a Thursday, and
+// return that time. Every test that needs the time uses this one clock, as
+// only the first installed clock counts.
+#[cfg(test)]
+pub fn pinned() -> DateTime<Utc> {
+    static PINNED: OnceLock<TestClock> = OnceLock::new();
+    let clock = PINNED.get_or_init(|| TestClock::at("2026-10-15T12:00:00Z".parse().unwrap()));
+    let _ = CLOCK.set(clock);
+    clock.time
+}
@@ -0,0 +1,199 @@
+use log::info;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{ADJUST_INTERVAL_MS, MAX_JOBS, MIN_JOBS};
+use crate::human::{format_duration, format_size};
+
+// Fixed per-file cost counted on top of the bytes processed, so small-file
+// workloads dominated by metadata latency still register as throughput.
+const OP_COST_BYTES: u64 = 64 * 1024;
+
+struct State {
+    target: usize,
+    active: usize,
+    window_start: Instant,
+    window_units: u64,
+    window_ops: u64,
+    window_busy: Duration,
+    last_rate: f64,
+    growing: bool,
+}
+
+// Hill-climbing controller: grows the worker count while throughput keeps
+// improving and backs off once adding workers makes it worse, so fast disks
+// end up with many workers and a single spinning disk with few.
+struct Controller {
+    state: Mutex<State>,
+    cond: Condvar,
+    min: usize,
+    max: usize,
+}
+
+impl Controller {
+    fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            state: Mutex::new(State {
+                target: min,
+                active: 0,
+                window_start: Instant::now(),
+                window_units: 0,
+                window_ops: 0,
+                window_busy: Duration::ZERO,
+                last_rate: 0.0,
+                growing: true,
+            }),
+            cond: Condvar::new(),
+            min,
+            max,
+        }
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.active >= state.target {
+            state = self.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.active += 1;
+        Permit {
+            controller: self,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn release(&self, bytes: u64, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.active -= 1;
+        state.window_units += bytes + OP_COST_BYTES;
+        state.window_ops += 1;
+        state.window_busy += elapsed;
+
+        let window = state.window_start.elapsed();
+        if window >= Duration::from_millis(ADJUST_INTERVAL_MS) {
+            let rate = state.window_units as f64 / window.as_secs_f64();
+            let latency = state.window_busy / state.window_ops as u32;
+
+            // Reverse direction whenever the last move made things worse
+            if rate < state.last_rate * 0.95 {
+                state.growing = !state.growing;
+            }
+            let previous = state.target;
+            state.target = if state.growing {
+                (state.target + 1).min(self.max)
+            } else {
+                state.target.saturating_sub(1).max(self.min)
+            };
+            if state.target != previous {
+                info!(
+                    "Adjusted workers {} -> {} ({}/s, {} avg latency)",
+                    previous,
+                    state.target,
+                    format_size(rate as u64),
+                    format_duration(latency)
+                );
+            }
+
+            state.last_rate = rate;
+            state.window_start = Instant::now();
+            state.window_units = 0;
+            state.window_ops = 0;
+            state.window_busy = Duration::ZERO;
+        }
+        self.cond.notify_all();
+    }
+}
+
+// A worker slot; released on drop so a panicking job cannot leak it and
+// stall the remaining workers.
+struct Permit<'a> {
+    controller: &'a Controller,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.controller.release(self.bytes, self.started.elapsed());
+    }
+}
+
+// Run `work` over every job emitted by `produce` using an adaptively sized
+// pool of workers. `work` returns the number of bytes it processed. Emitting
+// a job returns false once a job has failed, and the producer should stop.
+// The first error is returned once all workers have stopped.
+pub fn run_adaptive<T, P, W>(produce: P, work: W) -> io::Result<()>
+where
+    T: Send,
+    P: FnOnce(&mut dyn FnMut(T) -> bool) -> io::Result<()>,
+    W: Fn(T) -> io::Result<u64> + Sync,
+{
+    let controller = Controller::new(MIN_JOBS, MAX_JOBS);
+    let (sender, receiver) = mpsc::sync_channel::<T>(controller.max * 4);
+    let receiver = Mutex::new(receiver);
+    let first_error: Mutex<Option<io::Error>> = Mutex::new(None);
+
+    let produced = thread::scope(|scope| {
+        for _ in 0..controller.max {
+            scope.spawn(|| loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // After a failure keep draining the queue without doing work
+                if first_error.lock().unwrap_or_else(|e| e.into_inner()).is_some() {
+                    continue;
+                }
+                let mut permit = controller.acquire();
+                // A panicking job must not take its worker down, or the
+                // producer could block forever on a queue nobody drains
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(job)))
+                    .unwrap_or_else(|_| Err(io::Error::other("Worker panicked")));
+                permit.bytes = *result.as_ref().unwrap_or(&0);
+                drop(permit);
+                if let Err(e) = result {
+                    first_error.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert(e);
+                }
+            });
+        }
+
+        let sender = sender;
+        produce(&mut |job| {
+            first_error.lock().unwrap_or_else(|e| e.into_inner()).is_none() && sender.send(job).is_ok()
+        })
+    });
+
+    if let Some(e) = first_error.into_inner().unwrap_or_else(|e| e.into_inner()) {
+        return Err(e);
+    }
+    produced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_producing_after_a_failure() {
+        let mut emitted = 0;
+        let result = run_adaptive(
+            |emit| {
+                for job in 0..100_000u32 {
+                    if !emit(job) {
+                        break;
+                    }
+                    emitted += 1;
+                }
+                Ok(())
+            },
+            |job| if job == 0 { Err(io::Error::other("failed")) } else { Ok(0) },
+        );
+        assert!(result.is_err());
+        assert!(emitted < 100_000);
+    }
+}
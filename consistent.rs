@@ -0,0 +1,125 @@
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::config::{CONSISTENT_COPY, CONSISTENT_COPY_RETRIES};
+use crate::error::Error;
+use crate::pattern::path_matches;
+use crate::reflink::copy_file;
+
+// Snapshots are staged inside the repository so they can be renamed into the
+// checkpoint instead of copied a second time
+const SNAPSHOT_DIR: &str = ".snapshots";
+const RETRY_DELAY_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // SQLite online backup API: a transactionally consistent copy, even of a
+    // database that is being written to
+    Sqlite,
+    // Plain copy, repeated until the file did not change while it was being
+    // copied (LevelDB tables and logs, anything rewritten in place)
+    Stable,
+}
+
+// The strategy configured for a source file; the first matching pattern wins
+pub fn strategy_for(rel: &Path) -> io::Result<Option<Strategy>> {
+    for (pattern, strategy) in CONSISTENT_COPY {
+        if !path_matches(pattern, rel) {
+            continue;
+        }
+        return match *strategy {
+            "sqlite" => Ok(Some(Strategy::Sqlite)),
+            "stable" => Ok(Some(Strategy::Stable)),
+            other => Err(Error::Config(format!("Unknown consistent copy strategy {:?} for {:?}", other, pattern)).into()),
+        };
+    }
+    Ok(None)
+}
+
+fn sqlite_backup(source: &Path, dest: &Path) -> io::Result<()> {
+    let failed = |e: rusqlite::Error| io::Error::other(format!("SQLite backup of {:?} failed: {}", source, e));
+    let db = rusqlite::Connection::open_with_flags(source, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(failed)?;
+    db.backup("main", dest, None).map_err(failed)
+}
+
+fn stable_copy(source: &Path, dest: &Path, use_reflink: bool) -> io::Result<()> {
+    let state = |path: &Path| -> io::Result<(u64, Option<SystemTime>)> {
+        let metadata = fs::metadata(path)?;
+        Ok((metadata.len(), metadata.modified().ok()))
+    };
+    for attempt in 0..=CONSISTENT_COPY_RETRIES {
+        let before = state(source)?;
+        copy_file(source, dest, use_reflink)?;
+        if state(source)? == before {
+            return Ok(());
+        }
+        if attempt < CONSISTENT_COPY_RETRIES {
+            info!("{:?} changed while being copied, retrying", source);
+            thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+        }
+    }
+    warn!("{:?} kept changing during {} copies; keeping the last one", source, CONSISTENT_COPY_RETRIES + 1);
+    Ok(())
+}
+
+// A consistent copy of a source file, staged until it is moved into the
+// checkpoint. It is removed again if it never is (e.g. the file is unchanged).
+pub struct Snapshot {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl Snapshot {
+    pub fn take(source: &Path, rel: &Path, backup_dir: &Path, strategy: Strategy, use_reflink: bool) -> io::Result<Self> {
+        let snapshot = Self::staged(rel, backup_dir, "")?;
+        match strategy {
+            Strategy::Sqlite => {
+                if let Err(e) = sqlite_backup(source, &snapshot.path) {
+                    warn!("{}; falling back to a plain copy", e);
+                    stable_copy(source, &snapshot.path, use_reflink)?;
+                }
+            }
+            Strategy::Stable => stable_copy(source, &snapshot.path, use_reflink)?,
+        }
+        Ok(snapshot)
+    }
+
+    // A place to stage data derived from the source file `rel`, e.g. its
+    // filtered content (see filters.rs); `tag` tells apart several at once
+    pub fn staged(rel: &Path, backup_dir: &Path, tag: &str) -> io::Result<Self> {
+        let dir = backup_dir.join(SNAPSHOT_DIR);
+        fs::create_dir_all(&dir)?;
+        let name = format!("{}-{:016x}{}", std::process::id(), xxh3_64(rel.to_string_lossy().as_bytes()), tag);
+        let snapshot = Self { path: dir.join(name), persisted: false };
+        if snapshot.path.exists() {
+            fs::remove_file(&snapshot.path)?;
+        }
+        Ok(snapshot)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Move the snapshot to its place in the checkpoint; returns its size
+    pub fn persist(mut self, dest: &Path) -> io::Result<u64> {
+        let size = fs::metadata(&self.path)?.len();
+        fs::rename(&self.path, dest)?;
+        self.persisted = true;
+        Ok(size)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use log::info;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backup_utils::FileInfo;
+use crate::browse;
+use crate::checkpoint::{
+    checkpoint_ref, ensure_unlocked, list_checkpoints, record_root, remove_from_chain, resolve_checkpoint,
+    resolve_files, set_latest,
+};
+use crate::config::CHECKPOINT_NAME;
+use crate::journal;
+use crate::manifest::{has_manifest, rewrite_entries};
+use crate::pack::{PackRef, PACK_DIR};
+use crate::zip_handler::{meta_zips, read_zip_metas, write_zip_metas};
+
+fn checkpoint_name(checkpoint: &Path) -> String {
+    checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Stored data of `checkpoint` that later checkpoints resolve their files to:
+// path within the checkpoint (a data file or a pack) and the checkpoints
+// needing it
+fn needed_data(backup_dir: &Path, checkpoint: &Path, later: &[PathBuf]) -> io::Result<BTreeMap<PathBuf, BTreeSet<String>>> {
+    let mut needed: BTreeMap<PathBuf, BTreeSet<String>> = BTreeMap::new();
+    for dependent in later {
+        for file in resolve_files(backup_dir, dependent)?.into_values() {
+            if let Ok(inner) = file.stored_at.strip_prefix(checkpoint) {
+                needed.entry(inner.to_path_buf()).or_default().insert(checkpoint_name(dependent));
+            }
+        }
+    }
+    Ok(needed)
+}
+
+// Move the data later checkpoints need into `next` and point their manifests
+// and metas there. Packs keep their name unless `next` has one of the same
+// name already.
+fn rehome(
+    checkpoint: &Path,
+    next: &Path,
+    dependents: &[&PathBuf],
+    needed: &BTreeMap<PathBuf, BTreeSet<String>>,
+) -> io::Result<()> {
+    let (old, new) = (checkpoint_name(checkpoint), checkpoint_name(next));
+    let mut renamed_packs = HashMap::new();
+    let mut moves = Vec::new();
+    // Check every target before moving anything
+    for inner in needed.keys() {
+        let mut target = next.join(inner);
+        if inner.starts_with(PACK_DIR) && target.exists() {
+            let pack = inner.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let renamed = format!("{}.{}", old, pack);
+            target = next.join(PACK_DIR).join(&renamed);
+            renamed_packs.insert(pack, renamed);
+        }
+        if target.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Cannot move {:?} into {}: it already has a file there", inner, new),
+            ));
+        }
+        moves.push((checkpoint.join(inner), target));
+    }
+    for (source, target) in &moves {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(source, target)?;
+    }
+    info!("Moved {} stored files from {} into {}", moves.len(), old, new);
+
+    let repoint = |stored_in: &mut Option<String>, packed: &mut Option<PackRef>| {
+        if stored_in.as_deref() != Some(old.as_str()) {
+            return false;
+        }
+        *stored_in = Some(new.clone());
+        if let Some(packed) = packed {
+            if let Some(renamed) = renamed_packs.get(&packed.pack) {
+                packed.pack = renamed.clone();
+            }
+        }
+        true
+    };
+    for dependent in dependents {
+        if has_manifest(dependent) {
+            rewrite_entries(dependent, |entry| repoint(&mut entry.stored_in, &mut entry.packed))?;
+        }
+        for zip in meta_zips(dependent) {
+            let mut metas = read_zip_metas(&zip)?;
+            let mut changed = false;
+            for meta in metas.iter_mut() {
+                let mut info = FileInfo::parse(&meta.content)?;
+                if repoint(&mut info.stored_in, &mut info.packed) {
+                    meta.content = info.to_meta_string();
+                    changed = true;
+                }
+            }
+            if changed {
+                write_zip_metas(&zip, &metas)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Delete a checkpoint. Later checkpoints may still hold files whose data it
+// stores; the deletion is then refused, or with `rehome` that data is moved
+// into the next checkpoint first and the references to it are rewritten.
+pub fn delete_checkpoint(backup_dir: &Path, reference: &str, rehome_data: bool) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, reference)?;
+    ensure_unlocked(&checkpoint)?;
+    let name = checkpoint_name(&checkpoint);
+    let checkpoints = list_checkpoints(backup_dir)?;
+    let position = checkpoints.iter().position(|c| *c == checkpoint).unwrap_or(checkpoints.len());
+    let later = checkpoints.get(position + 1..).unwrap_or_default();
+
+    let needed = needed_data(backup_dir, &checkpoint, later)?;
+    if !needed.is_empty() {
+        let dependents: BTreeSet<&String> = needed.values().flatten().collect();
+        let listed: Vec<&str> = dependents.iter().map(|name| name.as_str()).collect();
+        if !rehome_data {
+            return Err(io::Error::other(format!(
+                "Checkpoint {} stores {} files or packs that later checkpoints still use ({}); \
+                 use --rehome to move them into the next checkpoint",
+                name,
+                needed.len(),
+                listed.join(", ")
+            )));
+        }
+        let next = &later[0];
+        let dependents: Vec<&PathBuf> = later.iter().filter(|c| dependents.contains(&checkpoint_name(c))).collect();
+        ensure_unlocked(next)?;
+        for dependent in &dependents {
+            ensure_unlocked(dependent)?;
+        }
+        rehome(&checkpoint, next, &dependents, &needed)?;
+        for dependent in dependents {
+            record_root(backup_dir, dependent)?;
+        }
+    }
+
+    fs::remove_dir_all(&checkpoint)?;
+    remove_from_chain(backup_dir, &name)?;
+    browse::forget(backup_dir, &name)?;
+
+    let latest = backup_dir.join(CHECKPOINT_NAME);
+    if fs::read_to_string(&latest).is_ok_and(|content| checkpoint_ref(content.trim()) == name) {
+        match position.checked_sub(1).and_then(|i| checkpoints.get(i)) {
+            Some(previous) => set_latest(backup_dir, &checkpoint_name(previous))?,
+            None => fs::remove_file(&latest)?,
+        }
+        // The journal's changes since the previous checkpoint went into this one
+        journal::request_rescan(backup_dir)?;
+    }
+    info!("Deleted checkpoint {}", name);
+    Ok(())
+}
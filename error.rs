@@ -0,0 +1,107 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Failure classes of the backup engine. Modules still returning io::Result
+// carry these inside the io::Error (see `From<Error> for io::Error`), so the
+// class survives until the CLI picks an exit code for it.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Config(String),
+    #[error("Failed to read {path:?}: {source}")]
+    Traversal {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to hash {path:?}: {source}")]
+    Hashing {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Zip archive {path:?}: {source}")]
+    Zip {
+        path: PathBuf,
+        #[source]
+        source: zip::result::ZipError,
+    },
+    #[error("Backup target unavailable: {0}")]
+    Backend(String),
+    #[error("Verification failed for {path:?}: {reason}")]
+    Verification { path: PathBuf, reason: String },
+    #[error("Critical path {path:?} was not backed up: {source}")]
+    Critical {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Source {path:?} went missing: {reason}")]
+    SourceLost { path: PathBuf, reason: String },
+    #[error("Suspected mass change, run stopped: {0}; `mass-change accept` lets the next run proceed")]
+    MassChange(String),
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn traversal(path: &Path, source: io::Error) -> Self {
+        Self::Traversal { path: path.to_path_buf(), source }
+    }
+
+    pub fn hashing(path: &Path, source: io::Error) -> Self {
+        Self::Hashing { path: path.to_path_buf(), source }
+    }
+
+    pub fn critical(path: &Path, source: io::Error) -> Self {
+        Self::Critical { path: path.to_path_buf(), source }
+    }
+
+    pub fn zip(path: &Path, source: zip::result::ZipError) -> Self {
+        Self::Zip { path: path.to_path_buf(), source }
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Config(_) => io::ErrorKind::InvalidInput,
+            Self::Traversal { source, .. } | Self::Hashing { source, .. } | Self::Io(source) => source.kind(),
+            Self::Zip { .. } | Self::Verification { .. } => io::ErrorKind::InvalidData,
+            Self::Backend(_) => io::ErrorKind::NotFound,
+            // Never mistaken for a file that may be skipped
+            Self::Critical { .. } | Self::SourceLost { .. } | Self::MassChange(_) => io::ErrorKind::Other,
+        }
+    }
+
+    // Process exit status for the class, so scripts can tell failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Backend(_) => 3,
+            Self::Verification { .. } => 4,
+            Self::Traversal { .. } | Self::Hashing { .. } => 5,
+            Self::Zip { .. } => 6,
+            Self::Critical { .. } => 7,
+            Self::SourceLost { .. } => 8,
+            Self::MassChange(_) => 9,
+            Self::Io(_) => 1,
+        }
+    }
+}
+
+// Unwraps an Error that travelled through io::Result code
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        e.downcast::<Error>().unwrap_or_else(Self::Io)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
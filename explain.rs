@@ -0,0 +1,174 @@
+use std::io;
+use std::path::Path;
+
+use crate::checkpoint::{checkpoints_up_to, resolve_checkpoint};
+use crate::human::format_size;
+use crate::manifest::{has_manifest, lookup, read_entries, ManifestEntry};
+
+// Why a run stored a file's data instead of pointing at the copy of an
+// earlier checkpoint. Recorded among the manifest flags as "copied:<cause>",
+// which older binaries carry along like any other marker.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CopyCause {
+    // Not in the last checkpoint; a renamed or moved file looks like this too,
+    // as files are matched by path
+    New,
+    SizeChanged,
+    // Same size, other hash, and a different modification time
+    ContentChanged,
+    // Same size and modification time but another hash: a change a
+    // modification time fast path would have missed
+    ContentChangedInPlace,
+    // Unchanged, but HARDLINK_UNCHANGED couldn't link the earlier copy
+    NotLinked,
+}
+
+const FLAG_PREFIX: &str = "copied:";
+
+impl CopyCause {
+    fn name(self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::SizeChanged => "size",
+            Self::ContentChanged => "content",
+            Self::ContentChangedInPlace => "content-same-mtime",
+            Self::NotLinked => "not-linked",
+        }
+    }
+
+    pub fn flag(self) -> String {
+        format!("{}{}", FLAG_PREFIX, self.name())
+    }
+
+    fn from_flags(flags: &[String]) -> Option<Self> {
+        let name = flags.iter().find_map(|flag| flag.strip_prefix(FLAG_PREFIX))?;
+        [Self::New, Self::SizeChanged, Self::ContentChanged, Self::ContentChangedInPlace, Self::NotLinked]
+            .into_iter()
+            .find(|cause| cause.name() == name)
+    }
+
+    // What the cause would have been, judged by the entry of the checkpoint
+    // before, for checkpoints written before causes were recorded. None for
+    // the same data, which HARDLINK_UNCHANGED links rather than copies.
+    fn inferred(entry: &ManifestEntry, before: Option<&ManifestEntry>) -> Option<Self> {
+        match before {
+            None => Some(Self::New),
+            Some(before) if before.size != entry.size => Some(Self::SizeChanged),
+            Some(before) if before.hash == entry.hash => None,
+            Some(before) if before.time_stamp == entry.time_stamp => Some(Self::ContentChangedInPlace),
+            Some(_) => Some(Self::ContentChanged),
+        }
+    }
+}
+
+fn timestamp(seconds: i64) -> String {
+    chrono::DateTime::from_timestamp(seconds, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| seconds.to_string())
+}
+
+// Files of `checkpoint` holding the same data as `entry` under another path
+fn same_content_elsewhere(checkpoint: &Path, entry: &ManifestEntry) -> io::Result<Vec<ManifestEntry>> {
+    let mut found = Vec::new();
+    for other in read_entries(checkpoint) {
+        let other = other?;
+        if other.size == entry.size && other.hash == entry.hash && other.path != entry.path {
+            found.push(other);
+        }
+    }
+    Ok(found)
+}
+
+// Explain why the data of `rel` in a checkpoint was copied: by the checkpoint
+// itself, with the cause its run recorded, or, for a file it found
+// unchanged, by the earlier checkpoint that holds the data.
+pub fn why_copied(backup_dir: &Path, checkpoint_name: &str, rel: &Path) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, checkpoint_name)?;
+    if !has_manifest(&checkpoint) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{:?} has no manifest; run `repo upgrade` first", checkpoint),
+        ));
+    }
+    let name = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let entry = lookup(&checkpoint, rel)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not in {}", rel, name)))?;
+
+    // The checkpoint whose run copied the data
+    let stored_in = entry.stored_in.clone().unwrap_or_else(|| name.clone());
+    let chain = checkpoints_up_to(backup_dir, &backup_dir.join(&stored_in))?;
+    let (copier, before) = match chain.split_last() {
+        Some((last, rest)) if last.file_name().is_some_and(|n| *n == *stored_in) => (last.clone(), rest.last()),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{:?} in {} is stored in {}, which no longer exists", rel, name, stored_in),
+            ))
+        }
+    };
+    if stored_in != name {
+        println!("{:?} was unchanged in {}; its data was copied by {}", rel, name, stored_in);
+    }
+    // The entry as the copying run recorded it
+    let copied = if stored_in == name { Some(entry) } else { lookup(&copier, rel)? };
+    let Some(copied) = copied else {
+        println!("{} holds the data under another path or was rewritten since; no cause to show", stored_in);
+        return Ok(());
+    };
+    let previous = match before.filter(|before| has_manifest(before)) {
+        Some(before) => lookup(before, rel)?,
+        None => None,
+    };
+    let before_name = before.and_then(|b| b.file_name()).map(|n| n.to_string_lossy().to_string());
+    let last = before_name.as_deref().unwrap_or("no earlier checkpoint");
+    let recorded = CopyCause::from_flags(&copied.flags);
+    let Some(cause) = recorded.or_else(|| CopyCause::inferred(&copied, previous.as_ref())) else {
+        println!("{} didn't copy {:?}: unchanged since {}, whose copy it links to", stored_in, rel, last);
+        return Ok(());
+    };
+    match cause {
+        CopyCause::New => {
+            match &before_name {
+                Some(before) => println!("{} copied {:?}: new file, not in {}", stored_in, rel, before),
+                None => println!("{} copied {:?}: new file, in the first checkpoint", stored_in, rel),
+            }
+            if let Some(before) = before.filter(|before| has_manifest(before)) {
+                for other in same_content_elsewhere(before, &copied)? {
+                    println!(
+                        "  {} held the same data as {:?}; renames and moves aren't detected, files are matched by path",
+                        last, other.path
+                    );
+                }
+            }
+        }
+        CopyCause::SizeChanged => {
+            let old = previous.as_ref().map_or("?".to_string(), |p| format_size(p.size));
+            println!("{} copied {:?}: size changed from {} to {}", stored_in, rel, old, format_size(copied.size));
+        }
+        CopyCause::ContentChanged => {
+            println!("{} copied {:?}: content changed (hash {} -> {})", stored_in, rel, previous.as_ref().map_or("?", |p| &p.hash), copied.hash);
+            if let Some(previous) = &previous {
+                println!("  modified {} -> {}", timestamp(previous.time_stamp), timestamp(copied.time_stamp));
+            }
+        }
+        CopyCause::ContentChangedInPlace => {
+            println!(
+                "{} copied {:?}: content changed though size and modification time ({}) did not",
+                stored_in,
+                rel,
+                timestamp(copied.time_stamp)
+            );
+            println!("  Every file is hashed, so changes that keep the modification time are caught too");
+        }
+        CopyCause::NotLinked => {
+            println!(
+                "{} copied {:?}: unchanged, but the copy in {} couldn't be hard-linked (HARDLINK_UNCHANGED)",
+                stored_in, rel, last
+            );
+        }
+    }
+    if recorded.is_none() {
+        println!("  (inferred from {}; {} predates recording the cause)", last, stored_in);
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::checkpoint::{resolve_checkpoint, resolve_files, write_atomic};
+use crate::manifest::{escape_path, unescape_path};
+use crate::priority::RunGuard;
+use crate::settings::Config;
+use crate::status::Progress;
+use crate::verify::{already_restored, restore_stored};
+
+// Plain-tree export: every file of a checkpoint written out in full at its
+// checkpoint-relative path, with its backup modification time, so rsync and
+// other tools that only understand plain files can take it from there. The
+// sidecar lists each file as "hash size mtime path" after a checkpoint line.
+pub const TREE_MANIFEST_NAME: &str = ".backup-manifest";
+
+// Files of the export already in `out`, from its sidecar. Only an empty
+// directory or an earlier export may be exported into.
+fn previous_export(out: &Path) -> io::Result<HashSet<PathBuf>> {
+    match fs::read_to_string(out.join(TREE_MANIFEST_NAME)) {
+        Ok(manifest) => Ok(manifest
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.splitn(4, '\t').nth(3))
+            .map(unescape_path)
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let empty = match fs::read_dir(out) {
+                Ok(mut entries) => entries.next().is_none(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+                Err(e) => return Err(e),
+            };
+            if !empty {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} is neither empty nor an earlier export", out),
+                ));
+            }
+            Ok(HashSet::new())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Export a checkpoint into `out` as a plain directory tree. Exporting into an
+// earlier export brings it up to date: files that are already right are
+// kept, and those the checkpoint no longer has are removed.
+pub fn export_tree(
+    config: &Config,
+    checkpoint_name: &str,
+    paths: &[String],
+    out: &Path,
+    run: &RunGuard,
+    progress: &Progress,
+) -> io::Result<()> {
+    let backup_dir = config.backup_dir();
+    let checkpoint = resolve_checkpoint(backup_dir, checkpoint_name)?;
+    let files = resolve_files(backup_dir, &checkpoint)?;
+    let checkpoint_name = checkpoint
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let previous = previous_export(out)?;
+    fs::create_dir_all(out)?;
+
+    let selected: Vec<_> = files
+        .iter()
+        .filter(|(rel, _)| paths.is_empty() || paths.iter().any(|p| rel.starts_with(p.trim_end_matches('/'))))
+        .collect();
+    for (_, file) in &selected {
+        progress.add_total(file.info.size);
+    }
+    progress.scan_complete();
+
+    let mut manifest = format!("checkpoint\t{}\n", checkpoint_name);
+    for (rel, file) in &selected {
+        manifest.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            file.info.hash,
+            file.info.size,
+            file.info.time_stamp.timestamp(),
+            escape_path(rel)
+        ));
+    }
+    // Until the export is complete the sidecar also lists the files still to
+    // be removed, so an interrupted export can be resumed and cleaned up
+    let exported: HashSet<&PathBuf> = selected.iter().map(|(rel, _)| *rel).collect();
+    let stale: Vec<&PathBuf> = previous.iter().filter(|rel| !exported.contains(rel)).collect();
+    let mut pending = manifest.clone();
+    for rel in &stale {
+        pending.push_str(&format!("-\t-\t-\t{}\n", escape_path(rel)));
+    }
+    write_atomic(&out.join(TREE_MANIFEST_NAME), pending.as_bytes())?;
+
+    let (mut kept, mut mismatched) = (0, 0);
+    for (rel, file) in &selected {
+        run.pause_if_needed();
+        progress.begin_file(rel);
+        let out_path = out.join(rel);
+        let modified = SystemTime::from(file.info.time_stamp);
+        if already_restored(&out_path, file.info.size, &file.info.hash) {
+            fs::File::options().write(true).open(&out_path)?.set_modified(modified)?;
+            kept += 1;
+        } else if !restore_stored(config, file, &out_path, run, |f| f.set_modified(modified))? {
+            mismatched += 1;
+        }
+        progress.finish_file(file.info.size);
+    }
+    if selected.is_empty() {
+        warn!("No files in {:?} matched {:?}", checkpoint, paths);
+    }
+
+    for rel in &stale {
+        match fs::remove_file(out.join(rel)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Directories left empty go too; others stay
+        for dir in rel.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()) {
+            if fs::remove_dir(out.join(dir)).is_err() {
+                break;
+            }
+        }
+    }
+    write_atomic(&out.join(TREE_MANIFEST_NAME), manifest.as_bytes())?;
+
+    if mismatched > 0 {
+        warn!("{} files failed verification", mismatched);
+    }
+    info!(
+        "Exported {} files from {:?} into {:?} ({} already there, {} removed)",
+        selected.len(),
+        checkpoint,
+        out,
+        kept,
+        stale.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::checkpoint::write_atomic;
+use crate::clock;
+use crate::human::format_duration;
+use crate::plugins;
+use crate::runid::{self, random_bytes};
+use crate::settings::Config;
+
+// Two-person rule for operations that delete data (delete, prune). A
+// repository can require them to be confirmed with a second passphrase,
+// held by someone other than whoever runs them, and/or to be requested
+// first and confirmed only after a delay: the request is announced to the
+// notification plugins, and anybody may cancel it in the meantime. Changing
+// or removing the guard needs the same confirmation.
+const GUARD_FILE: &str = ".guard.json";
+const PENDING_FILE: &str = ".guard-pending.json";
+// Where the second passphrase is taken from instead of asking for it
+const PASSPHRASE_ENV: &str = "NBU_CONFIRM_PASSPHRASE";
+// How long a request stays confirmable once its delay has passed
+const CONFIRM_WINDOW: Duration = Duration::from_secs(7 * 24 * 3600);
+// xxh3 is not a password hash; rounds only slow down guessing. The guard
+// stops mistakes and casual misuse, not someone who can edit the repository.
+const STRETCH_ROUNDS: u32 = 200_000;
+
+#[derive(Serialize, Deserialize)]
+struct Passphrase {
+    salt: String,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Guard {
+    passphrase: Option<Passphrase>,
+    delay_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    token: String,
+    action: String,
+    // RFC 3339
+    requested_at: String,
+    run_id: String,
+}
+
+impl Request {
+    // An unreadable time counts as long ago, so the request expires
+    fn requested_at(&self) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&self.requested_at).map(|at| at.with_timezone(&Utc)).unwrap_or_default()
+    }
+}
+
+fn load<T: for<'de> Deserialize<'de> + Default>(path: &Path) -> io::Result<T> {
+    match fs::read(path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    write_atomic(path, &serde_json::to_vec_pretty(value).map_err(io::Error::other)?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn stretch(salt: &str, passphrase: &str) -> String {
+    let mut digest = xxh3_128(format!("{}\0{}", salt, passphrase).as_bytes());
+    for _ in 0..STRETCH_ROUNDS {
+        digest = xxh3_128(&[&digest.to_le_bytes()[..], passphrase.as_bytes()].concat());
+    }
+    format!("{:032x}", digest)
+}
+
+// Read a passphrase from PASSPHRASE_ENV or the terminal, without echoing it
+fn read_passphrase(prompt: &str) -> io::Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    let tty = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } == 0;
+    if tty {
+        let mut quiet = term;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) };
+    }
+    let mut line = String::new();
+    let result = io::stdin().lock().read_line(&mut line);
+    if tty {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) };
+        eprintln!();
+    }
+    result?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn denied(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+// Check the second passphrase
+fn check_passphrase(passphrase: &Passphrase, action: &str) -> io::Result<()> {
+    let given = read_passphrase(&format!("Second passphrase to confirm `{}`: ", action))?;
+    if stretch(&passphrase.salt, &given) != passphrase.hash {
+        return Err(denied(format!("Wrong second passphrase; `{}` not confirmed", action)));
+    }
+    Ok(())
+}
+
+// With a delay: take the matching request given by `confirm` once its delay
+// is over, or record a new request and refuse for now
+fn check_delay(config: &Config, delay: Duration, action: &str, confirm: Option<&str>) -> io::Result<()> {
+    let path = config.backup_dir().join(PENDING_FILE);
+    let mut pending: Vec<Request> = load(&path)?;
+    let now = clock::now();
+    let window = chrono::Duration::from_std(delay + CONFIRM_WINDOW).map_err(io::Error::other)?;
+    pending.retain(|request| request.requested_at() + window > now);
+
+    if let Some(token) = confirm {
+        let Some(index) = pending.iter().position(|request| request.token == token) else {
+            return Err(denied(format!("No pending request {}", token)));
+        };
+        let request = &pending[index];
+        if request.action != action {
+            return Err(denied(format!("Request {} was for `{}`, not `{}`", token, request.action, action)));
+        }
+        let due = request.requested_at() + chrono::Duration::from_std(delay).map_err(io::Error::other)?;
+        if now < due {
+            let left = (due - now).to_std().unwrap_or_default();
+            return Err(denied(format!("Request {} can be confirmed in {}", token, format_duration(left))));
+        }
+        pending.remove(index);
+        save(&path, &pending)?;
+        return Ok(());
+    }
+
+    let token = hex(&random_bytes());
+    let confirm_after = now + chrono::Duration::from_std(delay).map_err(io::Error::other)?;
+    pending.push(Request {
+        token: token.clone(),
+        action: action.to_string(),
+        requested_at: now.to_rfc3339(),
+        run_id: runid::current(),
+    });
+    save(&path, &pending)?;
+    plugins::notify(
+        &config.plugin_dir,
+        "confirmation_requested",
+        serde_json::json!({ "action": action, "token": token, "confirm_after": confirm_after.to_rfc3339() }),
+    );
+    Err(denied(format!(
+        "`{}` needs confirmation: run it again with --confirm {} after {} (cancel with `guard cancel {}`)",
+        action,
+        token,
+        confirm_after.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+        token
+    )))
+}
+
+// Let `action` go ahead if the repository's guard is satisfied. `confirm`
+// is the token of an earlier request, for guards with a delay.
+pub fn authorize(config: &Config, action: &str, confirm: Option<&str>) -> io::Result<()> {
+    let guard: Guard = load(&config.backup_dir().join(GUARD_FILE))?;
+    if let Some(passphrase) = &guard.passphrase {
+        check_passphrase(passphrase, action)?;
+    }
+    if let Some(delay) = guard.delay_secs {
+        check_delay(config, Duration::from_secs(delay), action, confirm)?;
+    }
+    if guard.passphrase.is_some() || guard.delay_secs.is_some() {
+        info!("`{}` confirmed", action);
+    }
+    Ok(())
+}
+
+// Set up the guard: a second passphrase, a delay or both. Replaces the
+// current guard, which has to confirm the change.
+pub fn set(config: &Config, passphrase: bool, delay: Option<Duration>, confirm: Option<&str>) -> io::Result<()> {
+    if !passphrase && delay.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Give --passphrase, --delay <duration> or both"));
+    }
+    authorize(config, "guard set", confirm)?;
+    let passphrase = if passphrase {
+        let first = read_passphrase("New second passphrase: ")?;
+        if env::var(PASSPHRASE_ENV).is_err() && read_passphrase("Repeat it: ")? != first {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The passphrases don't match"));
+        }
+        if first.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The passphrase is empty"));
+        }
+        let salt = hex(&random_bytes());
+        Some(Passphrase { hash: stretch(&salt, &first), salt })
+    } else {
+        None
+    };
+    let guard = Guard { passphrase, delay_secs: delay.map(|delay| delay.as_secs()) };
+    save(&config.backup_dir().join(GUARD_FILE), &guard)?;
+    show(config.backup_dir())
+}
+
+// Remove the guard, once it confirmed that
+pub fn off(config: &Config, confirm: Option<&str>) -> io::Result<()> {
+    let backup_dir = config.backup_dir();
+    authorize(config, "guard off", confirm)?;
+    match fs::remove_file(backup_dir.join(GUARD_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    warn!("Destructive operations on {:?} no longer need confirmation", backup_dir);
+    Ok(())
+}
+
+// Drop a pending request; anybody may
+pub fn cancel(config: &Config, token: &str) -> io::Result<()> {
+    let path = config.backup_dir().join(PENDING_FILE);
+    let mut pending: Vec<Request> = load(&path)?;
+    let Some(index) = pending.iter().position(|request| request.token == token) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No pending request {}", token)));
+    };
+    let request = pending.remove(index);
+    save(&path, &pending)?;
+    info!("Cancelled request {} for `{}`", token, request.action);
+    let details = serde_json::json!({ "action": request.action, "token": token });
+    plugins::notify(&config.plugin_dir, "confirmation_cancelled", details);
+    Ok(())
+}
+
+pub fn show(backup_dir: &Path) -> io::Result<()> {
+    let guard: Guard = load(&backup_dir.join(GUARD_FILE))?;
+    match (&guard.passphrase, guard.delay_secs) {
+        (None, None) => println!("Guard:      off"),
+        (passphrase, delay) => {
+            println!("Guard:      {}", if passphrase.is_some() { "second passphrase" } else { "no passphrase" });
+            if let Some(delay) = delay {
+                println!("Delay:      {}", format_duration(Duration::from_secs(delay)));
+            }
+        }
+    }
+    let pending: Vec<Request> = load(&backup_dir.join(PENDING_FILE))?;
+    for request in pending {
+        println!(
+            "Pending:    {}  `{}` requested {} by run {}",
+            request.token,
+            request.action,
+            request.requested_at().with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+            request.run_id
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+use crate::config::{HASH_PARALLEL_MIN_SIZE, HASH_SEGMENT_SIZE, HASH_THREADS};
+use crate::timeout;
+
+// File hashes are xxh3-64 of the data, written as 16 hex digits. A single
+// xxh3 stream can't be split across threads, so files of at least
+// HASH_PARALLEL_MIN_SIZE get a tree hash instead: xxh3-64 of the
+// concatenated (little endian) xxh3-64 hashes of their segments, written
+// "t<segment MiB>:<hash>". Anything checking data against a recorded hash
+// computes it the way the recorded one was, whatever the settings now.
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum Scheme {
+    Flat,
+    // Segment size in bytes
+    Tree(u64),
+}
+
+impl Scheme {
+    // How a file of `size` bytes is hashed now
+    pub fn for_size(size: u64) -> Self {
+        if HASH_PARALLEL_MIN_SIZE != 0 && size >= HASH_PARALLEL_MIN_SIZE {
+            Scheme::Tree((HASH_SEGMENT_SIZE / MIB).max(1) * MIB)
+        } else {
+            Scheme::Flat
+        }
+    }
+
+    // How `hash` was computed
+    pub fn of(hash: &str) -> Self {
+        hash.strip_prefix('t')
+            .and_then(|rest| rest.split_once(':'))
+            .and_then(|(mib, _)| mib.parse::<u64>().ok())
+            .filter(|&mib| mib > 0)
+            .map_or(Scheme::Flat, |mib| Scheme::Tree(mib * MIB))
+    }
+}
+
+fn tree_hash(segment: u64, digests: &[u64]) -> String {
+    let bytes: Vec<u8> = digests.iter().flat_map(|digest| digest.to_le_bytes()).collect();
+    format!("t{}:{:016x}", segment / MIB, xxh3_64(&bytes))
+}
+
+// Hash of data fed to it in order, in either scheme
+pub struct ContentHasher {
+    segment: Option<u64>,
+    current: Xxh3,
+    // Bytes of the current segment hashed so far
+    filled: u64,
+    digests: Vec<u64>,
+}
+
+impl ContentHasher {
+    pub fn new(scheme: Scheme) -> Self {
+        let segment = match scheme {
+            Scheme::Flat => None,
+            Scheme::Tree(segment) => Some(segment),
+        };
+        Self { segment, current: Xxh3::new(), filled: 0, digests: Vec::new() }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        let Some(segment) = self.segment else {
+            self.current.update(data);
+            return;
+        };
+        while !data.is_empty() {
+            let take = ((segment - self.filled) as usize).min(data.len());
+            self.current.update(&data[..take]);
+            self.filled += take as u64;
+            data = &data[take..];
+            if self.filled == segment {
+                self.digests.push(self.current.digest());
+                self.current.reset();
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> String {
+        match self.segment {
+            None => format!("{:016x}", self.current.digest()),
+            Some(segment) => {
+                if self.filled > 0 || self.digests.is_empty() {
+                    self.digests.push(self.current.digest());
+                }
+                tree_hash(segment, &self.digests)
+            }
+        }
+    }
+}
+
+// Hash of the first `size` bytes of `path` in segments of `segment` bytes,
+// each thread reading its own segments through its own handle
+pub fn hash_segments(path: &Path, size: u64, segment: u64, buffer_size: usize) -> io::Result<String> {
+    let count = size.div_ceil(segment).max(1) as usize;
+    let threads = match HASH_THREADS {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(count);
+    let next = AtomicUsize::new(0);
+    let digests = Mutex::new(vec![0u64; count]);
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    let mut file = timeout::open(path)?;
+                    let mut buffer = vec![0u8; buffer_size];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= count {
+                            return Ok(());
+                        }
+                        let start = index as u64 * segment;
+                        let mut left = segment.min(size - start);
+                        file.seek_to(start)?;
+                        let mut hasher = Xxh3::new();
+                        while left > 0 {
+                            let read = file.read(&mut buffer[..(left as usize).min(buffer_size)])?;
+                            if read == 0 {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    format!("{:?} shrank while it was hashed", path),
+                                ));
+                            }
+                            hasher.update(&buffer[..read]);
+                            left -= read as u64;
+                        }
+                        digests.lock().unwrap()[index] = hasher.digest();
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap_or_else(|_| Err(io::Error::other("Hashing thread panicked"))))
+            .collect::<io::Result<Vec<()>>>()
+    })?;
+    Ok(tree_hash(segment, &digests.into_inner().unwrap()))
+}
@@ -0,0 +1,239 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::{DISK_HEALTH_CHECK, DISK_HEALTH_COMMAND};
+use crate::error::Error;
+
+// SMART attributes that count sectors going bad; any non-zero raw value is reported
+const FAILING_ATTRIBUTES: &[(u64, &str)] = &[
+    (5, "reallocated_sectors"),
+    (197, "pending_sectors"),
+    (198, "offline_uncorrectable"),
+];
+
+// Health snapshot of one disk, as recorded in the run report
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiskHealth {
+    // Source or target path the disk was found for
+    pub path: String,
+    pub device: String,
+    // None when the state could not be read (unsupported, spun down, ...)
+    pub healthy: Option<bool>,
+    pub problems: Vec<String>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, u64>,
+}
+
+fn unescape_mount_path(path: &str) -> String {
+    // mountinfo escapes spaces and the like as \ooo octal
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(c)) => {
+                out.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// Mount point and major:minor of the filesystem holding `path`, from the
+// longest matching mount point
+fn mount_of(path: &Path) -> Option<(PathBuf, String)> {
+    let path = fs::canonicalize(path).ok()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let mount_point = PathBuf::from(unescape_mount_path(fields.get(4)?));
+            let device = fields.get(2)?.to_string();
+            path.starts_with(&mount_point).then_some((mount_point, device))
+        })
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+}
+
+fn mounted_device(path: &Path) -> Option<String> {
+    mount_of(path).map(|(_, device)| device)
+}
+
+// Mount point of the filesystem holding `path`
+pub fn mount_point(path: &Path) -> Option<PathBuf> {
+    mount_of(path).map(|(mount_point, _)| mount_point)
+}
+
+// Bytes available to this user on the filesystem holding `path`
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Whole disks behind a block device: partitions map to their disk, device
+// mapper and md devices to the disks underneath
+fn whole_disks(sys: &Path) -> Vec<String> {
+    let slaves: Vec<PathBuf> = fs::read_dir(sys.join("slaves"))
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    if !slaves.is_empty() {
+        return slaves.iter().filter_map(|s| fs::canonicalize(s).ok()).flat_map(|s| whole_disks(&s)).collect();
+    }
+    let disk = if sys.join("partition").exists() { sys.parent() } else { Some(sys) };
+    disk.and_then(|d| d.file_name()).map(|n| format!("/dev/{}", n.to_string_lossy())).into_iter().collect()
+}
+
+// Whether a block device is a dm-crypt mapping or sits on top of one
+fn is_encrypted(sys: &Path) -> bool {
+    if fs::read_to_string(sys.join("dm/uuid")).is_ok_and(|uuid| uuid.starts_with("CRYPT-")) {
+        return true;
+    }
+    fs::read_dir(sys.join("slaves"))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| fs::canonicalize(e.path()).ok())
+                .any(|slave| is_encrypted(&slave))
+        })
+        .unwrap_or(false)
+}
+
+// Whether the filesystem holding `path` is on a dm-crypt (LUKS) volume; None
+// when that can't be told (virtual or network filesystems)
+pub fn on_encrypted_volume(path: &Path) -> Option<bool> {
+    let device = mounted_device(path)?;
+    let sys = fs::canonicalize(Path::new("/sys/dev/block").join(device)).ok()?;
+    Some(is_encrypted(&sys))
+}
+
+fn devices_for(path: &Path) -> Vec<String> {
+    let Some(device) = mounted_device(path) else {
+        return Vec::new();
+    };
+    // Virtual and network filesystems have no block device in sysfs
+    match fs::canonicalize(Path::new("/sys/dev/block").join(&device)) {
+        Ok(sys) => whole_disks(&sys),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Interpret `smartctl --json` output; None if the output is something else
+fn parse_smartctl(output: &[u8], health: &mut DiskHealth) -> Option<()> {
+    let json: Value = serde_json::from_slice(output).ok()?;
+    json.get("smartctl")?;
+    let mut passed = json.pointer("/smart_status/passed").and_then(Value::as_bool);
+
+    let ata = json.pointer("/ata_smart_attributes/table").and_then(Value::as_array);
+    for attribute in ata.into_iter().flatten() {
+        let id = attribute["id"].as_u64();
+        let raw = attribute.pointer("/raw/value").and_then(Value::as_u64);
+        if let (Some((_, name)), Some(raw)) = (FAILING_ATTRIBUTES.iter().find(|(a, _)| Some(*a) == id), raw) {
+            health.attributes.insert(name.to_string(), raw);
+            if raw > 0 {
+                health.problems.push(format!("{} {}", raw, name.replace('_', " ")));
+            }
+        }
+    }
+    if let Some(nvme) = json.get("nvme_smart_health_information_log") {
+        for name in ["critical_warning", "media_errors"] {
+            if let Some(value) = nvme[name].as_u64() {
+                health.attributes.insert(name.to_string(), value);
+                if value > 0 {
+                    health.problems.push(format!("{} {}", name.replace('_', " "), value));
+                }
+            }
+        }
+    }
+    if passed == Some(false) {
+        health.problems.push("SMART overall health self-assessment failed".to_string());
+    }
+    if passed.is_none() && !health.attributes.is_empty() {
+        passed = Some(true);
+    }
+    health.healthy = passed.map(|passed| passed && health.problems.is_empty());
+    Some(())
+}
+
+fn check_disk(path: &Path, device: &str) -> DiskHealth {
+    let mut health = DiskHealth {
+        path: path.display().to_string(),
+        device: device.to_string(),
+        healthy: None,
+        problems: Vec::new(),
+        attributes: BTreeMap::new(),
+    };
+    let Some((program, args)) = DISK_HEALTH_COMMAND.split_first() else {
+        return health;
+    };
+    let output = match Command::new(program).args(args).arg(device).stdin(Stdio::null()).output() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to run disk health command {:?}: {}", program, e);
+            return health;
+        }
+    };
+    // Anything other than smartctl reports through its exit status
+    if parse_smartctl(&output.stdout, &mut health).is_none() {
+        health.healthy = Some(output.status.success());
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stdout);
+            let message = message.lines().next().unwrap_or_default();
+            health.problems.push(format!("{} ({})", message, output.status).trim().to_string());
+        }
+    }
+    health
+}
+
+// Query the health of the disks holding each path before a backup. Problems
+// are logged; with DISK_HEALTH_CHECK = "abort" they also fail the run.
+pub fn preflight(paths: &[&Path]) -> io::Result<Vec<DiskHealth>> {
+    match DISK_HEALTH_CHECK {
+        "" => return Ok(Vec::new()),
+        "warn" | "abort" => {}
+        other => {
+            return Err(Error::Config(format!(
+                "Invalid DISK_HEALTH_CHECK {:?}, expected \"warn\" or \"abort\"",
+                other
+            ))
+            .into())
+        }
+    }
+    let mut results: Vec<DiskHealth> = Vec::new();
+    for path in paths {
+        for device in devices_for(path) {
+            if results.iter().any(|h| h.device == device) {
+                continue;
+            }
+            let health = check_disk(path, &device);
+            match health.healthy {
+                Some(true) => info!("Disk {} ({:?}) is healthy", device, path),
+                Some(false) => warn!("Disk {} ({:?}) reports problems: {}", device, path, health.problems.join(", ")),
+                None => info!("Health of disk {} ({:?}) is unavailable", device, path),
+            }
+            results.push(health);
+        }
+    }
+    let unhealthy: Vec<&str> = results.iter().filter(|h| h.healthy == Some(false)).map(|h| h.device.as_str()).collect();
+    if DISK_HEALTH_CHECK == "abort" && !unhealthy.is_empty() {
+        return Err(io::Error::other(format!("Disk health check failed for {}", unhealthy.join(", "))));
+    }
+    Ok(results)
+}
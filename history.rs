@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::checkpoint::write_atomic;
+use crate::clock;
+use crate::config::HISTORY_FILE_NAME;
+use crate::health::DiskHealth;
+use crate::pipeline::StageTimes;
+use crate::human::format_duration;
+use crate::resources::ResourceUsage;
+use crate::settings::Config;
+use crate::targets::all_targets;
+
+// Report of a finished run, kept inside the checkpoint a backup produced
+pub const RUN_REPORT_NAME: &str = ".run-report.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RunReport {
+    #[serde(default)]
+    pub run_id: String,
+    pub operation: String,
+    // Repository the run worked on; a fallback target when the primary was down
+    #[serde(default)]
+    pub repository: String,
+    pub pid: u32,
+    pub started_at: String,
+    pub finished_at: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub files: u64,
+    pub bytes: u64,
+    // Files skipped because they disappeared after being listed
+    #[serde(default)]
+    pub vanished: u64,
+    // Files and directories skipped as unreadable (SKIP_UNREADABLE_FILES)
+    #[serde(default)]
+    pub unreadable: u64,
+    // Files skipped because reading them hit FILE_HARD_TIMEOUT_SECS
+    #[serde(default)]
+    pub timed_out: Vec<String>,
+    pub duration_ms: u64,
+    pub resources: ResourceUsage,
+    // Disk health snapshot taken before the run, if configured
+    #[serde(default)]
+    pub disk_health: Vec<DiskHealth>,
+    // Time the workers spent reading, hashing, compressing and writing data
+    #[serde(default)]
+    pub stages: StageTimes,
+}
+
+// Append the report as one JSON line to BACKUP_DIR/HISTORY_FILE_NAME, the
+// run history that slow runs can be compared against.
+pub fn record_run(backup_dir: &Path, report: &RunReport) {
+    let path = backup_dir.join(HISTORY_FILE_NAME);
+    let result = serde_json::to_string(report)
+        .map_err(io::Error::other)
+        .and_then(|line| {
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            // A single write keeps lines whole when runs finish concurrently
+            file.write_all(format!("{}\n", line).as_bytes())
+        });
+    if let Err(e) = result {
+        warn!("Failed to record run in {:?}: {}", path, e);
+    }
+}
+
+pub fn save_report(checkpoint: &Path, report: &RunReport) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(report).map_err(io::Error::other)?;
+    write_atomic(&checkpoint.join(RUN_REPORT_NAME), &json)
+}
+
+// The most recent successful run of `operation` in the history, if any
+pub fn last_success(backup_dir: &Path, operation: &str) -> Option<RunReport> {
+    let content = fs::read_to_string(backup_dir.join(HISTORY_FILE_NAME)).ok()?;
+    content
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<RunReport>(line).ok())
+        .find(|report| report.success && report.operation == operation)
+}
+
+// The most recent successful run of `operation` on any target
+pub fn newest_success(config: &Config, operation: &str) -> Option<RunReport> {
+    all_targets(config)
+        .filter_map(|target| last_success(target, operation))
+        .max_by_key(|report| DateTime::parse_from_rfc3339(&report.finished_at).ok())
+}
+
+// Print how long ago `report` finished, for monitoring; an error (and a
+// non-zero exit) when there is none or it is older than `max_age`
+pub fn check_freshness(report: Option<RunReport>, max_age: Duration) -> io::Result<()> {
+    let Some(report) = report else {
+        println!("NONE: no successful backup recorded");
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No successful backup recorded"));
+    };
+    let finished = DateTime::parse_from_rfc3339(&report.finished_at)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid time {:?}", report.finished_at)))?;
+    let age = (clock::now() - finished.with_timezone(&Utc)).to_std().unwrap_or_default();
+    let state = if age <= max_age { "OK" } else { "STALE" };
+    println!(
+        "{}: last successful backup {} ago ({}, {})",
+        state,
+        format_duration(age),
+        finished.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+        report.repository
+    );
+    if age > max_age {
+        return Err(io::Error::other(format!("Last successful backup is older than {}", format_duration(max_age))));
+    }
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use log::warn;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::config::{RESTORE_VALIDATE_HOOK, SCAN_HOOK};
+
+#[derive(Debug, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    Flagged,
+}
+
+// Run the configured content scanner (e.g. a clamdscan wrapper) for a file,
+// passing its path and hash as the last two arguments. Exit status 0 means
+// clean; anything else flags the file.
+pub fn scan_file(path: &Path, hash: &str) -> io::Result<ScanVerdict> {
+    let Some((program, args)) = SCAN_HOOK.split_first() else {
+        return Ok(ScanVerdict::Clean);
+    };
+    let status = Command::new(program)
+        .args(args)
+        .arg(path)
+        .arg(hash)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run scan hook {:?}: {}", program, e)))?;
+    if status.success() {
+        Ok(ScanVerdict::Clean)
+    } else {
+        warn!("Scan hook flagged {:?} ({})", path, status);
+        Ok(ScanVerdict::Flagged)
+    }
+}
+
+// Run the restore validation hook (e.g. start a database on the restored
+// files) with the staging directory as its last argument. Exit status 0
+// accepts the restore.
+pub fn validate_restore(dir: &Path) -> io::Result<()> {
+    let Some((program, args)) = RESTORE_VALIDATE_HOOK.split_first() else {
+        return Ok(());
+    };
+    let status = Command::new(program)
+        .args(args)
+        .arg(dir)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run restore validation hook {:?}: {}", program, e)))?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Restore validation hook rejected {:?} ({})", dir, status),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::io;
+use std::time::Duration;
+
+use crate::config::{NUMBER_LOCALE, SIZE_UNITS_BINARY};
+
+// Shared human-friendly rendering for sizes, counts, rates and durations, so
+// every report and log line formats them the same way.
+
+const BINARY_UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+const DECIMAL_UNITS: [&str; 6] = ["B", "kB", "MB", "GB", "TB", "PB"];
+
+// (thousands separator, decimal separator) for the configured locale
+fn separators() -> (&'static str, &'static str) {
+    match NUMBER_LOCALE {
+        "de" | "nl" | "it" | "es" | "pt" => (".", ","),
+        "fr" | "sv" | "fi" | "pl" | "ru" => ("\u{202f}", ","),
+        "ch" => ("'", "."),
+        _ => (",", "."),
+    }
+}
+
+fn group_thousands(digits: &str, separator: &str) -> String {
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push_str(separator);
+        }
+        out.push(c);
+    }
+    out
+}
+
+// 1234567 -> "1,234,567" (or "1.234.567" etc. depending on NUMBER_LOCALE)
+pub fn format_count(n: u64) -> String {
+    group_thousands(&n.to_string(), separators().0)
+}
+
+fn format_decimal(value: f64, precision: usize) -> String {
+    let (thousands, decimal) = separators();
+    let text = format!("{:.*}", precision, value);
+    match text.split_once('.') {
+        Some((int, frac)) => format!("{}{}{}", group_thousands(int, thousands), decimal, frac),
+        None => group_thousands(&text, thousands),
+    }
+}
+
+// Sizes in GiB-style binary units or GB-style decimal units per SIZE_UNITS_BINARY
+pub fn format_size(bytes: u64) -> String {
+    let (base, units) = if SIZE_UNITS_BINARY {
+        (1024.0, BINARY_UNITS)
+    } else {
+        (1000.0, DECIMAL_UNITS)
+    };
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", format_count(bytes), units[0])
+    } else {
+        format!("{} {}", format_decimal(value, 1), units[unit])
+    }
+}
+
+pub fn format_rate(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        return "-".to_string();
+    }
+    format!("{}/s", format_size((bytes as f64 / secs) as u64))
+}
+
+// 3725s -> "1h 2m 5s", 0.25s -> "250ms"
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs == 0 {
+        return format!("{}ms", d.as_millis());
+    }
+    let (h, m, s) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    match (h, m) {
+        (0, 0) => format!("{}s", s),
+        (0, _) => format!("{}m {}s", m, s),
+        _ => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+// "4h", "30m", "1h30m", "90s" or "2d" -> Duration
+pub fn parse_duration(text: &str) -> io::Result<Duration> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid duration {:?}, expected e.g. \"4h\", \"30m\" or \"1h30m\"", text),
+        )
+    };
+    let mut secs = 0u64;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        secs += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() || secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+// "500G", "1.5TiB", "200MB" or "4096" -> bytes. Single-letter and IEC units
+// are binary, SI units ("GB") decimal.
+pub fn parse_size(text: &str) -> io::Result<u64> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid size {:?}, expected e.g. \"500G\", \"1.5TiB\" or \"200MB\"", text),
+        )
+    };
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(invalid()),
+    };
+    let bytes = number * multiplier as f64;
+    if bytes < 1.0 || !bytes.is_finite() {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
@@ -0,0 +1,276 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::checkpoint::{list_checkpoints, CheckpointInfo};
+
+// Import of snapshots from a restic or Borg (1.x) repository. Their formats
+// are encrypted and change between versions, so the tools themselves read
+// them: snapshots are listed as JSON and each one is streamed out as a tar
+// (`restic dump --archive tar`, `borg export-tar`), unpacked into a scratch
+// directory and backed up from there like any source. The repository's
+// password is handed to the tool in its own environment variable; without a
+// password file the tool's usual environment applies, or it asks.
+pub const SCRATCH_DIR: &str = ".import";
+
+#[derive(Clone, Copy)]
+pub enum Tool {
+    Restic,
+    Borg,
+}
+
+impl Tool {
+    pub fn parse(name: &str) -> io::Result<Self> {
+        match name {
+            "restic" => Ok(Tool::Restic),
+            "borg" => Ok(Tool::Borg),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown tool {:?}: use restic or borg", name))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Tool::Restic => "restic",
+            Tool::Borg => "borg",
+        }
+    }
+
+    fn password_env(self) -> &'static str {
+        match self {
+            Tool::Restic => "RESTIC_PASSWORD",
+            Tool::Borg => "BORG_PASSPHRASE",
+        }
+    }
+}
+
+pub struct Snapshot {
+    // restic snapshot ID or Borg archive name
+    pub id: String,
+    pub time: DateTime<Utc>,
+    // Host and paths, or the archive name, for the log
+    pub label: String,
+}
+
+#[derive(Deserialize)]
+struct ResticSnapshot {
+    id: String,
+    time: String,
+    #[serde(default)]
+    hostname: String,
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct BorgList {
+    archives: Vec<BorgArchive>,
+}
+
+#[derive(Deserialize)]
+struct BorgArchive {
+    name: String,
+    time: String,
+}
+
+// restic writes RFC 3339, Borg local time without an offset
+fn parse_time(time: &str) -> io::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid snapshot time {:?}", time)))
+}
+
+fn invalid_json(tool: Tool, e: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected output from {}: {}", tool.name(), e))
+}
+
+// A repository of another tool, with the password to open it
+pub struct Source {
+    tool: Tool,
+    repo: String,
+    password: Option<String>,
+}
+
+impl Source {
+    pub fn new(tool: Tool, repo: &str, password_file: Option<&Path>) -> io::Result<Self> {
+        let password = password_file
+            .map(|path| {
+                fs::read_to_string(path)
+                    .map(|password| password.trim_end_matches(['\r', '\n']).to_string())
+                    .map_err(|e| io::Error::new(e.kind(), format!("Cannot read password file {:?}: {}", path, e)))
+            })
+            .transpose()?;
+        Ok(Self { tool, repo: repo.to_string(), password })
+    }
+
+    // "<tool>:<id>", recorded in the checkpoint a snapshot becomes
+    pub fn origin(&self, snapshot: &Snapshot) -> String {
+        format!("{}:{}", self.tool.name(), snapshot.id)
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(self.tool.name());
+        command.args(args).stderr(Stdio::inherit());
+        if let Some(password) = &self.password {
+            command.env(self.tool.password_env(), password);
+        }
+        command
+    }
+
+    fn spawn_error(&self, e: io::Error) -> io::Error {
+        if e.kind() == io::ErrorKind::NotFound {
+            io::Error::new(e.kind(), format!("{} is not installed or not in PATH", self.tool.name()))
+        } else {
+            e
+        }
+    }
+
+    fn failed(&self, what: &str, status: std::process::ExitStatus) -> io::Error {
+        io::Error::other(format!("{} {} failed ({})", self.tool.name(), what, status))
+    }
+
+    // All snapshots in the repository, oldest first
+    pub fn snapshots(&self) -> io::Result<Vec<Snapshot>> {
+        let args = match self.tool {
+            Tool::Restic => vec!["-r", &self.repo, "snapshots", "--json"],
+            Tool::Borg => vec!["list", "--json", &self.repo],
+        };
+        let output = self.command(&args).output().map_err(|e| self.spawn_error(e))?;
+        if !output.status.success() {
+            return Err(self.failed("listing snapshots", output.status));
+        }
+        let mut snapshots = match self.tool {
+            Tool::Restic => serde_json::from_slice::<Vec<ResticSnapshot>>(&output.stdout)
+                .map_err(|e| invalid_json(self.tool, e))?
+                .into_iter()
+                .map(|s| {
+                    let label = format!("{} {}", s.hostname, s.paths.join(" "));
+                    Ok(Snapshot { time: parse_time(&s.time)?, id: s.id, label })
+                })
+                .collect::<io::Result<Vec<_>>>()?,
+            Tool::Borg => serde_json::from_slice::<BorgList>(&output.stdout)
+                .map_err(|e| invalid_json(self.tool, e))?
+                .archives
+                .into_iter()
+                .map(|a| Ok(Snapshot { time: parse_time(&a.time)?, label: a.name.clone(), id: a.name }))
+                .collect::<io::Result<Vec<_>>>()?,
+        };
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+        Ok(snapshots)
+    }
+
+    // Unpack the part of `snapshot` below `subdir` into `dest`; returns the
+    // number of files
+    pub fn unpack(&self, snapshot: &Snapshot, subdir: &Path, dest: &Path) -> io::Result<u64> {
+        let archive = format!("{}::{}", self.repo, snapshot.id);
+        let args = match self.tool {
+            Tool::Restic => vec!["-r", &self.repo, "dump", "--archive", "tar", &snapshot.id, "/"],
+            Tool::Borg => vec!["export-tar", &archive, "-"],
+        };
+        let mut child = self.command(&args).stdout(Stdio::piped()).spawn().map_err(|e| self.spawn_error(e))?;
+        let stream = child.stdout.take().ok_or_else(|| io::Error::other("No output stream"))?;
+        // The stream is closed before waiting, so a tool still writing to it
+        // after a failed unpack exits instead of blocking
+        let result = unpack_tar(stream, subdir, dest);
+        let status = child.wait()?;
+        let files = result?;
+        if !status.success() {
+            return Err(self.failed(&format!("reading snapshot {}", snapshot.id), status));
+        }
+        Ok(files)
+    }
+}
+
+// `path` from the tar relative to `subdir`, if it is below it and can't
+// leave the destination
+fn relative(path: &Path, subdir: &Path) -> Option<PathBuf> {
+    let path: PathBuf = path
+        .components()
+        .filter(|c| !matches!(c, Component::RootDir | Component::CurDir))
+        .collect();
+    let rel = path.strip_prefix(subdir).ok()?;
+    let normal = rel.components().all(|c| matches!(c, Component::Normal(_)));
+    (normal && !rel.as_os_str().is_empty()).then(|| rel.to_path_buf())
+}
+
+// Directories, files and hard links are unpacked with their modes and
+// modification times; other entries aren't backed up, so they are skipped
+fn unpack_tar(stream: impl Read, subdir: &Path, dest: &Path) -> io::Result<u64> {
+    let mut archive = tar::Archive::new(stream);
+    let mut files = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let Some(rel) = relative(&entry.path()?, subdir) else {
+            continue;
+        };
+        let target = dest.join(&rel);
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            // Modes of directories aren't kept; a read-only one would only
+            // stop its contents from being unpacked
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if entry_type.is_file() || entry_type.is_gnu_sparse() || entry_type.is_contiguous() {
+            entry.unpack(&target)?;
+            files += 1;
+        } else if entry_type.is_hard_link() {
+            match entry.link_name()?.and_then(|link| relative(&link, subdir)) {
+                Some(link) => {
+                    fs::hard_link(dest.join(link), &target)?;
+                    files += 1;
+                }
+                None => warn!("Skipping {:?}: it links to a file outside the imported tree", rel),
+            }
+        } else {
+            debug!("Skipping {:?}: {:?} entries are not backed up", rel, entry_type);
+        }
+    }
+    Ok(files)
+}
+
+// The snapshots named in `wanted` (IDs, unique ID prefixes or archive names),
+// or all of them
+pub fn select(snapshots: Vec<Snapshot>, wanted: &[String]) -> io::Result<Vec<Snapshot>> {
+    if wanted.is_empty() {
+        return Ok(snapshots);
+    }
+    let mut ids = HashSet::new();
+    for name in wanted {
+        let prefixed: Vec<&Snapshot> = snapshots.iter().filter(|s| s.id.starts_with(name.as_str())).collect();
+        let snapshot = match prefixed.iter().find(|s| s.id == *name) {
+            Some(exact) => exact,
+            None if prefixed.len() == 1 => prefixed[0],
+            None => {
+                let problem = if prefixed.is_empty() { "matches no snapshot" } else { "is ambiguous" };
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("Snapshot {:?} {}", name, problem)));
+            }
+        };
+        ids.insert(snapshot.id.clone());
+    }
+    Ok(snapshots.into_iter().filter(|s| ids.contains(&s.id)).collect())
+}
+
+// Origins of the checkpoints already imported, so a repeated import only
+// converts new snapshots
+pub fn imported(backup_dir: &Path) -> io::Result<HashSet<String>> {
+    let mut origins = HashSet::new();
+    for checkpoint in list_checkpoints(backup_dir)? {
+        if let Some(origin) = CheckpointInfo::load(&checkpoint)?.imported_from {
+            origins.insert(origin);
+        }
+    }
+    Ok(origins)
+}
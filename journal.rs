@@ -0,0 +1,163 @@
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use crate::checkpoint::write_atomic;
+use crate::clock;
+use crate::history::{RunReport, RUN_REPORT_NAME};
+use crate::manifest::{escape_path, has_manifest, unescape_path};
+use crate::priority::is_alive;
+
+// Change journal kept by the `watch` command in BACKUP_DIR/.journal: `watcher`
+// holds the watcher's pid and start time, `changes` the source paths
+// (relative to SRC_DIR) touched since the last backup took the journal.
+const JOURNAL_DIR: &str = ".journal";
+const WATCHER_FILE: &str = "watcher";
+const CHANGES_FILE: &str = "changes";
+// Written when events were lost; the next backup has to walk the whole tree
+const RESCAN_MARKER: &str = "!rescan";
+
+// Paths changed since the last backup, and the checkpoint they are relative to
+pub struct JournalChanges {
+    pub paths: HashSet<PathBuf>,
+    pub baseline: PathBuf,
+}
+
+fn append_changes(dir: &Path, lines: &str) -> io::Result<()> {
+    // Reopened for every batch so a backup can rename the file away at any time
+    let mut file = OpenOptions::new().create(true).append(true).open(dir.join(CHANGES_FILE))?;
+    file.write_all(lines.as_bytes())
+}
+
+// Make the next backup walk the full tree, e.g. after the checkpoint the
+// recorded changes were relative to was deleted
+pub fn request_rescan(backup_dir: &Path) -> io::Result<()> {
+    let dir = backup_dir.join(JOURNAL_DIR);
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    append_changes(&dir, &format!("{}\n", RESCAN_MARKER))
+}
+
+// Give changes taken by a backup back to the journal, when that backup
+// didn't keep a checkpoint, so the next one processes them
+pub fn return_changes(backup_dir: &Path, changes: &JournalChanges) -> io::Result<()> {
+    let lines: String = changes.paths.iter().map(|path| format!("{}\n", escape_path(path))).collect();
+    append_changes(&backup_dir.join(JOURNAL_DIR), &lines)
+}
+
+// Watch the source tree and record every changed path until killed.
+pub fn watch(src: &Path, backup_dir: &Path) -> io::Result<()> {
+    let dir = backup_dir.join(JOURNAL_DIR);
+    fs::create_dir_all(&dir)?;
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+    watcher.watch(src, RecursiveMode::Recursive).map_err(io::Error::other)?;
+    // Anything before this moment is unknown to the journal
+    append_changes(&dir, &format!("{}\n", RESCAN_MARKER))?;
+    write_atomic(
+        &dir.join(WATCHER_FILE),
+        format!("{}\n{}\n", std::process::id(), clock::now().to_rfc3339()).as_bytes(),
+    )?;
+    info!("Watching {:?} for changes", src);
+
+    for event in receiver {
+        let mut lines = String::new();
+        match event {
+            Ok(event) if event.need_rescan() => {
+                warn!("Change events were lost, next backup will walk the full tree");
+                lines.push_str(RESCAN_MARKER);
+                lines.push('\n');
+            }
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => continue,
+            Ok(event) => {
+                for path in event.paths {
+                    let Ok(rel) = path.strip_prefix(src) else {
+                        continue;
+                    };
+                    lines.push_str(&escape_path(rel));
+                    lines.push('\n');
+                }
+            }
+            Err(e) => {
+                warn!("Watch error: {}", e);
+                lines.push_str(RESCAN_MARKER);
+                lines.push('\n');
+            }
+        }
+        if !lines.is_empty() {
+            append_changes(&dir, &lines)?;
+        }
+    }
+    Ok(())
+}
+
+// The journal covers everything since `last_checkpoint` only if its watcher is
+// still running and was already running when that backup started (and the
+// backup succeeded, so no recorded change went unprocessed).
+fn covers(dir: &Path, last_checkpoint: &Path) -> bool {
+    let Ok(watcher) = fs::read_to_string(dir.join(WATCHER_FILE)) else {
+        return false;
+    };
+    let mut lines = watcher.lines();
+    let (Some(pid), Some(started)) = (lines.next(), lines.next()) else {
+        return false;
+    };
+    let Ok(started) = chrono::DateTime::parse_from_rfc3339(started) else {
+        return false;
+    };
+    let Some(report) = fs::read(last_checkpoint.join(RUN_REPORT_NAME))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<RunReport>(&json).ok())
+    else {
+        return false;
+    };
+    let last_started = chrono::DateTime::parse_from_rfc3339(&report.started_at).ok();
+    is_alive(pid) && report.success && last_started.is_some_and(|last| started < last)
+}
+
+// Take the changes recorded since the last backup, starting a new journal
+// epoch. None means the journal can't be trusted and the tree must be walked.
+pub fn take_changes(
+    backup_dir: &Path,
+    last_checkpoint: &Path,
+    ignore_dirs: &[String],
+) -> io::Result<Option<JournalChanges>> {
+    let dir = backup_dir.join(JOURNAL_DIR);
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let taken = dir.join(format!("{}.{}", CHANGES_FILE, std::process::id()));
+    let content = match fs::rename(dir.join(CHANGES_FILE), &taken) {
+        Ok(()) => {
+            let content = fs::read_to_string(&taken)?;
+            fs::remove_file(&taken)?;
+            content
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    if !has_manifest(last_checkpoint) || !covers(&dir, last_checkpoint) {
+        info!("Change journal doesn't cover the last backup, walking the full tree");
+        return Ok(None);
+    }
+    if content.lines().any(|line| line == RESCAN_MARKER) {
+        info!("Change journal lost events, walking the full tree");
+        return Ok(None);
+    }
+    let paths: HashSet<PathBuf> = content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(unescape_path)
+        .filter(|path| !path.iter().any(|c| ignore_dirs.iter().any(|ignore| c == ignore.as_str())))
+        .collect();
+    info!("Change journal: {} changed paths since the last backup", paths.len());
+    Ok(Some(JournalChanges {
+        paths,
+        baseline: last_checkpoint.to_path_buf(),
+    }))
+}
@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::runid;
+
+// Log files shared by every process using the same log_dir: one per day,
+// process_<date>.log. Overlapping runs (cron starting a backup
+// while the last one is still going) append to the same file, so each record
+// is written with a single write() under an exclusive flock, which keeps
+// lines whole even on filesystems where O_APPEND alone isn't atomic. Every
+// line carries its run ID, and runs.tsv records which files a run wrote to,
+// so `logs show --run <id>` can pick one run's output back out.
+const INDEX_FILE: &str = "runs.tsv";
+
+struct Sink {
+    dir: PathBuf,
+    day: String,
+    file: Option<File>,
+    // (run, day) pairs already in the index
+    indexed: Vec<(String, String)>,
+}
+
+fn log_name(day: &str) -> String {
+    format!("process_{}.log", day)
+}
+
+// Append `bytes` in one write while holding an exclusive lock on the file
+fn append_locked(file: &mut File, bytes: &[u8]) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = file.write_all(bytes);
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+    result
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_line(sink: &mut Sink, line: &str) -> io::Result<()> {
+    let now = chrono::Local::now();
+    let day = now.format("%Y-%m-%d").to_string();
+    if sink.file.is_none() || sink.day != day {
+        fs::create_dir_all(&sink.dir)?;
+        sink.file = Some(open_append(&sink.dir.join(log_name(&day)))?);
+        sink.day = day.clone();
+    }
+    let run = runid::current();
+    if !sink.indexed.contains(&(run.clone(), day.clone())) {
+        let entry = format!("{}\t{}\t{}\t{}\n", run, log_name(&day), now.to_rfc3339(), std::process::id());
+        append_locked(&mut open_append(&sink.dir.join(INDEX_FILE))?, entry.as_bytes())?;
+        sink.indexed.push((run, day));
+    }
+    let file = sink.file.as_mut().ok_or_else(|| io::Error::other("No log file"))?;
+    append_locked(file, format!("{}\n", line).as_bytes())
+}
+
+// fern output writing formatted records to the shared log files in `dir`
+pub fn output(dir: &Path) -> fern::Output {
+    let sink = Mutex::new(Sink { dir: dir.to_path_buf(), day: String::new(), file: None, indexed: Vec::new() });
+    fern::Output::call(move |record| {
+        let mut sink = sink.lock().unwrap();
+        if let Err(e) = write_line(&mut sink, &record.args().to_string()) {
+            eprintln!("Failed to write log file: {}", e);
+        }
+    })
+}
+
+// fern output appending formatted records to extra_log_file, locked the same way
+pub fn extra_output(path: &Path) -> io::Result<fern::Output> {
+    let file = Mutex::new(open_append(path)?);
+    Ok(fern::Output::call(move |record| {
+        let line = format!("{}\n", record.args());
+        if let Err(e) = append_locked(&mut file.lock().unwrap(), line.as_bytes()) {
+            eprintln!("Failed to write log file: {}", e);
+        }
+    }))
+}
+
+// Log files `run` (an ID or a unique prefix) wrote to, from the index; every
+// log file for runs that predate it
+fn files_of(dir: &Path, run: &str) -> io::Result<(String, Vec<PathBuf>)> {
+    let index = fs::read_to_string(dir.join(INDEX_FILE)).unwrap_or_default();
+    let entries: Vec<(&str, &str)> = index
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some((fields.next()?, fields.next()?))
+        })
+        .filter(|(id, _)| id.starts_with(run))
+        .collect();
+    let ids: HashSet<&str> = entries.iter().map(|(id, _)| *id).collect();
+    match ids.len() {
+        1 => {
+            let mut files: Vec<PathBuf> = Vec::new();
+            for (_, name) in &entries {
+                let path = dir.join(name);
+                if !files.contains(&path) {
+                    files.push(path);
+                }
+            }
+            Ok((entries[0].0.to_string(), files))
+        }
+        0 => {
+            let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+                .collect();
+            files.sort();
+            Ok((run.to_string(), files))
+        }
+        n => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} matches {} runs", run, n))),
+    }
+}
+
+// Print the log lines of one run, in the order they were written. Lines of a
+// multi-line message follow the line they belong to.
+pub fn show_run(dir: &Path, run: &str) -> io::Result<()> {
+    let (run, files) = files_of(dir, run)?;
+    let tag = format!("[{}]", run);
+    let mut out = io::stdout().lock();
+    let mut found = false;
+    for file in files {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut in_run = false;
+        for line in content.lines() {
+            // Records start with a date; anything else continues the last one
+            let record_start = line.as_bytes().first().is_some_and(u8::is_ascii_digit);
+            if record_start {
+                in_run = line.contains(&tag);
+            }
+            if in_run {
+                writeln!(out, "{}", line)?;
+                found = true;
+            }
+        }
+    }
+    if !found {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No log lines of run {}", run)));
+    }
+    Ok(())
+}
+
+// Print the most recent runs of the index: ID, start, process and log file
+pub fn show_runs(dir: &Path, limit: usize) -> io::Result<()> {
+    let index = fs::read_to_string(dir.join(INDEX_FILE)).unwrap_or_default();
+    // A run that went on past midnight is listed once, with its start
+    let mut seen = HashSet::new();
+    let runs: Vec<Vec<&str>> = index
+        .lines()
+        .map(|line| line.split('\t').collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4 && seen.insert(fields[0]))
+        .collect();
+    let runs = &runs[runs.len().saturating_sub(limit)..];
+    for fields in runs {
+        println!("{}\t{}\tpid {}\t{}", fields[0], fields[2], fields[3], fields[1]);
+    }
+    Ok(())
+}
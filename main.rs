@@ -1,105 +1,506 @@
+mod audit;
 mod backup_utils;
+mod bench;
+mod browse;
+mod btime;
+mod bundle;
+mod chaos;
+mod check;
+mod checkpoint;
+mod checksums;
+mod churn;
+mod cli;
+mod clock;
+mod concurrency;
 mod config;
+mod consistent;
+mod delete;
+mod error;
+mod explain;
+mod export;
+mod filters;
+mod guard;
+mod hashing;
+mod health;
+mod history;
+mod hooks;
+mod human;
+mod import;
+mod journal;
+mod logs;
+mod manifest;
+mod migrate;
+mod mirror;
+mod mqtt;
+mod pack;
+mod pattern;
+mod pipeline;
+mod plan;
+mod plugins;
+mod prefetch;
+mod priority;
+mod pushgateway;
+mod reflink;
+mod repack;
+mod replica;
+mod repo;
+mod report;
+mod resources;
+mod restore;
+mod retention;
+mod runid;
+mod safety;
+mod sandbox;
+mod seekable;
+mod settings;
+mod setup;
+mod share;
+mod shell;
+mod stage;
+mod stats;
+mod status;
+mod syslog;
+mod tape;
+mod targets;
+mod timeout;
+mod transfer;
+mod verify;
+mod versions;
+mod window;
 mod zip_handler;
 
-use backup_utils::{traverse_backup, traverse_meta};
-use chrono;
-use config::{BACKUP_DIR, CHECKPOINT_NAME, REMOVE_TEMP_IMMEDIATELY, COMPRESS_FILE_NAME, SRC_DIR, TEMP_EXT};
+use backup_utils::{regenerate_meta, traverse_backup, traverse_meta, Scope};
+use bundle::{create_bundle, extract_bundle};
+use checksums::ChecksumDb;
+use clap::Parser;
+use cli::{
+    AnnotateArgs, AuditAction, BackupArgs, BundleArgs, CatArgs, CheckArgs, Cli, Command, ConfigAction, DeleteArgs,
+    ExportArgs, ExportIndexArgs, ExportStreamArgs, ExtractBundleArgs, FindArgs, GuardAction, ImportArgs, IndexAction,
+    LastSuccessArgs, LockArgs, LogsAction, MassChangeAction, MetaArgs, MigrateArgs, MirrorAction, MirrorArgs,
+    PreviewArgs, PruneArgs, RenameArgs, RepackArgs, ReplicaAction, RepoAction, ReportArgs, RestoreArgs, RestoreStreamArgs,
+    SearchArgs, Shaping, ShareAction, ShareArgs, StatsArgs, WhyCopiedArgs,
+};
+use checkpoint::{
+    annotate_checkpoint, append_to_chain, claim_checkpoint_name, lock_checkpoint,
+    rename_checkpoint, resolve_checkpoint, CheckpointInfo,
+};
+use config::{
+    BACKUP_PRIORITY, CHECKPOINT_NAME, DUAL_PHASE, HARDLINK_UNCHANGED, MIN_CHANGED_BYTES, MIN_CHANGED_FILES,
+    REMOVE_TEMP_IMMEDIATELY, REPACK_BYTES_PER_RUN, REPACK_PRIORITY, REPACK_RATE_LIMIT, RESTORE_PRIORITY, TEMP_EXT,
+    TEMP_SPACE_LIMIT, USE_CHANGE_JOURNAL,
+};
 use std::{
     fs, io,
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
-use zip_handler::{compress_dir, extract_dir};
-use std::io::Write;
+use manifest::{ManifestEntry, MANIFEST_DIR};
+use settings::Config;
+use status::Progress;
+use window::BackupWindow;
+use zip_handler::{compress_dir, MetaExtractor};
+use std::io::{BufRead, IsTerminal, Write};
 use fern::colors::{Color, ColoredLevelConfig};
 use log::{error, info, warn};
 
 fn read_last_checkpoint(backup_dir: &Path) -> io::Result<PathBuf> {
-    let checkpoint = backup_dir.join(CHECKPOINT_NAME);
-
-    // Try to read the file; if it fails (e.g. not found), treat as empty
-    let content = fs::read_to_string(&checkpoint)
-        .map(|s| s.trim().to_string())
-        .unwrap_or_default();
-
-    if content.is_empty() {
-        Ok(PathBuf::new())
-    } else {
-        Ok(backup_dir.join(content))
-    }
+    // No usable pointer means there is no backup to build on yet
+    Ok(checkpoint::read_latest(backup_dir).map(|name| backup_dir.join(name)).unwrap_or_default())
 }
 
 fn new_checkpoint_name() -> String {
-    chrono::Utc::now().format("%Y-%m-%d_%H-%M_%S").to_string()
+    clock::now().format("%Y-%m-%d_%H-%M_%S").to_string()
 }
 
-fn copy_dir_recursive(src: &Path, dst: &Path) -> io::Result<()> {
+// Copy the meta zips of checkpoint `src`, keeping their place in the tree
+fn copy_meta_zips(src: &Path, zips: &[PathBuf], dst: &Path, use_reflink: bool) -> io::Result<()> {
     fs::create_dir_all(dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let file_type = entry.file_type()?;
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
-        if file_type.is_dir() {
-            copy_dir_recursive(&src_path, &dst_path)?;
-        } else {
-            if src_path.file_name().map_or(false, |name| name == COMPRESS_FILE_NAME) {
-                // Only copy COMPRESS_FILE_NAME
-                info!("Copying {:?}", src_path);
-                fs::copy(&src_path, &dst_path)?;
-            }
+    for zip in zips {
+        let dst_path = dst.join(zip.strip_prefix(src).map_err(io::Error::other)?);
+        if let Some(parent) = dst_path.parent() {
+            fs::create_dir_all(parent)?;
         }
+        info!("Copying {:?}", zip);
+        reflink::copy_file(zip, &dst_path, use_reflink)?;
+    }
+    Ok(())
+}
+
+// Copy the manifest of a checkpoint, which the run diffs the tree against
+fn copy_manifest(src: &Path, dst: &Path, use_reflink: bool) -> io::Result<()> {
+    let Ok(shards) = fs::read_dir(src.join(MANIFEST_DIR)) else {
+        return Ok(());
+    };
+    fs::create_dir_all(dst.join(MANIFEST_DIR))?;
+    for shard in shards {
+        let shard = shard?;
+        reflink::copy_file(&shard.path(), &dst.join(MANIFEST_DIR).join(shard.file_name()), use_reflink)?;
+    }
+    Ok(())
+}
+
+// Fail unless `needed` bytes of scratch space fit within TEMP_SPACE_LIMIT and
+// on the backup volume, rather than fill the volume the run stores to
+fn check_scratch_space(backup_dir: &Path, needed: u64) -> io::Result<()> {
+    if TEMP_SPACE_LIMIT != 0 && needed > TEMP_SPACE_LIMIT {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "The last checkpoint's metadata takes {} of scratch space, over TEMP_SPACE_LIMIT ({})",
+                human::format_size(needed),
+                human::format_size(TEMP_SPACE_LIMIT)
+            ),
+        ));
+    }
+    let free = health::free_space(backup_dir)?;
+    if needed > free {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "The last checkpoint's metadata takes {} of scratch space; only {} is free on {:?}",
+                human::format_size(needed),
+                human::format_size(free),
+                backup_dir
+            ),
+        ));
     }
     Ok(())
 }
 
-fn generate_meta(dir: &Path) -> io::Result<()> {
-    info!("meta generate  = {:?}", dir);
+// If last_checkpoint exists, copy its metadata to a temporary directory for
+// the run to compare against, extracted as the run gets to each directory;
+// nothing to compare against otherwise
+fn extract_last_checkpoint(backup_dir: &Path, last_checkpoint: &Path, use_reflink: bool) -> io::Result<MetaExtractor> {
+    if !last_checkpoint.is_dir() {
+        return Ok(MetaExtractor::start(Path::new(""), 0));
+    }
+    let temp_dir = backup_dir.join(TEMP_EXT);
+    if temp_dir.exists() {
+        fs::remove_dir_all(&temp_dir)?;
+    }
+    let zips = zip_handler::meta_zips(last_checkpoint);
+    let shards: Vec<PathBuf> = fs::read_dir(last_checkpoint.join(MANIFEST_DIR))
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    let mut needed = 0;
+    for path in zips.iter().chain(&shards) {
+        needed += fs::metadata(path)?.len();
+    }
+    check_scratch_space(backup_dir, needed)?;
+    fs::create_dir_all(&temp_dir)?;
+    copy_meta_zips(last_checkpoint, &zips, &temp_dir, use_reflink)?;
+    copy_manifest(last_checkpoint, &temp_dir, use_reflink)?;
+    Ok(MetaExtractor::start(&temp_dir, needed))
+}
+
+// Generate metadata for `dir` into `out`, the directory itself unless that is
+// read-only
+fn generate_meta(config: &Config, dir: &Path, out: &Path) -> io::Result<()> {
+    info!("meta generate  = {:?} into {:?}", dir, out);
 
-    let _ = traverse_meta(Path::new(dir));
+    let run = priority::register(config.backup_dir(), BACKUP_PRIORITY)?;
+    let checksums = ChecksumDb::open(&config.checksum_db, dir)?;
+    let result = traverse_meta(dir, out, &config.ignore_dirs, &checksums, &run);
+    checksums.save(result.is_ok())?;
+    // A failed traversal leaves the metas incomplete; don't zip them up
+    result?;
 
     // Compress the new checkpoint directory
-    compress_dir(&dir)?;
+    compress_dir(out)?;
 
     Ok(())
 }
 
-fn backup() -> io::Result<()> {
+// Whether a run changed less than both MIN_CHANGED_FILES and
+// MIN_CHANGED_BYTES (passed as `min_files` and `min_bytes`) ask for since the
+// last checkpoint, so it shouldn't keep its own. A minimum of 0 is unset.
+fn too_few_changes(last_checkpoint: &Path, new_checkpoint: &Path, min_files: u64, min_bytes: u64) -> io::Result<bool> {
+    if (min_files == 0 && min_bytes == 0) || !manifest::has_manifest(last_checkpoint) {
+        return Ok(false);
+    }
+    let (files, bytes) = manifest::changes_between(last_checkpoint, new_checkpoint)?;
+    let met = |value: u64, minimum: u64| minimum > 0 && value >= minimum;
+    let too_few = !met(files, min_files) && !met(bytes, min_bytes);
+    if too_few {
+        info!(
+            "{} files and {} changed since {:?}, below the minimum; no new checkpoint",
+            files,
+            human::format_size(bytes),
+            last_checkpoint
+        );
+    }
+    Ok(too_few)
+}
+
+// Flag a checkpoint left partial by a time limit, and point a partial last
+// checkpoint at the run continuing it
+fn mark_partial(last_checkpoint: &Path, new_checkpoint: &Path, partial: bool, appended: bool) -> io::Result<()> {
+    let mut info = CheckpointInfo::load(new_checkpoint)?;
+    // A full run appending to a partial checkpoint completes it
+    if info.partial != partial {
+        info.partial = partial;
+        info.save(new_checkpoint)?;
+    }
+    if appended || !last_checkpoint.is_dir() {
+        return Ok(());
+    }
+    let mut last = CheckpointInfo::load(last_checkpoint)?;
+    if last.partial {
+        let name = new_checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+        info!("{:?} continues partial checkpoint {:?}", name, last_checkpoint);
+        last.continued_by = Some(name);
+        last.save(last_checkpoint)?;
+    }
+    Ok(())
+}
+
+fn backup(
+    config: &Config,
+    confirm: bool,
+    window: Option<BackupWindow>,
+    max_duration: Option<Duration>,
+    stage_per_run: Option<u64>,
+    limit_rate: Option<u64>,
+) -> error::Result<()> {
+    let backup_dir = targets::select_backup_dir(config)?;
+    repo::prepare_for_backup(&backup_dir)?;
+
+    // The source is only read, so read-only snapshots and mounts are fine
+    match safety::validate_source(config.src_dir()) {
+        Ok(true) => {}
+        Ok(false) => info!("Source {} is read-only", config.src_dir.display()),
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    }
+
+    // Never let the traversal walk into its own output
+    if let Err(e) = safety::validate_backup_paths(config.src_dir(), &backup_dir, &config.ignore_dirs) {
+        error!("{}", e);
+        return Err(e.into());
+    }
+
     // Read latest_checkpoint file if it exists
-    let last_checkpoint = read_last_checkpoint(Path::new(BACKUP_DIR))?;
+    let last_checkpoint = read_last_checkpoint(&backup_dir)?;
 
-    // Generate new checkpoint name
-    let new_checkpoint_name = new_checkpoint_name();
-    let new_checkpoint = Path::new(BACKUP_DIR).join(&new_checkpoint_name);
+    // An unmounted share must not look like every file was deleted
+    let source_mount = safety::watch_source(config.src_dir(), &last_checkpoint)?;
 
-    info!("src     = {:?}", SRC_DIR);
-    info!("backup  = {:?}", BACKUP_DIR);
-    info!("last_cp = {:?}", last_checkpoint);
-    info!("new_cp  = {:?}", new_checkpoint);
+    // A dual-phase run that was killed continues in its own checkpoint
+    let resume = if DUAL_PHASE { plan::interrupted_run(&backup_dir)? } else { None };
 
-    print!("Are you sure you want to create a new backup? (y/n): ");
-    io::stdout().flush().unwrap();
-    let mut confirm = String::new();
-    io::stdin().read_line(&mut confirm).unwrap();
-    if confirm.trim().to_lowercase() != "y" {
-        warn!("Backup cancelled.");
-        return Ok(());
+    // A staged first backup only takes the next subtrees that fit the budget
+    let stage = match stage_per_run {
+        Some(budget) => stage::next_stage(config, &backup_dir, budget, !last_checkpoint.is_dir())?,
+        None => None,
+    };
+
+    // Generate new checkpoint name, unless this run merges into the last one.
+    // Time-limited and staged runs leave out part of the tree, so they always
+    // get a checkpoint of their own.
+    let append_to = if resume.is_some() || max_duration.is_some() || stage.is_some() {
+        None
+    } else {
+        checkpoint::append_target(&last_checkpoint, &config.append_within)?
+    };
+    let last_partial = last_checkpoint.is_dir() && CheckpointInfo::load(&last_checkpoint)?.partial;
+    let new_checkpoint_name = append_to.clone().or(resume.clone()).unwrap_or_else(new_checkpoint_name);
+    let new_checkpoint = backup_dir.join(&new_checkpoint_name);
+
+    info!("src     = {:?}", config.src_dir);
+    info!("backup  = {:?}", backup_dir);
+    info!("last_cp = {:?}", last_checkpoint);
+    if append_to.is_some() {
+        info!("append  = {:?}", new_checkpoint);
+    } else if resume.is_some() {
+        info!("resume  = {:?}", new_checkpoint);
+    } else {
+        info!("new_cp  = {:?}", new_checkpoint);
     }
 
-    // If last_checkpoint exists, extract it to a temporary directory
-    let mut extracted_checkpoint = PathBuf::new();
-    if last_checkpoint.exists() && last_checkpoint.is_dir() {
-        let temp_dir = Path::new(BACKUP_DIR).join(TEMP_EXT);
-        if temp_dir.exists() {
-            fs::remove_dir_all(&temp_dir)?;
+    if confirm {
+        print!("Are you sure you want to create a new backup? (y/n): ");
+        io::stdout().flush().unwrap();
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm).unwrap();
+        if confirm.trim().to_lowercase() != "y" {
+            warn!("Backup cancelled.");
+            return Ok(());
         }
-        fs::create_dir_all(&temp_dir)?;
-        copy_dir_recursive(&last_checkpoint, &temp_dir)?;
-        extract_dir(&temp_dir)?;
-        extracted_checkpoint = temp_dir;
     }
 
-    let _ = traverse_backup(Path::new(SRC_DIR), &extracted_checkpoint, &new_checkpoint);
+    plugins::check_policy(
+        &config.plugin_dir,
+        "backup",
+        serde_json::json!({
+            "src": config.src_dir.to_string_lossy(),
+            "backup_dir": backup_dir.to_string_lossy(),
+            "checkpoint": new_checkpoint_name,
+        }),
+    )?;
+    let disk_health = health::preflight(&[config.src_dir(), &backup_dir])?;
+
+    // Claim the directory now; another run may have taken the name meanwhile
+    let new_checkpoint_name = match append_to.as_ref().or(resume.as_ref()) {
+        Some(name) => name.clone(),
+        None => claim_checkpoint_name(&backup_dir, &new_checkpoint_name)?,
+    };
+    let new_checkpoint = backup_dir.join(&new_checkpoint_name);
+
+    let last_metas = extract_last_checkpoint(&backup_dir, &last_checkpoint, config.use_reflink)?;
+    if resume.is_some() {
+        // Metas are rewritten for every file; drop those of files since deleted
+        checkpoint::clear_loose_metas(&new_checkpoint)?;
+    }
+
+    let mut run = priority::register(&backup_dir, BACKUP_PRIORITY)?;
+    if let Some(window) = window.filter(|w| w.pauses_on_overrun()) {
+        run.confine_to(window);
+    }
+    if let Some(max_duration) = max_duration {
+        info!("Run limited to {}", human::format_duration(max_duration));
+        run.limit_to(max_duration);
+    }
+    if let Some(limit_rate) = limit_rate {
+        info!("Writes limited to {}/s", human::format_size(limit_rate));
+        run.limit_rate(limit_rate);
+    }
+    // An appending run rewrites the journal's baseline, and hard links need the
+    // walk. So do time-limited runs, which may not reach every journaled change,
+    // and the run after one, whose baseline lacks what it didn't reach. A
+    // resumed run's changes were taken by the attempt that was killed, and a
+    // staged run picks its own subtrees.
+    let journal = if USE_CHANGE_JOURNAL
+        && append_to.is_none()
+        && resume.is_none()
+        && stage.is_none()
+        && !HARDLINK_UNCHANGED
+        && max_duration.is_none()
+        && !last_partial
+    {
+        journal::take_changes(&backup_dir, &last_checkpoint, &config.ignore_dirs)?
+    } else {
+        None
+    };
+    let checksums = ChecksumDb::open(&config.checksum_db, config.src_dir())?;
+    let progress = Progress::start(&backup_dir, "backup");
+    progress.record_disk_health(disk_health);
+    let result = traverse_backup(
+        config,
+        &last_metas,
+        &new_checkpoint,
+        match (&journal, &stage) {
+            (Some(journal), _) => Scope::Journal(journal),
+            (None, Some(stage)) => Scope::Subtrees(&stage.subtrees),
+            (None, None) if append_to.is_some() => Scope::Append,
+            (None, None) => Scope::Full,
+        },
+        &checksums,
+        &run,
+        &progress,
+    );
+    // Nothing is extracted below it any more once the run is through
+    let extracted_checkpoint = last_metas.finish();
+    let stopped = matches!(result, Ok(false));
+    // Whatever went wrong while the source disappeared, that is the failure
+    let result = safety::check_source().and(result.map(|_| ()));
+    // A suspected mass change leaves no checkpoint behind
+    if matches!(result, Err(error::Error::MassChange(_))) {
+        progress.finish(&result);
+        if append_to.is_none() && resume.is_none() {
+            fs::remove_dir_all(&new_checkpoint)?;
+        }
+        if extracted_checkpoint.exists() && REMOVE_TEMP_IMMEDIATELY {
+            fs::remove_dir_all(&extracted_checkpoint)?;
+        }
+        if let Some(journal) = &journal {
+            journal::return_changes(&backup_dir, journal)?;
+        }
+        return result;
+    }
+    let source_lost = matches!(result, Err(error::Error::SourceLost { .. }));
+    if let Err(error::Error::Critical { path, source }) = &result {
+        plugins::notify(
+            &config.plugin_dir,
+            "critical_path_failed",
+            serde_json::json!({ "checkpoint": new_checkpoint_name, "path": path, "error": source.to_string() }),
+        );
+    }
+    if let Err(e @ error::Error::SourceLost { .. }) = &result {
+        let details = serde_json::json!({ "checkpoint": new_checkpoint_name, "error": e.to_string() });
+        plugins::notify(&config.plugin_dir, "source_lost", details);
+    }
+    if source_lost {
+        let carried = backup_utils::carry_over_unreached(config.src_dir(), &last_checkpoint, &new_checkpoint, true)?;
+        error!(
+            "Source went missing; {} files keep their state from {:?} instead of being taken for deleted",
+            carried, last_checkpoint
+        );
+    } else if stopped {
+        let carried = backup_utils::carry_over_unreached(config.src_dir(), &last_checkpoint, &new_checkpoint, false)?;
+        warn!(
+            "Time limit reached; {} files not reached keep their state from {:?} until the next run",
+            carried, last_checkpoint
+        );
+    } else if stage.is_some() {
+        let carried = backup_utils::carry_over_unreached(config.src_dir(), &last_checkpoint, &new_checkpoint, false)?;
+        info!("Carried over {} files stored by earlier stages", carried);
+    }
+    // Until the last stage is done the checkpoint lacks part of the tree
+    let partial = stopped || source_lost || stage.as_ref().is_some_and(|stage| !stage.last);
+    if let Some(stage) = stage.as_ref().filter(|_| result.is_ok() && !stopped) {
+        stage::record_stage(&backup_dir, stage, &new_checkpoint_name)?;
+    }
+    // A journal run only looks at changed files, so it can't tell what's stale
+    if let Err(e) = checksums.save(journal.is_none() && stage.is_none() && result.is_ok() && !partial) {
+        warn!("Failed to save checksum database: {}", e);
+    }
+    let report = progress.finish(&result);
+    if append_to.is_none()
+        && resume.is_none()
+        && !partial
+        && result.is_ok()
+        && too_few_changes(&last_checkpoint, &new_checkpoint, MIN_CHANGED_FILES, MIN_CHANGED_BYTES)?
+    {
+        fs::remove_dir_all(&new_checkpoint)?;
+        if extracted_checkpoint.exists() && REMOVE_TEMP_IMMEDIATELY {
+            fs::remove_dir_all(&extracted_checkpoint)?;
+        }
+        if let Some(journal) = &journal {
+            journal::return_changes(&backup_dir, journal)?;
+        }
+        return Ok(());
+    }
+    repo::stamp_checkpoint(&new_checkpoint)?;
+    mark_partial(&last_checkpoint, &new_checkpoint, partial, append_to.is_some())?;
+    let mut checkpoint_info = CheckpointInfo::load(&new_checkpoint)?;
+    checkpoint_info.source_mount = source_mount;
+    checkpoint_info.save(&new_checkpoint)?;
+    if let Err(e) = history::save_report(&new_checkpoint, &report) {
+        warn!("Failed to save run report: {}", e);
+    }
+    let html_report = match report::save_html(&backup_dir, &new_checkpoint, &report).and_then(std::path::absolute) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!("Failed to write HTML report: {}", e);
+            None
+        }
+    };
+    plugins::notify(
+        &config.plugin_dir,
+        "backup_finished",
+        serde_json::json!({
+            "checkpoint": new_checkpoint_name,
+            "partial": partial,
+            "report": report,
+            "html_report": html_report,
+        }),
+    );
+    // After a failed run the manifest is incomplete, so keep everything
+    if (append_to.is_some() || resume.is_some()) && result.is_ok() {
+        checkpoint::prune_unreferenced(&new_checkpoint)?;
+    }
 
     // Compress the new checkpoint directory
     compress_dir(&new_checkpoint)?;
@@ -110,12 +511,474 @@ fn backup() -> io::Result<()> {
     }
 
     // Update the latest checkpoint file
-    let latest_path = Path::new(BACKUP_DIR).join(CHECKPOINT_NAME);
-    fs::write(&latest_path, new_checkpoint_name)?;
+    let latest_path = backup_dir.join(CHECKPOINT_NAME);
+    if append_to.is_none() {
+        append_to_chain(&backup_dir, &new_checkpoint_name)?;
+    } else {
+        checkpoint::record_root(&backup_dir, &new_checkpoint)?;
+    }
+    checkpoint::set_latest(&backup_dir, &new_checkpoint_name)?;
     info!("Updated latest checkpoint: {:?}", latest_path);
+    if let Err(e) = browse::update(&backup_dir, &new_checkpoint) {
+        warn!("Failed to update browsing index: {}", e);
+    }
+
+    // Backends get complete checkpoints only
+    if result.is_ok() && !partial {
+        plugins::store(&config.plugin_dir, &new_checkpoint)?;
+    }
+    if let Some(mirror_dir) = config.mirror_dir.as_deref().filter(|_| result.is_ok() && !partial) {
+        if let Err(e) = mirror::apply(config, &backup_dir, &new_checkpoint, mirror_dir, &run) {
+            warn!("Failed to update mirror {:?}: {}", mirror_dir, e);
+            plugins::notify(
+                &config.plugin_dir,
+                "mirror_failed",
+                serde_json::json!({
+                    "checkpoint": new_checkpoint_name,
+                    "mirror": mirror_dir.to_string_lossy(),
+                    "error": e.to_string(),
+                }),
+            );
+        }
+    }
+
+    // A critical path that wasn't backed up fails the run, not just its report
+    if let Err(e @ error::Error::Critical { .. }) = result {
+        return Err(e);
+    }
+    Ok(())
+
+}
+
+// Convert one snapshot of another tool into a checkpoint named after its
+// time, compared against the latest checkpoint like a backup
+fn import_snapshot(
+    config: &Config,
+    backup_dir: &Path,
+    source: &import::Source,
+    snapshot: &import::Snapshot,
+    subdir: &Path,
+) -> error::Result<()> {
+    let scratch = backup_dir.join(import::SCRATCH_DIR);
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch)?;
+    }
+    fs::create_dir_all(&scratch)?;
+    let files = source.unpack(snapshot, subdir, &scratch)?;
+    info!("Unpacked {} files of snapshot {} ({})", human::format_count(files), snapshot.id, snapshot.label.trim());
+
+    let last_checkpoint = read_last_checkpoint(backup_dir)?;
+    let last_metas = extract_last_checkpoint(backup_dir, &last_checkpoint, config.use_reflink)?;
+    let name = claim_checkpoint_name(backup_dir, &snapshot.time.format("%Y-%m-%d_%H-%M_%S").to_string())?;
+    let new_checkpoint = backup_dir.join(&name);
+    let run = priority::register(backup_dir, BACKUP_PRIORITY)?;
+    let progress = Progress::start(backup_dir, "import");
+    // The unpacked snapshot takes the place of the source
+    let unpacked = Config { src_dir: scratch.clone(), ..config.clone() };
+    let result = traverse_backup(
+        &unpacked,
+        &last_metas,
+        &new_checkpoint,
+        Scope::Full,
+        &ChecksumDb::disabled(),
+        &run,
+        &progress,
+    )
+    .map(|_| ());
+    let extracted_checkpoint = last_metas.finish();
+    let report = progress.finish(&result);
+    fs::remove_dir_all(&scratch)?;
+    if extracted_checkpoint.exists() {
+        fs::remove_dir_all(&extracted_checkpoint)?;
+    }
+    // A failed conversion leaves no checkpoint; the next import retries it
+    if let Err(e) = result {
+        fs::remove_dir_all(&new_checkpoint)?;
+        return Err(e);
+    }
 
+    repo::stamp_checkpoint(&new_checkpoint)?;
+    let mut checkpoint_info = CheckpointInfo::load(&new_checkpoint)?;
+    checkpoint_info.imported_from = Some(source.origin(snapshot));
+    checkpoint_info.save(&new_checkpoint)?;
+    if let Err(e) = history::save_report(&new_checkpoint, &report) {
+        warn!("Failed to save run report: {}", e);
+    }
+    compress_dir(&new_checkpoint)?;
+    append_to_chain(backup_dir, &name)?;
+    checkpoint::set_latest(backup_dir, &name)?;
+    if let Err(e) = browse::update(backup_dir, &new_checkpoint) {
+        warn!("Failed to update browsing index: {}", e);
+    }
+    info!("Imported snapshot {} as {}", snapshot.id, name);
     Ok(())
+}
 
+// Import the selected snapshots of a restic or Borg repository, oldest first,
+// skipping those imported before
+fn import_snapshots(config: &Config, source: &import::Source, wanted: &[String], subdir: &Path) -> error::Result<()> {
+    let backup_dir = targets::select_backup_dir(config)?;
+    repo::prepare_for_backup(&backup_dir)?;
+    let imported = import::imported(&backup_dir)?;
+    let snapshots = import::select(source.snapshots()?, wanted)?;
+    let pending: Vec<_> = snapshots.iter().filter(|s| !imported.contains(&source.origin(s))).collect();
+    info!(
+        "{} of {} snapshots to import",
+        human::format_count(pending.len() as u64),
+        human::format_count(snapshots.len() as u64)
+    );
+    for snapshot in pending {
+        import_snapshot(config, &backup_dir, source, snapshot, subdir)?;
+    }
+    Ok(())
+}
+
+// Carry out a parsed command
+fn run_cli_command(config: &Config, command: Command, yes: bool) -> io::Result<()> {
+    // Generating metas for a directory, restores from bundles and streams,
+    // writing a config, reading logs and checking for a recent backup don't
+    // touch the repository
+    let touches_repository = !matches!(
+        command,
+        Command::Meta(MetaArgs { dir: Some(_), .. })
+            | Command::ExtractBundle(_)
+            | Command::RestoreStream(_)
+            | Command::Config(_)
+            | Command::Logs(_)
+            | Command::LastSuccess(_)
+    );
+    if touches_repository {
+        repo::ensure_compatible(config.backup_dir())?;
+    }
+    match command {
+        Command::Backup(BackupArgs { dry_run: true, .. }) => {
+            let last_checkpoint = read_last_checkpoint(config.backup_dir())?;
+            let run = priority::register(config.backup_dir(), BACKUP_PRIORITY)?;
+            backup_utils::dry_run(config, &last_checkpoint, &run).map_err(io::Error::from)
+        }
+        Command::Backup(BackupArgs { max_duration, stage_per_run, limit_rate, .. }) => {
+            // Only ask when someone is there to answer
+            let confirm = !yes && io::stdin().is_terminal();
+            backup(config, confirm, None, max_duration, stage_per_run, limit_rate).map_err(io::Error::from)
+        }
+        Command::Meta(MetaArgs { dir: Some(dir), out, .. }) => match (safety::validate_source(&dir)?, out) {
+            (_, Some(out)) => {
+                fs::create_dir_all(&out)?;
+                generate_meta(config, &dir, &out)
+            }
+            (true, None) => generate_meta(config, &dir, &dir),
+            // A read-only snapshot is only read; its metadata goes elsewhere
+            (false, None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is read-only; give --out <dir> to write its meta to", dir),
+            )),
+        },
+        Command::Meta(MetaArgs { checkpoint, path, force, .. }) => {
+            let checkpoint = resolve_checkpoint(config.backup_dir(), checkpoint.as_deref().unwrap_or_default())?;
+            let subtree = path.unwrap_or_default();
+            let run = priority::register(config.backup_dir(), BACKUP_PRIORITY)?;
+            let count = regenerate_meta(&checkpoint, &subtree, &config.ignore_dirs, force, &run)?;
+            checkpoint::record_root(config.backup_dir(), &checkpoint)?;
+            info!("Regenerated {} metas in {:?}", count, checkpoint.join(&subtree));
+            Ok(())
+        }
+        Command::Restore(RestoreArgs { checkpoint, at, to, paths, force, sandbox, priority, shaping }) => {
+            // clap takes exactly one of the two
+            let checkpoint = match at {
+                Some(at) => {
+                    let found = checkpoint::checkpoint_at(config.backup_dir(), checkpoint::parse_point_in_time(&at)?)?;
+                    info!("Restoring {:?}, the newest checkpoint as of {}", found, at);
+                    found.file_name().unwrap_or_default().to_string_lossy().to_string()
+                }
+                None => checkpoint.unwrap_or_default(),
+            };
+            let mut run = priority::register(config.backup_dir(), priority.unwrap_or(RESTORE_PRIORITY))?;
+            shape_transfer(&mut run, &shaping)?;
+            let progress = Progress::start(config.backup_dir(), "restore");
+            let restore = |out: &Path| restore::restore_tree(config, &checkpoint, &paths, out, force, &run, &progress);
+            let result = if sandbox { sandbox::restore_sandboxed(&to, restore) } else { restore(&to) };
+            progress.finish(&result);
+            audit::record(config.backup_dir(), "restore", &checkpoint_label(config, &checkpoint), &paths, &to.to_string_lossy(), &result);
+            result
+        }
+        Command::Bundle(BundleArgs { checkpoint, paths, out, priority, shaping }) => {
+            let mut run = priority::register(config.backup_dir(), priority.unwrap_or(RESTORE_PRIORITY))?;
+            shape_transfer(&mut run, &shaping)?;
+            let progress = Progress::start(config.backup_dir(), "bundle");
+            let result = create_bundle(config.backup_dir(), &checkpoint, &paths, &out, &run, &progress);
+            progress.finish(&result);
+            let target = out.to_string_lossy();
+            audit::record(config.backup_dir(), "bundle", &checkpoint_label(config, &checkpoint), &paths, &target, &result);
+            result
+        }
+        Command::ExtractBundle(ExtractBundleArgs { bundle, dest, sandbox, shaping }) => {
+            let mut run = priority::register_if_present(config.backup_dir(), RESTORE_PRIORITY)?;
+            shape_transfer(&mut run, &shaping)?;
+            let progress = Progress::start(config.backup_dir(), "extract-bundle");
+            let result = if sandbox {
+                sandbox::restore_sandboxed(&dest, |staging| extract_bundle(config, &bundle, staging, &run, &progress))
+            } else {
+                extract_bundle(config, &bundle, &dest, &run, &progress)
+            };
+            progress.finish(&result);
+            let target = dest.to_string_lossy();
+            audit::record(config.backup_dir(), "extract-bundle", &bundle.to_string_lossy(), &[], &target, &result);
+            result
+        }
+        Command::Export(ExportArgs { checkpoint, out, paths, priority, shaping, .. }) => {
+            let mut run = priority::register(config.backup_dir(), priority.unwrap_or(RESTORE_PRIORITY))?;
+            shape_transfer(&mut run, &shaping)?;
+            let progress = Progress::start(config.backup_dir(), "export");
+            let result = export::export_tree(config, &checkpoint, &paths, &out, &run, &progress);
+            progress.finish(&result);
+            let target = out.to_string_lossy();
+            audit::record(config.backup_dir(), "export", &checkpoint_label(config, &checkpoint), &paths, &target, &result);
+            result
+        }
+        Command::MassChange(MassChangeAction::Status) => churn::status(config.backup_dir()),
+        Command::MassChange(MassChangeAction::Accept) => {
+            let result = churn::accept(config.backup_dir());
+            audit::record(config.backup_dir(), "mass-change-accept", "", &[], "", &result);
+            result
+        }
+        Command::ExportIndex(ExportIndexArgs { format, out }) => stats::export_index(config.backup_dir(), &format, &out),
+        Command::ExportStream(ExportStreamArgs { checkpoint, out, index }) => {
+            let progress = Progress::start(config.backup_dir(), "export-stream");
+            let result = tape::export_stream(config.backup_dir(), &checkpoint, &out, &index, &progress);
+            progress.finish(&result);
+            audit::record(config.backup_dir(), "export-stream", &checkpoint_label(config, &checkpoint), &[], &out, &result);
+            result
+        }
+        Command::RestoreStream(RestoreStreamArgs { stream, index, paths, dest, sandbox, shaping }) => {
+            let mut run = priority::register_if_present(config.backup_dir(), RESTORE_PRIORITY)?;
+            shape_transfer(&mut run, &shaping)?;
+            let progress = Progress::start(config.backup_dir(), "restore-stream");
+            let restore = |dest: &Path| tape::restore_stream(config, &stream, &index, &paths, dest, &run, &progress);
+            let result = if sandbox {
+                sandbox::restore_sandboxed(&dest, restore)
+            } else {
+                restore(&dest)
+            };
+            progress.finish(&result);
+            let target = dest.to_string_lossy();
+            audit::record(config.backup_dir(), "restore-stream", &stream.to_string_lossy(), &paths, &target, &result);
+            result
+        }
+        Command::Cat(CatArgs { checkpoint, path, offset, length }) => {
+            let result = cat_file(config.backup_dir(), &checkpoint, &path, offset, length.unwrap_or(u64::MAX));
+            audit::record(config.backup_dir(), "cat", &checkpoint_label(config, &checkpoint), &[path], "-", &result);
+            result
+        }
+        Command::WhyCopied(WhyCopiedArgs { checkpoint, path }) => {
+            explain::why_copied(config.backup_dir(), &checkpoint, Path::new(&path))
+        }
+        Command::Preview(PreviewArgs { checkpoint, path, lines, tail }) => {
+            let result = preview_file(config.backup_dir(), &checkpoint, &path, lines, tail);
+            audit::record(config.backup_dir(), "preview", &checkpoint_label(config, &checkpoint), &[path], "-", &result);
+            result
+        }
+        Command::Find(FindArgs { pattern, checkpoint }) => find_files(config.backup_dir(), &checkpoint, &pattern),
+        Command::Delete(DeleteArgs { checkpoint, rehome, confirm }) => {
+            // Confirmed by name, so "latest" can't mean another checkpoint later
+            let action = format!("delete {}{}", checkpoint_label(config, &checkpoint), if rehome { " --rehome" } else { "" });
+            guard::authorize(config, &action, confirm.as_deref())?;
+            delete::delete_checkpoint(config.backup_dir(), &checkpoint, rehome)
+        }
+        Command::Repack(RepackArgs { max_bytes, max_duration, priority, shaping, .. }) => {
+            let mut run = priority::register(config.backup_dir(), priority.unwrap_or(REPACK_PRIORITY))?;
+            if REPACK_RATE_LIMIT != 0 {
+                run.limit_rate(REPACK_RATE_LIMIT);
+            }
+            shape_transfer(&mut run, &shaping)?;
+            if let Some(max_duration) = max_duration {
+                run.limit_to(max_duration);
+            }
+            let progress = Progress::start(config.backup_dir(), "repack");
+            let max_bytes = max_bytes.unwrap_or(REPACK_BYTES_PER_RUN);
+            let result = repack::apply_policies(config.backup_dir(), max_bytes, &run, &progress);
+            progress.finish(&result);
+            result
+        }
+        Command::Rename(RenameArgs { checkpoint, new_name }) => {
+            rename_checkpoint(config.backup_dir(), &checkpoint, &new_name)
+        }
+        Command::Lock(LockArgs { checkpoint, until }) => lock_checkpoint(config.backup_dir(), &checkpoint, &until),
+        Command::Migrate(MigrateArgs { checkpoint, verify, to, revert }) => {
+            let mut backup_dir = config.backup_dir().to_path_buf();
+            if let Some(to) = to {
+                migrate::copy_repository(&backup_dir, &to, config.use_reflink)?;
+                backup_dir = to;
+            }
+            match checkpoint {
+                // clap requires a checkpoint to revert
+                Some(checkpoint) if revert => {
+                    let checkpoint = resolve_checkpoint(&backup_dir, &checkpoint)?;
+                    migrate::revert_checkpoint(&backup_dir, &checkpoint, config.use_reflink)
+                }
+                Some(checkpoint) => {
+                    let checkpoint = resolve_checkpoint(&backup_dir, &checkpoint)?;
+                    migrate::migrate_checkpoint(&backup_dir, &checkpoint, verify, config.use_reflink)
+                }
+                None => migrate::migrate_repository(&backup_dir, verify, config.use_reflink),
+            }
+        }
+        Command::Repo(RepoAction::Info) => repo::info(config.backup_dir()),
+        Command::Repo(RepoAction::Relocate { dir }) => repo::relocate(dir.as_deref().unwrap_or(config.backup_dir())),
+        Command::Repo(RepoAction::Upgrade) => repo::upgrade(config.backup_dir(), config.use_reflink),
+        Command::Audit(AuditAction::Show { user, from, path, since }) => {
+            let from = from.map(|from| checkpoint_label(config, &from));
+            let since = since.map(|since| since.to_string());
+            let filter = audit::AuditFilter {
+                user: user.as_deref(),
+                from: from.as_deref(),
+                path: path.as_deref(),
+                since: since.as_deref(),
+            };
+            audit::show(config.backup_dir(), &filter)
+        }
+        Command::Replica(ReplicaAction::Verify { replica, primary }) => {
+            replica::verify_replica(primary.as_deref().unwrap_or(config.backup_dir()), &replica)
+        }
+        Command::Prune(PruneArgs { keep_last, keep_daily, keep_weekly, keep_monthly, dry_run, confirm }) => {
+            let configured = retention::Policy::configured(config);
+            let policy = retention::Policy {
+                keep_last: keep_last.unwrap_or(configured.keep_last),
+                keep_daily: keep_daily.unwrap_or(configured.keep_daily),
+                keep_weekly: keep_weekly.unwrap_or(configured.keep_weekly),
+                keep_monthly: keep_monthly.unwrap_or(configured.keep_monthly),
+            };
+            if !dry_run {
+                guard::authorize(config, "prune", confirm.as_deref())?;
+            }
+            retention::prune_checkpoints(config.backup_dir(), &policy, dry_run)?;
+            versions::prune_versions(config.backup_dir(), dry_run).map(|_| ())
+        }
+        Command::Guard(GuardAction::Status) => guard::show(config.backup_dir()),
+        Command::Guard(GuardAction::Set { passphrase, delay, confirm }) => {
+            guard::set(config, passphrase, delay, confirm.as_deref())
+        }
+        Command::Guard(GuardAction::Off { confirm }) => guard::off(config, confirm.as_deref()),
+        Command::Guard(GuardAction::Cancel { token }) => guard::cancel(config, &token),
+        Command::Check(CheckArgs { quick, backends }) => {
+            let run = priority::register(config.backup_dir(), BACKUP_PRIORITY)?;
+            check::check_repository(config, quick, backends, &run)
+        }
+        Command::Annotate(AnnotateArgs { checkpoint, clear: true, .. }) => {
+            annotate_checkpoint(config.backup_dir(), &checkpoint, None)
+        }
+        Command::Annotate(AnnotateArgs { checkpoint, text, .. }) if text.is_empty() => {
+            let info = CheckpointInfo::load(&resolve_checkpoint(config.backup_dir(), &checkpoint)?)?;
+            println!("{}", info.annotation.unwrap_or_default());
+            Ok(())
+        }
+        Command::Annotate(AnnotateArgs { checkpoint, text, .. }) => {
+            annotate_checkpoint(config.backup_dir(), &checkpoint, Some(&text.join(" ")))
+        }
+        Command::Config(ConfigAction::Init { out, force }) => {
+            setup::init_config(out.as_deref().unwrap_or(Path::new(settings::CONFIG_FILE_NAME)), force)
+        }
+        Command::Import(ImportArgs { tool, repository, snapshots, path, password_file }) => {
+            let source = import::Source::new(import::Tool::parse(&tool)?, &repository, password_file.as_deref())?;
+            let subdir = Path::new(path.as_deref().unwrap_or("").trim_matches('/'));
+            import_snapshots(config, &source, &snapshots, subdir).map_err(io::Error::from)
+        }
+        Command::Logs(LogsAction::Show { run: Some(run) }) => logs::show_run(&config.log_dir, &run),
+        Command::Logs(LogsAction::Show { run: None }) => logs::show_runs(&config.log_dir, 20),
+        Command::Status => status::show(config.backup_dir()),
+        Command::LastSuccess(LastSuccessArgs { max_age, target }) => {
+            let report = match target {
+                Some(target) => history::last_success(&target, "backup"),
+                None => history::newest_success(config, "backup"),
+            };
+            history::check_freshness(report, max_age)
+        }
+        Command::Report(ReportArgs { checkpoint, out }) => {
+            let checkpoint = resolve_checkpoint(config.backup_dir(), &checkpoint)?;
+            let html = report::for_checkpoint(config.backup_dir(), &checkpoint)?;
+            match out {
+                Some(out) => fs::write(out, html),
+                None => io::stdout().write_all(html.as_bytes()),
+            }
+        }
+        Command::Stats(StatsArgs { dedup: true, prune, .. }) => stats::dedup_stats(config.backup_dir(), &prune),
+        Command::Stats(StatsArgs { checkpoint, path, .. }) => {
+            let dir = Path::new(path.as_deref().unwrap_or("").trim_matches('/'));
+            stats::dir_stats(config.backup_dir(), checkpoint.as_deref().unwrap_or("latest"), dir)
+        }
+        Command::Mirror(MirrorArgs { action, checkpoint, to }) => {
+            let status = matches!(action, Some(MirrorAction::Status { .. }));
+            let to = match action {
+                Some(MirrorAction::Status { to }) => to,
+                None => to,
+            };
+            let mirror_dir = to
+                .as_deref()
+                .or(config.mirror_dir.as_deref())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No MIRROR_DIR configured; pass --to <dir>"))?;
+            if status {
+                return mirror::show(mirror_dir);
+            }
+            let checkpoint = resolve_checkpoint(config.backup_dir(), checkpoint.as_deref().unwrap_or("latest"))?;
+            let run = priority::register(config.backup_dir(), RESTORE_PRIORITY)?;
+            mirror::apply(config, config.backup_dir(), &checkpoint, mirror_dir, &run)
+        }
+        Command::Share(ShareArgs { action: Some(ShareAction::List), .. }) => share::list(config),
+        Command::Share(ShareArgs { action: Some(ShareAction::Serve), .. }) => share::serve(config),
+        Command::Share(ShareArgs { action: Some(ShareAction::Revoke { token }), .. }) => {
+            share::revoke(config.backup_dir(), &token)
+        }
+        Command::Share(ShareArgs { checkpoint, path, expires, .. }) => {
+            // clap requires both without an action
+            let (checkpoint, path) = (checkpoint.unwrap_or_default(), path.unwrap_or_default());
+            let result = share::create(config, &checkpoint, &path, expires);
+            audit::record(config.backup_dir(), "share", &checkpoint_label(config, &checkpoint), &[path], "-", &result);
+            result
+        }
+        Command::Shell => shell::run_shell(config),
+        Command::Index(IndexAction::Build) => browse::build(config.backup_dir()),
+        Command::Search(SearchArgs { pattern }) => match browse::Index::open(config.backup_dir())? {
+            Some(mut index) => browse::print_search(&mut index, &pattern),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No browsing index; run `index build`")),
+        },
+        Command::Plugins => {
+            for plugin in plugins::discover(&config.plugin_dir)? {
+                println!("{}\t{}\t{}", plugin.name, plugin.capabilities.join(","), plugin.path.display());
+            }
+            Ok(())
+        }
+        Command::Bench => bench::run_bench(config),
+        Command::Watch => journal::watch(config.src_dir(), config.backup_dir()),
+        Command::Daemon => {
+            let window = window::configured(config)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "daemon requires BACKUP_WINDOW to be configured")
+            })?;
+            mqtt::start_publisher(config)?;
+            share::start_server(config)?;
+            loop {
+                if !window.is_open() {
+                    info!("Next backup in {} (window {})", human::format_duration(window.until_open()), window);
+                    window.wait_until_open();
+                }
+                info!("Scheduled backup starting as run {}", runid::start());
+                let started = Instant::now();
+                let exit_code = match backup(config, false, Some(window), None, None, None) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        error!("Scheduled backup failed: {}", e);
+                        e.exit_code()
+                    }
+                };
+                // The daemon itself never ends, so each run is pushed
+                pushgateway::push(config, "backup", exit_code, started);
+                if window.is_open() {
+                    // One run per window
+                    window.wait_until_closed();
+                } else if !window.pauses_on_overrun() {
+                    warn!("Backup overran window {}", window);
+                }
+            }
+        }
+    }
 }
 
 fn ask_user_for_mode() -> String {
@@ -126,45 +989,240 @@ fn ask_user_for_mode() -> String {
     input.trim().to_lowercase()
 }
 
-fn init_logger() -> Result<(), fern::InitError> {
+fn init_logger(config: &Config, console_to_stderr: bool) -> Result<(), fern::InitError> {
     let colors = ColoredLevelConfig::new()
         .info(Color::Green)
         .warn(Color::Yellow)
         .error(Color::Red);
 
-    fern::Dispatch::new()
+    let level = |name: &str| {
+        name.parse::<log::LevelFilter>().map_err(|_| {
+            fern::InitError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid log level {:?}", name)))
+        })
+    };
+    let mut formatted = fern::Dispatch::new()
         .format(move |out, msg, record| {
             out.finish(format_args!(
-                "{date} {level} [{target}] {msg}",
+                "{date} {level} [{run}] [{target}] {msg}",
                 date   = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
                 level  = colors.color(record.level()),
+                run    = runid::current(),
                 target = record.target(),
                 msg    = msg
             ))
         })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())          // console
-        // Ensure the logs directory exists
-        .chain({
-            let log_dir = Path::new("logs");
-            if !log_dir.exists() {
-                fs::create_dir_all(log_dir)?;
+        .chain(
+            fern::Dispatch::new()
+                .level(level(&config.log_level)?)
+                .chain(if console_to_stderr {
+                    fern::Output::from(std::io::stderr())
+                } else {
+                    fern::Output::from(std::io::stdout())
+                })                                 // console
+                .chain(logs::output(&config.log_dir)), // file
+        );
+    if !config.extra_log_file.as_os_str().is_empty() {
+        let extra = logs::extra_output(&config.extra_log_file)?;
+        formatted = formatted.chain(fern::Dispatch::new().level(level(&config.extra_log_level)?).chain(extra));
+    }
+    // The system log gets bare messages; it keeps time and level itself
+    let mut dispatch = fern::Dispatch::new().chain(formatted);
+    if !config.syslog.is_empty() {
+        let system = syslog::output(&config.syslog, &config.syslog_identifier, &config.syslog_facility)?;
+        dispatch = dispatch.chain(fern::Dispatch::new().level(level(&config.syslog_level)?).chain(system));
+    }
+    dispatch.apply()?;
+    Ok(())
+}
+
+// Transfer shaping given on the command line: `--limit-rate <size>` per
+// second, and `--window HH:MM-HH:MM` outside which the run pauses
+fn shape_transfer(run: &mut priority::RunGuard, shaping: &Shaping) -> io::Result<()> {
+    if let Some(rate) = shaping.limit_rate {
+        info!("Transfer rate limited to {}/s", human::format_size(rate));
+        run.limit_rate(rate);
+    }
+    if let Some(window) = &shaping.window {
+        run.confine_to(window::parse(window, "pause")?);
+    }
+    Ok(())
+}
+
+// Name of the checkpoint a reference like "latest" resolves to, for the audit log
+fn checkpoint_label(config: &Config, reference: &str) -> String {
+    resolve_checkpoint(config.backup_dir(), reference)
+        .ok()
+        .and_then(|checkpoint| checkpoint.file_name().map(|name| name.to_string_lossy().to_string()))
+        .unwrap_or_else(|| reference.to_string())
+}
+
+// Write part of a backed up file to stdout; only the stored data holding
+// that range is read
+fn cat_file(backup_dir: &Path, reference: &str, rel: &str, offset: u64, length: u64) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, reference)?;
+    let files = checkpoint::resolve_files(backup_dir, &checkpoint)?;
+    let file = files.get(Path::new(rel)).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} is not in checkpoint {:?}", rel, checkpoint))
+    })?;
+    let mut stdout = io::stdout().lock();
+    io::copy(&mut file.open_range(offset, length)?, &mut stdout)?;
+    stdout.flush()
+}
+
+// Print the first (or, with `tail`, the last) `lines` lines of a backed up
+// text file. Only as much data as those lines need is decoded, and no more
+// than PREVIEW_MAX_BYTES of it.
+const PREVIEW_MAX_BYTES: u64 = 1024 * 1024;
+
+fn preview_file(backup_dir: &Path, reference: &str, rel: &str, lines: usize, tail: bool) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, reference)?;
+    let file = checkpoint::resolve_file(backup_dir, &checkpoint, Path::new(rel))?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} is not in checkpoint {:?}", rel, checkpoint))
+    })?;
+    let size = file.info.size;
+    let mut data = Vec::new();
+    if tail {
+        // Read back from the end, a larger piece each time, until it holds
+        // enough line breaks (the one ending the file doesn't count)
+        let mut length = 4096.min(size);
+        loop {
+            data.clear();
+            file.open_range(size - length, length)?.read_to_end(&mut data)?;
+            let breaks = data.iter().filter(|&&b| b == b'\n').count() - usize::from(data.ends_with(b"\n"));
+            if breaks >= lines || length == size || length >= PREVIEW_MAX_BYTES {
+                break;
+            }
+            length = (length * 4).min(size).min(PREVIEW_MAX_BYTES);
+        }
+    } else {
+        let mut reader = io::BufReader::new(file.open_range(0, PREVIEW_MAX_BYTES)?);
+        for _ in 0..lines {
+            if reader.read_until(b'\n', &mut data)? == 0 {
+                break;
             }
-            fern::log_file(format!(
-                "logs/process_{}.log",
-                chrono::Local::now().format("%Y-%m-%d")
-            ))?
-        }) // file
-        .apply()?;
+        }
+    }
+    if data.contains(&0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a text file", rel)));
+    }
+    let text = String::from_utf8_lossy(&data);
+    let mut shown: Vec<&str> = text.lines().collect();
+    if tail {
+        // The first line is cut off unless the piece read starts the file
+        if (data.len() as u64) < size && !shown.is_empty() {
+            shown.remove(0);
+        }
+        shown.drain(..shown.len().saturating_sub(lines));
+    }
+    let mut stdout = io::stdout().lock();
+    for line in shown {
+        writeln!(stdout, "{}", line)?;
+    }
+    stdout.flush()
+}
+
+// Print the files of a checkpoint matching `pattern` (as in pattern.rs). An
+// exact path only reads the manifest shard that can hold it.
+fn find_files(backup_dir: &Path, reference: &str, pattern: &str) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, reference)?;
+    if !manifest::has_manifest(&checkpoint) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Checkpoint {:?} has no manifest; run `migrate` first", checkpoint),
+        ));
+    }
+    let print = |entry: &ManifestEntry| println!("{:>10}  {}", human::format_size(entry.size), entry.path.display());
+    if pattern.contains('/') && !pattern.contains(['*', '?']) {
+        if let Some(entry) = manifest::lookup(&checkpoint, Path::new(pattern))? {
+            print(&entry);
+        }
+        return Ok(());
+    }
+    let mut matches = Vec::new();
+    for entry in manifest::read_entries(&checkpoint) {
+        let entry = entry?;
+        if pattern::path_matches(pattern, &entry.path) {
+            matches.push(entry);
+        }
+    }
+    matches.sort_by(|a, b| a.path.cmp(&b.path));
+    matches.iter().for_each(print);
     Ok(())
 }
 
 fn main() -> io::Result<()> {
-    // Initialize logger
-    if let Err(e) = init_logger() {
+    let started = Instant::now();
+    let cli = Cli::parse();
+    let command_name = cli.command.as_ref().map_or("interactive", Command::name).to_string();
+
+    // Initialize logger; keep stdout clean when it carries a data stream
+    let data_on_stdout = match &cli.command {
+        Some(Command::ExportIndex(ExportIndexArgs { out, .. })) => out == "-",
+        Some(Command::ExportStream(ExportStreamArgs { out, .. })) => out == "-",
+        Some(command) => matches!(
+            command,
+            Command::Cat(_) | Command::Preview(_) | Command::Share(_) | Command::Search(_) | Command::LastSuccess(_)
+        ),
+        None => false,
+    };
+    // Paths and selection from the settings file, if there is one
+    let config = match settings::load(cli.config.as_deref(), cli.src.as_deref(), cli.backup_dir.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            // Without settings there is no log to write to yet
+            eprintln!("{}", e);
+            pushgateway::push(&Config::default(), &command_name, e.exit_code(), started);
+            std::process::exit(e.exit_code());
+        }
+    };
+    if let Err(e) = init_logger(&config, data_on_stdout) {
         eprintln!("Failed to initialize logger: {}", e);
-        return Err(io::Error::new(io::ErrorKind::Other, "Logger initialization failed"));
+        return Err(io::Error::other("Logger initialization failed"));
+    }
+
+    if let Some(time) = &cli.clock {
+        let time = chrono::DateTime::parse_from_rfc3339(time)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid --clock time, expected RFC 3339"))?;
+        warn!("Clock pinned to {}", time);
+        clock::install(Box::leak(Box::new(clock::TestClock::at(time.to_utc()))));
+    }
+
+    if let Some(rate) = &cli.chaos {
+        let rate = rate.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid --chaos rate"))?;
+        let seed = match &cli.chaos_seed {
+            Some(seed) => seed.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid --chaos-seed"))?,
+            None => std::process::id() as u64,
+        };
+        chaos::enable(rate, seed);
     }
+
+    let result = match cli.command {
+        Some(command) => run_cli_command(&config, command, cli.yes),
+        // Without a command, ask what to do
+        None if io::stdin().is_terminal() => run_interactive(&config),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No command given; see nas-backup-utils --help",
+        )),
+    };
+    // The exit status tells scripts which kind of failure it was
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(e) => {
+            let e = error::Error::from(e);
+            error!("{}", e);
+            e.exit_code()
+        }
+    };
+    pushgateway::push(&config, &command_name, exit_code, started);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+// The prompts run without a command
+fn run_interactive(config: &Config) -> io::Result<()> {
     let mode = ask_user_for_mode();
     if mode == "m" || mode == "meta" {
         // Ask user for directory to generate meta for
@@ -173,17 +1231,73 @@ fn main() -> io::Result<()> {
         let mut dir_input = String::new();
         io::stdin().read_line(&mut dir_input).unwrap();
         let dir = Path::new(dir_input.trim());
-        if dir.exists() && dir.is_dir() && dir.metadata().map(|m| m.permissions().readonly() == false).unwrap_or(false) {
-            // Call generate_meta function
-            generate_meta(dir)?;
-        } else {
-            error!("Invalid directory: {:?}", dir);
+        match safety::validate_source(dir) {
+            Ok(true) => generate_meta(config, dir, dir)?,
+            // A read-only snapshot is only read; its metadata goes elsewhere
+            Ok(false) => {
+                info!("{:?} is read-only. Enter directory to write its meta to: ", dir);
+                io::stdout().flush().unwrap();
+                let mut out_input = String::new();
+                io::stdin().read_line(&mut out_input).unwrap();
+                let out = Path::new(out_input.trim());
+                fs::create_dir_all(out)?;
+                generate_meta(config, dir, out)?;
+            }
+            Err(e) => error!("Invalid directory: {}", e),
         }
     } else if mode == "b" || mode == "backup" {
-        // Call backup function
-        backup()?;
+        backup(config, true, None, None, None, None)?;
     } else {
         error!("Invalid mode selected. Exiting.");
-    } 
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use manifest::{ManifestEntry, ManifestWriter};
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nas-backup-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A checkpoint whose manifest lists `files`, each of `size` bytes
+    fn checkpoint_of(dir: &Path, files: std::ops::Range<u32>, size: u64) -> PathBuf {
+        let writer = ManifestWriter::create(dir).unwrap();
+        for i in files {
+            writer
+                .add(&ManifestEntry {
+                    path: PathBuf::from(format!("file-{}", i)),
+                    size,
+                    hash: format!("{:016x}", i),
+                    time_stamp: 0,
+                    stored_in: None,
+                    birth_time: None,
+                    packed: None,
+                    seekable: None,
+                    flags: Vec::new(),
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        dir.to_path_buf()
+    }
+
+    #[test]
+    fn keeps_a_run_that_only_deleted_files() {
+        let dir = scratch("deletions");
+        let last = checkpoint_of(&dir.join("last"), 0..1000, 4096);
+        let new = checkpoint_of(&dir.join("new"), 0..10, 4096);
+        assert_eq!(manifest::changes_between(&last, &new).unwrap(), (990, 990 * 4096));
+        // Either minimum met keeps it
+        assert!(!too_few_changes(&last, &new, 100, 1 << 30).unwrap());
+        assert!(!too_few_changes(&last, &new, 0, 1 << 20).unwrap());
+        assert!(too_few_changes(&last, &new, 1000, 1 << 30).unwrap());
+        assert!(too_few_changes(&last, &new, 0, 1 << 30).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
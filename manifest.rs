@@ -0,0 +1,521 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use xxhash_rust::xxh3::{xxh3_128, xxh3_64};
+
+use crate::chaos;
+use crate::checkpoint::checkpoint_ref;
+use crate::pack::PackRef;
+use crate::seekable::SeekIndex;
+
+// Checkpoint-level manifest stored next to the data as MANIFEST_DIR/<xx>.tsv,
+// sharded by the first byte of the path hash. Shards are written and read
+// line by line, so memory use does not depend on how many files a single
+// directory holds.
+pub const MANIFEST_DIR: &str = ".manifest";
+const SHARD_COUNT: usize = 256;
+// Hash of every shard, one per line, as of the last time the root was taken
+const SHARD_HASHES_FILE: &str = "shards.xxh";
+// Leaf value for a shard with no entries
+const EMPTY_SHARD: &str = "-";
+// Totals of every directory, one `<dir>\t<files>\t<bytes>` line each
+const DIR_SUMMARY_FILE: &str = "dirs.tsv";
+
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+    pub time_stamp: i64,
+    // Checkpoint that physically holds the data, if known
+    pub stored_in: Option<String>,
+    // Creation time of the source file, if the filesystem recorded one
+    pub birth_time: Option<i64>,
+    // Place in a pack of the holding checkpoint, for packed small files
+    pub packed: Option<PackRef>,
+    // Frame layout of large files stored compressed in the seekable format
+    pub seekable: Option<SeekIndex>,
+    // Markers such as "flagged" (content scan hook)
+    pub flags: Vec<String>,
+}
+
+fn shard_of(rel: &Path) -> usize {
+    xxh3_64(rel.to_string_lossy().as_bytes()) as usize % SHARD_COUNT
+}
+
+fn shard_path(checkpoint: &Path, shard: usize) -> PathBuf {
+    checkpoint.join(MANIFEST_DIR).join(format!("{:02x}.tsv", shard))
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+// Paths in manifests and the other line-based files are written with the
+// escapes of `escape`, and every byte of a name that isn't valid UTF-8 (old
+// Samba shares, Latin-1 names) as \xHH, so any path reads back exactly as
+// it was. Nothing used to write \x, so older files read back as before.
+pub(crate) fn escape_path(path: &Path) -> String {
+    let mut out = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        out.push_str(&escape(chunk.valid()));
+        for byte in chunk.invalid() {
+            let _ = write!(out, "\\x{:02x}", byte);
+        }
+    }
+    out
+}
+
+pub(crate) fn unescape_path(s: &str) -> PathBuf {
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        out.extend_from_slice(&rest.as_bytes()[..i]);
+        let escaped = &rest[i + 1..];
+        let byte = escaped.strip_prefix('x').and_then(|hex| hex.get(..2)).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        rest = match (byte, escaped.chars().next()) {
+            (Some(byte), _) => {
+                out.push(byte);
+                &escaped[3..]
+            }
+            (None, Some(c)) => {
+                match c {
+                    't' => out.push(b'\t'),
+                    'n' => out.push(b'\n'),
+                    c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+                &escaped[c.len_utf8()..]
+            }
+            (None, None) => {
+                out.push(b'\\');
+                ""
+            }
+        };
+    }
+    out.extend_from_slice(rest.as_bytes());
+    PathBuf::from(OsString::from_vec(out))
+}
+
+// A file name as a string for zip entries: valid UTF-8 as it is, so existing
+// entries keep their names, anything else escaped as by `escape_path`
+pub(crate) fn name_string(name: &OsStr) -> String {
+    match name.to_str() {
+        Some(name) => name.to_string(),
+        None => escape_path(Path::new(name)),
+    }
+}
+
+impl ManifestEntry {
+    pub(crate) fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            escape_path(&self.path),
+            self.size,
+            self.hash,
+            self.time_stamp,
+            self.stored_in.as_deref().unwrap_or(""),
+            self.flags.join(","),
+            self.birth_time.map(|bt| bt.to_string()).unwrap_or_default(),
+            self.packed.as_ref().map(PackRef::to_string).unwrap_or_default(),
+            self.seekable.as_ref().map(SeekIndex::to_string).unwrap_or_default()
+        )
+    }
+
+    pub(crate) fn parse(line: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid manifest line: {}", line));
+        let fields: Vec<&str> = line.split('\t').collect();
+        // The flags, birth time, pack and seek index columns were added
+        // later; older manifests have five to eight fields
+        if !(5..=9).contains(&fields.len()) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            path: unescape_path(fields[0]),
+            size: fields[1].parse().map_err(|_| invalid())?,
+            hash: fields[2].to_string(),
+            time_stamp: fields[3].parse().map_err(|_| invalid())?,
+            stored_in: (!fields[4].is_empty()).then(|| checkpoint_ref(fields[4]).to_string()),
+            flags: fields
+                .get(5)
+                .map(|f| f.split(',').filter(|f| !f.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+            birth_time: match fields.get(6) {
+                Some(bt) if !bt.is_empty() => Some(bt.parse().map_err(|_| invalid())?),
+                _ => None,
+            },
+            packed: match fields.get(7) {
+                Some(pack) if !pack.is_empty() => Some(PackRef::parse(pack)?),
+                _ => None,
+            },
+            seekable: match fields.get(8) {
+                Some(index) if !index.is_empty() => Some(SeekIndex::parse(index)?),
+                _ => None,
+            },
+        })
+    }
+}
+
+// Thread-safe streaming writer; shard files are opened on first use.
+pub struct ManifestWriter {
+    checkpoint: PathBuf,
+    shards: Vec<Mutex<Option<BufWriter<File>>>>,
+}
+
+impl ManifestWriter {
+    pub fn create(checkpoint: &Path) -> io::Result<Self> {
+        fs::create_dir_all(checkpoint.join(MANIFEST_DIR))?;
+        Ok(Self {
+            checkpoint: checkpoint.to_path_buf(),
+            shards: (0..SHARD_COUNT).map(|_| Mutex::new(None)).collect(),
+        })
+    }
+
+    pub fn add(&self, entry: &ManifestEntry) -> io::Result<()> {
+        let shard = shard_of(&entry.path);
+        let mut writer = self.shards[shard].lock().unwrap();
+        if writer.is_none() {
+            *writer = Some(BufWriter::new(File::create(shard_path(&self.checkpoint, shard))?));
+        }
+        chaos::write_all(writer.as_mut().unwrap(), entry.to_line().as_bytes(), "manifest")
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        for (i, shard) in self.shards.into_iter().enumerate() {
+            if let Some(mut writer) = shard.into_inner().unwrap() {
+                writer.flush()?;
+                drop(writer);
+                sort_shard(&shard_path(&self.checkpoint, i))?;
+            }
+        }
+        save_dir_summary(&self.checkpoint)
+    }
+}
+
+// Entries arrive in whatever order the workers finish; sorting each shard by
+// path makes the manifest of identical data byte-identical between runs.
+fn sort_shard(path: &Path) -> io::Result<()> {
+    let content = fs::read_to_string(path)?;
+    let mut lines: Vec<&str> = content.lines().collect();
+    if lines.is_sorted() {
+        return Ok(());
+    }
+    lines.sort_unstable();
+    let tmp = path.with_extension("tsv.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for line in lines {
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    drop(writer);
+    chaos::point("manifest sort")?;
+    fs::rename(&tmp, path)
+}
+
+pub fn has_manifest(checkpoint: &Path) -> bool {
+    checkpoint.join(MANIFEST_DIR).is_dir()
+}
+
+fn read_shard(path: &Path) -> impl Iterator<Item = io::Result<ManifestEntry>> {
+    let lines = File::open(path).ok().map(|file| BufReader::new(file).lines());
+    lines
+        .into_iter()
+        .flatten()
+        .map(|line| line.and_then(|line| ManifestEntry::parse(&line)))
+}
+
+// Stream every entry of a checkpoint manifest, one shard at a time.
+pub fn read_entries(checkpoint: &Path) -> impl Iterator<Item = io::Result<ManifestEntry>> + '_ {
+    (0..SHARD_COUNT).flat_map(move |shard| read_shard(&shard_path(checkpoint, shard)))
+}
+
+// The entry of one file, read from the only shard that can hold it. On a
+// repository on a remote mount (see targets.rs) this transfers one shard
+// instead of the manifest. Path prefixes don't narrow down the shards, as
+// they are split by path hash, so anything but an exact path reads them all.
+pub fn lookup(checkpoint: &Path, rel: &Path) -> io::Result<Option<ManifestEntry>> {
+    for entry in read_shard(&shard_path(checkpoint, shard_of(rel))) {
+        let entry = entry?;
+        if entry.path == rel {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+// The entries of the files `rels`, reading only the shards that can hold
+// them; paths the manifest lacks are left out
+pub fn lookup_all(checkpoint: &Path, rels: &[PathBuf]) -> io::Result<Vec<ManifestEntry>> {
+    let mut by_shard: BTreeMap<usize, HashSet<&Path>> = BTreeMap::new();
+    for rel in rels {
+        by_shard.entry(shard_of(rel)).or_default().insert(rel);
+    }
+    let mut entries = Vec::with_capacity(rels.len());
+    for (shard, wanted) in by_shard {
+        for entry in read_shard(&shard_path(checkpoint, shard)) {
+            let entry = entry?;
+            if wanted.contains(entry.path.as_path()) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Files added, changed or removed from checkpoint `old` to `new`, and their
+// bytes: the new size of those added or changed, the old size of those
+// removed. A path always falls in the same shard, so the manifests are
+// compared shard by shard.
+pub fn changes_between(old: &Path, new: &Path) -> io::Result<(u64, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    for shard in 0..SHARD_COUNT {
+        let mut before = HashMap::new();
+        for entry in read_shard(&shard_path(old, shard)) {
+            let entry = entry?;
+            before.insert(entry.path, (entry.size, entry.hash));
+        }
+        for entry in read_shard(&shard_path(new, shard)) {
+            let entry = entry?;
+            if before.remove(&entry.path) != Some((entry.size, entry.hash)) {
+                files += 1;
+                bytes += entry.size;
+            }
+        }
+        files += before.len() as u64;
+        bytes += before.values().map(|(size, _)| size).sum::<u64>();
+    }
+    Ok((files, bytes))
+}
+
+// Point entries stored in checkpoint `old` at `new` instead, shard by shard.
+pub fn rewrite_manifest_stored_in(checkpoint: &Path, old: &str, new: &str) -> io::Result<()> {
+    rewrite_entries(checkpoint, |entry| {
+        if entry.stored_in.as_deref() != Some(old) {
+            return false;
+        }
+        entry.stored_in = Some(new.to_string());
+        true
+    })
+}
+
+// Apply `update` to every entry, shard by shard; it returns whether it
+// changed the entry. Only shards with changes are rewritten.
+pub fn rewrite_entries(checkpoint: &Path, mut update: impl FnMut(&mut ManifestEntry) -> bool) -> io::Result<()> {
+    for shard in 0..SHARD_COUNT {
+        let path = shard_path(checkpoint, shard);
+        if !path.exists() {
+            continue;
+        }
+        let tmp = path.with_extension("tsv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut changed = false;
+        for entry in read_shard(&path) {
+            let mut entry = entry?;
+            changed |= update(&mut entry);
+            writer.write_all(entry.to_line().as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        if changed {
+            fs::rename(&tmp, &path)?;
+        } else {
+            fs::remove_file(&tmp)?;
+        }
+    }
+    Ok(())
+}
+
+// Rewrite shards whose data pointers hold absolute paths so they hold bare
+// checkpoint names. Returns the number of entries changed.
+pub fn normalize_stored_in(checkpoint: &Path) -> io::Result<usize> {
+    let mut changed = 0;
+    for shard in 0..SHARD_COUNT {
+        let path = shard_path(checkpoint, shard);
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        let mut lines = Vec::new();
+        let mut shard_changed = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.split('\t').nth(4).is_some_and(|stored_in| Path::new(stored_in).is_absolute()) {
+                // Parsing already reduces the pointer to a bare name
+                lines.push(ManifestEntry::parse(&line)?.to_line());
+                shard_changed += 1;
+            } else {
+                lines.push(line + "\n");
+            }
+        }
+        if shard_changed > 0 {
+            let tmp = path.with_extension("tsv.tmp");
+            fs::write(&tmp, lines.concat())?;
+            fs::rename(&tmp, &path)?;
+            changed += shard_changed;
+        }
+    }
+    Ok(changed)
+}
+
+// Replace the entries for the given paths, adding any the manifest doesn't
+// have yet. Flags already recorded for a path are kept.
+pub fn upsert_entries(checkpoint: &Path, entries: Vec<ManifestEntry>) -> io::Result<()> {
+    let mut by_shard: HashMap<usize, HashMap<PathBuf, ManifestEntry>> = HashMap::new();
+    for entry in entries {
+        by_shard.entry(shard_of(&entry.path)).or_default().insert(entry.path.clone(), entry);
+    }
+    for (shard, mut pending) in by_shard {
+        let path = shard_path(checkpoint, shard);
+        let tmp = path.with_extension("tsv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in read_shard(&path) {
+            let entry = entry?;
+            let entry = match pending.remove(&entry.path) {
+                Some(updated) => ManifestEntry { flags: entry.flags, ..updated },
+                None => entry,
+            };
+            writer.write_all(entry.to_line().as_bytes())?;
+        }
+        for entry in pending.into_values() {
+            writer.write_all(entry.to_line().as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp, &path)?;
+        sort_shard(&path)?;
+    }
+    save_dir_summary(checkpoint)
+}
+
+// Drop the entries for `paths` whose data is stored in checkpoint `stored_in`.
+// Returns the number of entries removed.
+pub fn remove_entries(checkpoint: &Path, paths: &HashSet<PathBuf>, stored_in: &str) -> io::Result<usize> {
+    let mut shards: Vec<usize> = paths.iter().map(|path| shard_of(path)).collect();
+    shards.sort_unstable();
+    shards.dedup();
+    let mut removed = 0;
+    for shard in shards {
+        let path = shard_path(checkpoint, shard);
+        if !path.exists() {
+            continue;
+        }
+        let tmp = path.with_extension("tsv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut shard_removed = 0;
+        for entry in read_shard(&path) {
+            let entry = entry?;
+            if entry.stored_in.as_deref() == Some(stored_in) && paths.contains(&entry.path) {
+                shard_removed += 1;
+                continue;
+            }
+            writer.write_all(entry.to_line().as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        if shard_removed > 0 {
+            fs::rename(&tmp, &path)?;
+            removed += shard_removed;
+        } else {
+            fs::remove_file(&tmp)?;
+        }
+    }
+    if removed > 0 {
+        save_dir_summary(checkpoint)?;
+    }
+    Ok(removed)
+}
+
+// Merkle-style digest of a manifest: the hash of every shard in shard order.
+// Shards are sorted, so identical manifests always produce identical hashes.
+pub fn shard_hashes(checkpoint: &Path) -> io::Result<Vec<String>> {
+    (0..SHARD_COUNT)
+        .map(|shard| match fs::read(shard_path(checkpoint, shard)) {
+            Ok(content) => Ok(format!("{:032x}", xxh3_128(&content))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(EMPTY_SHARD.to_string()),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+pub fn root_hash(shard_hashes: &[String]) -> String {
+    format!("{:032x}", xxh3_128(shard_hashes.join("\n").as_bytes()))
+}
+
+pub fn save_shard_hashes(checkpoint: &Path, shard_hashes: &[String]) -> io::Result<()> {
+    let path = checkpoint.join(MANIFEST_DIR).join(SHARD_HASHES_FILE);
+    let tmp = path.with_extension("xxh.tmp");
+    fs::write(&tmp, shard_hashes.join("\n") + "\n")?;
+    fs::rename(&tmp, &path)
+}
+
+// Shard hashes saved alongside the root, if any were
+pub fn saved_shard_hashes(checkpoint: &Path) -> io::Result<Option<Vec<String>>> {
+    match fs::read_to_string(checkpoint.join(MANIFEST_DIR).join(SHARD_HASHES_FILE)) {
+        Ok(content) => Ok(Some(content.lines().map(str::to_string).collect())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Files and bytes below a directory of a checkpoint, subdirectories included
+#[derive(Clone, Copy, Default)]
+pub struct DirTotals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+// Totals of every directory holding files, keyed by path ("" for the root)
+pub type DirSummary = BTreeMap<PathBuf, DirTotals>;
+
+fn summarize(checkpoint: &Path) -> io::Result<DirSummary> {
+    let mut summary = DirSummary::new();
+    for entry in read_entries(checkpoint) {
+        let entry = entry?;
+        for dir in entry.path.ancestors().skip(1) {
+            let totals = summary.entry(dir.to_path_buf()).or_default();
+            totals.files += 1;
+            totals.bytes += entry.size;
+        }
+    }
+    Ok(summary)
+}
+
+// Record the directory totals of a finished or changed manifest, so listing
+// them doesn't need to read every entry
+fn save_dir_summary(checkpoint: &Path) -> io::Result<()> {
+    let mut content = String::new();
+    for (dir, totals) in summarize(checkpoint)? {
+        content.push_str(&format!("{}\t{}\t{}\n", escape_path(&dir), totals.files, totals.bytes));
+    }
+    let path = checkpoint.join(MANIFEST_DIR).join(DIR_SUMMARY_FILE);
+    let tmp = path.with_extension("tsv.tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, &path)
+}
+
+// Directory totals of a checkpoint; manifests written before the summary
+// existed are added up from their entries
+pub fn dir_summary(checkpoint: &Path) -> io::Result<DirSummary> {
+    let content = match fs::read_to_string(checkpoint.join(MANIFEST_DIR).join(DIR_SUMMARY_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return summarize(checkpoint),
+        Err(e) => return Err(e),
+    };
+    let mut summary = DirSummary::new();
+    for line in content.lines() {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid directory summary line: {}", line));
+        let [dir, files, bytes] = line.split('\t').collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+        let totals = DirTotals {
+            files: files.parse().map_err(|_| invalid())?,
+            bytes: bytes.parse().map_err(|_| invalid())?,
+        };
+        summary.insert(unescape_path(dir), totals);
+    }
+    Ok(summary)
+}
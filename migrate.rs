@@ -0,0 +1,139 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::backup_utils::compute_xxhash_like;
+use crate::checkpoint::{ensure_unlocked, list_checkpoints, record_root, resolve_files};
+use crate::config::COMPRESS_FILE_NAME;
+use crate::manifest::{has_manifest, ManifestEntry, ManifestWriter, MANIFEST_DIR};
+use crate::reflink::copy_file;
+use crate::zip_handler::{meta_zips, update_zip_stored_in};
+
+// Original meta zips of migrated checkpoints, kept so a migration can be undone
+const LEGACY_BACKUP_DIR: &str = ".legacy-meta";
+
+// Convert one legacy checkpoint (per-file .meta in meta zips) to the manifest
+// layout: write its manifest and record in each .meta which checkpoint holds
+// the data. The meta zips are saved first so `revert_checkpoint` can restore them.
+pub fn migrate_checkpoint(backup_dir: &Path, checkpoint: &Path, verify: bool, use_reflink: bool) -> io::Result<()> {
+    if has_manifest(checkpoint) {
+        info!("Already migrated: {:?}", checkpoint);
+        return Ok(());
+    }
+    ensure_unlocked(checkpoint)?;
+
+    let files = resolve_files(backup_dir, checkpoint)?;
+    let manifest = ManifestWriter::create(checkpoint)?;
+    let mut pointers = HashMap::new();
+    let mut mismatches = 0;
+
+    for (rel, file) in files {
+        let stored_in = file
+            .stored_at
+            .strip_prefix(backup_dir)
+            .ok()
+            .and_then(|p| p.components().next())
+            .map(|c| c.as_os_str().to_string_lossy().to_string());
+        if verify && compute_xxhash_like(&file.stored_at, &file.info.hash)? != file.info.hash {
+            warn!("Hash mismatch for {:?} stored at {:?}", rel, file.stored_at);
+            mismatches += 1;
+            continue;
+        }
+        if let Some(stored_in) = &stored_in {
+            pointers.insert(rel.with_extension("meta"), stored_in.clone());
+        }
+        manifest.add(&ManifestEntry {
+            path: rel,
+            size: file.info.size,
+            hash: file.info.hash,
+            time_stamp: file.info.time_stamp.timestamp(),
+            stored_in,
+            birth_time: file.info.birth_time,
+            packed: None,
+            seekable: None,
+            flags: Vec::new(),
+        })?;
+    }
+    manifest.finish()?;
+    record_root(backup_dir, checkpoint)?;
+
+    let name = checkpoint.file_name().unwrap_or_default();
+    for zip in meta_zips(checkpoint) {
+        let rel_dir = zip
+            .parent()
+            .and_then(|p| p.strip_prefix(checkpoint).ok())
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let saved = backup_dir.join(LEGACY_BACKUP_DIR).join(name).join(&rel_dir).join(COMPRESS_FILE_NAME);
+        if let Some(parent) = saved.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        copy_file(&zip, &saved, use_reflink)?;
+        update_zip_stored_in(&zip, |entry, current| match current {
+            Some(_) => None,
+            None => pointers.get(&rel_dir.join(entry)).cloned(),
+        })?;
+    }
+
+    if mismatches > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} files in {:?} failed verification and were left out of the manifest", mismatches, checkpoint),
+        ));
+    }
+    info!("Migrated {:?}", checkpoint);
+    Ok(())
+}
+
+// Undo a migration: put the saved meta zips back and drop the manifest
+pub fn revert_checkpoint(backup_dir: &Path, checkpoint: &Path, use_reflink: bool) -> io::Result<()> {
+    let saved_root = backup_dir
+        .join(LEGACY_BACKUP_DIR)
+        .join(checkpoint.file_name().unwrap_or_default());
+    if !saved_root.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No saved legacy metadata for {:?}", checkpoint),
+        ));
+    }
+    for saved in meta_zips(&saved_root) {
+        let rel = saved.strip_prefix(&saved_root).map_err(io::Error::other)?;
+        copy_file(&saved, &checkpoint.join(rel), use_reflink)?;
+    }
+    let manifest_dir = checkpoint.join(MANIFEST_DIR);
+    if manifest_dir.exists() {
+        fs::remove_dir_all(manifest_dir)?;
+    }
+    fs::remove_dir_all(&saved_root)?;
+    info!("Reverted migration of {:?}", checkpoint);
+    Ok(())
+}
+
+// Copy a whole repository (checkpoints and chain state, not scratch
+// directories) so it can be migrated without touching the original.
+pub fn copy_repository(from: &Path, to: &Path, use_reflink: bool) -> io::Result<()> {
+    for entry in WalkDir::new(from).into_iter().filter_entry(|e| {
+        e.depth() != 1 || !e.file_type().is_dir() || !e.file_name().to_string_lossy().starts_with('.')
+    }) {
+        let entry = entry.map_err(io::Error::other)?;
+        let target = to.join(entry.path().strip_prefix(from).map_err(io::Error::other)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            copy_file(entry.path(), &target, use_reflink)?;
+        }
+    }
+    info!("Copied repository {:?} -> {:?}", from, to);
+    Ok(())
+}
+
+pub fn migrate_repository(backup_dir: &Path, verify: bool, use_reflink: bool) -> io::Result<()> {
+    // Oldest first, mirroring the order the checkpoints were written in
+    for checkpoint in list_checkpoints(backup_dir)? {
+        migrate_checkpoint(backup_dir, &checkpoint, verify, use_reflink)?;
+    }
+    Ok(())
+}
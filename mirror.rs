@@ -0,0 +1,123 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+use crate::checkpoint::{resolve_files, write_atomic, ResolvedFile};
+use crate::clock;
+use crate::human::{format_count, format_size};
+use crate::priority::RunGuard;
+use crate::settings::Config;
+use crate::verify::restore_stored;
+
+// A mirror is a plain restored tree of the latest checkpoint kept up to date
+// by applying each new checkpoint to it: files whose size or modification
+// time differ from the checkpoint are restored again, files the checkpoint
+// no longer has are removed, everything else is left alone. The marker file
+// records which checkpoint the mirror reflects and keeps a mirror from being
+// set up over a directory that holds anything else.
+const MIRROR_MARKER: &str = ".nbu-mirror.json";
+
+#[derive(Serialize, Deserialize)]
+struct MirrorState {
+    checkpoint: String,
+    updated_at: String,
+}
+
+fn load_state(mirror: &Path) -> io::Result<Option<MirrorState>> {
+    let path = mirror.join(MIRROR_MARKER);
+    match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn up_to_date(path: &Path, file: &ResolvedFile) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| {
+        metadata.is_file()
+            && metadata.len() == file.info.size
+            && metadata.modified().is_ok_and(|modified| modified == SystemTime::from(file.info.time_stamp))
+    })
+}
+
+// Bring the mirror at `mirror` up to date with `checkpoint`
+pub fn apply(config: &Config, backup_dir: &Path, checkpoint: &Path, mirror: &Path, run: &RunGuard) -> io::Result<()> {
+    let name = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let state = load_state(mirror)?;
+    if state.is_none() && fs::read_dir(mirror).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} is not empty and not a mirror; refusing to overwrite it", mirror),
+        ));
+    }
+    fs::create_dir_all(mirror)?;
+    let files = resolve_files(backup_dir, checkpoint)?;
+
+    let (mut written, mut bytes, mut unverified) = (0, 0, 0);
+    for (rel, file) in &files {
+        let out_path = mirror.join(rel);
+        if up_to_date(&out_path, file) {
+            continue;
+        }
+        // A directory where the checkpoint now has a file, or the reverse
+        if out_path.is_dir() {
+            fs::remove_dir_all(&out_path)?;
+        }
+        let modified = SystemTime::from(file.info.time_stamp);
+        if !restore_stored(config, file, &out_path, run, |f| f.set_modified(modified))? {
+            unverified += 1;
+        }
+        written += 1;
+        bytes += file.info.size;
+    }
+
+    let mut removed = 0;
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for entry in WalkDir::new(mirror).min_depth(1) {
+        let entry = entry.map_err(io::Error::from)?;
+        let rel = entry.path().strip_prefix(mirror).map_err(io::Error::other)?;
+        if entry.file_type().is_dir() {
+            dirs.push(entry.path().to_path_buf());
+        } else if rel != Path::new(MIRROR_MARKER) && !files.contains_key(rel) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    // Deepest first, so emptied parents go too
+    for dir in dirs.iter().rev() {
+        if fs::read_dir(dir)?.next().is_none() {
+            fs::remove_dir(dir)?;
+        }
+    }
+
+    let state = MirrorState { checkpoint: name.clone(), updated_at: clock::local_now().to_rfc3339() };
+    let json = serde_json::to_vec_pretty(&state).map_err(io::Error::other)?;
+    write_atomic(&mirror.join(MIRROR_MARKER), &json)?;
+    if unverified > 0 {
+        warn!("{} files in mirror {:?} didn't match their recorded hash", unverified, mirror);
+    }
+    info!(
+        "Mirror {:?} now reflects {}: {} files ({}) written, {} removed",
+        mirror,
+        name,
+        format_count(written),
+        format_size(bytes),
+        format_count(removed)
+    );
+    Ok(())
+}
+
+// Which checkpoint the mirror at `mirror` reflects
+pub fn show(mirror: &Path) -> io::Result<()> {
+    match load_state(mirror)? {
+        Some(state) => println!("Mirror {} reflects {} (updated {})", mirror.display(), state.checkpoint, state.updated_at),
+        None => println!("{} is not a mirror yet", mirror.display()),
+    }
+    Ok(())
+}
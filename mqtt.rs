@@ -0,0 +1,160 @@
+use log::{info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{MQTT_DISCOVERY_PREFIX, STATUS_FILE_NAME, STATUS_INTERVAL_SECS};
+use crate::history::newest_success;
+use crate::settings::Config;
+use crate::targets::all_targets;
+
+const DEFAULT_PORT: u16 = 1883;
+const RECONNECT_DELAY_SECS: u64 = 10;
+
+// (object id, name, value template, extra discovery fields) of each sensor
+const SENSORS: &[(&str, &str, &str, &str)] = &[
+    ("state", "Backup state", "{{ value_json.state }}", "{}"),
+    ("progress", "Backup progress", "{{ value_json.progress_percent }}", r#"{"unit_of_measurement": "%"}"#),
+    ("files", "Backup files done", "{{ value_json.files_done }}", "{}"),
+    ("eta", "Backup time remaining", "{{ value_json.eta_seconds }}", r#"{"unit_of_measurement": "s", "device_class": "duration"}"#),
+    ("last_success", "Last successful backup", "{{ value_json.last_success }}", r#"{"device_class": "timestamp"}"#),
+];
+
+fn topic(config: &Config, suffix: &str) -> String {
+    format!("{}/{}", config.mqtt_topic, suffix)
+}
+
+fn node_id(config: &Config) -> String {
+    config.mqtt_topic.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+// Home Assistant discovery messages: one retained config per sensor, all
+// reading the shared state topic and grouped under one device
+fn discovery(config: &Config) -> Vec<(String, String)> {
+    let node = node_id(config);
+    SENSORS
+        .iter()
+        .map(|(object, name, template, extra)| {
+            let mut sensor = json!({
+                "name": name,
+                "unique_id": format!("{}_{}", node, object),
+                "state_topic": topic(config, "state"),
+                "value_template": template,
+                "availability_topic": topic(config, "availability"),
+                "device": { "identifiers": [node], "name": "NAS backup" },
+            });
+            if let (Some(sensor), Ok(Value::Object(extra))) = (sensor.as_object_mut(), serde_json::from_str(extra)) {
+                sensor.extend(extra);
+            }
+            (format!("{}/sensor/{}/{}/config", MQTT_DISCOVERY_PREFIX, node, object), sensor.to_string())
+        })
+        .collect()
+}
+
+// The status file most recently written by any target, with the repository it came from
+fn newest_status(config: &Config) -> Option<(&Path, Value)> {
+    all_targets(config)
+        .filter_map(|target| {
+            let path = target.join(STATUS_FILE_NAME);
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((modified, target, path))
+        })
+        .max_by_key(|(modified, _, _)| *modified)
+        .and_then(|(_, target, path)| {
+            let status = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+            Some((target, status))
+        })
+}
+
+fn state_payload(config: &Config) -> String {
+    let (target, status) = newest_status(config).unwrap_or((Path::new(""), json!({ "state": "unknown" })));
+    let last_success = newest_success(config, "backup").map(|report| report.finished_at);
+    json!({
+        "run_id": status["run_id"],
+        "state": status["state"],
+        "progress_percent": status["progress_percent"],
+        "files_done": status["files_done"],
+        "files_total": status["files_total"],
+        "bytes_done": status["bytes_done"],
+        "eta_seconds": status["eta_seconds"],
+        "repository": target.display().to_string(),
+        "last_success": last_success,
+    })
+    .to_string()
+}
+
+fn options(config: &Config) -> io::Result<MqttOptions> {
+    let broker = config.mqtt_broker.as_str();
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid MQTT_BROKER: {:?}", broker))
+            })?,
+        ),
+        None => (broker, DEFAULT_PORT),
+    };
+    let client_id = format!("{}-{}", node_id(config), std::process::id());
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(topic(config, "availability"), "offline", QoS::AtLeastOnce, true));
+    if !config.mqtt_username.is_empty() {
+        options.set_credentials(&config.mqtt_username, &config.mqtt_password);
+    }
+    Ok(options)
+}
+
+// Publish daemon status to MQTT_BROKER, if configured, on background threads:
+// the state of the current or last run, its progress and the time of the
+// last successful backup, refreshed every STATUS_INTERVAL_SECS.
+pub fn start_publisher(config: &Config) -> io::Result<()> {
+    if config.mqtt_broker.is_empty() {
+        return Ok(());
+    }
+    let (client, mut connection) = Client::new(options(config)?, 32);
+
+    // Discovery and availability are retained, but are sent again on every
+    // connect in case the broker lost them
+    let announcer = client.clone();
+    let announced = config.clone();
+    thread::spawn(move || loop {
+        let broker = &announced.mqtt_broker;
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}", broker);
+                    for (topic, sensor) in discovery(&announced) {
+                        let _ = announcer.try_publish(topic, QoS::AtLeastOnce, true, sensor);
+                    }
+                    let _ = announcer.try_publish(topic(&announced, "availability"), QoS::AtLeastOnce, true, "online");
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection to {} failed: {}", broker, e);
+                    thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS));
+                }
+            }
+        }
+    });
+
+    // The state topic is retained, so it only needs publishing when it changes
+    let config = config.clone();
+    thread::spawn(move || {
+        let mut last_sent = String::new();
+        loop {
+            let payload = state_payload(&config);
+            if payload != last_sent {
+                match client.publish(topic(&config, "state"), QoS::AtLeastOnce, true, payload.clone()) {
+                    Ok(()) => last_sent = payload,
+                    Err(e) => warn!("Failed to publish backup status: {}", e),
+                }
+            }
+            thread::sleep(Duration::from_secs(STATUS_INTERVAL_SECS));
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use log::info;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::chaos;
+use crate::config::{HARDLINK_UNCHANGED, PACK_FILES_BELOW, PACK_SIZE};
+use crate::timeout;
+
+// Small files are stored concatenated in pack files in this directory of the
+// checkpoint instead of one file each; their manifest entries and metas say
+// which pack and at what offset. Packs are never rewritten, so data of files
+// dropped later stays in them.
+pub const PACK_DIR: &str = ".packs";
+
+// Where packed data starts: a pack in PACK_DIR of the checkpoint holding the
+// data, and a byte offset. Written as "<pack>@<offset>".
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackRef {
+    pub pack: String,
+    pub offset: u64,
+}
+
+impl fmt::Display for PackRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.pack, self.offset)
+    }
+}
+
+impl PackRef {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid pack reference: {}", text));
+        let (pack, offset) = text.rsplit_once('@').ok_or_else(invalid)?;
+        if pack.is_empty() || pack.contains('/') {
+            return Err(invalid());
+        }
+        Ok(Self { pack: pack.to_string(), offset: offset.parse().map_err(|_| invalid())? })
+    }
+
+    pub fn path(&self, checkpoint: &Path) -> PathBuf {
+        checkpoint.join(PACK_DIR).join(&self.pack)
+    }
+}
+
+// The `size` bytes of a packed file
+pub fn open_packed(pack: &Path, offset: u64, size: u64) -> io::Result<io::Take<File>> {
+    let mut file = File::open(pack)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(file.take(size))
+}
+
+struct OpenPack {
+    name: String,
+    file: File,
+    len: u64,
+}
+
+// Packs the small files of one run into PACK_SIZE pack files. Workers share
+// one open pack; each file is appended whole under the lock.
+pub struct PackWriter {
+    dir: PathBuf,
+    current: Mutex<Option<OpenPack>>,
+}
+
+impl PackWriter {
+    pub fn new(checkpoint: &Path) -> Self {
+        Self { dir: checkpoint.join(PACK_DIR), current: Mutex::new(None) }
+    }
+
+    // Whether a file of `size` bytes goes into a pack. Hard-linked checkpoints
+    // are meant to be plain trees, so nothing is packed there.
+    pub fn accepts(&self, size: u64) -> bool {
+        !HARDLINK_UNCHANGED && PACK_FILES_BELOW.is_some_and(|below| size < below)
+    }
+
+    // A new pack, numbered after those already there (an appending run adds
+    // to a checkpoint that may have some)
+    fn create_pack(&self) -> io::Result<OpenPack> {
+        fs::create_dir_all(&self.dir)?;
+        let mut number = fs::read_dir(&self.dir)?.count();
+        loop {
+            let name = format!("pack-{:05}", number);
+            match OpenOptions::new().write(true).create_new(true).open(self.dir.join(&name)) {
+                Ok(file) => return Ok(OpenPack { name, file, len: 0 }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => number += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Append the contents of the file `data` to the current pack
+    pub fn add(&self, data: &Path) -> io::Result<(PackRef, u64)> {
+        self.add_data(&timeout::read(data)?)
+    }
+
+    // Append `data` to the current pack, starting a new pack when it would
+    // grow past PACK_SIZE
+    pub fn add_data(&self, data: &[u8]) -> io::Result<(PackRef, u64)> {
+        let size = data.len() as u64;
+        let mut current = self.current.lock().unwrap();
+        if current.as_ref().is_some_and(|pack| pack.len > 0 && pack.len + size > PACK_SIZE) {
+            if let Some(full) = current.take() {
+                full.file.sync_all()?;
+            }
+        }
+        if current.is_none() {
+            *current = Some(self.create_pack()?);
+        }
+        let pack = current.as_mut().expect("a pack is open");
+        let offset = pack.len;
+        chaos::write_all(&mut pack.file, data, "pack")?;
+        pack.len += size;
+        Ok((PackRef { pack: pack.name.clone(), offset }, size))
+    }
+
+    // Flush the last pack to disk
+    pub fn finish(&self) -> io::Result<()> {
+        if let Some(mut pack) = self.current.lock().unwrap().take() {
+            pack.file.flush()?;
+            pack.file.sync_all()?;
+            info!("Packed small files into {:?}", self.dir.join(&pack.name));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use std::path::Path;
+
+// '*' matches any run of characters, '?' any single one
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| wildcard_match(rest, &text[skip..])),
+        Some((&c, rest)) => text
+            .split_first()
+            .is_some_and(|(&t, text)| (c == b'?' || c == t) && wildcard_match(rest, text)),
+    }
+}
+
+// Match a per-path setting against a file. Patterns containing a '/' are
+// matched against the path relative to the source root, others against the
+// file name.
+pub fn path_matches(pattern: &str, rel: &Path) -> bool {
+    let text = if pattern.contains('/') {
+        rel.to_string_lossy()
+    } else {
+        rel.file_name().unwrap_or_default().to_string_lossy()
+    };
+    wildcard_match(pattern.as_bytes(), text.as_bytes())
+}
+
+// Whether files below the directory `dir` may match `pattern`: any file name
+// pattern may, a path pattern only if its literal start and the directory lie
+// on one branch
+pub fn may_match_below(pattern: &str, dir: &Path) -> bool {
+    let dir = format!("{}/", dir.to_string_lossy());
+    let literal = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+    !pattern.contains('/') || literal.starts_with(&dir) || dir.starts_with(literal)
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::chaos;
+use crate::config::{HASH_BUFFER_SIZE, SEEKABLE_FRAME_SIZE};
+use crate::hashing::{ContentHasher, Scheme};
+use crate::pack::{PackRef, PackWriter};
+use crate::priority::RunGuard;
+use crate::reflink::reflink;
+use crate::seekable::{compress_frame, read_full, SeekIndex, SeekableWriter};
+use crate::timeout;
+
+// New and changed files are stored in a single pass: every block read from
+// the source is hashed, compressed if the file is stored seekable, and written
+// to the checkpoint before the next one is read, so the data is read once and
+// no temporary copy is made. Every write counts against the run's rate limit.
+// Stored data isn't encrypted (that is left to the volume), so there is no
+// encryption stage. Backends receive the finished checkpoint afterwards.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Read,
+    Hash,
+    Compress,
+    Write,
+}
+
+// Time spent in each stage, summed over all workers
+#[derive(Default)]
+pub struct Timings {
+    nanos: [AtomicU64; 4],
+}
+
+// Stage times of a run as kept in its report
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct StageTimes {
+    pub read_ms: u64,
+    pub hash_ms: u64,
+    pub compress_ms: u64,
+    pub write_ms: u64,
+}
+
+impl Timings {
+    pub fn time<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.nanos[stage as usize].fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
+
+    pub fn times(&self) -> StageTimes {
+        let ms = |stage: Stage| self.nanos[stage as usize].load(Ordering::Relaxed) / 1_000_000;
+        StageTimes {
+            read_ms: ms(Stage::Read),
+            hash_ms: ms(Stage::Hash),
+            compress_ms: ms(Stage::Compress),
+            write_ms: ms(Stage::Write),
+        }
+    }
+}
+
+// Where the data of a file goes
+pub enum Target<'a> {
+    // A plain copy; the flag (use_reflink) allows making it a reflink
+    Plain(&'a Path, bool),
+    Seekable(&'a Path),
+    Pack(&'a PackWriter),
+}
+
+pub struct Stored {
+    // Size and hash of the data read
+    pub size: u64,
+    pub hash: String,
+    // Bytes written
+    pub written: u64,
+    pub packed: Option<PackRef>,
+    pub seekable: Option<SeekIndex>,
+}
+
+// Read `source` once, hashing it and storing it at `target`
+pub fn store(source: &Path, target: Target, timings: &Timings, run: &RunGuard) -> io::Result<Stored> {
+    // Read in order, so even huge files are hashed on this thread; their
+    // data has to be copied anyway
+    let mut hasher = ContentHasher::new(Scheme::for_size(fs::metadata(source)?.len()));
+    let mut stored = Stored { size: 0, hash: String::new(), written: 0, packed: None, seekable: None };
+    match target {
+        // Packed files are small, so they are read whole
+        Target::Pack(packs) => {
+            let data = timings.time(Stage::Read, || timeout::read(source))?;
+            timings.time(Stage::Hash, || hasher.update(&data));
+            let (packed, written) = timings.time(Stage::Write, || packs.add_data(&data))?;
+            run.throttle(written);
+            stored.size = data.len() as u64;
+            stored.written = written;
+            stored.packed = Some(packed);
+        }
+        Target::Seekable(dest) => {
+            let mut input = timeout::open(source)?;
+            let mut writer = SeekableWriter::create(dest)?;
+            let mut buffer = vec![0u8; SEEKABLE_FRAME_SIZE as usize];
+            loop {
+                let read = timings.time(Stage::Read, || read_full(&mut input, &mut buffer))?;
+                if read == 0 {
+                    break;
+                }
+                timings.time(Stage::Hash, || hasher.update(&buffer[..read]));
+                let frame = timings.time(Stage::Compress, || compress_frame(&buffer[..read]))?;
+                timings.time(Stage::Write, || writer.write_frame(&frame, read))?;
+                run.throttle(frame.len() as u64);
+                stored.size += read as u64;
+            }
+            let (index, written) = timings.time(Stage::Write, || writer.finish())?;
+            stored.written = written;
+            stored.seekable = Some(index);
+        }
+        Target::Plain(dest, use_reflink) => {
+            // A reflink shares the blocks without reading them, which leaves
+            // only the hash to read the data for. It is taken from the clone,
+            // so it matches what was stored even if the source changes
+            // meanwhile.
+            let (mut input, mut output) = if use_reflink && timings.time(Stage::Write, || reflink(source, dest))? {
+                (timeout::open(dest)?, None)
+            } else {
+                let input = timeout::open(source)?;
+                let output = File::create(dest)?;
+                output.set_permissions(fs::metadata(source)?.permissions())?;
+                (input, Some(output))
+            };
+            let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+            loop {
+                let read = timings.time(Stage::Read, || input.read(&mut buffer))?;
+                if read == 0 {
+                    break;
+                }
+                timings.time(Stage::Hash, || hasher.update(&buffer[..read]));
+                if let Some(output) = output.as_mut() {
+                    timings.time(Stage::Write, || chaos::write_all(output, &buffer[..read], "store"))?;
+                    run.throttle(read as u64);
+                }
+                stored.size += read as u64;
+            }
+            stored.written = stored.size;
+        }
+    }
+    stored.hash = hasher.finish();
+    Ok(stored)
+}
@@ -0,0 +1,267 @@
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::checkpoint::{list_checkpoints, read_chain_entries};
+use crate::human::{format_count, format_size};
+use crate::manifest::{escape_path, has_manifest, read_entries, unescape_path, ManifestEntry};
+
+// Dual-phase runs first record every file they are going to process in the
+// checkpoint's plan, then copy the data. Each finished file is appended to
+// the done journal with its manifest entry, so a run that was killed can be
+// resumed exactly where it stopped. Both files are removed when the run ends.
+pub const PLAN_NAME: &str = ".plan.tsv";
+pub const PLAN_DONE_NAME: &str = ".plan-done.tsv";
+
+// One line of the plan: "<size>\t<mtime ns>\t<escaped path>"
+pub struct PlannedFile {
+    pub rel: PathBuf,
+    pub size: u64,
+    pub mtime_ns: i128,
+}
+
+fn invalid(path: &Path, line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line in {:?}: {}", path, line))
+}
+
+// Every run lists the files in its scope and diffs them against the last
+// checkpoint's manifest before any data is read, so progress knows the whole
+// job from the start, the files to copy can be scheduled as a batch, and a dry
+// run reports exactly what a real run would do.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Change {
+    New,
+    Modified,
+    // Same size and modification time; only hashed against the checksum cache
+    Unchanged,
+}
+
+// Size and modification time (Unix seconds) of the files of the last
+// checkpoint. Without a manifest nothing can be judged up front, so every
+// file that may exist there counts as modified.
+pub struct LastState {
+    files: HashMap<PathBuf, (u64, i64)>,
+    known: bool,
+}
+
+impl LastState {
+    pub fn load(last_checkpoint: &Path) -> io::Result<Self> {
+        if !last_checkpoint.is_dir() {
+            return Ok(Self { files: HashMap::new(), known: true });
+        }
+        if !has_manifest(last_checkpoint) {
+            return Ok(Self { files: HashMap::new(), known: false });
+        }
+        let mut files = HashMap::new();
+        for entry in read_entries(last_checkpoint) {
+            let entry = entry?;
+            files.insert(entry.path, (entry.size, entry.time_stamp));
+        }
+        Ok(Self { files, known: true })
+    }
+
+    pub fn classify(&self, rel: &Path, size: u64, mtime_ns: i128) -> Change {
+        match self.files.get(rel) {
+            None if self.known => Change::New,
+            Some(&(last_size, time_stamp)) if last_size == size && time_stamp as i128 == mtime_ns.div_euclid(1_000_000_000) => {
+                Change::Unchanged
+            }
+            _ => Change::Modified,
+        }
+    }
+
+    pub fn contains(&self, rel: &Path) -> bool {
+        self.files.contains_key(rel)
+    }
+
+    // Number of files of the last checkpoint, if it has a manifest
+    pub fn file_count(&self) -> Option<u64> {
+        self.known.then_some(self.files.len() as u64)
+    }
+
+    // Files of the last checkpoint not among the `present` ones of a full scan
+    pub fn removed(&self, present: u64) -> Option<u64> {
+        self.known.then(|| (self.files.len() as u64).saturating_sub(present))
+    }
+}
+
+// Files and bytes per kind of change
+#[derive(Default)]
+pub struct DiffSummary {
+    pub new: (u64, u64),
+    pub modified: (u64, u64),
+    pub unchanged: (u64, u64),
+    // Files of the last checkpoint no longer there; only known for full scans
+    pub removed: Option<u64>,
+}
+
+impl DiffSummary {
+    pub fn record(&mut self, change: Change, size: u64) {
+        let counter = match change {
+            Change::New => &mut self.new,
+            Change::Modified => &mut self.modified,
+            Change::Unchanged => &mut self.unchanged,
+        };
+        counter.0 += 1;
+        counter.1 += size;
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} new ({}), {} modified ({}), {} unchanged ({})",
+            format_count(self.new.0),
+            format_size(self.new.1),
+            format_count(self.modified.0),
+            format_size(self.modified.1),
+            format_count(self.unchanged.0),
+            format_size(self.unchanged.1)
+        )?;
+        if let Some(removed) = self.removed {
+            write!(f, ", {} removed", format_count(removed))?;
+        }
+        Ok(())
+    }
+}
+
+// Written to a temporary file and renamed, so a plan that exists is complete
+pub fn write_plan<'a>(checkpoint: &Path, files: impl Iterator<Item = (&'a Path, u64, i128)>) -> io::Result<()> {
+    let path = checkpoint.join(PLAN_NAME);
+    let tmp = path.with_extension("tsv.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for (rel, size, mtime_ns) in files {
+        writeln!(writer, "{}\t{}\t{}", size, mtime_ns, escape_path(rel))?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(&tmp, &path)
+}
+
+fn read_plan(checkpoint: &Path) -> io::Result<Vec<PlannedFile>> {
+    let path = checkpoint.join(PLAN_NAME);
+    let mut files = Vec::new();
+    for line in BufReader::new(File::open(&path)?).lines() {
+        let line = line?;
+        let mut fields = line.splitn(3, '\t');
+        let (Some(size), Some(mtime_ns), Some(rel)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid(&path, &line));
+        };
+        files.push(PlannedFile {
+            rel: unescape_path(rel),
+            size: size.parse().map_err(|_| invalid(&path, &line))?,
+            mtime_ns: mtime_ns.parse().map_err(|_| invalid(&path, &line))?,
+        });
+    }
+    Ok(files)
+}
+
+// Append-only record of the files a dual-phase run has finished, one
+// "<mtime ns>\t<manifest line>" per file
+pub struct DoneJournal {
+    file: Mutex<File>,
+}
+
+impl DoneJournal {
+    pub fn open(checkpoint: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(checkpoint.join(PLAN_DONE_NAME))?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    // One unbuffered write per file, so a killed run leaves whole lines
+    pub fn record(&self, mtime_ns: i128, entry: &ManifestEntry) -> io::Result<()> {
+        let line = format!("{}\t{}", mtime_ns, entry.to_line());
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+}
+
+// Files finished by an earlier attempt, with the source mtime they were read
+// at. A torn last line from a kill is ignored.
+pub fn read_done(checkpoint: &Path) -> io::Result<HashMap<PathBuf, (i128, ManifestEntry)>> {
+    let path = checkpoint.join(PLAN_DONE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut done = HashMap::new();
+    for line in content.lines() {
+        let parsed = line
+            .split_once('\t')
+            .and_then(|(mtime_ns, entry)| Some((mtime_ns.parse().ok()?, ManifestEntry::parse(entry).ok()?)));
+        match parsed {
+            Some((mtime_ns, entry)) => {
+                done.insert(entry.path.clone(), (mtime_ns, entry));
+            }
+            None => warn!("Ignoring invalid line in {:?}: {}", path, line),
+        }
+    }
+    Ok(done)
+}
+
+// Whether the data an earlier attempt stored for `entry` in `checkpoint` is
+// all there. Data kept in older checkpoints was never this run's to write.
+pub fn stored_intact(checkpoint: &Path, entry: &ManifestEntry) -> bool {
+    let name = checkpoint.file_name().map(|name| name.to_string_lossy().to_string());
+    if entry.stored_in != name {
+        return true;
+    }
+    let stored_size = fs::metadata(checkpoint.join(&entry.path)).map(|m| m.len());
+    match (&entry.packed, &entry.seekable) {
+        (Some(packed), _) => fs::metadata(packed.path(checkpoint)).is_ok_and(|m| m.len() >= packed.offset + entry.size),
+        (None, Some(index)) => stored_size.is_ok_and(|len| len == index.stored_size()),
+        (None, None) => stored_size.is_ok_and(|len| len == entry.size),
+    }
+}
+
+// The run is over, whether it finished or failed
+pub fn finish(checkpoint: &Path) -> io::Result<()> {
+    for name in [PLAN_NAME, PLAN_DONE_NAME] {
+        match fs::remove_file(checkpoint.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Checkpoint of a dual-phase run that was killed before it finished: it has
+// a plan but never made it into the chain
+pub fn interrupted_run(backup_dir: &Path) -> io::Result<Option<String>> {
+    let chain: HashSet<String> = read_chain_entries(backup_dir)?.into_iter().map(|(name, _)| name).collect();
+    Ok(list_checkpoints(backup_dir)?
+        .into_iter()
+        .rev()
+        .filter(|checkpoint| checkpoint.join(PLAN_NAME).is_file())
+        .filter_map(|checkpoint| Some(checkpoint.file_name()?.to_string_lossy().to_string()))
+        .find(|name| !chain.contains(name)))
+}
+
+pub struct Remaining {
+    pub files: u64,
+    pub bytes: u64,
+    pub total_files: u64,
+    pub total_bytes: u64,
+}
+
+// Work the plan of `checkpoint` still has left
+pub fn remaining(checkpoint: &Path) -> io::Result<Remaining> {
+    let done = read_done(checkpoint)?;
+    let mut remaining = Remaining { files: 0, bytes: 0, total_files: 0, total_bytes: 0 };
+    for file in read_plan(checkpoint)? {
+        remaining.total_files += 1;
+        remaining.total_bytes += file.size;
+        // A file that changed after it was planned is read again
+        if done.get(&file.rel).is_none_or(|(mtime_ns, _)| *mtime_ns != file.mtime_ns) {
+            remaining.files += 1;
+            remaining.bytes += file.size;
+        }
+    }
+    Ok(remaining)
+}
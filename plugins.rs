@@ -0,0 +1,275 @@
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::checkpoint::write_atomic;
+use crate::config::PLUGIN_TIMEOUT_SECS;
+use crate::error::Error;
+use crate::runid;
+
+// Plugin protocol. Every executable in plugin_dir is a plugin. For each call
+// it is started without arguments, gets one JSON object on a single line on
+// stdin and answers with one JSON object on a single line on stdout, then
+// exits. Its stderr goes to the console. Requests carry "protocol" (this
+// version), "method" and the "run_id" of the calling run next to the
+// method's fields:
+//
+//   describe  -> {"name": "...", "capabilities": ["notify", "policy", "backend"]}
+//   notify    {"event": "backup_finished", "checkpoint": "...", "partial": false,
+//              "report": {run report}, "html_report": "/abs/report.html"} -> {}
+//   policy    {"action": "backup", "src": "...", "backup_dir": "...",
+//              "checkpoint": "..."} -> {"allow": true|false, "reason": "..."}
+//   store     {"checkpoint": "...", "path": "/abs/checkpoint/dir"}
+//             -> {"checksums": {"<path in checkpoint>": "<algorithm>:<value>"}}
+//   attest    {"checkpoint": "..."}
+//             -> {"checksums": {"<path in checkpoint>": "<algorithm>:<value>"}}
+//
+// Backends whose storage keeps content checksums (S3 ETags or SHA-256
+// additional checksums, say) may return them from store; they are kept in
+// the checkpoint. A backend that can look them up again without downloading
+// anything describes the "attest" capability, which lets `check --backends`
+// verify its copies against what it reported at upload time.
+//
+// Any answer with an "error" string fails the call. Plugins only receive the
+// methods of the capabilities they describe.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+// Checksums backends reported for the checkpoint: plugin name -> path in the
+// checkpoint -> checksum
+pub const BACKEND_CHECKSUMS_NAME: &str = ".backend-checksums.json";
+pub type BackendChecksums = BTreeMap<String, BTreeMap<String, String>>;
+
+pub struct Plugin {
+    pub path: PathBuf,
+    pub name: String,
+    pub capabilities: Vec<String>,
+}
+
+impl Plugin {
+    fn can(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    fn call(&self, method: &str, params: Value) -> io::Result<Value> {
+        call(&self.path, method, params)
+            .map_err(|e| io::Error::new(e.kind(), format!("Plugin {} ({}): {}", self.name, method, e)))
+    }
+
+    // Checksums the backend holds for its copy of `checkpoint`
+    pub fn attest(&self, checkpoint: &str) -> io::Result<BTreeMap<String, String>> {
+        Ok(checksums_of(&self.call("attest", json!({ "checkpoint": checkpoint }))?))
+    }
+}
+
+fn checksums_of(answer: &Value) -> BTreeMap<String, String> {
+    answer["checksums"]
+        .as_object()
+        .map(|checksums| {
+            checksums.iter().filter_map(|(path, checksum)| Some((path.clone(), checksum.as_str()?.to_string()))).collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn recorded_checksums(checkpoint: &Path) -> io::Result<BackendChecksums> {
+    let path = checkpoint.join(BACKEND_CHECKSUMS_NAME);
+    match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BackendChecksums::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn record_checksums(checkpoint: &Path, plugin: &str, checksums: BTreeMap<String, String>) -> io::Result<()> {
+    let mut recorded = recorded_checksums(checkpoint)?;
+    recorded.insert(plugin.to_string(), checksums);
+    let json = serde_json::to_vec_pretty(&recorded).map_err(io::Error::other)?;
+    write_atomic(&checkpoint.join(BACKEND_CHECKSUMS_NAME), &json)
+}
+
+// Backend plugins that can attest to their copies
+pub fn attesting_backends(dir: &Path) -> io::Result<Vec<Plugin>> {
+    Ok(discover(dir)?.into_iter().filter(|p| p.can("backend") && p.can("attest")).collect())
+}
+
+// Stop a plugin that is still running past its deadline
+fn reap(mut child: Child, deadline: Instant) -> io::Result<()> {
+    loop {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            warn!("Plugin {} did not exit, killing it", child.id());
+            let _ = child.kill();
+            child.wait()?;
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn call(path: &Path, method: &str, params: Value) -> io::Result<Value> {
+    let mut request = json!({ "protocol": PROTOCOL_VERSION, "method": method, "run_id": runid::current() });
+    if let (Some(request), Value::Object(params)) = (request.as_object_mut(), params) {
+        request.extend(params);
+    }
+    let deadline = Instant::now() + Duration::from_secs(PLUGIN_TIMEOUT_SECS);
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    match stdin.write_all(format!("{}\n", request).as_bytes()) {
+        // A plugin that doesn't need the request may exit without reading it
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+        _ => drop(stdin),
+    }
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = sender.send(BufReader::new(stdout).read_line(&mut line).map(|_| line));
+    });
+    let line = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(line) => line?,
+        Err(_) => {
+            let _ = child.kill();
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no answer within {}s", PLUGIN_TIMEOUT_SECS),
+            ));
+        }
+    };
+    reap(child, deadline)?;
+
+    if line.trim().is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no answer"));
+    }
+    let response: Value = serde_json::from_str(&line)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid answer: {}", e)))?;
+    if !response.is_object() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "answer is not a JSON object"));
+    }
+    if let Some(error) = response.get("error").and_then(Value::as_str) {
+        return Err(io::Error::other(error.to_string()));
+    }
+    Ok(response)
+}
+
+// Plugins in `dir` ("" for none), in name order. Executables that fail to
+// describe themselves are skipped with a warning.
+pub fn discover(dir: &Path) -> io::Result<Vec<Plugin>> {
+    if dir.as_os_str().is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0))
+            .map(|e| e.path())
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("Plugin directory {:?} does not exist", dir);
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+    paths.sort();
+
+    let mut plugins = Vec::new();
+    for path in paths {
+        let description = match call(&path, "describe", json!({})) {
+            Ok(description) => description,
+            Err(e) => {
+                warn!("Skipping plugin {:?}: {}", path, e);
+                continue;
+            }
+        };
+        let name = description["name"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        let capabilities = description["capabilities"]
+            .as_array()
+            .map(|c| c.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default();
+        plugins.push(Plugin { path, name, capabilities });
+    }
+    Ok(plugins)
+}
+
+// Ask the policy plugins whether `action` may go ahead. A plugin that refuses,
+// or fails to answer, stops it.
+pub fn check_policy(dir: &Path, action: &str, details: Value) -> io::Result<()> {
+    for plugin in discover(dir)?.iter().filter(|p| p.can("policy")) {
+        let mut params = details.clone();
+        params["action"] = json!(action);
+        let answer = plugin.call("policy", params)?;
+        if answer["allow"].as_bool() != Some(true) {
+            let reason = answer["reason"].as_str().unwrap_or("no reason given");
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Plugin {} refused the {}: {}", plugin.name, action, reason),
+            ));
+        }
+        info!("Plugin {} allowed the {}", plugin.name, action);
+    }
+    Ok(())
+}
+
+// Tell the notification plugins about `event`. Failures are only logged.
+pub fn notify(dir: &Path, event: &str, details: Value) {
+    let plugins = match discover(dir) {
+        Ok(plugins) => plugins,
+        Err(e) => {
+            warn!("Failed to list plugins: {}", e);
+            return;
+        }
+    };
+    for plugin in plugins.iter().filter(|p| p.can("notify")) {
+        let mut params = details.clone();
+        params["event"] = json!(event);
+        if let Err(e) = plugin.call("notify", params) {
+            warn!("{}", e);
+        }
+    }
+}
+
+// Hand a finished checkpoint to the backend plugins. Every backend is tried;
+// the call fails if any of them did.
+pub fn store(dir: &Path, checkpoint: &Path) -> io::Result<()> {
+    let name = checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let path = std::path::absolute(checkpoint)?;
+    let mut failed = Vec::new();
+    for plugin in discover(dir)?.iter().filter(|p| p.can("backend")) {
+        match plugin.call("store", json!({ "checkpoint": name, "path": path })) {
+            Ok(answer) => {
+                info!("Plugin {} stored {}", plugin.name, name);
+                let checksums = checksums_of(&answer);
+                if !checksums.is_empty() {
+                    record_checksums(checkpoint, &plugin.name, checksums)?;
+                }
+            }
+            Err(e) => {
+                warn!("{}", e);
+                failed.push(plugin.name.clone());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(Error::Backend(format!("plugins {} failed to store {}", failed.join(", "), name)).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::config::{PREFETCH_AHEAD, PREFETCH_BYTES, PREFETCH_THREADS};
+use crate::timeout;
+
+// Readahead stage between the directory walk and the hashing workers: files
+// are opened (pulling their inode into cache) and their first PREFETCH_BYTES
+// requested with POSIX_FADV_WILLNEED while the workers are still busy with
+// earlier files, hiding seek and metadata latency on spinning disks.
+pub struct Prefetcher {
+    sender: Option<mpsc::SyncSender<PathBuf>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    pub fn start() -> Self {
+        if PREFETCH_AHEAD == 0 || PREFETCH_THREADS == 0 {
+            return Self { sender: None, threads: Vec::new() };
+        }
+        let (sender, receiver) = mpsc::sync_channel::<PathBuf>(PREFETCH_AHEAD);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..PREFETCH_THREADS)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let path = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                        Ok(path) => path,
+                        Err(_) => break,
+                    };
+                    // Purely advisory: files that can't be opened are left to the workers
+                    if let Ok(file) = File::open(&path) {
+                        will_need(&file);
+                    }
+                })
+            })
+            .collect();
+        Self { sender: Some(sender), threads }
+    }
+
+    // Queue a file the workers will read soon. Never blocks: when the
+    // prefetcher is already PREFETCH_AHEAD files behind, the hint is dropped.
+    pub fn hint(&self, path: PathBuf) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(path);
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.sender.take();
+        // With file time limits set, a prefetch stuck opening a hung file
+        // must not hold up the end of the run; it is left behind instead
+        if timeout::enabled() {
+            return;
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn will_need(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, PREFETCH_BYTES as libc::off_t, libc::POSIX_FADV_WILLNEED);
+    }
+}
+
+// Elsewhere opening the file still warms the metadata caches
+#[cfg(not(target_os = "linux"))]
+fn will_need(_file: &File) {}
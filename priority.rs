@@ -0,0 +1,159 @@
+use log::info;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::PREEMPT_POLL_SECS;
+use crate::transfer::{RateLimit, Throttled};
+use crate::window::BackupWindow;
+
+const RUNS_DIR: &str = ".runs";
+
+// Registrations are named "<pid>-<n>.run", n counting the guards of the
+// process, so the runs one daemon process starts each have their own
+static GUARDS: AtomicU64 = AtomicU64::new(0);
+
+// Registration of a running process in BACKUP_DIR/.runs, holding its priority.
+// Higher numbers win; a run pauses while any live run with a higher priority exists.
+pub struct RunGuard {
+    path: PathBuf,
+    priority: u8,
+    // Set for scheduled runs that pause when their backup window closes
+    window: Option<BackupWindow>,
+    // Set for time-limited runs, which schedule no new files after it
+    deadline: Option<Instant>,
+    // Set for runs limited to a transfer rate
+    rate: Option<RateLimit>,
+}
+
+pub fn register(backup_dir: &Path, priority: u8) -> io::Result<RunGuard> {
+    let runs_dir = backup_dir.join(RUNS_DIR);
+    fs::create_dir_all(&runs_dir)?;
+    let path = runs_dir.join(format!("{}-{}.run", std::process::id(), GUARDS.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&path, priority.to_string())?;
+    info!("Registered run with priority {}", priority);
+    Ok(RunGuard { path, priority, window: None, deadline: None, rate: None })
+}
+
+// Register against the repository at `backup_dir` if there is one. Restores
+// from a bundle or stream don't need one; without it they run standalone,
+// neither pausing for nor preempting other runs.
+pub fn register_if_present(backup_dir: &Path, priority: u8) -> io::Result<RunGuard> {
+    if backup_dir.is_dir() {
+        register(backup_dir, priority)
+    } else {
+        Ok(RunGuard { path: PathBuf::new(), priority, window: None, deadline: None, rate: None })
+    }
+}
+
+pub(crate) fn is_alive(pid: &str) -> bool {
+    let Ok(pid) = pid.parse::<libc::pid_t>() else {
+        return false;
+    };
+    // Signal 0 only checks the process exists; EPERM means it does, but
+    // belongs to another user
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+// Registrations of live runs in `runs_dir` other than `own`, as (name,
+// priority); ones left behind by crashed runs are removed
+fn live_registrations(runs_dir: &Path, own: &Path) -> Vec<(String, u8)> {
+    let Ok(entries) = fs::read_dir(runs_dir) else {
+        return Vec::new();
+    };
+    let mut live = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path == own || path.extension().is_none_or(|ext| ext != "run") {
+            continue;
+        }
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        // "<pid>-<n>", or just the pid for registrations of older versions
+        let pid = name.split('-').next().unwrap_or_default();
+        if !is_alive(pid) {
+            // Left behind by a crashed run
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        let priority = fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| s.trim().parse::<u8>().ok())
+            .unwrap_or(0);
+        live.push((name, priority));
+    }
+    live
+}
+
+// Runs currently registered in the repository at `backup_dir`
+pub fn live_runs(backup_dir: &Path) -> Vec<String> {
+    live_registrations(&backup_dir.join(RUNS_DIR), Path::new("")).into_iter().map(|(name, _)| name).collect()
+}
+
+impl RunGuard {
+    fn higher_priority_run(&self) -> Option<String> {
+        live_registrations(self.path.parent()?, &self.path)
+            .into_iter()
+            .find(|(_, priority)| *priority > self.priority)
+            .map(|(name, _)| name)
+    }
+
+    pub fn confine_to(&mut self, window: BackupWindow) {
+        self.window = Some(window);
+    }
+
+    pub fn limit_to(&mut self, max_duration: Duration) {
+        self.deadline = Some(Instant::now() + max_duration);
+    }
+
+    pub fn limit_rate(&mut self, bytes_per_sec: u64) {
+        self.rate = Some(RateLimit::new(bytes_per_sec));
+    }
+
+    // Account for `bytes` moved into or out of the repository, waiting as long
+    // as a rate-limited run has to
+    pub fn throttle(&self, bytes: u64) {
+        if let Some(rate) = &self.rate {
+            rate.throttle(bytes);
+        }
+    }
+
+    // `reader` with its data counted against the rate limit
+    pub fn throttled<R>(&self, reader: R) -> Throttled<'_, R> {
+        Throttled::new(reader, self.rate.as_ref())
+    }
+
+    // Whether a time-limited run has used up its time
+    pub fn out_of_time(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Called between files: blocks while a higher-priority run is active or,
+    // for a run confined to a backup window, while the window is closed.
+    pub fn pause_if_needed(&self) {
+        if let Some(pid) = self.higher_priority_run() {
+            info!("Paused: higher-priority run {} is active", pid);
+            while self.higher_priority_run().is_some() {
+                thread::sleep(Duration::from_secs(PREEMPT_POLL_SECS));
+            }
+            info!("Resumed after higher-priority run finished");
+        }
+        if let Some(window) = self.window.filter(|w| !w.is_open()) {
+            info!("Paused: outside backup window {}", window);
+            window.wait_until_open();
+            info!("Resumed: backup window {} opened", window);
+        }
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        if self.path.as_os_str().is_empty() {
+            return;
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,181 @@
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::history::RunReport;
+use crate::runid;
+use crate::settings::Config;
+
+// Cron-started runs are over before Prometheus would scrape them, so every
+// command pushes its metrics to a Pushgateway at PUSHGATEWAY_URL when it ends,
+// in the Prometheus text format. They are grouped by job (PUSHGATEWAY_JOB),
+// instance (the host name) and command, so a `status` doesn't replace the
+// metrics of the last `backup`. The run ID is a label of the info metrics,
+// not part of the grouping key: the Pushgateway keeps every group until it is
+// deleted, so a group per run would pile up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reports of the runs this process finished, for the push at the end
+static REPORTS: Mutex<Vec<RunReport>> = Mutex::new(Vec::new());
+
+pub fn record(report: &RunReport) {
+    REPORTS.lock().unwrap_or_else(|e| e.into_inner()).push(report.clone());
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    CStr::from_bytes_until_nul(&buffer)
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// Label value with \, " and newlines escaped
+fn label(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+// Grouping key value as a URL path segment, with anything but letters,
+// digits, '-', '_' and '.' replaced
+fn group_value(value: &str) -> String {
+    value.chars().map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect()
+}
+
+struct Metrics(String);
+
+impl Metrics {
+    fn add(&mut self, name: &str, help: &str, samples: &[(String, f64)]) {
+        self.0.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+        for (labels, value) in samples {
+            self.0.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    }
+}
+
+fn render(command: &str, exit_code: i32, elapsed: Duration, reports: &[RunReport]) -> String {
+    let mut metrics = Metrics(String::new());
+    let command = format!("command=\"{}\"", label(command));
+    metrics.add(
+        "nas_backup_command_info",
+        "Run ID of the command",
+        &[(format!("{},run_id=\"{}\"", command, label(&runid::current())), 1.0)],
+    );
+    metrics.add("nas_backup_command_success", "Whether the command succeeded", &[(command.clone(), (exit_code == 0) as u8 as f64)]);
+    metrics.add("nas_backup_command_exit_code", "Exit status of the command", &[(command.clone(), exit_code as f64)]);
+    metrics.add(
+        "nas_backup_command_duration_seconds",
+        "How long the command ran",
+        &[(command.clone(), elapsed.as_secs_f64())],
+    );
+    metrics.add(
+        "nas_backup_command_finished_timestamp_seconds",
+        "When the command ended",
+        &[(command, clock::now().timestamp() as f64)],
+    );
+
+    // Runs with progress reporting (backups, exports, restores and the like),
+    // the last of each operation on each repository
+    let mut runs: BTreeMap<(&str, &str), &RunReport> = BTreeMap::new();
+    for report in reports {
+        runs.insert((&report.operation, &report.repository), report);
+    }
+    let per_run = |value: fn(&RunReport) -> f64| -> Vec<(String, f64)> {
+        runs.values()
+            .map(|report| {
+                let labels = format!("operation=\"{}\",repository=\"{}\"", label(&report.operation), label(&report.repository));
+                (labels, value(report))
+            })
+            .collect()
+    };
+    if !reports.is_empty() {
+        let info = runs
+            .values()
+            .map(|r| {
+                let labels = format!(
+                    "operation=\"{}\",repository=\"{}\",run_id=\"{}\"",
+                    label(&r.operation),
+                    label(&r.repository),
+                    label(&r.run_id)
+                );
+                (labels, 1.0)
+            })
+            .collect::<Vec<_>>();
+        metrics.add("nas_backup_run_info", "Run ID of the run", &info);
+        metrics.add("nas_backup_run_success", "Whether the run succeeded", &per_run(|r| r.success as u8 as f64));
+        metrics.add("nas_backup_run_files", "Files the run processed", &per_run(|r| r.files as f64));
+        metrics.add("nas_backup_run_bytes", "Bytes the run processed", &per_run(|r| r.bytes as f64));
+        metrics.add("nas_backup_run_vanished_files", "Files that vanished before they were read", &per_run(|r| r.vanished as f64));
+        metrics.add("nas_backup_run_unreadable_files", "Files and directories skipped as unreadable", &per_run(|r| r.unreadable as f64));
+        metrics.add(
+            "nas_backup_run_timed_out_files",
+            "Files skipped because reading them timed out",
+            &per_run(|r| r.timed_out.len() as f64),
+        );
+        metrics.add("nas_backup_run_duration_seconds", "How long the run took", &per_run(|r| r.duration_ms as f64 / 1000.0));
+        metrics.add(
+            "nas_backup_run_cpu_seconds",
+            "User and system CPU time of the process",
+            &per_run(|r| (r.resources.cpu_user_ms + r.resources.cpu_system_ms) as f64 / 1000.0),
+        );
+        metrics.add("nas_backup_run_peak_rss_bytes", "Peak resident memory of the process", &per_run(|r| (r.resources.peak_rss_kb * 1024) as f64));
+    }
+    metrics.0
+}
+
+// PUT `body` to an http:// URL; the Pushgateway replaces the group's metrics
+fn put(url: &str, body: &str) -> io::Result<()> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Only http:// Pushgateway URLs are supported"))?;
+    let (authority, path) = rest.split_once('/').map_or((rest, String::new()), |(a, p)| (a, format!("/{}", p)));
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Cannot resolve {}", authority)))?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("Pushgateway answered {:?}", status))),
+    }
+}
+
+// Push the metrics of the command ending with `exit_code` after `started`
+pub fn push(config: &Config, command: &str, exit_code: i32, started: Instant) {
+    let reports = std::mem::take(&mut *REPORTS.lock().unwrap_or_else(|e| e.into_inner()));
+    if config.pushgateway_url.is_empty() {
+        return;
+    }
+    let body = render(command, exit_code, started.elapsed(), &reports);
+    let url = format!(
+        "{}/metrics/job/{}/instance/{}/command/{}",
+        config.pushgateway_url.trim_end_matches('/'),
+        group_value(&config.pushgateway_job),
+        group_value(&hostname()),
+        group_value(command)
+    );
+    match put(&url, &body) {
+        Ok(()) => debug!("Pushed metrics to {}", url),
+        Err(e) => warn!("Failed to push metrics to {}: {}", url, e),
+    }
+}
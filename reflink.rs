@@ -0,0 +1,48 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use crate::chaos;
+
+// Share the blocks of `src` with `dst` instead of copying them. Fails with
+// EXDEV, EOPNOTSUPP, EINVAL and the like when the two aren't on the same
+// copy-on-write filesystem.
+#[cfg(target_os = "linux")]
+fn clone_file(src: &File, dst: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let rc = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_src: &File, _dst: &File) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Create `dst` as a reflink of `src` if both share a copy-on-write filesystem
+// (Btrfs, XFS, bcachefs), which takes no time and no space until either side
+// is modified. Returns whether it worked. Callers skip it unless use_reflink
+// is set.
+pub fn reflink(src: &Path, dst: &Path) -> io::Result<bool> {
+    let source = File::open(src)?;
+    let dest = File::create(dst)?;
+    if clone_file(&source, &dest).is_err() {
+        return Ok(false);
+    }
+    dest.set_permissions(source.metadata()?.permissions())?;
+    Ok(true)
+}
+
+// Copy a file like fs::copy, but as a reflink where possible if `use_reflink`
+// is set. Returns the number of bytes.
+pub fn copy_file(src: &Path, dst: &Path, use_reflink: bool) -> io::Result<u64> {
+    chaos::point("copy")?;
+    if use_reflink && reflink(src, dst)? {
+        return Ok(fs::metadata(src)?.len());
+    }
+    fs::copy(src, dst)
+}
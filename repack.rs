@@ -0,0 +1,324 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backup_utils::FileInfo;
+use crate::checkpoint::{ensure_unlocked, list_checkpoints, record_root, write_atomic};
+use crate::config::{HARDLINK_UNCHANGED, SEEKABLE_FRAME_SIZE};
+use crate::hashing::{ContentHasher, Scheme};
+use crate::human::format_size;
+use crate::manifest::{has_manifest, read_entries, rewrite_entries, ManifestEntry};
+use crate::priority::RunGuard;
+use crate::seekable::{self, compress_frame, read_full, SeekIndex, SeekableWriter};
+use crate::status::Progress;
+use crate::zip_handler::{meta_zips, read_zip_metas, write_zip_metas};
+
+// Stored data is written the way the settings said when it was stored, so
+// changing SEEKABLE_FILES_ABOVE or SEEKABLE_FRAME_SIZE leaves older files
+// as they were. `repack --apply-policies` rewrites those a few at a time:
+// each standalone file whose layout (plain, or seekable with some frame
+// size) isn't what a backup would write now is rewritten next to it,
+// checked against its recorded hash, and swapped in, and every manifest and
+// meta that points at it gets the new layout. Packs are never rewritten,
+// and neither is data that a retention-locked checkpoint refers to. The
+// compression level isn't recorded, so a changed SEEKABLE_ZSTD_LEVEL alone
+// doesn't make a file differ.
+//
+// A batch of rewritten files is recorded in PENDING_FILE before any of them
+// is swapped in; an interrupted batch is finished by the next repack.
+const PENDING_FILE: &str = ".repack-pending.json";
+// Rewritten files wait in this directory of their checkpoint
+const TEMP_DIR: &str = ".repack";
+
+#[derive(Serialize, Deserialize)]
+struct PendingFile {
+    path: PathBuf,
+    temp: String,
+    seekable: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pending {
+    checkpoint: String,
+    files: Vec<PendingFile>,
+}
+
+fn checkpoint_name(checkpoint: &Path) -> String {
+    checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Frame size a file of `size` bytes is stored with now; None for plain
+fn wanted_layout(size: u64) -> Option<u64> {
+    seekable::accepts(size).then_some(SEEKABLE_FRAME_SIZE)
+}
+
+fn needs_repack(entry: &ManifestEntry) -> bool {
+    entry.packed.is_none() && entry.seekable.as_ref().map(|index| index.frame_size) != wanted_layout(entry.size)
+}
+
+// Write the data of the file stored at `stored` to `temp` in the layout
+// wanted now, checking it against the recorded hash on the way
+fn rewrite(stored: &Path, entry: &ManifestEntry, temp: &Path, run: &RunGuard) -> io::Result<Option<SeekIndex>> {
+    let reader: Box<dyn Read> = match &entry.seekable {
+        Some(_) => seekable::open(stored)?,
+        None => Box::new(File::open(stored)?),
+    };
+    let mut reader = run.throttled(reader);
+    let mut hasher = ContentHasher::new(Scheme::of(&entry.hash));
+    let mut buffer = vec![0u8; SEEKABLE_FRAME_SIZE as usize];
+    let mut size = 0;
+    let index = match wanted_layout(entry.size) {
+        Some(_) => {
+            let mut writer = SeekableWriter::create(temp)?;
+            loop {
+                let read = read_full(&mut reader, &mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                writer.write_frame(&compress_frame(&buffer[..read])?, read)?;
+                size += read as u64;
+            }
+            Some(writer.finish()?.0)
+        }
+        None => {
+            let mut out = File::create(temp)?;
+            loop {
+                let read = read_full(&mut reader, &mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                out.write_all(&buffer[..read])?;
+                size += read as u64;
+            }
+            None
+        }
+    };
+    if size != entry.size || hasher.finish() != entry.hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} doesn't match its recorded hash; left as it is", stored),
+        ));
+    }
+    fs::set_permissions(temp, fs::metadata(stored)?.permissions())?;
+    File::open(temp)?.sync_all()?;
+    Ok(index)
+}
+
+// Swap the files of `pending` in and point every manifest and meta holding
+// them at the new layout. Done again after an interruption, it only does
+// what is left.
+fn apply(backup_dir: &Path, pending: &Pending) -> io::Result<()> {
+    let checkpoint = backup_dir.join(&pending.checkpoint);
+    let mut layouts = HashMap::new();
+    for file in &pending.files {
+        let temp = checkpoint.join(TEMP_DIR).join(&file.temp);
+        if temp.exists() {
+            fs::rename(&temp, checkpoint.join(&file.path))?;
+        }
+        let index = file.seekable.as_deref().map(SeekIndex::parse).transpose()?;
+        layouts.insert(file.path.clone(), index);
+    }
+    let meta_names: HashMap<PathBuf, PathBuf> =
+        layouts.keys().map(|rel| (rel.with_extension("meta"), rel.clone())).collect();
+
+    let update = |stored_in: &Option<String>, path: &Path, seekable: &mut Option<SeekIndex>| {
+        if stored_in.as_deref() != Some(pending.checkpoint.as_str()) {
+            return false;
+        }
+        match layouts.get(path) {
+            Some(index) if index != seekable => {
+                seekable.clone_from(index);
+                true
+            }
+            _ => false,
+        }
+    };
+    // Only the holding checkpoint and later ones can refer to its data
+    let checkpoints = list_checkpoints(backup_dir)?;
+    let position = checkpoints.iter().position(|c| *c == checkpoint).unwrap_or(checkpoints.len());
+    for dependent in &checkpoints[position..] {
+        if has_manifest(dependent) {
+            let mut changed = false;
+            rewrite_entries(dependent, |entry| {
+                let updated = update(&entry.stored_in, &entry.path, &mut entry.seekable);
+                changed |= updated;
+                updated
+            })?;
+            if changed {
+                record_root(backup_dir, dependent)?;
+            }
+        }
+        for zip in meta_zips(dependent) {
+            let dir = zip.parent().and_then(|p| p.strip_prefix(dependent).ok()).unwrap_or(Path::new("")).to_path_buf();
+            let mut metas = read_zip_metas(&zip)?;
+            let mut changed = false;
+            for meta in metas.iter_mut() {
+                let Some(rel) = meta_names.get(&dir.join(&meta.name)) else {
+                    continue;
+                };
+                let mut info = FileInfo::parse(&meta.content)?;
+                if update(&info.stored_in, rel, &mut info.seekable) {
+                    meta.content = info.to_meta_string();
+                    changed = true;
+                }
+            }
+            if changed {
+                write_zip_metas(&zip, &metas)?;
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(checkpoint.join(TEMP_DIR));
+    Ok(())
+}
+
+fn commit(backup_dir: &Path, pending: &Pending) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(pending).map_err(io::Error::other)?;
+    write_atomic(&backup_dir.join(PENDING_FILE), &json)?;
+    apply(backup_dir, pending)?;
+    fs::remove_file(backup_dir.join(PENDING_FILE))
+}
+
+// Finish a batch an earlier repack was interrupted in
+fn finish_pending(backup_dir: &Path) -> io::Result<()> {
+    let path = backup_dir.join(PENDING_FILE);
+    let pending: Pending = match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e)))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    info!("Finishing the interrupted repack of {} files in {}", pending.files.len(), pending.checkpoint);
+    apply(backup_dir, &pending)?;
+    fs::remove_file(path)
+}
+
+// Rewrite `entries`, stored in `checkpoint`, unless a locked checkpoint
+// among `later` refers to them. Returns the files rewritten and failed.
+fn repack_checkpoint(
+    backup_dir: &Path,
+    checkpoint: &Path,
+    later: &[PathBuf],
+    entries: Vec<ManifestEntry>,
+    run: &RunGuard,
+    progress: &Progress,
+) -> io::Result<(usize, usize)> {
+    let name = checkpoint_name(checkpoint);
+    let mut batch: BTreeMap<PathBuf, ManifestEntry> = entries.into_iter().map(|e| (e.path.clone(), e)).collect();
+    for dependent in later.iter().filter(|dependent| has_manifest(dependent)) {
+        if ensure_unlocked(dependent).is_ok() {
+            continue;
+        }
+        for entry in read_entries(dependent) {
+            let entry = entry?;
+            if entry.stored_in.as_deref() == Some(name.as_str()) && batch.remove(&entry.path).is_some() {
+                progress.finish_file(entry.size);
+            }
+        }
+    }
+
+    fs::create_dir_all(checkpoint.join(TEMP_DIR))?;
+    let mut pending = Pending { checkpoint: name, files: Vec::new() };
+    let mut failed = 0;
+    for (i, entry) in batch.values().enumerate() {
+        run.pause_if_needed();
+        if run.out_of_time() {
+            break;
+        }
+        progress.begin_file(&entry.path);
+        let temp = i.to_string();
+        match rewrite(&checkpoint.join(&entry.path), entry, &checkpoint.join(TEMP_DIR).join(&temp), run) {
+            Ok(index) => pending.files.push(PendingFile {
+                path: entry.path.clone(),
+                temp,
+                seekable: index.as_ref().map(SeekIndex::to_string),
+            }),
+            Err(e) => {
+                warn!("Not repacking {:?}: {}", entry.path, e);
+                let _ = fs::remove_file(checkpoint.join(TEMP_DIR).join(&temp));
+                failed += 1;
+            }
+        }
+        progress.finish_file(entry.size);
+    }
+    if pending.files.is_empty() {
+        let _ = fs::remove_dir_all(checkpoint.join(TEMP_DIR));
+        return Ok((0, failed));
+    }
+    commit(backup_dir, &pending)?;
+    info!("Repacked {} files in {}", pending.files.len(), pending.checkpoint);
+    Ok((pending.files.len(), failed))
+}
+
+// Rewrite stored files whose layout differs from what the settings ask for
+// now, oldest checkpoint first, until about `max_bytes` of data (0 for no
+// limit) were rewritten or the run is out of time. Later runs go on from
+// there.
+pub fn apply_policies(backup_dir: &Path, max_bytes: u64, run: &RunGuard, progress: &Progress) -> io::Result<()> {
+    if HARDLINK_UNCHANGED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Checkpoints are plain trees with HARDLINK_UNCHANGED, so there is nothing to repack",
+        ));
+    }
+    finish_pending(backup_dir)?;
+
+    let checkpoints = list_checkpoints(backup_dir)?;
+    let mut planned: Vec<(usize, Vec<ManifestEntry>)> = Vec::new();
+    let (mut planned_bytes, mut left, mut left_bytes) = (0, 0, 0);
+    for (i, checkpoint) in checkpoints.iter().enumerate() {
+        if !has_manifest(checkpoint) {
+            continue;
+        }
+        let name = checkpoint_name(checkpoint);
+        let mut entries = Vec::new();
+        for entry in read_entries(checkpoint) {
+            let entry = entry?;
+            if entry.stored_in.as_deref() != Some(name.as_str()) || !needs_repack(&entry) {
+                continue;
+            }
+            if max_bytes != 0 && planned_bytes >= max_bytes {
+                left += 1;
+                left_bytes += entry.size;
+                continue;
+            }
+            planned_bytes += entry.size;
+            progress.add_total(entry.size);
+            entries.push(entry);
+        }
+        if entries.is_empty() {
+            continue;
+        }
+        if let Err(e) = ensure_unlocked(checkpoint) {
+            info!("Skipping {}: {}", name, e);
+            continue;
+        }
+        planned.push((i, entries));
+    }
+    progress.scan_complete();
+
+    let (mut repacked, mut failed) = (0, 0);
+    for (i, entries) in planned {
+        if run.out_of_time() {
+            break;
+        }
+        let (done, failures) = repack_checkpoint(backup_dir, &checkpoints[i], &checkpoints[i + 1..], entries, run, progress)?;
+        repacked += done;
+        failed += failures;
+    }
+    info!("Repacked {} files", repacked);
+    if left > 0 {
+        info!("{} more files ({}) are left for later runs", left, format_size(left_bytes));
+    }
+    if run.out_of_time() {
+        info!("Out of time; later runs go on from here");
+    }
+    if failed > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} files could not be repacked", failed)));
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::checkpoint::read_chain_entries;
+use crate::error::Error;
+use crate::manifest::{has_manifest, read_entries, shard_hashes, ManifestEntry};
+
+// Relative path and size of every file in a checkpoint. Only directory
+// metadata is read, so this is cheap on a remote mount.
+fn inventory(checkpoint: &Path) -> io::Result<BTreeMap<PathBuf, u64>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(checkpoint) {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(checkpoint).map_err(io::Error::other)?.to_path_buf();
+        files.insert(rel, entry.metadata().map_err(io::Error::from)?.len());
+    }
+    Ok(files)
+}
+
+fn manifest_entries(checkpoint: &Path) -> io::Result<BTreeMap<PathBuf, ManifestEntry>> {
+    read_entries(checkpoint).map(|entry| entry.map(|entry| (entry.path.clone(), entry))).collect()
+}
+
+// Compare the manifests of one checkpoint shard by shard, and entry by entry
+// where shards differ. Returns the number of divergent entries.
+fn compare_manifests(name: &str, primary: &Path, replica: &Path) -> io::Result<usize> {
+    match (has_manifest(primary), has_manifest(replica)) {
+        (false, false) => return Ok(0),
+        (true, false) => {
+            error!("{}: manifest missing on the replica", name);
+            return Ok(1);
+        }
+        (false, true) => {
+            error!("{}: replica has a manifest the primary doesn't", name);
+            return Ok(1);
+        }
+        (true, true) => {}
+    }
+    if shard_hashes(primary)? == shard_hashes(replica)? {
+        return Ok(0);
+    }
+    let ours = manifest_entries(primary)?;
+    let theirs = manifest_entries(replica)?;
+    let mut problems = 0;
+    for (path, entry) in &ours {
+        match theirs.get(path) {
+            None => error!("{}: {:?} missing from the replica's manifest", name, path),
+            Some(other) if (other.size, &other.hash, &other.stored_in) != (entry.size, &entry.hash, &entry.stored_in) => {
+                error!(
+                    "{}: {:?} differs (primary {} bytes {}, replica {} bytes {})",
+                    name, path, entry.size, entry.hash, other.size, other.hash
+                );
+            }
+            Some(_) => continue,
+        }
+        problems += 1;
+    }
+    for path in theirs.keys().filter(|path| !ours.contains_key(*path)) {
+        error!("{}: {:?} is only in the replica's manifest", name, path);
+        problems += 1;
+    }
+    Ok(problems)
+}
+
+// Compare the files of one checkpoint by name and size. Returns the number of
+// missing, extra or different-sized files.
+fn compare_objects(name: &str, primary: &Path, replica: &Path) -> io::Result<usize> {
+    let ours = inventory(primary)?;
+    let theirs = inventory(replica)?;
+    let mut problems = 0;
+    for (path, size) in &ours {
+        match theirs.get(path) {
+            None => error!("{}: {:?} missing on the replica", name, path),
+            Some(other) if other != size => {
+                error!("{}: {:?} is {} bytes on the primary but {} on the replica", name, path, size, other)
+            }
+            Some(_) => continue,
+        }
+        problems += 1;
+    }
+    for path in theirs.keys().filter(|path| !ours.contains_key(*path)) {
+        warn!("{}: {:?} exists only on the replica", name, path);
+        problems += 1;
+    }
+    Ok(problems)
+}
+
+// Check that `replica` mirrors `primary` without reading any data file: the
+// chains must list the same checkpoints with the same manifest root hashes,
+// manifests must match shard by shard, and every file must be present with
+// the same size on both sides.
+pub fn verify_replica(primary: &Path, replica: &Path) -> io::Result<()> {
+    let ours: BTreeMap<String, Option<String>> = read_chain_entries(primary)?.into_iter().collect();
+    let theirs: BTreeMap<String, Option<String>> = read_chain_entries(replica)?.into_iter().collect();
+    let mut problems = 0;
+    let mut compared = 0;
+
+    for (name, root) in &ours {
+        let Some(other_root) = theirs.get(name) else {
+            error!("Checkpoint {} is missing on the replica", name);
+            problems += 1;
+            continue;
+        };
+        if root != other_root {
+            error!("Checkpoint {} has a different manifest root hash on the replica", name);
+            problems += 1;
+        }
+        let (primary_dir, replica_dir) = (primary.join(name), replica.join(name));
+        if !replica_dir.is_dir() {
+            error!("Checkpoint {} is in the replica's chain but its directory is missing", name);
+            problems += 1;
+            continue;
+        }
+        if !primary_dir.is_dir() {
+            warn!("Checkpoint {} is missing on the primary itself, skipping", name);
+            continue;
+        }
+        problems += compare_manifests(name, &primary_dir, &replica_dir)?;
+        problems += compare_objects(name, &primary_dir, &replica_dir)?;
+        compared += 1;
+    }
+    for name in theirs.keys().filter(|name| !ours.contains_key(*name)) {
+        warn!("Checkpoint {} exists only on the replica", name);
+        problems += 1;
+    }
+
+    if problems > 0 {
+        return Err(Error::Verification {
+            path: replica.to_path_buf(),
+            reason: format!("{} differences from {:?}", problems, primary),
+        }
+        .into());
+    }
+    info!("Replica {:?} matches {:?} ({} checkpoints)", replica, primary, compared);
+    Ok(())
+}
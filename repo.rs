@@ -0,0 +1,272 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::checkpoint::{checkpoint_ref, list_checkpoints, normalize_references, record_root, write_atomic, CheckpointInfo};
+use crate::clock;
+use crate::config::{HASH_PARALLEL_MIN_SIZE, PACK_FILES_BELOW, PACK_SIZE, SEEKABLE_FILES_ABOVE, SEEKABLE_ZSTD_LEVEL};
+use crate::human::{format_count, format_size};
+use crate::manifest::normalize_stored_in;
+use crate::migrate::migrate_repository;
+use crate::pack::PACK_DIR;
+use crate::zip_handler::{meta_zips, update_zip_stored_in};
+
+// Version of the on-disk layout. Bump it whenever older binaries would
+// misread what a newer one writes, and teach `upgrade` to convert.
+//   0: no marker; checkpoints may predate manifests and hold absolute references
+//   1: every checkpoint has a manifest, references are bare names, the chain
+//      holds manifest root hashes
+pub const FORMAT_VERSION: u32 = 1;
+const FORMAT_FILE: &str = ".format.json";
+const BINARY_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize, Deserialize)]
+struct FormatInfo {
+    format: u32,
+    written_by: String,
+}
+
+fn read_format(backup_dir: &Path) -> io::Result<Option<FormatInfo>> {
+    match fs::read(backup_dir.join(FORMAT_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}: {}", FORMAT_FILE, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_format(backup_dir: &Path) -> io::Result<()> {
+    let info = FormatInfo { format: FORMAT_VERSION, written_by: BINARY_VERSION.to_string() };
+    let json = serde_json::to_vec_pretty(&info).map_err(io::Error::other)?;
+    write_atomic(&backup_dir.join(FORMAT_FILE), &json)
+}
+
+// Refuse to touch a repository written in a newer format than this binary
+// understands, e.g. by an updated copy of the tool on another machine.
+pub fn ensure_compatible(backup_dir: &Path) -> io::Result<()> {
+    match read_format(backup_dir)? {
+        Some(info) if info.format > FORMAT_VERSION => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Repository {:?} uses format {} (written by nas-backup-utils {}), but this is version {} which \
+                 only supports formats up to {}. Use a newer nas-backup-utils for this repository.",
+                backup_dir, info.format, info.written_by, BINARY_VERSION, FORMAT_VERSION
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Checks before a backup writes to the repository; a new repository starts
+// out in the current format.
+pub fn prepare_for_backup(backup_dir: &Path) -> io::Result<()> {
+    ensure_compatible(backup_dir)?;
+    match read_format(backup_dir)? {
+        None if list_checkpoints(backup_dir)?.is_empty() => write_format(backup_dir),
+        None => {
+            info!("Repository {:?} predates format versioning; run `repo upgrade` to convert it", backup_dir);
+            Ok(())
+        }
+        Some(info) if info.format < FORMAT_VERSION => {
+            info!("Repository {:?} uses format {}; run `repo upgrade` to convert it", backup_dir, info.format);
+            Ok(())
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+// Record the binary and format that wrote a checkpoint
+pub fn stamp_checkpoint(checkpoint: &Path) -> io::Result<()> {
+    let mut info = CheckpointInfo::load(checkpoint)?;
+    info.written_by = Some(BINARY_VERSION.to_string());
+    info.format = Some(FORMAT_VERSION);
+    info.save(checkpoint)
+}
+
+// Bring a repository written in an older format up to the current one
+pub fn upgrade(backup_dir: &Path, use_reflink: bool) -> io::Result<()> {
+    ensure_compatible(backup_dir)?;
+    let from = read_format(backup_dir)?.map_or(0, |info| info.format);
+    if from == FORMAT_VERSION {
+        info!("Repository {:?} already uses format {}", backup_dir, FORMAT_VERSION);
+        return Ok(());
+    }
+    // 0 -> 1: manifests everywhere, bare references, chain root hashes
+    migrate_repository(backup_dir, false, use_reflink)?;
+    relocate(backup_dir)?;
+    for checkpoint in list_checkpoints(backup_dir)? {
+        record_root(backup_dir, &checkpoint)?;
+    }
+    write_format(backup_dir)?;
+    info!("Upgraded repository {:?} from format {} to {}", backup_dir, from, FORMAT_VERSION);
+    Ok(())
+}
+
+// Fix up a repository after it was moved to a new disk or mount point.
+// Current versions only store checkpoint names relative to the repository
+// root; this rewrites any absolute references left by older versions or
+// hand edits, everywhere a checkpoint can be referenced from.
+pub fn relocate(backup_dir: &Path) -> io::Result<()> {
+    if !backup_dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Repository not found: {:?}", backup_dir),
+        ));
+    }
+    ensure_compatible(backup_dir)?;
+    let mut changed = normalize_references(backup_dir)?;
+
+    for checkpoint in list_checkpoints(backup_dir)? {
+        let normalized = normalize_stored_in(&checkpoint)?;
+        if normalized > 0 {
+            record_root(backup_dir, &checkpoint)?;
+        }
+        changed += normalized;
+        for zip in meta_zips(&checkpoint) {
+            update_zip_stored_in(&zip, |_, current| {
+                let current = current?;
+                let name = checkpoint_ref(current);
+                (name != current).then(|| {
+                    changed += 1;
+                    name.to_string()
+                })
+            })?;
+        }
+    }
+
+    info!("Relocated repository {:?}: {} absolute references rewritten", backup_dir, changed);
+    Ok(())
+}
+
+// Encryption of the volume holding `path`: dm-crypt/LUKS devices and
+// encrypting filesystems are recognised from /proc/self/mounts and sysfs
+fn volume_encryption(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    let (device, fs_type) = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (device, mount_point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+            path.starts_with(mount_point.replace("\\040", " ")).then_some((device, mount_point.len(), fs_type))
+        })
+        .max_by_key(|&(_, len, _)| len)
+        .map(|(device, _, fs_type)| (device, fs_type))?;
+    if matches!(fs_type, "ecryptfs" | "fuse.gocryptfs" | "fuse.encfs" | "fuse.cryfs") {
+        return Some(format!("{} filesystem", fs_type));
+    }
+    let dm = fs::canonicalize(device).ok()?;
+    let uuid = fs::read_to_string(Path::new("/sys/block").join(dm.file_name()?).join("dm/uuid")).ok()?;
+    let kind = uuid.strip_prefix("CRYPT-")?.split('-').next().unwrap_or_default();
+    Some(format!("dm-crypt {} on {}", kind, device))
+}
+
+// Objects in the repository: stored data files, pack files and metadata
+// files. Hardlinks to unchanged files share one inode and count once.
+#[derive(Default)]
+struct Objects {
+    data_files: u64,
+    packs: u64,
+    metadata_files: u64,
+    bytes: u64,
+}
+
+fn count_objects(backup_dir: &Path) -> io::Result<Objects> {
+    let mut objects = Objects::default();
+    let mut inodes = HashSet::new();
+    for entry in WalkDir::new(backup_dir) {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry.metadata().map_err(io::Error::from)?;
+        if !inodes.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        objects.bytes += metadata.len();
+        let rel = entry.path().strip_prefix(backup_dir).unwrap_or(entry.path());
+        let hidden = |name: &std::ffi::OsStr| name.to_string_lossy().starts_with('.');
+        if rel.iter().any(|name| name == PACK_DIR) {
+            objects.packs += 1;
+        } else if rel.components().count() > 1 && !rel.iter().any(hidden) {
+            objects.data_files += 1;
+        } else {
+            objects.metadata_files += 1;
+        }
+    }
+    Ok(objects)
+}
+
+// Everything about a repository that matters when auditing it against
+// requirements: format, encryption, hashing, compression, size and locks
+pub fn info(backup_dir: &Path) -> io::Result<()> {
+    if !backup_dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Repository not found: {:?}", backup_dir)));
+    }
+    println!("Repository:   {}", backup_dir.display());
+    match read_format(backup_dir)? {
+        Some(info) => println!(
+            "Format:       {} (written by nas-backup-utils {}; this is {}, supporting up to {})",
+            info.format, info.written_by, BINARY_VERSION, FORMAT_VERSION
+        ),
+        None => println!("Format:       unversioned (predates format {}; run `repo upgrade`)", FORMAT_VERSION),
+    }
+
+    // Data is stored as is; encryption at rest is the volume's job
+    match volume_encryption(backup_dir) {
+        Some(volume) => println!("Encryption:   at rest by the volume ({}); data is not encrypted by this tool", volume),
+        None => println!("Encryption:   none detected; data is stored unencrypted on an unencrypted volume"),
+    }
+    let tree = if HASH_PARALLEL_MIN_SIZE != 0 {
+        format!(" (per segment from {}, combined)", format_size(HASH_PARALLEL_MIN_SIZE))
+    } else {
+        String::new()
+    };
+    println!("Hashing:      xxh3-64 per file{}, manifest root hashes chained (integrity, not cryptographic)", tree);
+    let seekable = match SEEKABLE_FILES_ABOVE {
+        Some(above) => format!("zstd level {} for files above {}", SEEKABLE_ZSTD_LEVEL, format_size(above)),
+        None => "off".to_string(),
+    };
+    let packs = match PACK_FILES_BELOW {
+        Some(below) => format!("files below {} in packs of {}", format_size(below), format_size(PACK_SIZE)),
+        None => "off".to_string(),
+    };
+    println!("Compression:  seekable {}; metadata zipped; packing {}", seekable, packs);
+
+    let checkpoints = list_checkpoints(backup_dir)?;
+    let mut partial = 0;
+    let mut locks = Vec::new();
+    let today = clock::local_now().date_naive();
+    for checkpoint in &checkpoints {
+        let info = CheckpointInfo::load(checkpoint)?;
+        partial += info.partial as u64;
+        if let Some(until) = info.lock_date()?.filter(|until| *until >= today) {
+            locks.push((checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string(), until));
+        }
+    }
+    let objects = count_objects(backup_dir)?;
+    println!("Total size:   {}", format_size(objects.bytes));
+    println!(
+        "Objects:      {} checkpoints ({} partial), {} data files, {} packs, {} metadata files",
+        format_count(checkpoints.len() as u64),
+        format_count(partial),
+        format_count(objects.data_files),
+        format_count(objects.packs),
+        format_count(objects.metadata_files)
+    );
+    if locks.is_empty() {
+        println!("Retention:    no checkpoint is locked");
+    } else {
+        println!("Retention:    {} checkpoints locked", locks.len());
+        for (name, until) in locks {
+            println!("  {} until {}", name, until);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::checkpoint::{checkpoints_up_to, list_checkpoints, read_chain_entries, write_atomic};
+use crate::config::HISTORY_FILE_NAME;
+use crate::history::{RunReport, RUN_REPORT_NAME};
+use crate::human::{format_count, format_duration, format_size};
+use crate::manifest::{dir_summary, has_manifest, read_entries, ManifestEntry};
+
+// HTML version of a backup's run report, written next to the JSON one and
+// handed to notification plugins, which can attach it to a mail. It only
+// uses tables and inline styles, which mail clients render.
+pub const HTML_REPORT_NAME: &str = ".run-report.html";
+
+// Checkpoints shown in the growth chart, runs in the history table and files
+// in the list of the biggest changes
+const GROWTH_CHECKPOINTS: usize = 12;
+const HISTORY_RUNS: usize = 10;
+const TOP_CHANGED: usize = 20;
+
+const STYLE: &str = "font-family:sans-serif;font-size:14px;color:#222";
+const TABLE: &str = "border-collapse:collapse;margin-bottom:16px";
+const CELL: &str = "padding:3px 10px;border-bottom:1px solid #ddd;text-align:left";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn row(html: &mut String, cells: &[String]) {
+    html.push_str("<tr>");
+    for cell in cells {
+        let _ = write!(html, "<td style=\"{}\">{}</td>", CELL, cell);
+    }
+    html.push_str("</tr>\n");
+}
+
+fn table(html: &mut String, title: &str, header: &[&str], rows: &[Vec<String>]) {
+    let _ = writeln!(html, "<h3>{}</h3>\n<table style=\"{}\">", escape(title), TABLE);
+    if !header.is_empty() {
+        html.push_str("<tr>");
+        for name in header {
+            let _ = write!(html, "<th style=\"{}\">{}</th>", CELL, name);
+        }
+        html.push_str("</tr>\n");
+    }
+    for cells in rows {
+        row(html, cells);
+    }
+    html.push_str("</table>\n");
+}
+
+fn name_of(checkpoint: &Path) -> String {
+    checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+fn summary(report: &RunReport, checkpoint: &str) -> Vec<Vec<String>> {
+    let status = match (&report.error, report.success) {
+        (_, true) => "<b style=\"color:#2a7\">succeeded</b>".to_string(),
+        (Some(error), false) => format!("<b style=\"color:#c33\">failed</b>: {}", escape(error)),
+        (None, false) => "<b style=\"color:#c33\">failed</b>".to_string(),
+    };
+    let stages = &report.stages;
+    [
+        ("Checkpoint", escape(checkpoint)),
+        ("Status", status),
+        ("Run", escape(&report.run_id)),
+        ("Started", escape(&report.started_at)),
+        ("Duration", format_duration(Duration::from_millis(report.duration_ms))),
+        ("Files", format_count(report.files)),
+        ("Data", format_size(report.bytes)),
+        (
+            "Stages",
+            format!(
+                "read {}, hash {}, compress {}, write {}",
+                format_duration(Duration::from_millis(stages.read_ms)),
+                format_duration(Duration::from_millis(stages.hash_ms)),
+                format_duration(Duration::from_millis(stages.compress_ms)),
+                format_duration(Duration::from_millis(stages.write_ms))
+            ),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| vec![name.to_string(), value])
+    .collect()
+}
+
+// Size of each of the last checkpoints up to `checkpoint`, drawn as bars
+fn growth(html: &mut String, chain: &[PathBuf]) -> io::Result<()> {
+    let mut sizes = Vec::new();
+    for checkpoint in chain.iter().rev().take(GROWTH_CHECKPOINTS).rev().filter(|c| has_manifest(c)) {
+        let totals = dir_summary(checkpoint)?.get(Path::new("")).copied().unwrap_or_default();
+        sizes.push((name_of(checkpoint), totals));
+    }
+    let largest = sizes.iter().map(|(_, totals)| totals.bytes).max().unwrap_or(0).max(1);
+    let rows: Vec<Vec<String>> = sizes
+        .iter()
+        .map(|(name, totals)| {
+            let width = (totals.bytes * 300 / largest).max(1);
+            vec![
+                escape(name),
+                format_size(totals.bytes),
+                format_count(totals.files),
+                format!("<div style=\"background:#48c;height:12px;width:{}px\"></div>", width),
+            ]
+        })
+        .collect();
+    table(html, "Growth", &["Checkpoint", "Size", "Files", ""], &rows);
+    Ok(())
+}
+
+// Files skipped by this run and runs that failed recently
+fn errors(html: &mut String, backup_dir: &Path, report: &RunReport) {
+    let mut rows = Vec::new();
+    if report.vanished > 0 {
+        rows.push(vec!["this run".to_string(), format!("{} files vanished before they were read", report.vanished)]);
+    }
+    if report.unreadable > 0 {
+        rows.push(vec!["this run".to_string(), format!("{} files could not be read", report.unreadable)]);
+    }
+    for path in &report.timed_out {
+        rows.push(vec!["this run".to_string(), format!("Timed out reading {}", escape(path))]);
+    }
+    let history = fs::read_to_string(backup_dir.join(HISTORY_FILE_NAME)).unwrap_or_default();
+    let recent: Vec<RunReport> = history.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+    for run in recent.iter().rev().take(HISTORY_RUNS).filter(|run| !run.success && run.run_id != report.run_id) {
+        let error = run.error.as_deref().unwrap_or("failed");
+        rows.push(vec![escape(&run.started_at), format!("{} failed: {}", escape(&run.operation), escape(error))]);
+    }
+    if rows.is_empty() {
+        rows.push(vec!["-".to_string(), "No errors".to_string()]);
+    }
+    table(html, "Errors", &["When", "What"], &rows);
+}
+
+// The biggest files that are new or changed since the checkpoint before
+fn top_changed(html: &mut String, checkpoint: &Path, previous: Option<&PathBuf>) -> io::Result<()> {
+    let mut before = HashMap::new();
+    if let Some(previous) = previous.filter(|previous| has_manifest(previous)) {
+        for entry in read_entries(previous) {
+            let entry = entry?;
+            before.insert(entry.path, entry.hash);
+        }
+    }
+    let mut changed: Vec<(ManifestEntry, bool)> = Vec::new();
+    for entry in read_entries(checkpoint) {
+        let entry = entry?;
+        let new = match before.get(&entry.path) {
+            Some(hash) if *hash == entry.hash => continue,
+            Some(_) => false,
+            None => true,
+        };
+        changed.push((entry, new));
+        if changed.len() > TOP_CHANGED * 4 {
+            changed.sort_by_key(|(entry, _)| Reverse(entry.size));
+            changed.truncate(TOP_CHANGED);
+        }
+    }
+    changed.sort_by(|(a, _), (b, _)| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    changed.truncate(TOP_CHANGED);
+    let rows: Vec<Vec<String>> = changed
+        .iter()
+        .map(|(entry, new)| {
+            vec![
+                escape(&entry.path.to_string_lossy()),
+                format_size(entry.size),
+                if *new { "new" } else { "changed" }.to_string(),
+            ]
+        })
+        .collect();
+    table(html, "Biggest changes", &["File", "Size", ""], &rows);
+    Ok(())
+}
+
+// Checkpoints up to `checkpoint`. A backup reports before its checkpoint
+// joins the chain, so then it is put after all the others.
+fn chain_up_to(backup_dir: &Path, checkpoint: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = name_of(checkpoint);
+    if read_chain_entries(backup_dir)?.iter().any(|(entry, _)| *entry == name) {
+        return checkpoints_up_to(backup_dir, checkpoint);
+    }
+    let mut chain = list_checkpoints(backup_dir)?;
+    chain.retain(|c| c.file_name() != checkpoint.file_name());
+    chain.push(checkpoint.to_path_buf());
+    Ok(chain)
+}
+
+// Render the report of the run that produced `checkpoint`
+pub fn render(backup_dir: &Path, checkpoint: &Path, report: &RunReport) -> io::Result<String> {
+    let name = name_of(checkpoint);
+    let chain = chain_up_to(backup_dir, checkpoint)?;
+    let previous = chain.len().checked_sub(2).map(|i| &chain[i]);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Backup report {}</title></head>\n\
+         <body style=\"{}\">\n<h2>Backup report</h2>\n",
+        escape(&name),
+        STYLE
+    );
+    table(&mut html, "Summary", &[], &summary(report, &name));
+    growth(&mut html, &chain)?;
+    errors(&mut html, backup_dir, report);
+    if has_manifest(checkpoint) {
+        top_changed(&mut html, checkpoint, previous)?;
+    }
+    html.push_str("</body></html>\n");
+    Ok(html)
+}
+
+// Write the HTML report into the checkpoint; returns where it went
+pub fn save_html(backup_dir: &Path, checkpoint: &Path, report: &RunReport) -> io::Result<PathBuf> {
+    let path = checkpoint.join(HTML_REPORT_NAME);
+    write_atomic(&path, render(backup_dir, checkpoint, report)?.as_bytes())?;
+    Ok(path)
+}
+
+// Report of an earlier backup, from the run report kept in its checkpoint
+pub fn for_checkpoint(backup_dir: &Path, checkpoint: &Path) -> io::Result<String> {
+    let json = fs::read(checkpoint.join(RUN_REPORT_NAME))
+        .map_err(|e| io::Error::new(e.kind(), format!("No run report in {:?}: {}", checkpoint, e)))?;
+    let report: RunReport = serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    render(backup_dir, checkpoint, &report)
+}
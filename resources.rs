@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// Resource usage of this process so far. One process serves one run, so
+// sampling at the end of a run gives that run's totals.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ResourceUsage {
+    pub cpu_user_ms: u64,
+    pub cpu_system_ms: u64,
+    pub peak_rss_kb: u64,
+    // Bytes actually fetched from / sent to storage (Linux /proc/self/io);
+    // None where the kernel doesn't expose them
+    pub read_bytes: Option<u64>,
+    pub write_bytes: Option<u64>,
+}
+
+fn timeval_ms(tv: libc::timeval) -> u64 {
+    tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000
+}
+
+// Counters from /proc/self/io, e.g. "read_bytes: 4096"
+fn proc_io() -> (Option<u64>, Option<u64>) {
+    let Ok(contents) = fs::read_to_string("/proc/self/io") else {
+        return (None, None);
+    };
+    let field = |name: &str| {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.trim().parse().ok())
+    };
+    (field("read_bytes"), field("write_bytes"))
+}
+
+pub fn current() -> ResourceUsage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // getrusage only fails for an invalid `who`, leaving the zeroed defaults
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let (read_bytes, write_bytes) = proc_io();
+    ResourceUsage {
+        cpu_user_ms: timeval_ms(usage.ru_utime),
+        cpu_system_ms: timeval_ms(usage.ru_stime),
+        // Linux reports ru_maxrss in kilobytes
+        peak_rss_kb: usage.ru_maxrss as u64,
+        read_bytes,
+        write_bytes,
+    }
+}
@@ -0,0 +1,131 @@
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::fs::{self, File, FileTimes};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::btime::restore_birth_time;
+use crate::checkpoint::{resolve_checkpoint, resolve_paths, ResolvedFile};
+use crate::priority::RunGuard;
+use crate::settings::Config;
+use crate::status::Progress;
+use crate::verify::{already_restored, restore_stored};
+
+// Give a restored file the permissions and times of the original. Data
+// stored plain keeps the permissions of its source file; packed and seekable
+// data doesn't, so those files get the default ones.
+fn restore_attributes(out: &File, file: &ResolvedFile, rel: &Path) -> io::Result<()> {
+    if file.info.packed.is_none() && file.info.seekable.is_none() {
+        out.set_permissions(fs::metadata(&file.stored_at)?.permissions())?;
+    }
+    out.set_modified(SystemTime::from(file.info.time_stamp))?;
+    if let Some(birth_time) = file.info.birth_time {
+        if !restore_birth_time(out, birth_time)? {
+            debug!("Creation time of {:?} cannot be set on this system", rel);
+        }
+    }
+    Ok(())
+}
+
+// `path` with symlinks resolved as far as it exists
+fn resolved(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    for ancestor in path.ancestors() {
+        if let Ok(real) = fs::canonicalize(ancestor) {
+            return Ok(real.join(path.strip_prefix(ancestor).unwrap_or(Path::new(""))));
+        }
+    }
+    Ok(path)
+}
+
+// Restore a checkpoint into `out`, e.g. back over the source after a loss.
+// Each file's data comes from the checkpoint that stored it (see
+// resolve_paths), so an incremental checkpoint comes back as the full tree
+// it recorded, and `paths` restores subtrees without reading the rest.
+// Unlike an export, `out` may hold other files, which are left alone, as
+// are files already restored. A file that differs from the checkpoint is
+// only overwritten with `force`.
+pub fn restore_tree(
+    config: &Config,
+    checkpoint_name: &str,
+    paths: &[String],
+    out: &Path,
+    force: bool,
+    run: &RunGuard,
+    progress: &Progress,
+) -> io::Result<()> {
+    let backup_dir = config.backup_dir();
+    let checkpoint = resolve_checkpoint(backup_dir, checkpoint_name)?;
+    if resolved(out)?.starts_with(fs::canonicalize(backup_dir)?) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is inside backup directory {:?}; refusing to restore into the repository", out, backup_dir),
+        ));
+    }
+    fs::create_dir_all(out)?;
+    let files = resolve_paths(backup_dir, &checkpoint, paths)?;
+    let selected: Vec<_> = files.iter().collect();
+    if selected.is_empty() {
+        warn!("No files in {:?} matched {:?}", checkpoint, paths);
+    }
+
+    // Check what is there before anything is written, so a restore over the
+    // wrong directory stops without having changed it
+    let mut current: HashSet<&PathBuf> = HashSet::new();
+    let mut differing = Vec::new();
+    for (rel, file) in &selected {
+        let out_path = out.join(rel);
+        if already_restored(&out_path, file.info.size, &file.info.hash) {
+            current.insert(*rel);
+        } else if out_path.symlink_metadata().is_ok() {
+            differing.push(*rel);
+        }
+    }
+    if let Some(first) = differing.first().filter(|_| !force) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} files in {:?} differ from the checkpoint, e.g. {:?}; use --force to overwrite them",
+                differing.len(),
+                out,
+                first
+            ),
+        ));
+    }
+
+    for (_, file) in &selected {
+        progress.add_total(file.info.size);
+    }
+    progress.scan_complete();
+    let mut mismatched = 0;
+    for (rel, file) in &selected {
+        run.pause_if_needed();
+        progress.begin_file(rel);
+        let out_path = out.join(rel);
+        if current.contains(rel) {
+            // The data is right, so only its time may be off
+            let times = FileTimes::new().set_modified(SystemTime::from(file.info.time_stamp));
+            File::open(&out_path)?.set_times(times)?;
+        } else if !restore_stored(config, file, &out_path, run, |f| restore_attributes(f, file, rel))? {
+            mismatched += 1;
+        }
+        progress.finish_file(file.info.size);
+    }
+
+    info!(
+        "Restored {} files from {:?} into {:?} ({} already there, {} overwritten)",
+        selected.len() - current.len(),
+        checkpoint,
+        out,
+        current.len(),
+        differing.len()
+    );
+    if mismatched > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} files failed verification (RESTORE_VERIFY is {:?})", mismatched, config.restore_verify),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use chrono::{Datelike, Local, NaiveDate};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::{ensure_unlocked, list_checkpoints, read_chain_entries, read_latest, taken_at};
+use crate::clock;
+use crate::delete::delete_checkpoint;
+use crate::priority::live_runs;
+use crate::settings::Config;
+
+// Which checkpoints `prune` keeps; see KEEP_LAST and the others in config.rs,
+// which the settings file can override
+pub struct Policy {
+    pub keep_last: u32,
+    pub keep_daily: u32,
+    pub keep_weekly: u32,
+    pub keep_monthly: u32,
+}
+
+impl Policy {
+    pub fn configured(config: &Config) -> Self {
+        Self {
+            keep_last: config.keep_last,
+            keep_daily: config.keep_daily,
+            keep_weekly: config.keep_weekly,
+            keep_monthly: config.keep_monthly,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keep_last == 0 && self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+    }
+
+    // Why the policy keeps each checkpoint, newest first; the ones without a
+    // reason go. Periods are calendar days, ISO weeks and months in local
+    // time, counted back from the current one.
+    fn reasons(&self, newest_first: &[(PathBuf, Option<NaiveDate>)]) -> Vec<Vec<&'static str>> {
+        let today = clock::now().with_timezone(&Local).date_naive();
+        let monday = |date: NaiveDate| date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64);
+        let month = |date: NaiveDate| date.year() as i64 * 12 + date.month0() as i64;
+        let (mut days, mut weeks, mut months) = (HashSet::new(), HashSet::new(), HashSet::new());
+        let mut reasons = Vec::new();
+        for (i, (_, date)) in newest_first.iter().enumerate() {
+            let mut why = Vec::new();
+            if i < self.keep_last as usize {
+                why.push("last");
+            }
+            if let Some(date) = *date {
+                if (today - date).num_days() < self.keep_daily as i64 && days.insert(date) {
+                    why.push("daily");
+                }
+                if (monday(today) - monday(date)).num_weeks() < self.keep_weekly as i64 && weeks.insert(monday(date)) {
+                    why.push("weekly");
+                }
+                if month(today) - month(date) < self.keep_monthly as i64 && months.insert(month(date)) {
+                    why.push("monthly");
+                }
+            }
+            reasons.push(why);
+        }
+        reasons
+    }
+}
+
+fn checkpoint_name(checkpoint: &Path) -> String {
+    checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Delete the checkpoints the retention policy doesn't keep, oldest first.
+// Each goes the way of `delete --rehome`: stored data a later checkpoint
+// still resolves files to moves into the next checkpoint, so no file of a
+// remaining checkpoint loses its data. Checkpoints whose time is unknown
+// (renamed, without run report), the latest one and retention-locked ones
+// are always kept. Only checkpoints in the chain are considered: a directory
+// outside it may be one a run has claimed and is still writing. Refuses to
+// run while any run is registered. Returns the number of checkpoints deleted.
+pub fn prune_checkpoints(backup_dir: &Path, policy: &Policy, dry_run: bool) -> io::Result<usize> {
+    if policy.is_empty() {
+        info!("No retention policy configured");
+        return Ok(0);
+    }
+    let running = live_runs(backup_dir);
+    if !running.is_empty() {
+        return Err(io::Error::other(format!(
+            "A run is in progress ({}); prune once it has finished",
+            running.join(", ")
+        )));
+    }
+    let latest = read_latest(backup_dir);
+    let chained: HashSet<String> = read_chain_entries(backup_dir)?.into_iter().map(|(name, _)| name).collect();
+    let newest_first: Vec<(PathBuf, Option<NaiveDate>)> = list_checkpoints(backup_dir)?
+        .into_iter()
+        .rev()
+        .filter(|checkpoint| chained.contains(&checkpoint_name(checkpoint)))
+        .map(|checkpoint| {
+            let date = taken_at(&checkpoint).map(|time| time.with_timezone(&Local).date_naive());
+            (checkpoint, date)
+        })
+        .collect();
+
+    let mut doomed = Vec::new();
+    for (i, ((checkpoint, date), why)) in newest_first.iter().zip(policy.reasons(&newest_first)).enumerate() {
+        let name = checkpoint_name(checkpoint);
+        if !why.is_empty() {
+            info!("Keeping {} ({})", name, why.join(", "));
+        } else if i == 0 || latest.as_deref() == Some(name.as_str()) {
+            info!("Keeping {} (latest)", name);
+        } else if date.is_none() {
+            warn!("Keeping {}: can't tell when it was taken", name);
+        } else if let Err(e) = ensure_unlocked(checkpoint) {
+            info!("Keeping {}: {}", name, e);
+        } else {
+            doomed.push(name);
+        }
+    }
+
+    for name in doomed.iter().rev() {
+        if dry_run {
+            info!("Would delete checkpoint {}", name);
+        } else {
+            delete_checkpoint(backup_dir, name, true)?;
+        }
+    }
+    info!(
+        "{} {} of {} checkpoints per the retention policy",
+        if dry_run { "Would delete" } else { "Deleted" },
+        doomed.len(),
+        newest_first.len()
+    );
+    Ok(doomed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(keep_last: u32, keep_daily: u32, keep_weekly: u32, keep_monthly: u32) -> Policy {
+        Policy { keep_last, keep_daily, keep_weekly, keep_monthly }
+    }
+
+    // Checkpoints taken on `dates`, newest first; None for an unknown date
+    fn taken_on(dates: &[Option<&str>]) -> Vec<(PathBuf, Option<NaiveDate>)> {
+        dates
+            .iter()
+            .enumerate()
+            .map(|(i, date)| (PathBuf::from(i.to_string()), date.map(|d| d.parse().unwrap())))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_of_each_day() {
+        // Thursday, 2026-10-15
+        clock::pinned();
+        let checkpoints = taken_on(&[
+            Some("2026-10-15"),
+            Some("2026-10-15"),
+            Some("2026-10-14"),
+            Some("2026-10-13"),
+            Some("2026-10-12"),
+        ]);
+        let reasons = policy(0, 3, 0, 0).reasons(&checkpoints);
+        assert_eq!(reasons, vec![vec!["daily"], vec![], vec!["daily"], vec!["daily"], vec![]]);
+    }
+
+    #[test]
+    fn counts_weeks_and_months_back_from_the_current_one() {
+        clock::pinned();
+        let checkpoints = taken_on(&[
+            Some("2026-10-12"),
+            Some("2026-10-11"),
+            Some("2026-10-05"),
+            Some("2026-09-30"),
+            Some("2026-08-31"),
+        ]);
+        // Monday of this week, Sunday and Monday of the week before, then the
+        // week before that
+        let weekly = policy(0, 0, 2, 0).reasons(&checkpoints);
+        assert_eq!(weekly, vec![vec!["weekly"], vec!["weekly"], vec![], vec![], vec![]]);
+        let monthly = policy(0, 0, 0, 2).reasons(&checkpoints);
+        assert_eq!(monthly, vec![vec!["monthly"], vec![], vec![], vec!["monthly"], vec![]]);
+    }
+
+    #[test]
+    fn keeps_the_last_ones_whatever_their_date() {
+        clock::pinned();
+        let checkpoints = taken_on(&[None, Some("2020-01-01"), Some("2026-10-15"), None]);
+        let reasons = policy(2, 1, 0, 0).reasons(&checkpoints);
+        assert_eq!(reasons, vec![vec!["last"], vec!["last"], vec!["daily"], vec![]]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+
+use crate::clock;
+
+// Every run has an ID, a UUID in the version 7 layout: it starts with the
+// time in milliseconds, and a counter orders runs started within the same
+// millisecond, so sorting IDs sorts runs. The current ID is on every log
+// line, run report, status file, audit record and plugin call, so the
+// messages of one run can be picked out of a log shared by several profiles
+// running at once. A process starts with one; the daemon takes a new one per
+// scheduled backup.
+struct State {
+    current: Option<String>,
+    // Millisecond and counter of the last ID
+    last: (u64, u16),
+}
+
+static STATE: Mutex<State> = Mutex::new(State { current: None, last: (0, 0) });
+
+pub fn random_bytes() -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)).is_err() {
+        let seed = clock::now().timestamp_nanos_opt().unwrap_or_default() as u64 ^ ((std::process::id() as u64) << 32);
+        bytes = seed.to_be_bytes();
+    }
+    bytes
+}
+
+fn generate(state: &mut State) -> String {
+    let now = clock::now().timestamp_millis().max(0) as u64;
+    // A counter that runs out moves the ID on to the next millisecond
+    let (millis, counter) = match state.last {
+        (last, _) if now > last => (now, 0),
+        (last, counter) if counter < 0x0fff => (last, counter + 1),
+        (last, _) => (last + 1, 0),
+    };
+    state.last = (millis, counter);
+    let random = u64::from_be_bytes(random_bytes());
+    let (high, low) = ((millis << 16) | 0x7000 | counter as u64, (random >> 2) | 0x8000_0000_0000_0000);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+// Begin a new run and return its ID
+pub fn start() -> String {
+    let mut state = STATE.lock().unwrap();
+    let id = generate(&mut state);
+    state.current = Some(id.clone());
+    id
+}
+
+// ID of the run in progress
+pub fn current() -> String {
+    let mut state = STATE.lock().unwrap();
+    if let Some(id) = &state.current {
+        return id.clone();
+    }
+    let id = generate(&mut state);
+    state.current = Some(id.clone());
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_sort_in_order_past_the_counter() {
+        clock::pinned();
+        let mut state = State { current: None, last: (0, 0) };
+        let ids: Vec<String> = (0..10_000).map(|_| generate(&mut state)).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,153 @@
+use log::warn;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::checkpoint::CheckpointInfo;
+use crate::config::SOURCE_MOUNT_CHECK;
+use crate::error::Error;
+use crate::health::mount_point;
+use crate::manifest::{has_manifest, read_entries};
+
+// Identity of a directory independent of the path used to reach it, so the
+// same directory seen through a bind mount or symlink still compares equal.
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// Whether `inner` is `outer` or lies below it, either by canonical path or by
+// one of its ancestors being the very same directory as `outer`.
+fn contained_in(inner: &Path, outer: &Path) -> Option<PathBuf> {
+    if let Ok(rel) = inner.strip_prefix(outer) {
+        return Some(rel.to_path_buf());
+    }
+    let outer_id = dir_id(outer)?;
+    inner
+        .ancestors()
+        .find(|ancestor| dir_id(ancestor) == Some(outer_id))
+        .and_then(|ancestor| inner.strip_prefix(ancestor).ok())
+        .map(Path::to_path_buf)
+}
+
+fn canonical(path: &Path, what: &str) -> io::Result<PathBuf> {
+    fs::canonicalize(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("Cannot resolve {} {:?}: {}", what, path, e))
+    })
+}
+
+// Refuse configurations where the backup would traverse its own output
+// (backup dir inside the source) or write into the tree it reads from.
+pub fn validate_backup_paths(src: &Path, backup: &Path, ignore_dirs: &[String]) -> io::Result<()> {
+    let src = canonical(src, "source directory")?;
+    let backup = canonical(backup, "backup directory")?;
+
+    if let Some(rel) = contained_in(&backup, &src) {
+        // Fine when the traversal never enters it anyway
+        let ignored = rel.components().any(|c| ignore_dirs.iter().any(|ignore| c.as_os_str() == ignore.as_str()));
+        if ignored {
+            warn!("Backup directory {:?} is inside the source but excluded via IGNORE_DIRS", backup);
+        } else {
+            return Err(Error::Config(format!(
+                "Backup directory {:?} is inside source directory {:?}; refusing to back up into itself",
+                backup, src
+            ))
+            .into());
+        }
+    }
+
+    if contained_in(&src, &backup).is_some() {
+        return Err(Error::Config(format!(
+            "Source directory {:?} is inside backup directory {:?}; refusing to back up the repository into itself",
+            src, backup
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// Check that `src` can be backed up: an existing directory whose entries can
+// be listed. Read-only snapshots and mounts are fine, since nothing is ever
+// written into the source; returns whether it is writable, so callers that
+// would write next to the data can go elsewhere.
+pub fn validate_source(src: &Path) -> io::Result<bool> {
+    let metadata = fs::metadata(src)
+        .map_err(|e| Error::Config(format!("Cannot access source directory {:?}: {}", src, e)))?;
+    if !metadata.is_dir() {
+        return Err(Error::Config(format!("Source {:?} is not a directory", src)).into());
+    }
+    fs::read_dir(src).map_err(|e| Error::Config(format!("Cannot list source directory {:?}: {}", src, e)))?;
+    Ok(writable(src))
+}
+
+// Whether files can be created in `dir`; unlike the permission bits this also
+// sees read-only mounts and snapshots
+#[cfg(unix)]
+fn writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn writable(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|m| !m.permissions().readonly())
+}
+
+// Source of the running backup with its device and inode at the start. A
+// share that unmounts mid-run leaves its empty mount point behind, another
+// directory, and everything below it seems to vanish; the walk asks here
+// before it takes anything for deleted.
+static SOURCE: Mutex<Option<(PathBuf, (u64, u64))>> = Mutex::new(None);
+
+// Start watching `src` for a backup, unless it looks unmounted already (see
+// SOURCE_MOUNT_CHECK). Returns the mount point to record in the checkpoint.
+pub fn watch_source(src: &Path, last_checkpoint: &Path) -> io::Result<Option<String>> {
+    let mount = mount_point(src).map(|mount| mount.to_string_lossy().to_string());
+    if SOURCE_MOUNT_CHECK && last_checkpoint.is_dir() {
+        let missing = |reason: String| Error::SourceLost { path: src.to_path_buf(), reason };
+        let last = CheckpointInfo::load(last_checkpoint)?.source_mount;
+        if let Some(last) = last.filter(|last| mount.as_ref() != Some(last)) {
+            return Err(missing(format!(
+                "it was on mount {} at the last backup and is on {} now; is it mounted? (set SOURCE_MOUNT_CHECK = false after moving it)",
+                last,
+                mount.as_deref().unwrap_or("none")
+            ))
+            .into());
+        }
+        let empty = fs::read_dir(src)?.next().is_none();
+        if empty && has_manifest(last_checkpoint) && read_entries(last_checkpoint).next().is_some() {
+            return Err(missing(format!(
+                "it is empty but {:?} has files; is it mounted? (set SOURCE_MOUNT_CHECK = false if it really was emptied)",
+                last_checkpoint
+            ))
+            .into());
+        }
+    }
+    let metadata = fs::metadata(src)?;
+    *SOURCE.lock().unwrap() = Some((src.to_path_buf(), (metadata.dev(), metadata.ino())));
+    Ok(mount)
+}
+
+// Error if the watched source is gone or another directory now
+pub fn check_source() -> Result<(), Error> {
+    let Some((src, id)) = SOURCE.lock().unwrap().clone() else {
+        return Ok(());
+    };
+    let reason = match fs::metadata(&src) {
+        Ok(metadata) if (metadata.dev(), metadata.ino()) == id => return Ok(()),
+        Ok(_) => "it is no longer the directory the run started in; did its mount go away?".to_string(),
+        Err(e) => e.to_string(),
+    };
+    Err(Error::SourceLost { path: src, reason })
+}
@@ -0,0 +1,93 @@
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::clock;
+use crate::config::RESTORE_KEEP_PREVIOUS;
+use crate::hooks::validate_restore;
+
+// Swap two directories in one step where the kernel supports it, so the
+// destination is never missing or half-restored.
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let to_c = |p: &Path| CString::new(p.as_os_str().as_bytes()).map_err(io::Error::other);
+    let (a_c, b_c) = (to_c(a)?, to_c(b)?);
+    let rc = unsafe {
+        libc::renameat2(libc::AT_FDCWD, a_c.as_ptr(), libc::AT_FDCWD, b_c.as_ptr(), libc::RENAME_EXCHANGE)
+    };
+    if rc == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Filesystem (or kernel) without RENAME_EXCHANGE
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => exchange_by_rename(a, b),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    exchange_by_rename(a, b)
+}
+
+fn exchange_by_rename(a: &Path, b: &Path) -> io::Result<()> {
+    let mut tmp = b.as_os_str().to_owned();
+    tmp.push(".swap");
+    let tmp = PathBuf::from(tmp);
+    fs::rename(b, &tmp)?;
+    fs::rename(a, b)?;
+    fs::rename(&tmp, a)
+}
+
+// Restore into a staging directory next to `dest`, run RESTORE_VALIDATE_HOOK
+// on it and only then swap it in place of `dest` as a whole. The previous
+// contents are kept beside it when RESTORE_KEEP_PREVIOUS is set.
+pub fn restore_sandboxed(dest: &Path, restore: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let dest = std::path::absolute(dest)?;
+    let (Some(parent), Some(name)) = (dest.parent(), dest.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Sandboxed restore needs a named destination directory, got {:?}", dest),
+        ));
+    };
+    fs::create_dir_all(parent)?;
+    // A sibling, so the final swap stays on one filesystem
+    let staging = parent.join(format!(".{}.restore-{}", name.to_string_lossy(), std::process::id()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    if let Err(e) = restore(&staging) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+    if let Err(e) = validate_restore(&staging) {
+        warn!("Restored files left in {:?} for inspection", staging);
+        return Err(e);
+    }
+
+    if !dest.exists() {
+        fs::rename(&staging, &dest)?;
+    } else {
+        exchange(&staging, &dest)?;
+        // `staging` now holds what was at the destination before
+        if RESTORE_KEEP_PREVIOUS {
+            let previous = parent.join(format!(
+                "{}.pre-restore-{}",
+                name.to_string_lossy(),
+                clock::local_now().format("%Y-%m-%d_%H-%M-%S")
+            ));
+            fs::rename(&staging, &previous)?;
+            info!("Previous contents of {:?} kept in {:?}", dest, previous);
+        } else {
+            fs::remove_dir_all(&staging)?;
+        }
+    }
+    info!("Validated restore swapped into {:?}", dest);
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::chaos;
+use crate::config::{HARDLINK_UNCHANGED, SEEKABLE_FILES_ABOVE, SEEKABLE_FRAME_SIZE, SEEKABLE_ZSTD_LEVEL};
+use crate::timeout;
+
+// Large files can be stored compressed in the Zstandard seekable format:
+// independent frames holding SEEKABLE_FRAME_SIZE bytes of data each, followed
+// by the seek table in a skippable frame, so plain `zstd -d` still restores
+// them. The compressed size of every frame is also kept in the manifest and
+// the meta, so reading a range only decompresses the frames it spans.
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+
+// Frame layout of a stored file: the data size of each frame (the last one
+// may hold less) and the compressed size of every frame in order. Written as
+// "<frame size>:<compressed>,<compressed>,...".
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeekIndex {
+    pub frame_size: u64,
+    pub frames: Vec<u32>,
+}
+
+impl fmt::Display for SeekIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frames: Vec<String> = self.frames.iter().map(u32::to_string).collect();
+        write!(f, "{}:{}", self.frame_size, frames.join(","))
+    }
+}
+
+impl SeekIndex {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid seek index: {}", text));
+        let (frame_size, frames) = text.split_once(':').ok_or_else(invalid)?;
+        let frame_size: u64 = frame_size.parse().map_err(|_| invalid())?;
+        if frame_size == 0 {
+            return Err(invalid());
+        }
+        let frames = frames
+            .split(',')
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| frame.parse().map_err(|_| invalid()))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { frame_size, frames })
+    }
+
+    // Size of the stored file: the frames and the seek table after them
+    pub fn stored_size(&self) -> u64 {
+        self.frames.iter().map(|&frame| frame as u64).sum::<u64>() + 8 + self.frames.len() as u64 * 8 + 9
+    }
+}
+
+// Whether a file of `size` bytes is stored compressed. Hard-linked checkpoints
+// are meant to be plain trees, so nothing is compressed there.
+pub fn accepts(size: u64) -> bool {
+    !HARDLINK_UNCHANGED && SEEKABLE_FILES_ABOVE.is_some_and(|above| size > above)
+}
+
+// Fill `buffer` as far as `reader` allows; returns the number of bytes read
+pub(crate) fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// One frame of up to SEEKABLE_FRAME_SIZE bytes of data
+pub fn compress_frame(data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::bulk::compress(data, SEEKABLE_ZSTD_LEVEL)
+}
+
+// Writes compressed frames one after another, then the seek table
+pub struct SeekableWriter {
+    out: BufWriter<File>,
+    // (compressed, decompressed) per frame
+    table: Vec<(u32, u32)>,
+}
+
+impl SeekableWriter {
+    pub fn create(dest: &Path) -> io::Result<Self> {
+        Ok(Self { out: BufWriter::new(File::create(dest)?), table: Vec::new() })
+    }
+
+    // A frame from compress_frame holding `data_len` bytes of data
+    pub fn write_frame(&mut self, frame: &[u8], data_len: usize) -> io::Result<()> {
+        chaos::write_all(&mut self.out, frame, "store")?;
+        self.table.push((frame.len() as u32, data_len as u32));
+        Ok(())
+    }
+
+    // Returns the index and the bytes written
+    pub fn finish(mut self) -> io::Result<(SeekIndex, u64)> {
+        let out = &mut self.out;
+        out.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+        out.write_all(&(self.table.len() as u32 * 8 + 9).to_le_bytes())?;
+        for (compressed, decompressed) in &self.table {
+            out.write_all(&compressed.to_le_bytes())?;
+            out.write_all(&decompressed.to_le_bytes())?;
+        }
+        out.write_all(&(self.table.len() as u32).to_le_bytes())?;
+        // Descriptor: no per-frame checksums
+        out.write_all(&[0])?;
+        out.write_all(&SEEKABLE_MAGIC.to_le_bytes())?;
+        out.flush()?;
+
+        let index = SeekIndex {
+            frame_size: SEEKABLE_FRAME_SIZE,
+            frames: self.table.into_iter().map(|(compressed, _)| compressed).collect(),
+        };
+        let written = index.stored_size();
+        Ok((index, written))
+    }
+}
+
+// Compress `data` into `dest`. Returns the index and the bytes written.
+pub fn compress(data: &Path, dest: &Path) -> io::Result<(SeekIndex, u64)> {
+    let mut source = timeout::open(data)?;
+    let mut writer = SeekableWriter::create(dest)?;
+    let mut buffer = vec![0u8; SEEKABLE_FRAME_SIZE as usize];
+    loop {
+        let read = read_full(&mut source, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_frame(&compress_frame(&buffer[..read])?, read)?;
+    }
+    writer.finish()
+}
+
+// Reader over `length` bytes of data from `offset` on. Decompression starts
+// at the frame holding `offset` and ends with the frame holding the last byte.
+pub fn open_range(path: &Path, index: &SeekIndex, offset: u64, length: u64) -> io::Result<Box<dyn Read>> {
+    let first = (offset / index.frame_size) as usize;
+    if first >= index.frames.len() {
+        return Ok(Box::new(io::empty()));
+    }
+    let last = (offset.saturating_add(length).div_ceil(index.frame_size) as usize).clamp(first + 1, index.frames.len());
+    let start: u64 = index.frames[..first].iter().map(|&frame| frame as u64).sum();
+    let compressed: u64 = index.frames[first..last].iter().map(|&frame| frame as u64).sum();
+
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(BufReader::new(file.take(compressed)))?;
+    io::copy(&mut (&mut decoder).take(offset - first as u64 * index.frame_size), &mut io::sink())?;
+    Ok(Box::new(decoder.take(length)))
+}
+
+// Size of the data in a stored file, from its seek table
+pub fn data_size(path: &Path) -> io::Result<u64> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{:?} has no seek table", path));
+    let mut file = File::open(path)?;
+    let mut footer = [0u8; 9];
+    file.seek(SeekFrom::End(-9)).map_err(|_| invalid())?;
+    file.read_exact(&mut footer)?;
+    if footer[5..] != SEEKABLE_MAGIC.to_le_bytes() {
+        return Err(invalid());
+    }
+    let frames = u32::from_le_bytes(footer[..4].try_into().unwrap()) as usize;
+    let mut table = vec![0u8; frames * 8];
+    file.seek(SeekFrom::End(-9 - table.len() as i64))?;
+    file.read_exact(&mut table)?;
+    Ok(table.chunks(8).map(|entry| u32::from_le_bytes(entry[4..].try_into().unwrap()) as u64).sum())
+}
+
+// Reader over all data of a stored file
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(zstd::stream::read::Decoder::new(File::open(path)?)?))
+}
@@ -0,0 +1,249 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    APPEND_WITHIN, BACKUP_DIR, BACKUP_FALLBACKS, BACKUP_WINDOW, CHECKSUM_DB, CRITICAL_PATHS, EXTRA_LOG_FILE,
+    EXTRA_LOG_LEVEL, IGNORE_DIRS, IGNORE_GROUPS, IGNORE_OWNERS, INCLUDE_ONLY, KEEP_DAILY, KEEP_LAST, KEEP_MONTHLY,
+    KEEP_WEEKLY, LOG_DIR, LOG_LEVEL, MIRROR_DIR, MQTT_BROKER, MQTT_PASSWORD, MQTT_TOPIC, MQTT_USERNAME, PLUGIN_DIR,
+    PRIORITY_PATHS, PUSHGATEWAY_JOB, PUSHGATEWAY_URL, RESTORE_VERIFY, SHARE_BASE_URL, SHARE_LISTEN, SRC_DIR, SYSLOG,
+    SYSLOG_FACILITY, SYSLOG_IDENTIFIER, SYSLOG_LEVEL, TEMP_FILE_MIN_AGE_MINS, TEMP_FILE_PATTERNS, USE_REFLINK,
+    WINDOW_OVERRUN,
+};
+use crate::error::{Error, Result};
+use crate::{syslog, window};
+
+// Settings read at startup from a TOML file, so paths, selection, retention
+// and the services a run reports to can change without rebuilding: the file
+// given with `--config`, else CONFIG_FILE_NAME in the working directory, else
+// SYSTEM_CONFIG. Keys left out keep the value compiled in from config.rs, as
+// does everything when there is no file. The meaning of each key is that of
+// the constant of the same name in config.rs. main() loads them once and
+// hands them down to whatever needs them.
+pub const CONFIG_FILE_NAME: &str = "nas-backup.toml";
+const SYSTEM_CONFIG: &str = "/etc/nas-backup.toml";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub src_dir: PathBuf,
+    pub backup_dir: PathBuf,
+    pub backup_fallbacks: Vec<PathBuf>,
+    pub ignore_dirs: Vec<String>,
+    pub include_only: Vec<String>,
+    pub ignore_owners: Vec<String>,
+    pub ignore_groups: Vec<String>,
+    pub temp_file_patterns: Vec<String>,
+    pub temp_file_min_age_mins: u64,
+    pub critical_paths: Vec<String>,
+    pub priority_paths: Vec<String>,
+    pub backup_window: String,
+    pub append_within: String,
+    pub keep_last: u32,
+    pub keep_daily: u32,
+    pub keep_weekly: u32,
+    pub keep_monthly: u32,
+    pub restore_verify: String,
+    pub mirror_dir: Option<PathBuf>,
+    pub checksum_db: PathBuf,
+    pub use_reflink: bool,
+    pub plugin_dir: PathBuf,
+    pub log_dir: PathBuf,
+    pub log_level: String,
+    pub extra_log_file: PathBuf,
+    pub extra_log_level: String,
+    pub syslog: String,
+    pub syslog_level: String,
+    pub syslog_identifier: String,
+    pub syslog_facility: String,
+    pub mqtt_broker: String,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+    pub mqtt_topic: String,
+    pub pushgateway_url: String,
+    pub pushgateway_job: String,
+    pub share_listen: String,
+    pub share_base_url: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        Self {
+            src_dir: PathBuf::from(SRC_DIR),
+            backup_dir: PathBuf::from(BACKUP_DIR),
+            backup_fallbacks: BACKUP_FALLBACKS.iter().map(PathBuf::from).collect(),
+            ignore_dirs: strings(IGNORE_DIRS),
+            include_only: strings(INCLUDE_ONLY),
+            ignore_owners: strings(IGNORE_OWNERS),
+            ignore_groups: strings(IGNORE_GROUPS),
+            temp_file_patterns: strings(TEMP_FILE_PATTERNS),
+            temp_file_min_age_mins: TEMP_FILE_MIN_AGE_MINS,
+            critical_paths: strings(CRITICAL_PATHS),
+            priority_paths: strings(PRIORITY_PATHS),
+            backup_window: BACKUP_WINDOW.to_string(),
+            append_within: APPEND_WITHIN.to_string(),
+            keep_last: KEEP_LAST,
+            keep_daily: KEEP_DAILY,
+            keep_weekly: KEEP_WEEKLY,
+            keep_monthly: KEEP_MONTHLY,
+            restore_verify: RESTORE_VERIFY.to_string(),
+            mirror_dir: MIRROR_DIR.map(PathBuf::from),
+            checksum_db: PathBuf::from(CHECKSUM_DB),
+            use_reflink: USE_REFLINK,
+            plugin_dir: PathBuf::from(PLUGIN_DIR),
+            log_dir: PathBuf::from(LOG_DIR),
+            log_level: LOG_LEVEL.to_string(),
+            extra_log_file: PathBuf::from(EXTRA_LOG_FILE),
+            extra_log_level: EXTRA_LOG_LEVEL.to_string(),
+            syslog: SYSLOG.to_string(),
+            syslog_level: SYSLOG_LEVEL.to_string(),
+            syslog_identifier: SYSLOG_IDENTIFIER.to_string(),
+            syslog_facility: SYSLOG_FACILITY.to_string(),
+            mqtt_broker: MQTT_BROKER.to_string(),
+            mqtt_username: MQTT_USERNAME.to_string(),
+            mqtt_password: MQTT_PASSWORD.to_string(),
+            mqtt_topic: MQTT_TOPIC.to_string(),
+            pushgateway_url: PUSHGATEWAY_URL.to_string(),
+            pushgateway_job: PUSHGATEWAY_JOB.to_string(),
+            share_listen: SHARE_LISTEN.to_string(),
+            share_base_url: SHARE_BASE_URL.to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn src_dir(&self) -> &Path {
+        &self.src_dir
+    }
+
+    pub fn backup_dir(&self) -> &Path {
+        &self.backup_dir
+    }
+
+    fn parse(text: &str, origin: &Path) -> Result<Self> {
+        toml::from_str(text).map_err(|e| Error::Config(format!("{:?}: {}", origin, e)))
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.src_dir.as_os_str().is_empty() || self.backup_dir.as_os_str().is_empty() {
+            return Err("src_dir and backup_dir must be set".to_string());
+        }
+        // Components compare equal regardless of trailing slashes
+        if self.src_dir.components().eq(self.backup_dir.components()) {
+            return Err("src_dir and backup_dir are the same directory".to_string());
+        }
+        if self.backup_fallbacks.iter().any(|path| path.as_os_str().is_empty()) {
+            return Err("backup_fallbacks has an empty entry".to_string());
+        }
+        let lists = [
+            ("ignore_dirs", &self.ignore_dirs),
+            ("include_only", &self.include_only),
+            ("ignore_owners", &self.ignore_owners),
+            ("ignore_groups", &self.ignore_groups),
+            ("temp_file_patterns", &self.temp_file_patterns),
+            ("critical_paths", &self.critical_paths),
+            ("priority_paths", &self.priority_paths),
+        ];
+        if let Some((key, _)) = lists.iter().find(|(_, values)| values.iter().any(|value| value.trim().is_empty())) {
+            return Err(format!("{} has an empty entry", key));
+        }
+        if let Some(name) = self.ignore_dirs.iter().find(|name| name.contains('/')) {
+            return Err(format!("ignore_dirs takes directory names, not paths: {:?}", name));
+        }
+        if !self.backup_window.is_empty() {
+            window::parse(&self.backup_window, WINDOW_OVERRUN).map_err(|e| e.to_string())?;
+        }
+        let hours = self.append_within.strip_suffix('h').is_some_and(|h| h.parse::<i64>().is_ok());
+        if !matches!(self.append_within.as_str(), "" | "day") && !hours {
+            return Err(format!("append_within is {:?}, expected \"\", \"day\" or e.g. \"6h\"", self.append_within));
+        }
+        if !matches!(self.restore_verify.as_str(), "fail" | "warn" | "skip") {
+            return Err(format!("restore_verify is {:?}, expected \"fail\", \"warn\" or \"skip\"", self.restore_verify));
+        }
+        if self.log_dir.as_os_str().is_empty() {
+            return Err("log_dir must be set".to_string());
+        }
+        let levels = [
+            ("log_level", &self.log_level),
+            ("extra_log_level", &self.extra_log_level),
+            ("syslog_level", &self.syslog_level),
+        ];
+        if let Some((key, value)) = levels.iter().find(|(_, value)| value.parse::<log::LevelFilter>().is_err()) {
+            let expected = "\"error\", \"warn\", \"info\", \"debug\" or \"trace\"";
+            return Err(format!("{} is {:?}, expected {}", key, value, expected));
+        }
+        if !matches!(self.syslog.as_str(), "" | "syslog" | "journald") {
+            return Err(format!("syslog is {:?}, expected \"\", \"syslog\" or \"journald\"", self.syslog));
+        }
+        syslog::facility(&self.syslog_facility).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+// The file settings come from: `explicit` (--config), which has to exist, or
+// the first of the default locations that does
+fn locate(explicit: Option<&Path>) -> Result<Option<PathBuf>> {
+    if let Some(path) = explicit {
+        if !path.is_file() {
+            return Err(Error::Config(format!("Config file {:?} not found", path)));
+        }
+        return Ok(Some(path.to_path_buf()));
+    }
+    Ok([Path::new(CONFIG_FILE_NAME), Path::new(SYSTEM_CONFIG)].into_iter().find(|path| path.is_file()).map(Path::to_path_buf))
+}
+
+// Read the settings for this process; called once at startup. `src_dir` and
+// `backup_dir` (--src, --backup-dir) take precedence over the file.
+pub fn load(explicit: Option<&Path>, src_dir: Option<&Path>, backup_dir: Option<&Path>) -> Result<Config> {
+    let (mut config, origin) = match locate(explicit)? {
+        Some(path) => {
+            let config = Config::parse(&fs::read_to_string(&path)?, &path)?;
+            debug!("Settings from {:?}", path);
+            (config, format!("{:?}", path))
+        }
+        None => (Config::default(), "Settings".to_string()),
+    };
+    if let Some(src_dir) = src_dir {
+        config.src_dir = src_dir.to_path_buf();
+    }
+    if let Some(backup_dir) = backup_dir {
+        config.backup_dir = backup_dir.to_path_buf();
+    }
+    config.validate().map_err(|e| Error::Config(format!("{}: {}", origin, e)))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_settings_read_back() {
+        let config = Config { mirror_dir: Some(PathBuf::from("/mnt/standby")), ..Config::default() };
+        let text = toml::to_string(&config).unwrap();
+        let read = Config::parse(&text, Path::new("test")).unwrap();
+        assert_eq!(read.mirror_dir, config.mirror_dir);
+        assert_eq!(read.backup_dir, config.backup_dir);
+        let without_mirror = toml::to_string(&Config { mirror_dir: None, ..Config::default() }).unwrap();
+        assert_eq!(Config::parse(&without_mirror, Path::new("test")).unwrap().mirror_dir, None);
+    }
+
+    #[test]
+    fn validation_rejects_bad_values() {
+        let valid = || Config { src_dir: "/srv/share".into(), backup_dir: "/mnt/backup".into(), ..Config::default() };
+        assert!(valid().validate().is_ok());
+        assert!(Config { backup_dir: "/srv/share/".into(), ..valid() }.validate().is_err());
+        assert!(Config { append_within: "6h".into(), ..valid() }.validate().is_ok());
+        assert!(Config { append_within: "weekly".into(), ..valid() }.validate().is_err());
+        assert!(Config { restore_verify: "ignore".into(), ..valid() }.validate().is_err());
+        assert!(Config { priority_paths: vec![" ".into()], ..valid() }.validate().is_err());
+        assert!(Config { log_level: "verbose".into(), ..valid() }.validate().is_err());
+        assert!(Config { syslog: "journald".into(), syslog_facility: "local3".into(), ..valid() }.validate().is_ok());
+        assert!(Config { syslog: "rsyslog".into(), ..valid() }.validate().is_err());
+        assert!(Config { syslog_facility: "local8".into(), ..valid() }.validate().is_err());
+        assert!(Config::parse("keep_daily = 7\nshare_listen = \"0.0.0.0:8080\"", Path::new("test")).is_ok());
+        assert!(Config::parse("keep_hourly = 24", Path::new("test")).is_err());
+    }
+}
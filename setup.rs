@@ -0,0 +1,156 @@
+use log::{info, warn};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::write_atomic;
+use crate::health::on_encrypted_volume;
+use crate::safety::validate_backup_paths;
+use crate::settings::Config;
+use crate::window;
+
+fn ask(question: &str, default: Option<&str>) -> io::Result<String> {
+    match default {
+        Some(default) if !default.is_empty() => print!("{} [{}]: ", question, default),
+        _ => print!("{}: ", question),
+    }
+    io::stdout().flush()?;
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Configuration cancelled"));
+    }
+    let input = input.trim();
+    Ok(if input.is_empty() { default.unwrap_or_default().to_string() } else { input.to_string() })
+}
+
+fn ask_yes_no(question: &str, default: bool) -> io::Result<bool> {
+    loop {
+        let answer = ask(&format!("{} (y/n)", question), Some(if default { "y" } else { "n" }))?;
+        match answer.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n."),
+        }
+    }
+}
+
+// Ask until the answer passes `validate`, printing why it didn't
+fn ask_valid<T>(question: &str, default: Option<&str>, validate: impl Fn(&str) -> io::Result<T>) -> io::Result<T> {
+    loop {
+        let answer = ask(question, default)?;
+        match validate(&answer) {
+            Ok(value) => return Ok(value),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+fn existing_dir(path: &str) -> io::Result<PathBuf> {
+    if path.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "A directory is required."));
+    }
+    let path = fs::canonicalize(path)?;
+    if !path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a directory.", path)));
+    }
+    Ok(path)
+}
+
+fn ask_target(src: &Path) -> io::Result<PathBuf> {
+    loop {
+        let answer = ask("Backup target directory", None)?;
+        if answer.is_empty() {
+            println!("A directory is required.");
+            continue;
+        }
+        let target = PathBuf::from(&answer);
+        let created = !target.exists();
+        if created {
+            if !ask_yes_no(&format!("{:?} does not exist. Create it?", target), true)? {
+                continue;
+            }
+            if let Err(e) = fs::create_dir(&target) {
+                println!("Cannot create {:?}: {}", target, e);
+                continue;
+            }
+        }
+        // Exclusions are asked for after the target
+        match validate_backup_paths(src, &target, &[]).and_then(|_| existing_dir(&answer)) {
+            Ok(target) => return Ok(target),
+            Err(e) => {
+                println!("{}", e);
+                if created {
+                    let _ = fs::remove_dir(&target);
+                }
+            }
+        }
+    }
+}
+
+// Interactively ask for source, target, excludes, schedule and encryption and
+// write a settings file; the keys the wizard doesn't ask about keep the
+// compiled-in defaults
+pub fn init_config(out: &Path, force: bool) -> io::Result<()> {
+    if out.exists() && !force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists; use --force to overwrite it", out),
+        ));
+    }
+
+    let src = ask_valid("Source directory to back up", None, existing_dir)?;
+    let target = ask_target(&src)?;
+
+    let excludes: Vec<String> = ask("Directory names to exclude, comma separated", Some(""))?
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let schedule = ask_valid("Daily backup window for `daemon` (HH:MM-HH:MM, empty for none)", Some(""), |spec| {
+        if !spec.is_empty() {
+            window::parse(spec, "complete")?;
+        }
+        Ok(spec.to_string())
+    })?;
+
+    // Data is stored as plain files; encryption has to come from the volume
+    let mut encryption_note = None;
+    if ask_yes_no("Should backups be stored encrypted?", false)? {
+        let note = match on_encrypted_volume(&target) {
+            Some(true) => {
+                info!("{:?} is on an encrypted volume", target);
+                "# The backup target is on an encrypted (dm-crypt) volume"
+            }
+            Some(false) => {
+                warn!(
+                    "{:?} is not on an encrypted volume and backups are stored unencrypted; \
+                     put the target on a LUKS volume or an encrypted filesystem",
+                    target
+                );
+                "# Backups were requested encrypted but the target is not on an encrypted volume"
+            }
+            None => {
+                warn!("Could not tell whether {:?} is encrypted; make sure its filesystem is", target);
+                "# Backups were requested encrypted; the target's filesystem must provide it"
+            }
+        };
+        encryption_note = Some(note);
+    }
+
+    let settings = Config {
+        src_dir: src,
+        backup_dir: target,
+        ignore_dirs: excludes,
+        backup_window: schedule,
+        ..Config::default()
+    };
+    let mut config = toml::to_string(&settings).map_err(io::Error::other)?;
+    if let Some(note) = encryption_note {
+        config = format!("{}\n{}", note, config);
+    }
+
+    write_atomic(out, config.as_bytes())?;
+    info!("Wrote {:?}", out);
+    Ok(())
+}
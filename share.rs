@@ -0,0 +1,310 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::audit;
+use crate::checkpoint::{resolve_checkpoint, resolve_file, write_atomic};
+use crate::clock;
+use crate::runid::random_bytes;
+use crate::settings::Config;
+
+// Temporary download links for single files of a checkpoint. `share` records
+// a random token with the file and an expiry in SHARES_FILE; the daemon (or
+// `share serve`) answers GET and HEAD requests for /s/<token>/<name> over
+// plain HTTP/1.1, one request per connection, with single byte ranges so
+// interrupted downloads resume. Every download is recorded in the audit log.
+const SHARES_FILE: &str = ".shares.json";
+const LINK_PREFIX: &str = "/s/";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Request line and headers together
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Share {
+    token: String,
+    checkpoint: String,
+    path: String,
+    // RFC 3339
+    expires_at: String,
+}
+
+impl Share {
+    fn expired(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.expires_at).map_or(true, |at| at.with_timezone(&Utc) <= clock::now())
+    }
+}
+
+fn load(backup_dir: &Path) -> io::Result<Vec<Share>> {
+    let path = backup_dir.join(SHARES_FILE);
+    match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(backup_dir: &Path, shares: &[Share]) -> io::Result<()> {
+    write_atomic(&backup_dir.join(SHARES_FILE), &serde_json::to_vec_pretty(shares).map_err(io::Error::other)?)
+}
+
+fn link(config: &Config, share: &Share) -> String {
+    let base = if config.share_base_url.is_empty() {
+        format!("http://{}", config.share_listen)
+    } else {
+        config.share_base_url.clone()
+    };
+    let name = Path::new(&share.path).file_name().unwrap_or_default().to_string_lossy();
+    format!("{}{}{}/{}", base.trim_end_matches('/'), LINK_PREFIX, share.token, percent_encode(&name))
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Share `rel` of a checkpoint for `expires`; prints the link
+pub fn create(config: &Config, reference: &str, rel: &str, expires: Duration) -> io::Result<()> {
+    let backup_dir = config.backup_dir();
+    let checkpoint = resolve_checkpoint(backup_dir, reference)?;
+    let rel = rel.trim_matches('/');
+    if resolve_file(backup_dir, &checkpoint, Path::new(rel))?.is_none() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not in checkpoint {:?}", rel, checkpoint)));
+    }
+    let mut shares = load(backup_dir)?;
+    shares.retain(|share| !share.expired());
+    let token: String = [random_bytes(), random_bytes()].concat().iter().map(|b| format!("{:02x}", b)).collect();
+    let expires_at = clock::now() + chrono::Duration::from_std(expires).map_err(io::Error::other)?;
+    let share = Share {
+        token,
+        checkpoint: checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        path: rel.to_string(),
+        expires_at: expires_at.to_rfc3339(),
+    };
+    println!("{}", link(config, &share));
+    info!(
+        "Shared {} of {} until {}",
+        share.path,
+        share.checkpoint,
+        expires_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")
+    );
+    if config.share_listen.is_empty() {
+        warn!("SHARE_LISTEN is not configured, so nothing serves the link yet");
+    }
+    shares.push(share);
+    save(backup_dir, &shares)
+}
+
+pub fn list(config: &Config) -> io::Result<()> {
+    for share in load(config.backup_dir())?.iter().filter(|share| !share.expired()) {
+        println!("{}\t{}\t{}\t{}\t{}", share.token, share.expires_at, share.checkpoint, share.path, link(config, share));
+    }
+    Ok(())
+}
+
+pub fn revoke(backup_dir: &Path, token: &str) -> io::Result<()> {
+    let mut shares = load(backup_dir)?;
+    let before = shares.len();
+    shares.retain(|share| share.token != token);
+    if shares.len() == before {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No share {}", token)));
+    }
+    save(backup_dir, &shares)?;
+    info!("Revoked share {}", token);
+    Ok(())
+}
+
+enum Range {
+    Whole,
+    // Offset and length
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+// A single "bytes=" range; anything else, including several ranges, is
+// answered with the whole file as HTTP allows
+fn parse_range(header: Option<&str>, size: u64) -> Range {
+    let Some((first, last)) = header.and_then(|h| h.trim().strip_prefix("bytes=")).and_then(|r| r.split_once('-')) else {
+        return Range::Whole;
+    };
+    if last.contains(',') {
+        return Range::Whole;
+    }
+    match (first.trim().parse::<u64>().ok(), last.trim().parse::<u64>().ok()) {
+        // The last n bytes
+        (None, Some(n)) if first.trim().is_empty() => match n.min(size) {
+            0 => Range::Unsatisfiable,
+            n => Range::Part(size - n, n),
+        },
+        (Some(start), _) if start >= size => Range::Unsatisfiable,
+        (Some(start), None) if last.trim().is_empty() => Range::Part(start, size - start),
+        (Some(start), Some(end)) if end >= start => Range::Part(start, end.min(size - 1) - start + 1),
+        _ => Range::Whole,
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, String)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())
+}
+
+fn error_page(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    let body = format!("{}\n", status);
+    respond(stream, status, &[("Content-Type", "text/plain".to_string()), ("Content-Length", body.len().to_string())])?;
+    stream.write_all(body.as_bytes())
+}
+
+fn handle(mut stream: TcpStream, backup_dir: &Path) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD_BYTES));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut range_header = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range_header = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "GET" && method != "HEAD" {
+        return error_page(&mut stream, "405 Method Not Allowed");
+    }
+    let token = target.strip_prefix(LINK_PREFIX).and_then(|rest| rest.split('/').next()).unwrap_or("");
+    let shares = load(backup_dir)?;
+    let Some(share) = shares.iter().find(|share| !token.is_empty() && share.token == token) else {
+        return error_page(&mut stream, "404 Not Found");
+    };
+    if share.expired() {
+        return error_page(&mut stream, "410 Gone");
+    }
+    let file = match resolve_checkpoint(backup_dir, &share.checkpoint)
+        .and_then(|checkpoint| resolve_file(backup_dir, &checkpoint, Path::new(&share.path)))
+    {
+        Ok(Some(file)) => file,
+        Ok(None) => return error_page(&mut stream, "404 Not Found"),
+        Err(e) => {
+            warn!("Share {}: {}", share.token, e);
+            return error_page(&mut stream, "500 Internal Server Error");
+        }
+    };
+
+    let size = file.info.size;
+    let (status, offset, length) = match parse_range(range_header.as_deref(), size) {
+        Range::Whole => ("200 OK", 0, size),
+        Range::Part(offset, length) => ("206 Partial Content", offset, length),
+        Range::Unsatisfiable => {
+            respond(&mut stream, "416 Range Not Satisfiable", &[("Content-Range", format!("bytes */{}", size))])?;
+            return Ok(());
+        }
+    };
+    let name = Path::new(&share.path).file_name().unwrap_or_default().to_string_lossy().to_string();
+    let ascii_name: String = name.chars().map(|c| if (c.is_ascii_graphic() && c != '"') || c == ' ' { c } else { '_' }).collect();
+    let mut headers = vec![
+        ("Content-Type", "application/octet-stream".to_string()),
+        ("Content-Length", length.to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+        ("ETag", format!("\"{}\"", file.info.hash)),
+        (
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii_name, percent_encode(&name)),
+        ),
+    ];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", format!("bytes {}-{}/{}", offset, offset + length - 1, size)));
+    }
+    respond(&mut stream, status, &headers)?;
+    if method == "HEAD" {
+        return Ok(());
+    }
+    let result = file.open_range(offset, length).and_then(|mut data| io::copy(&mut data, &mut stream)).map(|_| ());
+    info!("Served {} bytes of {} ({}) to {}", length, share.path, share.checkpoint, peer);
+    audit::record(backup_dir, "share-download", &share.checkpoint, std::slice::from_ref(&share.path), &peer, &result);
+    result
+}
+
+fn accept_loop(listener: TcpListener, backup_dir: PathBuf) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let backup_dir = backup_dir.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, &backup_dir) {
+                        warn!("Share request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Accepting share connection failed: {}", e),
+        }
+    }
+}
+
+fn bind(address: &str) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot listen on {}: {}", address, e)))?;
+    info!("Serving shared files on {}", address);
+    Ok(listener)
+}
+
+// Serve share links in the background, if SHARE_LISTEN is configured
+pub fn start_server(config: &Config) -> io::Result<()> {
+    if config.share_listen.is_empty() {
+        return Ok(());
+    }
+    let listener = bind(&config.share_listen)?;
+    let backup_dir = config.backup_dir().to_path_buf();
+    thread::spawn(move || accept_loop(listener, backup_dir));
+    Ok(())
+}
+
+// Serve share links in the foreground, without the daemon
+pub fn serve(config: &Config) -> io::Result<()> {
+    if config.share_listen.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "SHARE_LISTEN is not configured"));
+    }
+    accept_loop(bind(&config.share_listen)?, config.backup_dir().to_path_buf());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expiring(expires_at: String) -> Share {
+        Share { token: "t".to_string(), checkpoint: "c".to_string(), path: "p".to_string(), expires_at }
+    }
+
+    #[test]
+    fn shares_expire_at_their_time() {
+        let now = clock::pinned();
+        assert!(!expiring((now + chrono::Duration::seconds(1)).to_rfc3339()).expired());
+        assert!(expiring(now.to_rfc3339()).expired());
+        assert!(expiring((now - chrono::Duration::hours(1)).to_rfc3339()).expired());
+        // Another offset, same instant
+        assert!(expiring(now.with_timezone(&chrono::FixedOffset::east_opt(3600).unwrap()).to_rfc3339()).expired());
+        // An expiry that can't be read doesn't keep a link alive
+        assert!(expiring("next week".to_string()).expired());
+    }
+}
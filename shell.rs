@@ -0,0 +1,339 @@
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::audit;
+use crate::browse::{self, Index, Tree, View};
+use crate::checkpoint::{list_checkpoints, resolve_checkpoint, resolve_files, CheckpointInfo, ResolvedFile};
+use crate::config::RESTORE_PRIORITY;
+use crate::human::format_size;
+use crate::priority::{self, RunGuard};
+use crate::settings::Config;
+use crate::verify::restore_stored;
+
+const HELP: &str = "\
+ls checkpoints            list the checkpoints
+ls [path]                 list a directory of a checkpoint
+cd [path]                 change directory; / is the list of checkpoints
+pwd                       print the current directory
+cat <file>                print a file
+restore <path> <dest>     restore a file or directory into dest
+search <pattern>          find files in all checkpoints
+help                      show this help
+exit                      leave the shell";
+
+// Interactive console for exploring a repository. Paths look like
+// /<checkpoint>/<path in the checkpoint>; checkpoints can be given by a
+// unique prefix of their name or as "latest".
+struct Session<'a> {
+    config: &'a Config,
+    backup_dir: PathBuf,
+    // Current checkpoint and directory within it; None at the top
+    cwd: Option<(String, PathBuf)>,
+    // Files of the checkpoint last looked at
+    loaded: Option<(String, BTreeMap<PathBuf, ResolvedFile>)>,
+    // Browsing index, which answers ls and cd without loading the files of
+    // the checkpoints it covers
+    index: Option<Index>,
+}
+
+// A command line split into words; quotes and backslashes work as in a shell
+fn split_words(line: &str) -> io::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None | Some('"'), '\\') => {
+                let escaped = chars.next().unwrap_or('\\');
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unterminated quote"));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+// Restore `files` into `dest` at their path below `parent`
+fn restore_files(
+    config: &Config,
+    files: &[(&PathBuf, &ResolvedFile)],
+    parent: &Path,
+    dest: &Path,
+    run: &RunGuard,
+) -> io::Result<()> {
+    let mut verified = 0;
+    for (file, resolved) in files {
+        let out_path = dest.join(file.strip_prefix(parent).unwrap_or(file));
+        let modified = SystemTime::from(resolved.info.time_stamp);
+        if restore_stored(config, resolved, &out_path, run, |f| f.set_modified(modified))? {
+            verified += 1;
+        }
+    }
+    info!("Restored {} files into {:?}, {} verified", files.len(), dest, verified);
+    Ok(())
+}
+
+impl Session<'_> {
+    // Name of the checkpoint `reference` means: an exact name, "latest" or a
+    // unique prefix
+    fn find_checkpoint(&self, reference: &str) -> io::Result<String> {
+        if let Ok(checkpoint) = resolve_checkpoint(&self.backup_dir, reference) {
+            return Ok(checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string());
+        }
+        let names: Vec<String> = list_checkpoints(&self.backup_dir)?
+            .iter()
+            .filter_map(|checkpoint| checkpoint.file_name().map(|name| name.to_string_lossy().to_string()))
+            .filter(|name| name.starts_with(reference))
+            .collect();
+        match names.as_slice() {
+            [name] => Ok(name.clone()),
+            [] => Err(io::Error::new(io::ErrorKind::NotFound, format!("No checkpoint {}", reference))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} matches {} checkpoints: {}", reference, names.len(), names.join(", ")),
+            )),
+        }
+    }
+
+    // Where `path` points from the current directory: a checkpoint and a path
+    // within it, or None for the top
+    fn resolve(&self, path: &str) -> io::Result<Option<(String, PathBuf)>> {
+        let mut components: Vec<String> = Vec::new();
+        if !path.starts_with('/') {
+            if let Some((name, dir)) = &self.cwd {
+                components.push(name.clone());
+                components.extend(dir.iter().map(|c| c.to_string_lossy().to_string()));
+            }
+        }
+        for component in Path::new(path).components() {
+            match component {
+                Component::ParentDir => {
+                    components.pop();
+                }
+                Component::Normal(name) => components.push(name.to_string_lossy().to_string()),
+                _ => {}
+            }
+        }
+        let Some((reference, rest)) = components.split_first() else {
+            return Ok(None);
+        };
+        Ok(Some((self.find_checkpoint(reference)?, rest.iter().collect())))
+    }
+
+    fn files(&mut self, checkpoint: &str) -> io::Result<&BTreeMap<PathBuf, ResolvedFile>> {
+        if self.loaded.as_ref().is_none_or(|(name, _)| name != checkpoint) {
+            let files = resolve_files(&self.backup_dir, &self.backup_dir.join(checkpoint))?;
+            self.loaded = Some((checkpoint.to_string(), files));
+        }
+        Ok(&self.loaded.as_ref().expect("files were loaded").1)
+    }
+
+    fn index_view(&mut self, checkpoint: &str) -> io::Result<Option<(&Tree, &View)>> {
+        match &mut self.index {
+            Some(index) => index.view(checkpoint),
+            None => Ok(None),
+        }
+    }
+
+    fn is_dir(&mut self, checkpoint: &str, rel: &Path) -> io::Result<bool> {
+        if let Some((tree, view)) = self.index_view(checkpoint)? {
+            return Ok(tree.find(rel, false).is_some_and(|dir| view.contains(dir)));
+        }
+        let files = self.files(checkpoint)?;
+        Ok(rel.as_os_str().is_empty() || files.keys().any(|file| file.starts_with(rel) && file != rel))
+    }
+
+    fn prompt(&self) -> String {
+        match &self.cwd {
+            Some((name, dir)) if dir.as_os_str().is_empty() => format!("/{}> ", name),
+            Some((name, dir)) => format!("/{}/{}> ", name, dir.display()),
+            None => "/> ".to_string(),
+        }
+    }
+
+    fn list_checkpoints(&self) -> io::Result<()> {
+        for checkpoint in list_checkpoints(&self.backup_dir)? {
+            let info = CheckpointInfo::load(&checkpoint)?;
+            let name = checkpoint.file_name().unwrap_or_default().to_string_lossy();
+            let partial = if info.partial { " (partial)" } else { "" };
+            match info.annotation {
+                Some(annotation) => println!("{}/{}\t{}", name, partial, annotation),
+                None => println!("{}/{}", name, partial),
+            }
+        }
+        Ok(())
+    }
+
+    // What ls shows for `rel`: the file itself, or the subdirectories and
+    // files directly below it, with the size of files
+    fn entries(&mut self, checkpoint: &str, rel: &Path) -> io::Result<BTreeMap<String, Option<u64>>> {
+        let mut entries: BTreeMap<String, Option<u64>> = BTreeMap::new();
+        if let Some((tree, view)) = self.index_view(checkpoint)? {
+            if let Some(size) = tree.find(rel, true).and_then(|file| view.size(file)) {
+                entries.insert(rel.display().to_string(), Some(size));
+            } else if let Some(dir) = tree.find(rel, false).filter(|&dir| view.contains(dir)) {
+                for &child in tree.children(dir).iter().filter(|&&child| view.contains(child)) {
+                    let size = view.size(child).filter(|_| tree.is_file(child));
+                    entries.insert(String::from_utf8_lossy(tree.name(child)).to_string(), size);
+                }
+            }
+            return Ok(entries);
+        }
+        let files = self.files(checkpoint)?;
+        if let Some(file) = files.get(rel) {
+            entries.insert(rel.display().to_string(), Some(file.info.size));
+            return Ok(entries);
+        }
+        for (file, resolved) in files.range(rel.to_path_buf()..) {
+            let Ok(inner) = file.strip_prefix(rel) else {
+                break;
+            };
+            let mut components = inner.components();
+            let Some(first) = components.next() else {
+                continue;
+            };
+            let size = components.next().is_none().then_some(resolved.info.size);
+            entries.insert(first.as_os_str().to_string_lossy().to_string(), size);
+        }
+        Ok(entries)
+    }
+
+    fn ls(&mut self, path: &str) -> io::Result<()> {
+        let Some((checkpoint, rel)) = self.resolve(path)? else {
+            return self.list_checkpoints();
+        };
+        let entries = self.entries(&checkpoint, &rel)?;
+        if entries.is_empty() && !rel.as_os_str().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such file or directory: {}", path)));
+        }
+        for (name, size) in entries {
+            match size {
+                Some(size) => println!("{:>10}  {}", format_size(size), name),
+                None => println!("{:>10}  {}/", "", name),
+            }
+        }
+        Ok(())
+    }
+
+    fn cd(&mut self, path: &str) -> io::Result<()> {
+        let target = self.resolve(path)?;
+        if let Some((checkpoint, rel)) = &target {
+            if !self.is_dir(checkpoint, rel)? {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("Not a directory: {}", path)));
+            }
+        }
+        self.cwd = target;
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str) -> io::Result<(String, PathBuf, &ResolvedFile)> {
+        let (checkpoint, rel) = self
+            .resolve(path)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file: /"))?;
+        let file = self
+            .files(&checkpoint)?
+            .get(&rel)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No such file: {}", path)))?;
+        Ok((checkpoint, rel, file))
+    }
+
+    fn cat(&mut self, path: &str) -> io::Result<()> {
+        let backup_dir = self.backup_dir.clone();
+        let (checkpoint, rel, file) = self.file(path)?;
+        let mut stdout = io::stdout().lock();
+        let result = file.open().and_then(|mut data| io::copy(&mut data, &mut stdout)).and_then(|_| stdout.flush());
+        let paths = [rel.to_string_lossy().to_string()];
+        audit::record(&backup_dir, "cat", &checkpoint, &paths, "-", &result);
+        result
+    }
+
+    // Restore a file or everything below a directory into `dest`, keeping the
+    // name of what was selected
+    fn restore(&mut self, path: &str, dest: &Path) -> io::Result<()> {
+        let (checkpoint, rel) = self
+            .resolve(path)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Select a checkpoint to restore from"))?;
+        let parent = rel.parent().unwrap_or(Path::new("")).to_path_buf();
+        let (config, backup_dir) = (self.config, self.backup_dir.clone());
+        let files = self.files(&checkpoint)?;
+        let selected: Vec<(&PathBuf, &ResolvedFile)> =
+            files.iter().filter(|(file, _)| file.starts_with(&rel)).collect();
+        let result = if selected.is_empty() {
+            Err(io::Error::new(io::ErrorKind::NotFound, format!("No such file or directory: {}", path)))
+        } else {
+            priority::register(&backup_dir, RESTORE_PRIORITY)
+                .and_then(|run| restore_files(config, &selected, &parent, dest, &run))
+        };
+        let paths = [rel.to_string_lossy().to_string()];
+        audit::record(&backup_dir, "restore", &checkpoint, &paths, &dest.to_string_lossy(), &result);
+        result
+    }
+
+    fn run(&mut self, words: &[String]) -> io::Result<bool> {
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            [] => {}
+            ["exit" | "quit"] => return Ok(false),
+            ["help"] => println!("{}", HELP),
+            ["pwd"] => println!("{}", self.prompt().trim_end_matches("> ")),
+            ["ls", "checkpoints"] => self.list_checkpoints()?,
+            ["ls"] => self.ls(".")?,
+            ["ls", path] => self.ls(path)?,
+            ["cd"] => self.cwd = None,
+            ["cd", path] => self.cd(path)?,
+            ["cat", path] => self.cat(path)?,
+            ["restore", path, dest] => self.restore(path, Path::new(dest))?,
+            ["search", pattern] => match &mut self.index {
+                Some(index) => browse::print_search(index, pattern)?,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "No browsing index; run `index build`")),
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unknown command; try help")),
+        }
+        Ok(true)
+    }
+}
+
+// Read commands from stdin until `exit` or end of input
+pub fn run_shell(config: &Config) -> io::Result<()> {
+    let backup_dir = config.backup_dir();
+    let mut session = Session {
+        config,
+        backup_dir: backup_dir.to_path_buf(),
+        cwd: None,
+        loaded: None,
+        index: Index::open(backup_dir)?,
+    };
+    // Start in the latest checkpoint if there is one
+    if let Ok(name) = session.find_checkpoint("latest") {
+        session.cwd = Some((name, PathBuf::new()));
+    }
+    let stdin = io::stdin();
+    loop {
+        print!("{}", session.prompt());
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        match split_words(&line).and_then(|words| session.run(&words)) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::backup_utils::priority_rank;
+use crate::checkpoint::write_atomic;
+use crate::human::{format_count, format_size};
+use crate::settings::Config;
+
+// A huge first backup can be spread over several runs (`backup
+// --stage-per-run 500G`): each run backs up the next subtrees of the source
+// that fit its budget, and the coverage map records which subtrees are done
+// and in which checkpoint. Subtrees larger than a whole budget are split into
+// their children. Until every subtree is covered the checkpoints are partial,
+// each carrying over what earlier stages stored; after that runs are full.
+const COVERAGE_FILE: &str = ".coverage.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Coverage {
+    // Subtree relative to SRC_DIR -> checkpoint that first stored it
+    pub covered: BTreeMap<PathBuf, String>,
+    pub complete: bool,
+}
+
+impl Coverage {
+    pub fn load(backup_dir: &Path) -> io::Result<Option<Self>> {
+        let path = backup_dir.join(COVERAGE_FILE);
+        match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {:?}: {}", path, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, backup_dir: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        write_atomic(&backup_dir.join(COVERAGE_FILE), &json)
+    }
+
+    fn is_covered(&self, rel: &Path) -> bool {
+        rel.ancestors().any(|ancestor| self.covered.contains_key(ancestor))
+    }
+}
+
+// The subtrees one run of a staged backup handles
+pub struct Stage {
+    pub subtrees: Vec<PathBuf>,
+    pub bytes: u64,
+    // Whether this stage completes the coverage
+    pub last: bool,
+}
+
+fn tree_size(ignore_dirs: &[String], path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in WalkDir::new(path).into_iter().filter_entry(|e| !ignored(ignore_dirs, e.path())) {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() {
+            size += entry.metadata().map_err(io::Error::from)?.len();
+        }
+    }
+    Ok(size)
+}
+
+fn ignored(ignore_dirs: &[String], path: &Path) -> bool {
+    path.file_name().and_then(|name| name.to_str()).is_some_and(|name| ignore_dirs.iter().any(|ignore| ignore == name))
+}
+
+// Pick uncovered entries of `dir` by PRIORITY_PATHS, otherwise in name order,
+// while they fit `remaining`.
+// Returns true once something was left for a later run.
+fn select(
+    config: &Config,
+    dir: &Path,
+    coverage: &Coverage,
+    budget: u64,
+    remaining: &mut u64,
+    stage: &mut Stage,
+) -> io::Result<bool> {
+    let src = config.src_dir();
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<io::Result<_>>()?;
+    let rank = |path: &PathBuf| priority_rank(&config.priority_paths, path.strip_prefix(src).unwrap_or(path));
+    entries.sort_by_cached_key(|path| (rank(path), path.clone()));
+    for path in entries {
+        let rel = path.strip_prefix(src).map_err(io::Error::other)?.to_path_buf();
+        if coverage.is_covered(&rel) || ignored(&config.ignore_dirs, &path) {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(&path)?;
+        let size = if metadata.is_dir() { tree_size(&config.ignore_dirs, &path)? } else { metadata.len() };
+        if size <= *remaining {
+            *remaining -= size;
+            stage.bytes += size;
+            stage.subtrees.push(rel);
+            continue;
+        }
+        // Too big for any one run, so it is covered child by child
+        if metadata.is_dir() && size > budget {
+            if select(config, &path, coverage, budget, remaining, stage)? {
+                return Ok(true);
+            }
+            continue;
+        }
+        // A single file over the budget still has to go some time
+        if stage.subtrees.is_empty() {
+            stage.bytes += size;
+            stage.subtrees.push(rel);
+            *remaining = 0;
+            continue;
+        }
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+// The next stage of a staged backup of SRC_DIR, or None when no staging is
+// needed: the coverage is complete, or the repository already had a full
+// backup before staging began.
+pub fn next_stage(config: &Config, backup_dir: &Path, budget: u64, first_backup: bool) -> io::Result<Option<Stage>> {
+    let mut coverage = match Coverage::load(backup_dir)? {
+        Some(coverage) if coverage.complete => return Ok(None),
+        Some(coverage) => coverage,
+        None if first_backup => Coverage::default(),
+        None => {
+            info!("Repository already has a backup, --stage-per-run only applies to the first one");
+            return Ok(None);
+        }
+    };
+    let mut stage = Stage { subtrees: Vec::new(), bytes: 0, last: false };
+    let mut remaining = budget;
+    stage.last = !select(config, config.src_dir(), &coverage, budget, &mut remaining, &mut stage)?;
+    if stage.subtrees.is_empty() {
+        // Everything was covered by earlier stages
+        coverage.complete = true;
+        coverage.save(backup_dir)?;
+        return Ok(None);
+    }
+    info!(
+        "Staged backup: {} subtrees ({}) this run, {} covered before{}",
+        format_count(stage.subtrees.len() as u64),
+        format_size(stage.bytes),
+        format_count(coverage.covered.len() as u64),
+        if stage.last { ", completing the coverage" } else { "" }
+    );
+    Ok(Some(stage))
+}
+
+// Record the subtrees of a finished stage as covered by `checkpoint`
+pub fn record_stage(backup_dir: &Path, stage: &Stage, checkpoint: &str) -> io::Result<()> {
+    let mut coverage = Coverage::load(backup_dir)?.unwrap_or_default();
+    for rel in &stage.subtrees {
+        coverage.covered.insert(rel.clone(), checkpoint.to_string());
+    }
+    coverage.complete = stage.last;
+    coverage.save(backup_dir)?;
+    if stage.last {
+        info!("Staged backup complete: all {} subtrees are covered", format_count(coverage.covered.len() as u64));
+    }
+    Ok(())
+}
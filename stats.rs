@@ -0,0 +1,252 @@
+use log::info;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::{checkpoint_ref, list_checkpoints, resolve_checkpoint};
+use crate::human::{format_count, format_size};
+use crate::manifest::{dir_summary, escape_path, has_manifest, read_entries, DirSummary, DirTotals};
+use crate::pack::PACK_DIR;
+
+// Stored data is shared between checkpoints: unchanged files are referenced
+// from the checkpoint that first stored them, so deleting a checkpoint only
+// frees the objects nobody else refers to. An object is a standalone data
+// file or a whole pack, identified by its holding checkpoint.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Object {
+    File(String, PathBuf),
+    Pack(String, String),
+}
+
+impl Object {
+    fn holder(&self) -> &str {
+        match self {
+            Object::File(holder, _) | Object::Pack(holder, _) => holder,
+        }
+    }
+
+    fn stored_size(&self, backup_dir: &Path) -> u64 {
+        let path = match self {
+            Object::File(holder, path) => backup_dir.join(holder).join(path),
+            Object::Pack(holder, pack) => backup_dir.join(holder).join(PACK_DIR).join(pack),
+        };
+        fs::metadata(path).map_or(0, |metadata| metadata.len())
+    }
+}
+
+fn checkpoint_name(checkpoint: &Path) -> String {
+    checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Space the objects of `objects` take
+fn stored_bytes<'a>(backup_dir: &Path, objects: impl Iterator<Item = &'a Object>) -> u64 {
+    objects.map(|object| object.stored_size(backup_dir)).sum()
+}
+
+// Report how much stored data checkpoints share and, for `prune`, how much
+// deleting those checkpoints would free
+pub fn dedup_stats(backup_dir: &Path, prune: &[String]) -> io::Result<()> {
+    let (checkpoints, legacy): (Vec<PathBuf>, Vec<PathBuf>) =
+        list_checkpoints(backup_dir)?.into_iter().partition(|c| has_manifest(c));
+    if !legacy.is_empty() {
+        info!("Skipping {} checkpoints without a manifest; run `migrate` to include them", legacy.len());
+    }
+    let prune: BTreeSet<String> = prune
+        .iter()
+        .map(|name| resolve_checkpoint(backup_dir, name).map(|checkpoint| checkpoint_name(&checkpoint)))
+        .collect::<io::Result<_>>()?;
+
+    // Checkpoints referring to each object, and the logical size of the files
+    let mut referrers: HashMap<Object, HashSet<String>> = HashMap::new();
+    let mut logical = 0;
+    for checkpoint in &checkpoints {
+        let name = checkpoint_name(checkpoint);
+        for entry in read_entries(checkpoint) {
+            let entry = entry?;
+            logical += entry.size;
+            let holder = entry.stored_in.as_deref().map_or(name.clone(), |holder| checkpoint_ref(holder).to_string());
+            let object = match entry.packed {
+                Some(packed) => Object::Pack(holder, packed.pack),
+                None => Object::File(holder, entry.path),
+            };
+            referrers.entry(object).or_default().insert(name.clone());
+        }
+    }
+
+    let stored = stored_bytes(backup_dir, referrers.keys());
+    let shared: Vec<&Object> = referrers.iter().filter(|(_, by)| by.len() > 1).map(|(object, _)| object).collect();
+    println!("Checkpoints:    {}", format_count(checkpoints.len() as u64));
+    println!("Logical size:   {} (every file of every checkpoint)", format_size(logical));
+    println!("Stored size:    {} in {} objects", format_size(stored), format_count(referrers.len() as u64));
+    println!(
+        "Shared:         {} in {} objects referenced by more than one checkpoint",
+        format_size(stored_bytes(backup_dir, shared.iter().copied())),
+        format_count(shared.len() as u64)
+    );
+    if stored > 0 {
+        println!("Dedup ratio:    {:.2}x", logical as f64 / stored as f64);
+    }
+    if prune.is_empty() {
+        return Ok(());
+    }
+
+    // Held by a pruned checkpoint: freed if only pruned checkpoints refer to
+    // it, otherwise it has to move into a kept one (delete --rehome)
+    let (mut freed, mut moved) = (Vec::new(), Vec::new());
+    for (object, by) in &referrers {
+        if !prune.contains(object.holder()) {
+            continue;
+        }
+        if by.iter().all(|name| prune.contains(name)) {
+            freed.push(object);
+        } else {
+            moved.push(object);
+        }
+    }
+    println!();
+    println!("Pruning {}:", prune.iter().cloned().collect::<Vec<_>>().join(", "));
+    println!(
+        "  would free    {} ({} objects)",
+        format_size(stored_bytes(backup_dir, freed.iter().copied())),
+        format_count(freed.len() as u64)
+    );
+    println!(
+        "  would keep    {} ({} objects still used by other checkpoints, moved with --rehome)",
+        format_size(stored_bytes(backup_dir, moved.iter().copied())),
+        format_count(moved.len() as u64)
+    );
+    Ok(())
+}
+
+// Change of a directory since the previous checkpoint
+fn growth(dir: &Path, totals: DirTotals, previous: Option<&DirSummary>) -> String {
+    let Some(previous) = previous else {
+        return String::new();
+    };
+    let before = previous.get(dir).copied().unwrap_or_default();
+    match totals.bytes.cmp(&before.bytes) {
+        _ if before.files == 0 => "new".to_string(),
+        std::cmp::Ordering::Greater => format!("+{}", format_size(totals.bytes - before.bytes)),
+        std::cmp::Ordering::Less => format!("-{}", format_size(before.bytes - totals.bytes)),
+        std::cmp::Ordering::Equal => "=".to_string(),
+    }
+}
+
+fn print_dir(label: &str, dir: &Path, totals: DirTotals, previous: Option<&DirSummary>) {
+    println!(
+        "{:>10}  {:>10} files  {:>10}  {}",
+        format_size(totals.bytes),
+        format_count(totals.files),
+        growth(dir, totals, previous),
+        label
+    );
+}
+
+// Size and file count of `dir` in a checkpoint and of each directory directly
+// below it, biggest first, with how much each grew since the checkpoint
+// before. Read from the directory totals recorded with the manifest.
+pub fn dir_stats(backup_dir: &Path, reference: &str, dir: &Path) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, reference)?;
+    if !has_manifest(&checkpoint) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{:?} has no manifest; run `migrate` first", checkpoint),
+        ));
+    }
+    let summary = dir_summary(&checkpoint)?;
+    let totals = summary
+        .get(dir)
+        .copied()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No directory {:?} in {:?}", dir, checkpoint)))?;
+    let checkpoints = list_checkpoints(backup_dir)?;
+    let before = checkpoints
+        .iter()
+        .position(|c| *c == checkpoint)
+        .and_then(|i| i.checked_sub(1))
+        .map(|i| &checkpoints[i])
+        .filter(|before| has_manifest(before));
+    let previous = before.map(|before| dir_summary(before)).transpose()?;
+    match before {
+        Some(before) => println!("{} (change since {})", checkpoint_name(&checkpoint), checkpoint_name(before)),
+        None => println!("{}", checkpoint_name(&checkpoint)),
+    }
+
+    let label = if dir.as_os_str().is_empty() { "/".to_string() } else { format!("{}/", dir.display()) };
+    print_dir(&label, dir, totals, previous.as_ref());
+    let mut children: Vec<(&PathBuf, &DirTotals)> = summary
+        .range(dir.to_path_buf()..)
+        .skip(1)
+        .take_while(|(child, _)| child.starts_with(dir))
+        .filter(|(child, _)| child.parent() == Some(dir))
+        .collect();
+    children.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+    let mut below = DirTotals::default();
+    for (child, child_totals) in &children {
+        let name = child.file_name().unwrap_or_default().to_string_lossy();
+        print_dir(&format!("  {}/", name), child, **child_totals, previous.as_ref());
+        below.files += child_totals.files;
+        below.bytes += child_totals.bytes;
+    }
+    if !children.is_empty() && totals.files > below.files {
+        let here = DirTotals { files: totals.files - below.files, bytes: totals.bytes - below.bytes };
+        println!("{:>10}  {:>10} files  {:>10}    (files here)", format_size(here.bytes), format_count(here.files), "");
+    }
+    Ok(())
+}
+
+// A CSV field, quoted when it has to be
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// Every file of every checkpoint as one record (path, size, hash, checkpoint,
+// and the checkpoint holding its data) for DuckDB, pandas and the like.
+// Paths are written as in the manifest. Only CSV: Parquet needs a library
+// this tool doesn't carry, and DuckDB converts the CSV in one statement.
+pub fn export_index(backup_dir: &Path, format: &str, out: &str) -> io::Result<()> {
+    match format {
+        "csv" => {}
+        "parquet" => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Parquet output is not built in; export CSV and convert it, e.g. with DuckDB: \
+                 COPY (SELECT * FROM 'index.csv') TO 'index.parquet' (FORMAT parquet)",
+            ))
+        }
+        other => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown format {:?}", other))),
+    }
+    let (checkpoints, legacy): (Vec<PathBuf>, Vec<PathBuf>) =
+        list_checkpoints(backup_dir)?.into_iter().partition(|c| has_manifest(c));
+    if !legacy.is_empty() {
+        info!("Skipping {} checkpoints without a manifest; run `migrate` to include them", legacy.len());
+    }
+    let sink: Box<dyn Write> = if out == "-" { Box::new(io::stdout().lock()) } else { Box::new(File::create(out)?) };
+    let mut writer = BufWriter::new(sink);
+    writeln!(writer, "path,size,hash,checkpoint,stored_in")?;
+    let mut records = 0u64;
+    for checkpoint in &checkpoints {
+        let name = checkpoint_name(checkpoint);
+        for entry in read_entries(checkpoint) {
+            let entry = entry?;
+            let holder = entry.stored_in.as_deref().map_or(name.as_str(), checkpoint_ref);
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                csv_field(&escape_path(&entry.path)),
+                entry.size,
+                entry.hash,
+                csv_field(&name),
+                csv_field(holder)
+            )?;
+            records += 1;
+        }
+    }
+    writer.flush()?;
+    info!("Exported {} records of {} checkpoints", format_count(records), format_count(checkpoints.len() as u64));
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::config::{STATUS_FILE_NAME, STATUS_INTERVAL_SECS};
+use crate::health::DiskHealth;
+use crate::history::{record_run, RunReport};
+use crate::human::{format_count, format_duration, format_rate, format_size};
+use crate::pipeline::Timings;
+use crate::plan::{interrupted_run, remaining};
+use crate::pushgateway;
+use crate::resources;
+use crate::runid;
+use crate::stage::Coverage;
+
+#[derive(Serialize)]
+struct StatusFile<'a> {
+    run_id: String,
+    pid: u32,
+    operation: &'a str,
+    state: &'a str,
+    progress_percent: Option<f64>,
+    current_path: &'a str,
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+    bytes_total: u64,
+    scan_complete: bool,
+    eta_seconds: Option<u64>,
+    updated_at: String,
+}
+
+struct State {
+    current_path: String,
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+    bytes_total: u64,
+    vanished: u64,
+    unreadable: u64,
+    timed_out: Vec<String>,
+    scan_complete: bool,
+    last_write: Option<Instant>,
+}
+
+// Live progress of a long-running operation, periodically written to
+// BACKUP_DIR/STATUS_FILE_NAME so monitoring can follow along without parsing logs.
+pub struct Progress {
+    backup_dir: PathBuf,
+    path: PathBuf,
+    operation: String,
+    started: Instant,
+    started_at: chrono::DateTime<chrono::Local>,
+    disk_health: Mutex<Vec<DiskHealth>>,
+    timings: Timings,
+    state: Mutex<State>,
+}
+
+impl Progress {
+    pub fn start(backup_dir: &Path, operation: &str) -> Self {
+        let progress = Self {
+            backup_dir: backup_dir.to_path_buf(),
+            path: backup_dir.join(STATUS_FILE_NAME),
+            operation: operation.to_string(),
+            started: Instant::now(),
+            started_at: clock::local_now(),
+            disk_health: Mutex::new(Vec::new()),
+            timings: Timings::default(),
+            state: Mutex::new(State {
+                current_path: String::new(),
+                files_done: 0,
+                files_total: 0,
+                bytes_done: 0,
+                bytes_total: 0,
+                vanished: 0,
+                unreadable: 0,
+                timed_out: Vec::new(),
+                scan_complete: false,
+                last_write: None,
+            }),
+        };
+        progress.write("running", true);
+        progress
+    }
+
+    // Register work discovered while scanning
+    pub fn add_total(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.files_total += 1;
+        state.bytes_total += bytes;
+    }
+
+    pub fn scan_complete(&self) {
+        self.state.lock().unwrap().scan_complete = true;
+    }
+
+    pub fn begin_file(&self, path: &Path) {
+        self.state.lock().unwrap().current_path = path.display().to_string();
+        self.write("running", false);
+    }
+
+    pub fn finish_file(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.files_done += 1;
+        state.bytes_done += bytes;
+    }
+
+    // A listed file that was gone by the time it was read
+    pub fn skip_vanished(&self) {
+        self.state.lock().unwrap().vanished += 1;
+    }
+
+    // A file or directory skipped because it couldn't be read
+    pub fn skip_unreadable(&self) {
+        self.state.lock().unwrap().unreadable += 1;
+    }
+
+    // A file skipped because reading it hit FILE_HARD_TIMEOUT_SECS
+    pub fn skip_timed_out(&self, rel: &Path) {
+        self.state.lock().unwrap().timed_out.push(rel.display().to_string());
+    }
+
+    // Where workers add the time spent in each stage of storing a file
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    // Pre-flight disk health snapshot to include in the run report
+    pub fn record_disk_health(&self, health: Vec<DiskHealth>) {
+        *self.disk_health.lock().unwrap() = health;
+    }
+
+    // Log the summary and record the run, including its resource usage, in the history
+
+    pub fn finish<E: std::fmt::Display>(&self, result: &Result<(), E>) -> RunReport {
+        let report = {
+            let mut state = self.state.lock().unwrap();
+            state.current_path.clear();
+            let elapsed = self.started.elapsed();
+            let usage = resources::current();
+            info!(
+                "{} {}: {} files, {} in {} ({}), cpu {} user / {} system, peak RSS {}",
+                self.operation,
+                if result.is_ok() { "finished" } else { "failed" },
+                format_count(state.files_done),
+                format_size(state.bytes_done),
+                format_duration(elapsed),
+                format_rate(state.bytes_done, elapsed),
+                format_duration(Duration::from_millis(usage.cpu_user_ms)),
+                format_duration(Duration::from_millis(usage.cpu_system_ms)),
+                format_size(usage.peak_rss_kb * 1024)
+            );
+            let stages = self.timings.times();
+            if stages.read_ms + stages.hash_ms + stages.compress_ms + stages.write_ms > 0 {
+                info!(
+                    "{} stages: read {}, hash {}, compress {}, write {}",
+                    self.operation,
+                    format_duration(Duration::from_millis(stages.read_ms)),
+                    format_duration(Duration::from_millis(stages.hash_ms)),
+                    format_duration(Duration::from_millis(stages.compress_ms)),
+                    format_duration(Duration::from_millis(stages.write_ms))
+                );
+            }
+            if state.vanished > 0 {
+                warn!("{} files vanished before they could be read", format_count(state.vanished));
+            }
+            if state.unreadable > 0 {
+                warn!("{} files and directories could not be read and were skipped", format_count(state.unreadable));
+            }
+            if !state.timed_out.is_empty() {
+                warn!("{} files timed out and were skipped", format_count(state.timed_out.len() as u64));
+            }
+            RunReport {
+                run_id: runid::current(),
+                operation: self.operation.clone(),
+                repository: self.backup_dir.display().to_string(),
+                pid: std::process::id(),
+                started_at: self.started_at.to_rfc3339(),
+                finished_at: clock::local_now().to_rfc3339(),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                files: state.files_done,
+                bytes: state.bytes_done,
+                vanished: state.vanished,
+                unreadable: state.unreadable,
+                timed_out: std::mem::take(&mut state.timed_out),
+                duration_ms: elapsed.as_millis() as u64,
+                resources: usage,
+                disk_health: self.disk_health.lock().unwrap().clone(),
+                stages,
+            }
+        };
+        self.write(if result.is_ok() { "finished" } else { "failed" }, true);
+        record_run(&self.backup_dir, &report);
+        pushgateway::record(&report);
+        report
+    }
+
+    fn write(&self, run_state: &str, force: bool) {
+        let mut state = self.state.lock().unwrap();
+        let due = state
+            .last_write
+            .is_none_or(|at| at.elapsed() >= Duration::from_secs(STATUS_INTERVAL_SECS));
+        if !force && !due {
+            return;
+        }
+        state.last_write = Some(Instant::now());
+
+        // Percentages and ETA are only meaningful once the full amount of work is known
+        let progress_percent = (state.scan_complete && state.bytes_total > 0)
+            .then(|| state.bytes_done as f64 * 100.0 / state.bytes_total as f64);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let eta_seconds = (state.scan_complete && state.bytes_done > 0).then(|| {
+            let rate = state.bytes_done as f64 / elapsed;
+            (state.bytes_total.saturating_sub(state.bytes_done) as f64 / rate) as u64
+        });
+
+        let status = StatusFile {
+            run_id: runid::current(),
+            pid: std::process::id(),
+            operation: &self.operation,
+            state: run_state,
+            progress_percent,
+            current_path: &state.current_path,
+            files_done: state.files_done,
+            files_total: state.files_total,
+            bytes_done: state.bytes_done,
+            bytes_total: state.bytes_total,
+            scan_complete: state.scan_complete,
+            eta_seconds,
+            updated_at: clock::local_now().to_rfc3339(),
+        };
+
+        // Write then rename so readers never see a half-written file
+        let tmp = self.path.with_extension("json.tmp");
+        let result = serde_json::to_vec_pretty(&status)
+            .map_err(io::Error::other)
+            .and_then(|json| fs::write(&tmp, json))
+            .and_then(|_| fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            warn!("Failed to write status file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Print the last status file and, if a dual-phase backup was interrupted,
+// the work its plan has left, as well as how far a staged backup has come
+pub fn show(backup_dir: &Path) -> io::Result<()> {
+    match fs::read(backup_dir.join(STATUS_FILE_NAME)) {
+        Ok(content) => {
+            let status: Value = serde_json::from_slice(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid status file: {}", e)))?;
+            let mut state = status["state"].as_str().unwrap_or("unknown").to_string();
+            let pid = status["pid"].as_u64().unwrap_or_default();
+            if state == "running" && !Path::new(&format!("/proc/{}", pid)).exists() {
+                state = "stopped without finishing".to_string();
+            }
+            println!(
+                "{} {} (pid {}), updated {}",
+                status["operation"].as_str().unwrap_or("unknown"),
+                state,
+                pid,
+                status["updated_at"].as_str().unwrap_or("unknown")
+            );
+            println!(
+                "  {} of {} files, {} of {}{}",
+                format_count(status["files_done"].as_u64().unwrap_or_default()),
+                format_count(status["files_total"].as_u64().unwrap_or_default()),
+                format_size(status["bytes_done"].as_u64().unwrap_or_default()),
+                format_size(status["bytes_total"].as_u64().unwrap_or_default()),
+                if status["scan_complete"].as_bool() == Some(true) { "" } else { " so far" }
+            );
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => println!("No operation has run yet"),
+        Err(e) => return Err(e),
+    }
+
+    if let Some(name) = interrupted_run(backup_dir)? {
+        let left = remaining(&backup_dir.join(&name))?;
+        println!(
+            "Interrupted backup {}: {} files ({}) of {} ({}) still to copy; the next backup resumes it",
+            name,
+            format_count(left.files),
+            format_size(left.bytes),
+            format_count(left.total_files),
+            format_size(left.total_bytes)
+        );
+    }
+    if let Some(coverage) = Coverage::load(backup_dir)?.filter(|coverage| !coverage.complete) {
+        println!(
+            "Staged backup: {} subtrees covered so far; the next staged backups continue it",
+            format_count(coverage.covered.len() as u64)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use log::{Level, Record};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+
+use crate::runid;
+
+// Log sinks for the system's log collection, one datagram per record: the
+// local syslog socket in the traditional "<PRI>timestamp tag[pid]: message"
+// form, or systemd-journald's native protocol, which keeps the run ID, the
+// module and the source location as fields of their own (RUN_ID, TARGET,
+// CODE_FILE, CODE_LINE) for `journalctl RUN_ID=...` and log shippers.
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+pub fn facility(name: &str) -> io::Result<u8> {
+    let facility = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        local => match local.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+            Some(n) if n < 8 => 16 + n,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid syslog facility: {:?}", name),
+                ))
+            }
+        },
+    };
+    Ok(facility)
+}
+
+fn syslog_line(facility: u8, identifier: &str, record: &Record) -> String {
+    format!(
+        "<{}>{} {}[{}]: [{}] {}",
+        facility * 8 + severity(record.level()),
+        chrono::Local::now().format("%b %e %H:%M:%S"),
+        identifier,
+        std::process::id(),
+        runid::current(),
+        record.args()
+    )
+}
+
+// One field of the journal's native protocol; values with line breaks are
+// sent with their length instead of as "NAME=value"
+fn journal_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+fn journal_entry(identifier: &str, record: &Record) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    journal_field(&mut datagram, "MESSAGE", &record.args().to_string());
+    journal_field(&mut datagram, "PRIORITY", &severity(record.level()).to_string());
+    journal_field(&mut datagram, "SYSLOG_IDENTIFIER", identifier);
+    journal_field(&mut datagram, "SYSLOG_PID", &std::process::id().to_string());
+    journal_field(&mut datagram, "RUN_ID", &runid::current());
+    journal_field(&mut datagram, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        journal_field(&mut datagram, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        journal_field(&mut datagram, "CODE_LINE", &line.to_string());
+    }
+    datagram
+}
+
+// fern output for the `kind` of system log ("syslog" or "journald"), with
+// records tagged `identifier` and sent to the facility named `facility_name`
+// (syslog only)
+pub fn output(kind: &str, identifier: &str, facility_name: &str) -> io::Result<fern::Output> {
+    let journal = match kind {
+        "syslog" => false,
+        "journald" => true,
+        other => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid syslog kind: {:?}", other))),
+    };
+    let facility = facility(facility_name)?;
+    let identifier = identifier.to_string();
+    // Sent to the path every time rather than connected once, so a
+    // restarted log daemon keeps receiving
+    let socket = Mutex::new(UnixDatagram::unbound()?);
+    let path = if journal { JOURNAL_SOCKET } else { SYSLOG_SOCKET };
+    Ok(fern::Output::call(move |record| {
+        let datagram = if journal {
+            journal_entry(&identifier, record)
+        } else {
+            syslog_line(facility, &identifier, record).into_bytes()
+        };
+        if let Err(e) = socket.lock().unwrap().send_to(&datagram, path) {
+            eprintln!("Failed to send log record to {}: {}", path, e);
+        }
+    }))
+}
@@ -0,0 +1,213 @@
+use log::{debug, info, warn};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::btime::restore_birth_time;
+use crate::checkpoint::{resolve_checkpoint, resolve_files};
+use crate::config::TAPE_BLOCK_SIZE;
+use crate::manifest::{escape_path, unescape_path};
+use crate::priority::RunGuard;
+use crate::settings::Config;
+use crate::status::Progress;
+use crate::verify::{already_restored, restore_verified};
+
+// PAX record libarchive (bsdtar) uses for creation times
+const CREATION_TIME_KEY: &str = "LIBARCHIVE.creationtime";
+
+// Tracks how many bytes went into the stream, giving each entry's offset
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Write a checkpoint as one sequential tar stream (to a file, a tape device or
+// stdout for mbuffer pipelines) plus an index sidecar of "offset size hash path"
+// lines recording where each entry's header starts.
+pub fn export_stream(
+    backup_dir: &Path,
+    checkpoint_name: &str,
+    out: &str,
+    index: &Path,
+    progress: &Progress,
+) -> io::Result<()> {
+    let checkpoint = resolve_checkpoint(backup_dir, checkpoint_name)?;
+    let files = resolve_files(backup_dir, &checkpoint)?;
+    for file in files.values() {
+        progress.add_total(file.info.size);
+    }
+    progress.scan_complete();
+
+    let sink: Box<dyn Write> = if out == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(out)?)
+    };
+    // Buffer in whole tape blocks so the drive sees fixed-size writes
+    let writer = CountingWriter {
+        inner: BufWriter::with_capacity(TAPE_BLOCK_SIZE, sink),
+        written: 0,
+    };
+    let mut builder = tar::Builder::new(writer);
+    let mut index_lines = String::new();
+
+    for (rel, file) in &files {
+        progress.begin_file(rel);
+        let offset = builder.get_ref().written;
+        if let Some(birth_time) = file.info.birth_time {
+            let birth_time = birth_time.to_string();
+            builder.append_pax_extensions([(CREATION_TIME_KEY, birth_time.as_bytes())])?;
+        }
+        if file.info.packed.is_some() || file.info.seekable.is_some() {
+            // Packed and compressed data have no file of their own to take
+            // mode, mtime and size from
+            let mut header = tar::Header::new_gnu();
+            header.set_size(file.info.size);
+            header.set_mode(0o644);
+            header.set_mtime(file.info.time_stamp.timestamp().max(0) as u64);
+            builder.append_data(&mut header, rel, file.open()?)?;
+        } else {
+            builder.append_file(rel, &mut File::open(&file.stored_at)?)?;
+        }
+        index_lines.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            offset,
+            file.info.size,
+            file.info.hash,
+            escape_path(rel)
+        ));
+        progress.finish_file(file.info.size);
+    }
+
+    let mut writer = builder.into_inner()?;
+    // tar tolerates trailing zeros; pad so the final tape block is complete
+    let block = TAPE_BLOCK_SIZE as u64;
+    let padding = (block - writer.written % block) % block;
+    writer.write_all(&vec![0u8; padding as usize])?;
+    writer.flush()?;
+
+    fs::write(index, index_lines)?;
+    info!(
+        "Streamed {} files ({} bytes) from {:?}, index {:?}",
+        files.len(),
+        writer.written,
+        checkpoint,
+        index
+    );
+    Ok(())
+}
+
+// Creation time recorded for an entry, in whole seconds
+fn creation_time<R: io::Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<i64>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    for extension in extensions {
+        let extension = extension?;
+        if extension.key() == Ok(CREATION_TIME_KEY) {
+            // Fractional seconds are allowed but not kept
+            let value = extension.value().unwrap_or_default();
+            return Ok(value.split('.').next().and_then(|secs| secs.parse().ok()));
+        }
+    }
+    Ok(None)
+}
+
+// Selectively restore entries from a seekable stream by jumping straight to
+// the offsets recorded in its index. Files an earlier, interrupted restore
+// wrote already are kept.
+pub fn restore_stream(
+    config: &Config,
+    stream: &Path,
+    index: &Path,
+    paths: &[String],
+    dest: &Path,
+    run: &RunGuard,
+    progress: &Progress,
+) -> io::Result<()> {
+    let mut selected = Vec::new();
+    for line in BufReader::new(File::open(index)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        let [offset, size, hash, rel] = fields[..] else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid index line: {}", line)));
+        };
+        if !paths.is_empty() && !paths.iter().any(|p| Path::new(rel).starts_with(p.trim_end_matches('/'))) {
+            continue;
+        }
+        let offset: u64 = offset
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid offset"))?;
+        let size: u64 = size.parse().unwrap_or(0);
+        progress.add_total(size);
+        selected.push((offset, size, hash.to_string(), unescape_path(rel)));
+    }
+    progress.scan_complete();
+
+    let mut file = File::open(stream)?;
+    let (mut corrupted, mut kept) = (0, 0);
+    for (offset, size, hash, rel) in &selected {
+        run.pause_if_needed();
+        progress.begin_file(rel);
+        let out_path = dest.join(rel);
+        if already_restored(&out_path, *size, hash) {
+            kept += 1;
+            progress.finish_file(*size);
+            continue;
+        }
+        file.seek(SeekFrom::Start(*offset))?;
+        let mut archive = tar::Archive::new(run.throttled(&mut file));
+        let mut entry = archive
+            .entries()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, format!("No entry at offset {}", offset)))??;
+        if entry.path()? != *rel {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Index points at {:?} but found {:?}", rel, entry.path()?),
+            ));
+        }
+        let mode = entry.header().mode()?;
+        let mtime = entry.header().mtime()?;
+        let birth_time = creation_time(&mut entry)?;
+        let prepare = |file: &File| {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+            if let Some(birth_time) = birth_time {
+                if !restore_birth_time(file, birth_time)? {
+                    debug!("Creation time of {:?} cannot be set on this system", rel);
+                }
+            }
+            Ok(())
+        };
+        if restore_verified(config, &mut entry, &out_path, hash, prepare)? {
+            info!("Restored: {}", out_path.display());
+        } else {
+            corrupted += 1;
+        }
+        progress.finish_file(*size);
+    }
+
+    if corrupted > 0 {
+        warn!("{} files failed verification", corrupted);
+    }
+    if kept > 0 {
+        info!("{} files were already restored", kept);
+    }
+    info!("Restored {} files into '{}'", selected.len(), dest.display());
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use log::warn;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TARGET_PROBE_TIMEOUT_SECS;
+use crate::error::{Error, Result};
+use crate::settings::Config;
+
+// Check that a target is mounted and writable. Runs on its own thread since a
+// dead network share can block filesystem calls indefinitely.
+fn probe(dir: &Path) -> io::Result<()> {
+    let dir = dir.to_path_buf();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = (|| {
+            if !fs::metadata(&dir)?.is_dir() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "not a directory"));
+            }
+            let probe = dir.join(format!(".probe-{}", std::process::id()));
+            fs::write(&probe, b"")?;
+            fs::remove_file(&probe)
+        })();
+        let _ = sender.send(result);
+    });
+    receiver
+        .recv_timeout(Duration::from_secs(TARGET_PROBE_TIMEOUT_SECS))
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "no response")))
+}
+
+// BACKUP_DIR followed by BACKUP_FALLBACKS
+pub fn all_targets(config: &Config) -> impl Iterator<Item = &Path> {
+    std::iter::once(&config.backup_dir).chain(&config.backup_fallbacks).map(Path::new)
+}
+
+// The repository a backup should go to: BACKUP_DIR, or the first reachable of
+// BACKUP_FALLBACKS when it isn't. Each target is a repository of its own.
+pub fn select_backup_dir(config: &Config) -> Result<PathBuf> {
+    for (i, target) in all_targets(config).enumerate() {
+        match probe(target) {
+            Ok(()) if i == 0 => return Ok(target.to_path_buf()),
+            Ok(()) => {
+                warn!("Backing up to fallback target {:?}", target);
+                return Ok(target.to_path_buf());
+            }
+            Err(e) => warn!("Backup target {:?} unreachable: {}", target, e),
+        }
+    }
+    Err(Error::Backend("no backup target is reachable".to_string()))
+}
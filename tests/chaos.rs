@@ -0,0 +1,99 @@
+// Backups run in chaos mode fail, stall and crash at random writes into the
+// repository. Whatever state each one leaves behind, the repository has to
+// pass `check`, and a clean run afterwards has to restore the source as it is.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const RUNS: u64 = 20;
+const CHAOS_RATE: &str = "0.05";
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nas-backup-it-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Run the binary on `src` and `backup_dir` from within `dir`, where its logs
+// go
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nas-backup-utils"))
+        .current_dir(dir)
+        .args(["--src", "src", "--backup-dir", "backup", "-y"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn assert_success(output: &Output, what: &str) {
+    assert!(
+        output.status.success(),
+        "{} failed ({}):\n{}",
+        what,
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// Deterministic file content
+fn content(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+// Change the source a little between runs: one file grows, one is added and
+// an older one goes
+fn change_source(src: &Path, seed: u64) {
+    let mut grown = fs::read(src.join("grows")).unwrap_or_default();
+    grown.extend(content(seed, 100));
+    fs::write(src.join("grows"), grown).unwrap();
+    fs::write(src.join("sub").join(format!("added-{}", seed)), content(seed, 1000 + seed as usize * 700)).unwrap();
+    if seed > 3 {
+        fs::remove_file(src.join("sub").join(format!("added-{}", seed - 3))).unwrap();
+    }
+}
+
+fn diff_trees(expected: &Path, actual: &Path) {
+    for entry in fs::read_dir(expected).unwrap() {
+        let path = entry.unwrap().path();
+        let restored = actual.join(path.file_name().unwrap());
+        if path.is_dir() {
+            diff_trees(&path, &restored);
+        } else {
+            assert!(fs::read(&path).unwrap() == fs::read(&restored).unwrap(), "{:?} differs", restored);
+        }
+    }
+    assert_eq!(fs::read_dir(expected).unwrap().count(), fs::read_dir(actual).unwrap().count(), "{:?}", actual);
+}
+
+#[test]
+fn repository_survives_faulty_backups() {
+    let dir = scratch("chaos");
+    let src = dir.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::create_dir_all(dir.join("backup")).unwrap();
+    for i in 0..5 {
+        fs::write(src.join(format!("file-{}", i)), content(100 + i, 5000 * i as usize)).unwrap();
+    }
+
+    for seed in 1..=RUNS {
+        change_source(&src, seed);
+        // Failing or crashing is the point; only what it leaves matters
+        run(&dir, &["backup", "--chaos", CHAOS_RATE, "--chaos-seed", &seed.to_string()]);
+        assert_success(&run(&dir, &["check"]), &format!("check after chaos seed {}", seed));
+    }
+
+    assert_success(&run(&dir, &["backup"]), "backup after the faulty ones");
+    assert_success(&run(&dir, &["check"]), "final check");
+    assert_success(&run(&dir, &["restore", "latest", "--to", "restored"]), "restore");
+    diff_trees(&src, &dir.join("restored"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,192 @@
+use log::warn;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{FILE_HARD_TIMEOUT_SECS, FILE_SOFT_TIMEOUT_SECS};
+use crate::human::format_duration;
+
+// A file on a failing disk or a stale network mount can block open() or
+// read() indefinitely, and with it a worker and in the end the whole run.
+// With FILE_SOFT_TIMEOUT_SECS or FILE_HARD_TIMEOUT_SECS set, files are opened
+// and read on a helper thread the caller waits for with a deadline. A blocked
+// system call can't be cancelled: a helper given up on is left behind and
+// exits once its call returns.
+#[derive(Clone, Copy)]
+enum Request {
+    Open,
+    Read { offset: u64, len: usize },
+}
+
+type Reply = (u64, io::Result<Vec<u8>>);
+
+fn soft_timeout() -> Option<Duration> {
+    (FILE_SOFT_TIMEOUT_SECS != 0).then(|| Duration::from_secs(FILE_SOFT_TIMEOUT_SECS))
+}
+
+fn hard_timeout() -> Option<Duration> {
+    (FILE_HARD_TIMEOUT_SECS != 0).then(|| Duration::from_secs(FILE_HARD_TIMEOUT_SECS))
+}
+
+// Whether opening and reading files is time limited
+pub fn enabled() -> bool {
+    soft_timeout().is_some() || hard_timeout().is_some()
+}
+
+// Whether `e` means an operation was given up after FILE_HARD_TIMEOUT_SECS
+pub fn timed_out(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut
+}
+
+// Helper thread opening `path` on its first request and serving the reads
+// sent to it, tagging each reply with the request's sequence number
+fn spawn_reader(path: PathBuf, replies: Sender<Reply>) -> Sender<(u64, Request)> {
+    let (requests, incoming) = mpsc::channel::<(u64, Request)>();
+    thread::spawn(move || {
+        let mut file = None;
+        for (seq, request) in incoming {
+            if file.is_none() {
+                match File::open(&path) {
+                    Ok(opened) => file = Some(opened),
+                    Err(e) => {
+                        let _ = replies.send((seq, Err(e)));
+                        continue;
+                    }
+                }
+            }
+            let Some(file) = &file else {
+                continue;
+            };
+            let result = match request {
+                Request::Open => Ok(Vec::new()),
+                Request::Read { offset, len } => {
+                    let mut buffer = vec![0u8; len];
+                    file.read_at(&mut buffer, offset).map(|read| {
+                        buffer.truncate(read);
+                        buffer
+                    })
+                }
+            };
+            if replies.send((seq, result)).is_err() {
+                break;
+            }
+        }
+    });
+    requests
+}
+
+pub struct Guarded {
+    path: PathBuf,
+    requests: Sender<(u64, Request)>,
+    reply_to: Sender<Reply>,
+    replies: Receiver<Reply>,
+    seq: u64,
+    offset: u64,
+}
+
+impl Guarded {
+    fn call(&mut self, request: Request) -> io::Result<Vec<u8>> {
+        self.seq += 1;
+        let seq = self.seq;
+        // A reader that went away is replaced by the retry below
+        let _ = self.requests.send((seq, request));
+        let started = Instant::now();
+        let mut retried = false;
+        loop {
+            let soft = soft_timeout().filter(|_| !retried);
+            let reply = match [soft, hard_timeout()].into_iter().flatten().min() {
+                Some(limit) => self.replies.recv_timeout(limit.saturating_sub(started.elapsed())),
+                None => self.replies.recv().map_err(RecvTimeoutError::from),
+            };
+            let what = if matches!(request, Request::Open) { "Opening" } else { "Reading" };
+            match reply {
+                Ok((tag, result)) if tag == seq => return result,
+                // Late reply to an attempt that was retried
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout)
+                    if soft.is_some_and(|soft| started.elapsed() >= soft)
+                        && hard_timeout().is_none_or(|hard| started.elapsed() < hard) =>
+                {
+                    // Try again on a fresh handle; whichever attempt answers
+                    // first is taken
+                    warn!("{} {:?} has taken {}, retrying", what, self.path, format_duration(started.elapsed()));
+                    self.requests = spawn_reader(self.path.clone(), self.reply_to.clone());
+                    let _ = self.requests.send((seq, request));
+                    retried = true;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} {:?} timed out after {}", what, self.path, format_duration(started.elapsed())),
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other(format!("Reader of {:?} stopped", self.path)))
+                }
+            }
+        }
+    }
+}
+
+// A file opened for reading, through a helper thread when timeouts are set
+pub enum SourceFile {
+    Direct(File),
+    Guarded(Guarded),
+}
+
+impl Read for SourceFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SourceFile::Direct(file) => file.read(buf),
+            SourceFile::Guarded(guarded) => {
+                let request = Request::Read { offset: guarded.offset, len: buf.len() };
+                let data = guarded.call(request)?;
+                buf[..data.len()].copy_from_slice(&data);
+                guarded.offset += data.len() as u64;
+                Ok(data.len())
+            }
+        }
+    }
+}
+
+impl SourceFile {
+    // Continue reading at `offset`
+    pub fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        match self {
+            SourceFile::Direct(file) => file.seek(SeekFrom::Start(offset)).map(|_| ()),
+            SourceFile::Guarded(guarded) => {
+                guarded.offset = offset;
+                Ok(())
+            }
+        }
+    }
+}
+
+// File::open, within the configured time limits
+pub fn open(path: &Path) -> io::Result<SourceFile> {
+    if !enabled() {
+        return File::open(path).map(SourceFile::Direct);
+    }
+    let (reply_to, replies) = mpsc::channel();
+    let mut guarded = Guarded {
+        path: path.to_path_buf(),
+        requests: spawn_reader(path.to_path_buf(), reply_to.clone()),
+        reply_to,
+        replies,
+        seq: 0,
+        offset: 0,
+    };
+    guarded.call(Request::Open)?;
+    Ok(SourceFile::Guarded(guarded))
+}
+
+// fs::read, within the configured time limits
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
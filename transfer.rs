@@ -0,0 +1,61 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Idle time (paused runs, slow sources) saved up for a burst at most
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+// Bandwidth limit shared by all workers of a run, in both directions: backups
+// account for the data they write into the repository, restores for the data
+// they read out of it. Each transfer sleeps just as long as it takes for the
+// bytes sent so far to be due at the configured rate.
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    // Start of the current accounting period and bytes sent since then
+    state: Mutex<(Instant, u64)>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self { bytes_per_sec: bytes_per_sec.max(1), state: Mutex::new((Instant::now(), 0)) }
+    }
+
+    pub fn throttle(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            state.1 += bytes;
+            let due = Duration::from_secs_f64(state.1 as f64 / self.bytes_per_sec as f64);
+            let elapsed = state.0.elapsed();
+            if elapsed > due + MAX_BURST {
+                *state = (Instant::now(), 0);
+            }
+            due.saturating_sub(elapsed)
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+// Reader whose data counts against a rate limit
+pub struct Throttled<'a, R> {
+    inner: R,
+    limit: Option<&'a RateLimit>,
+}
+
+impl<'a, R> Throttled<'a, R> {
+    pub fn new(inner: R, limit: Option<&'a RateLimit>) -> Self {
+        Self { inner, limit }
+    }
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(limit) = self.limit {
+            limit.throttle(n as u64);
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,140 @@
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backup_utils::compute_xxhash_like;
+use crate::checkpoint::ResolvedFile;
+use crate::error::{Error, Result};
+use crate::hashing::{ContentHasher, Scheme};
+use crate::priority::RunGuard;
+use crate::reflink::reflink;
+use crate::settings::Config;
+
+// Hashes everything written through it, so restored data is verified without
+// reading it back
+struct HashingWriter<W> {
+    inner: W,
+    hasher: ContentHasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn partial_path(out_path: &Path) -> PathBuf {
+    let name = out_path.file_name().unwrap_or_default().to_string_lossy();
+    out_path.with_file_name(format!(".{}.restoring", name))
+}
+
+// Whether `out_path` already holds the data, as left by an earlier attempt at
+// the same restore; files are only moved into place once complete
+pub fn already_restored(out_path: &Path, size: u64, expected_hash: &str) -> bool {
+    fs::metadata(out_path).is_ok_and(|m| m.is_file() && m.len() == size)
+        && compute_xxhash_like(out_path, expected_hash).is_ok_and(|hash| hash == expected_hash)
+}
+
+// Restore one file from `reader`, checking it against the manifest hash as it
+// is written. Data lands under a temporary name and only replaces `out_path`
+// once it verified, or on mismatch if RESTORE_VERIFY is "warn". "skip" leaves
+// mismatching files out, "fail" aborts the restore. `prepare` can set
+// permissions and times on the file before it is moved into place. Returns
+// whether the file verified.
+pub fn restore_verified(
+    config: &Config,
+    reader: &mut impl Read,
+    out_path: &Path,
+    expected_hash: &str,
+    prepare: impl FnOnce(&File) -> io::Result<()>,
+) -> Result<bool> {
+    if let Some(parent) = out_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = partial_path(out_path);
+    let mut writer = HashingWriter { inner: File::create(&partial)?, hasher: ContentHasher::new(Scheme::of(expected_hash)) };
+    let result = io::copy(reader, &mut writer).and_then(|_| prepare(&writer.inner));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e.into());
+    }
+    let HashingWriter { inner, hasher } = writer;
+    drop(inner);
+    settle(config, &partial, out_path, expected_hash, &hasher.finish())
+}
+
+// Restore a file of a checkpoint like restore_verified. Data stored plain is
+// reflinked where USE_REFLINK and the filesystem allow, sharing its blocks
+// instead of copying them; the clone is hashed all the same before it takes
+// the place of `out_path`, so a damaged stored copy is still caught.
+pub fn restore_stored(
+    config: &Config,
+    file: &ResolvedFile,
+    out_path: &Path,
+    run: &RunGuard,
+    prepare: impl FnOnce(&File) -> io::Result<()>,
+) -> Result<bool> {
+    let expected_hash = &file.info.hash;
+    if file.info.packed.is_none() && file.info.seekable.is_none() {
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = partial_path(out_path);
+        let linked = if config.use_reflink { reflink(&file.stored_at, &partial) } else { Ok(false) };
+        match linked {
+            Ok(true) => {
+                let result = compute_xxhash_like(&partial, expected_hash).and_then(|hash| {
+                    prepare(&File::options().write(true).open(&partial)?)?;
+                    Ok(hash)
+                });
+                return match result {
+                    Ok(hash) => settle(config, &partial, out_path, expected_hash, &hash),
+                    Err(e) => {
+                        let _ = fs::remove_file(&partial);
+                        Err(e)
+                    }
+                };
+            }
+            Ok(false) => {}
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e.into());
+            }
+        }
+    }
+    restore_verified(config, &mut run.throttled(file.open()?), out_path, expected_hash, prepare)
+}
+
+// Move the restored data at `partial` into place if its hash matches, or as
+// RESTORE_VERIFY says otherwise
+fn settle(config: &Config, partial: &Path, out_path: &Path, expected_hash: &str, hash: &str) -> Result<bool> {
+    if hash == expected_hash {
+        fs::rename(partial, out_path)?;
+        return Ok(true);
+    }
+    match config.restore_verify.as_str() {
+        "warn" => {
+            warn!("Hash mismatch for {}, restored anyway", out_path.display());
+            fs::rename(partial, out_path)?;
+        }
+        "skip" => {
+            warn!("Hash mismatch for {}, not restored", out_path.display());
+            fs::remove_file(partial)?;
+        }
+        _ => {
+            fs::remove_file(partial)?;
+            return Err(Error::Verification {
+                path: out_path.to_path_buf(),
+                reason: format!("hash mismatch (expected {}, got {})", expected_hash, hash),
+            });
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,172 @@
+use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::{ensure_unlocked, list_checkpoints, record_root};
+use crate::config::VERSION_LIMITS;
+use crate::manifest::{has_manifest, read_entries, remove_entries};
+use crate::pattern::path_matches;
+use crate::zip_handler::{is_legacy_dir, meta_name, meta_zip, read_zip_metas, write_zip_metas};
+
+// How many versions of a file to keep, from the first matching VERSION_LIMITS entry
+pub fn max_versions(rel: &Path) -> Option<usize> {
+    VERSION_LIMITS
+        .iter()
+        .find(|(pattern, _)| path_matches(pattern, rel))
+        .map(|(_, limit)| (*limit).max(1))
+}
+
+fn checkpoint_name(checkpoint: &Path) -> String {
+    checkpoint.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Stored copies outside their path's version limit, by the checkpoint holding
+// them. A version is a distinct content; copies of the N most recent contents
+// are kept, so unchanged files hard-linked into later checkpoints count once.
+fn expired_copies(checkpoints: &[PathBuf]) -> io::Result<HashMap<String, HashSet<PathBuf>>> {
+    // Oldest first, as the checkpoints are listed
+    let mut copies: HashMap<PathBuf, Vec<(String, String)>> = HashMap::new();
+    for checkpoint in checkpoints {
+        let name = checkpoint_name(checkpoint);
+        for entry in read_entries(checkpoint) {
+            let entry = entry?;
+            if entry.stored_in.as_deref() == Some(name.as_str()) && max_versions(&entry.path).is_some() {
+                copies.entry(entry.path).or_default().push((name.clone(), entry.hash));
+            }
+        }
+    }
+
+    let mut expired: HashMap<String, HashSet<PathBuf>> = HashMap::new();
+    for (path, copies) in copies {
+        let limit = max_versions(&path).unwrap_or(usize::MAX);
+        let mut kept: Vec<&str> = Vec::new();
+        for (_, hash) in copies.iter().rev() {
+            if kept.len() == limit {
+                break;
+            }
+            if !kept.contains(&hash.as_str()) {
+                kept.push(hash);
+            }
+        }
+        for (checkpoint, hash) in &copies {
+            if !kept.contains(&hash.as_str()) {
+                expired.entry(checkpoint.clone()).or_default().insert(path.clone());
+            }
+        }
+    }
+    Ok(expired)
+}
+
+// Remove the metas of `paths` from the meta zips of a checkpoint
+fn remove_metas(checkpoint: &Path, paths: &HashSet<PathBuf>) -> io::Result<()> {
+    let mut by_dir: BTreeMap<PathBuf, HashSet<String>> = BTreeMap::new();
+    for path in paths {
+        let dir = checkpoint.join(path.parent().unwrap_or(Path::new("")));
+        let name = meta_name(path.file_name().unwrap_or_default(), is_legacy_dir(&dir));
+        by_dir.entry(dir).or_default().insert(name);
+    }
+    for (dir, names) in by_dir {
+        let Some(zip) = meta_zip(&dir) else {
+            continue;
+        };
+        let mut entries = read_zip_metas(&zip)?;
+        let before = entries.len();
+        entries.retain(|entry| !names.contains(&entry.name));
+        if entries.is_empty() {
+            fs::remove_file(&zip)?;
+        } else if entries.len() != before {
+            write_zip_metas(&zip, &entries)?;
+        }
+    }
+    Ok(())
+}
+
+// Apply VERSION_LIMITS: delete stored copies of files beyond their most
+// recent N versions, along with the manifest entries and metas of every
+// checkpoint that refers to them. Copies held by or referenced from
+// retention-locked checkpoints are kept. Returns the number of copies removed.
+pub fn prune_versions(backup_dir: &Path, dry_run: bool) -> io::Result<usize> {
+    if VERSION_LIMITS.is_empty() {
+        info!("No version limits configured");
+        return Ok(0);
+    }
+    let (checkpoints, legacy): (Vec<PathBuf>, Vec<PathBuf>) =
+        list_checkpoints(backup_dir)?.into_iter().partition(|c| has_manifest(c));
+    if !legacy.is_empty() {
+        info!("Skipping {} checkpoints without a manifest; run `migrate` to include them", legacy.len());
+    }
+    let locked: HashSet<String> = checkpoints
+        .iter()
+        .filter(|c| ensure_unlocked(c).is_err())
+        .map(|c| checkpoint_name(c))
+        .collect();
+
+    let mut expired = expired_copies(&checkpoints)?;
+    expired.retain(|holder, _| !locked.contains(holder));
+
+    // Every checkpoint referring to an expired copy loses its entry, so a
+    // reference from a locked checkpoint keeps the copy
+    let mut references: HashMap<String, HashMap<String, HashSet<PathBuf>>> = HashMap::new();
+    for checkpoint in &checkpoints {
+        let name = checkpoint_name(checkpoint);
+        for entry in read_entries(checkpoint) {
+            let entry = entry?;
+            let Some(holder) = entry.stored_in else {
+                continue;
+            };
+            if expired.get(&holder).is_some_and(|paths| paths.contains(&entry.path)) {
+                references.entry(name.clone()).or_default().entry(holder).or_default().insert(entry.path);
+            }
+        }
+    }
+    for (referrer, holders) in &references {
+        if locked.contains(referrer) {
+            for (holder, paths) in holders {
+                if let Some(expired) = expired.get_mut(holder) {
+                    expired.retain(|path| !paths.contains(path));
+                }
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for (holder, paths) in &expired {
+        for path in paths {
+            if dry_run {
+                info!("Would remove {:?} stored in {}", path, holder);
+            } else {
+                match fs::remove_file(backup_dir.join(holder).join(path)) {
+                    Ok(()) => info!("Removed {:?} stored in {}", path, holder),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            removed += 1;
+        }
+    }
+    if dry_run {
+        info!("{} stored copies exceed their version limit", removed);
+        return Ok(removed);
+    }
+
+    for (referrer, holders) in references {
+        let checkpoint = backup_dir.join(&referrer);
+        let mut dropped = HashSet::new();
+        for (holder, paths) in holders {
+            let Some(expired) = expired.get(&holder) else {
+                continue;
+            };
+            let paths: HashSet<PathBuf> = paths.into_iter().filter(|path| expired.contains(path)).collect();
+            remove_entries(&checkpoint, &paths, &holder)?;
+            dropped.extend(paths);
+        }
+        if !dropped.is_empty() {
+            remove_metas(&checkpoint, &dropped)?;
+            record_root(backup_dir, &checkpoint)?;
+        }
+    }
+    info!("Removed {} stored copies beyond their version limit", removed);
+    Ok(removed)
+}
@@ -0,0 +1,118 @@
+use chrono::{Local, NaiveTime};
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::WINDOW_OVERRUN;
+use crate::error::Error;
+use crate::settings::Config;
+
+// Longest single sleep while waiting, so clock and DST changes are noticed
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+// Daily time range (local time) in which scheduled backups may run; the end
+// may be earlier than the start for windows spanning midnight.
+#[derive(Clone, Copy)]
+pub struct BackupWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    pause_on_overrun: bool,
+}
+
+// BACKUP_WINDOW parsed, or None when no window is configured
+pub fn configured(config: &Config) -> io::Result<Option<BackupWindow>> {
+    let window = &config.backup_window;
+    if window.is_empty() {
+        return Ok(None);
+    }
+    parse(window, WINDOW_OVERRUN).map(Some)
+}
+
+// A window given as "HH:MM-HH:MM" and its overrun behaviour ("complete" or "pause")
+pub fn parse(window: &str, overrun: &str) -> io::Result<BackupWindow> {
+    let invalid = || {
+        io::Error::from(Error::Config(format!("Invalid BACKUP_WINDOW {:?}, expected HH:MM-HH:MM", window)))
+    };
+    let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+    let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start == end {
+        return Err(invalid());
+    }
+    let pause_on_overrun = match overrun {
+        "complete" => false,
+        "pause" => true,
+        other => {
+            return Err(Error::Config(format!(
+                "Invalid WINDOW_OVERRUN {:?}, expected \"complete\" or \"pause\"",
+                other
+            ))
+            .into())
+        }
+    };
+    Ok(BackupWindow { start, end, pause_on_overrun })
+}
+
+// Time from `now` until `target` comes round next
+fn until(now: NaiveTime, target: NaiveTime) -> Duration {
+    let mut delta = target - now;
+    if delta < chrono::Duration::zero() {
+        delta += chrono::Duration::days(1);
+    }
+    delta.to_std().unwrap_or_default()
+}
+
+impl BackupWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.contains(Local::now().time())
+    }
+
+    pub fn pauses_on_overrun(&self) -> bool {
+        self.pause_on_overrun
+    }
+
+    pub fn until_open(&self) -> Duration {
+        let now = Local::now().time();
+        if self.contains(now) {
+            Duration::ZERO
+        } else {
+            until(now, self.start)
+        }
+    }
+
+    pub fn until_close(&self) -> Duration {
+        let now = Local::now().time();
+        if self.contains(now) {
+            until(now, self.end)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    pub fn wait_until_open(&self) {
+        while !self.is_open() {
+            thread::sleep(self.until_open().clamp(Duration::from_secs(1), MAX_WAIT));
+        }
+    }
+
+    pub fn wait_until_closed(&self) {
+        while self.is_open() {
+            thread::sleep(self.until_close().clamp(Duration::from_secs(1), MAX_WAIT));
+        }
+    }
+}
+
+impl fmt::Display for BackupWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
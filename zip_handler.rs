@@ -1,84 +1,472 @@
+use chrono::{Datelike, Timelike};
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use zip::write::{FileOptions, ZipWriter};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, UNIX_EPOCH};
+use zip::extra_fields::ExtraField;
+use zip::read::ZipFile;
+use zip::write::{FullFileOptions, ZipWriter};
 use walkdir::WalkDir;
 use zip::ZipArchive;
-use log::{info};
+use log::{info, warn};
 
-use crate::config::COMPRESS_FILE_NAME;
+use crate::config::{COMPRESS_FILE_NAME, META_EXTRACT_THREADS, TEMP_SPACE_LIMIT};
+use crate::human::format_size;
+use crate::manifest::name_string;
+use crate::error::{Error, Result};
 
-pub fn compress_dir(root_dir: &Path) -> io::Result<()> {
-    for entry in WalkDir::new(&root_dir)
+// Metadata of the files in a directory lives in this sidecar directory: the
+// meta of "report.txt" is ".nbmeta/report.txt.meta", zipped into
+// ".nbmeta/meta_files.zip". Legacy directories keep "report.meta" and the zip
+// next to the data instead, where a source file named "report.meta" collides
+// with the meta of "report" and "report.txt" shares its meta with "report.pdf".
+pub const META_DIR: &str = ".nbmeta";
+
+// Whether `dir` holds its metadata in the legacy layout
+pub fn is_legacy_dir(dir: &Path) -> bool {
+    !dir.join(META_DIR).is_dir() && dir.join(COMPRESS_FILE_NAME).is_file()
+}
+
+// Name of the meta of `file_name` in a directory's meta zip. Names that
+// aren't valid UTF-8 are escaped (see manifest::name_string), so their metas
+// stay apart and are found again.
+pub fn meta_name(file_name: &OsStr, legacy: bool) -> String {
+    if legacy {
+        name_string(Path::new(file_name).with_extension("meta").as_os_str())
+    } else {
+        format!("{}.meta", name_string(file_name))
+    }
+}
+
+// Where a run writes the meta of the data file at `data`
+pub fn meta_path(data: &Path) -> PathBuf {
+    data.with_file_name(META_DIR).join(meta_name(data.file_name().unwrap_or_default(), false))
+}
+
+// Where an extracted checkpoint has the meta of `data`, in either layout
+pub fn existing_meta_path(data: &Path) -> PathBuf {
+    let dir = data.parent().unwrap_or(Path::new(""));
+    if dir.join(META_DIR).is_dir() {
+        meta_path(data)
+    } else {
+        data.with_extension("meta")
+    }
+}
+
+// The meta zip of a directory, in whichever layout it uses
+pub fn meta_zip(dir: &Path) -> Option<PathBuf> {
+    let sidecar = dir.join(META_DIR).join(COMPRESS_FILE_NAME);
+    if sidecar.is_file() {
+        Some(sidecar)
+    } else if is_legacy_dir(dir) {
+        Some(dir.join(COMPRESS_FILE_NAME))
+    } else {
+        None
+    }
+}
+
+// Every meta zip below `root`. Data files that happen to be called like the
+// zip are not metadata and are left out.
+pub fn meta_zips(root: &Path) -> Vec<PathBuf> {
+    WalkDir::new(root)
         .into_iter()
+        .filter_entry(|e| e.file_name() != META_DIR)
         .filter_map(|e| e.ok())
         .filter(|e| e.file_type().is_dir())
-    {
-        compress_process(entry.path())?;
+        .filter_map(|e| meta_zip(e.path()))
+        .collect()
+}
+
+// Info-ZIP "UT" extra field: flags, then the modification time in Unix seconds
+const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+
+// One .meta inside a meta zip: its path relative to the zip's directory, its
+// contents, and the modification time and permissions of the file it was made from
+pub struct MetaEntry {
+    pub name: String,
+    pub content: String,
+    pub mtime: Option<i64>,
+    pub mode: Option<u32>,
+}
+
+impl MetaEntry {
+    pub fn new(name: String, content: String) -> Self {
+        Self { name, content, mtime: None, mode: None }
     }
-    info!("Compressed all .meta files in '{}'", root_dir.display());
-    Ok(())
 }
 
-pub fn extract_dir(root_dir: &Path) -> io::Result<()> {
-    for entry in WalkDir::new(&root_dir)
+// DOS timestamp for tools that ignore the UT field; UTC so the zip doesn't
+// depend on the time zone it was written in
+fn dos_time(mtime: i64) -> Option<zip::DateTime> {
+    let time = chrono::DateTime::from_timestamp(mtime, 0)?.naive_utc();
+    zip::DateTime::from_date_and_time(
+        u16::try_from(time.year()).ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .ok()
+}
+
+// Entry settings taken only from the entry itself, so identical metadata
+// always produces a byte-identical zip: explicit compression, and the meta
+// file's own mtime and permissions rather than the time of zipping.
+fn meta_entry_options(entry: &MetaEntry) -> zip::result::ZipResult<FullFileOptions<'static>> {
+    let mut options = FullFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .compression_level(Some(6))
+        .last_modified_time(entry.mtime.and_then(dos_time).unwrap_or_default())
+        .unix_permissions(entry.mode.unwrap_or(0o644));
+    if let Some(mtime) = entry.mtime.and_then(|mtime| u32::try_from(mtime).ok()) {
+        let mut field = vec![1u8];
+        field.extend_from_slice(&mtime.to_le_bytes());
+        options.add_extra_data(EXTENDED_TIMESTAMP_ID, field.into_boxed_slice(), false)?;
+    }
+    Ok(options)
+}
+
+fn entry_mtime<R: Read>(zip_file: &ZipFile<'_, R>) -> Option<i64> {
+    zip_file
+        .extra_data_fields()
+        .find_map(|field| match field {
+            ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+            _ => None,
+        })
+        .map(i64::from)
+}
+
+// Entry name of `file` below `dir`, with '/' separators as zip expects
+fn entry_name(dir: &Path, file: &Path) -> Result<String> {
+    let rel = file.strip_prefix(dir).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not inside {:?}", file, dir))
+    })?;
+    let parts: Vec<String> = rel.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+    Ok(parts.join("/"))
+}
+
+// Zip the loose metas in every META_DIR below `root_dir`
+pub fn compress_dir(root_dir: &Path) -> Result<()> {
+    for entry in WalkDir::new(root_dir)
         .into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_dir())
+        .filter(|e| e.file_type().is_dir() && e.file_name() == META_DIR)
     {
-        extract_zip(entry.path(), true)?;
+        compress_process(entry.path())?;
     }
-    info!("Extracted all zip files in '{}'", root_dir.display());
+    info!("Compressed all .meta files in '{}'", root_dir.display());
     Ok(())
 }
 
-fn extract_zip(dir: &Path, delete_zip: bool) -> io::Result<()> {
-    let zip_path = dir.join(COMPRESS_FILE_NAME);
-    if !zip_path.exists() {
-        return Ok(());
+// The metas of the last checkpoint, extracted from the meta zips copied below
+// `root` only for the directories a run visits. The scan requests each
+// directory it finds files in; META_EXTRACT_THREADS threads read and inflate
+// the requested zips while another writes the metas to disk, so discovery,
+// decompression and writes overlap. A worker about to compare a file waits
+// for its directory, and extracts it itself if it is still queued.
+//
+// The space taken below `root` is kept within TEMP_SPACE_LIMIT where it can
+// be: past it the threads stop extracting ahead, and the metas of a directory
+// are deleted as soon as its last file was compared.
+pub struct MetaExtractor {
+    shared: Arc<Shared>,
+    sender: Option<mpsc::Sender<PathBuf>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    root: PathBuf,
+    // By directory relative to `root`
+    dirs: Mutex<HashMap<PathBuf, Extraction>>,
+    changed: Condvar,
+    // Files the scan found per directory and not yet compared
+    pending: Mutex<HashMap<PathBuf, usize>>,
+    // Metas written per directory, kept only under a TEMP_SPACE_LIMIT
+    written: Mutex<HashMap<PathBuf, Written>>,
+    // Bytes below `root`
+    used: AtomicU64,
+    spilled: AtomicBool,
+}
+
+#[derive(Clone)]
+enum Extraction {
+    Queued,
+    Running,
+    Done,
+    Failed(String),
+}
+
+// A meta read from a zip, with where it goes
+struct ExtractedMeta {
+    path: PathBuf,
+    content: Vec<u8>,
+    mtime: Option<i64>,
+    mode: Option<u32>,
+}
+
+// The meta zip of a directory and its metas
+type ZipMetas = Option<(PathBuf, Vec<ExtractedMeta>)>;
+
+// What extracting a directory changed on disk
+#[derive(Default)]
+struct Written {
+    metas: Vec<PathBuf>,
+    bytes: u64,
+    // The zip, deleted once extracted
+    freed: u64,
+}
+
+impl Shared {
+    // Take a queued directory for extraction; false if someone else has it
+    fn claim(&self, dir: &Path) -> bool {
+        let mut dirs = self.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        match dirs.get_mut(dir) {
+            Some(state @ Extraction::Queued) => {
+                *state = Extraction::Running;
+                true
+            }
+            _ => false,
+        }
     }
 
-    // Open the zip file
-    let file = File::open(&zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
+    fn finish(&self, dir: &Path, result: Result<Written>) -> Result<()> {
+        let (state, result) = match result {
+            Ok(written) => {
+                self.used.fetch_add(written.bytes, Ordering::Relaxed);
+                self.free(written.freed);
+                if TEMP_SPACE_LIMIT != 0 {
+                    self.written.lock().unwrap_or_else(|e| e.into_inner()).insert(dir.to_path_buf(), written);
+                }
+                (Extraction::Done, Ok(()))
+            }
+            Err(e) => (Extraction::Failed(e.to_string()), Err(e)),
+        };
+        self.dirs.lock().unwrap_or_else(|e| e.into_inner()).insert(dir.to_path_buf(), state);
+        self.changed.notify_all();
+        result
+    }
+
+    fn free(&self, bytes: u64) {
+        let _ = self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| Some(used.saturating_sub(bytes)));
+    }
+
+    fn over_budget(&self) -> bool {
+        let used = self.used.load(Ordering::Relaxed);
+        let over = TEMP_SPACE_LIMIT != 0 && used > TEMP_SPACE_LIMIT;
+        if over && !self.spilled.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Scratch space of {} is over TEMP_SPACE_LIMIT ({}); extracting metas only when needed",
+                format_size(used),
+                format_size(TEMP_SPACE_LIMIT)
+            );
+        }
+        over
+    }
+}
 
-    // Extract each file in the zip
+impl MetaExtractor {
+    // Extractor for the zips below `root`, which take `copied` bytes; an
+    // empty path has no metas at all
+    pub fn start(root: &Path, copied: u64) -> Self {
+        let shared = Arc::new(Shared {
+            root: root.to_path_buf(),
+            dirs: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+            pending: Mutex::new(HashMap::new()),
+            written: Mutex::new(HashMap::new()),
+            used: AtomicU64::new(copied),
+            spilled: AtomicBool::new(false),
+        });
+        if root.as_os_str().is_empty() || META_EXTRACT_THREADS == 0 {
+            return Self { shared, sender: None, threads: Vec::new() };
+        }
+        let (sender, receiver) = mpsc::channel::<PathBuf>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (writes, written) = mpsc::sync_channel::<(PathBuf, Result<ZipMetas>)>(META_EXTRACT_THREADS);
+        let mut threads: Vec<JoinHandle<()>> = (0..META_EXTRACT_THREADS)
+            .map(|_| {
+                let (shared, receiver, writes) = (Arc::clone(&shared), Arc::clone(&receiver), writes.clone());
+                thread::spawn(move || loop {
+                    let dir = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                        Ok(dir) => dir,
+                        Err(_) => break,
+                    };
+                    // Left queued for the worker that needs it
+                    if shared.over_budget() || !shared.claim(&dir) {
+                        continue;
+                    }
+                    let metas = read_metas(&shared.root.join(&dir));
+                    if writes.send((dir, metas)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        drop(writes);
+        let writer = Arc::clone(&shared);
+        threads.push(thread::spawn(move || {
+            for (dir, metas) in written {
+                let _ = writer.finish(&dir, metas.and_then(write_metas));
+            }
+        }));
+        Self { shared, sender: Some(sender), threads }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.shared.root
+    }
+
+    // Queue the directory `dir` (relative to the root) for extraction, for
+    // one more file to compare
+    pub fn request(&self, dir: &Path) {
+        if TEMP_SPACE_LIMIT != 0 {
+            *self.shared.pending.lock().unwrap_or_else(|e| e.into_inner()).entry(dir.to_path_buf()).or_default() += 1;
+        }
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let mut dirs = self.shared.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        if !dirs.contains_key(dir) {
+            dirs.insert(dir.to_path_buf(), Extraction::Queued);
+            let _ = sender.send(dir.to_path_buf());
+        }
+    }
+
+    // Wait until the metas of `dir` are on disk, extracting them here unless
+    // another thread already is
+    pub fn wait(&self, dir: &Path) -> Result<()> {
+        if self.shared.root.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let mut dirs = self.shared.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match dirs.get(dir).cloned() {
+                None | Some(Extraction::Queued) => {
+                    dirs.insert(dir.to_path_buf(), Extraction::Running);
+                    drop(dirs);
+                    let result = read_metas(&self.shared.root.join(dir)).and_then(write_metas);
+                    return self.shared.finish(dir, result);
+                }
+                Some(Extraction::Running) => dirs = self.shared.changed.wait(dirs).unwrap_or_else(|e| e.into_inner()),
+                Some(Extraction::Done) => return Ok(()),
+                Some(Extraction::Failed(e)) => return Err(io::Error::other(e).into()),
+            }
+        }
+    }
+
+    // A file of `dir` was compared; once it was the last one, past the limit
+    // the directory's metas are deleted
+    pub fn release(&self, dir: &Path) {
+        if TEMP_SPACE_LIMIT == 0 {
+            return;
+        }
+        let mut pending = self.shared.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(count) = pending.get_mut(dir) else {
+            return;
+        };
+        *count = count.saturating_sub(1);
+        if *count > 0 || !self.shared.over_budget() {
+            return;
+        }
+        pending.remove(dir);
+        drop(pending);
+        let Some(written) = self.shared.written.lock().unwrap_or_else(|e| e.into_inner()).remove(dir) else {
+            return;
+        };
+        for meta in &written.metas {
+            let _ = fs::remove_file(meta);
+        }
+        self.shared.free(written.bytes);
+    }
+
+    // Stop extracting and return the root, once no thread writes below it
+    pub fn finish(self) -> PathBuf {
+        self.shared.root.clone()
+    }
+}
+
+impl Drop for MetaExtractor {
+    fn drop(&mut self) {
+        // Directories nobody waited for aren't needed any more
+        self.shared.dirs.lock().unwrap_or_else(|e| e.into_inner()).retain(|_, state| !matches!(state, Extraction::Queued));
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Read the metas in the meta zip of `dir`, if it has one
+fn read_metas(dir: &Path) -> Result<ZipMetas> {
+    let Some(zip_path) = meta_zip(dir) else {
+        return Ok(None);
+    };
+    let out_dir = zip_path.parent().unwrap_or(dir);
+    let mut archive = ZipArchive::new(File::open(&zip_path)?).map_err(|e| Error::zip(&zip_path, e))?;
+    let mut metas = Vec::with_capacity(archive.len());
     for i in 0..archive.len() {
-        let mut zip_file = archive.by_index(i)?;
-        let name = zip_file.name_raw();
+        let mut zip_file = archive.by_index(i).map_err(|e| Error::zip(&zip_path, e))?;
+        let name = zip_file.name().to_string();
         // Ensure the file has a .meta extension
-        let name_str = String::from_utf8_lossy(name).to_string();
-        if !name_str.ends_with(".meta") {
-            info!("Skipping non-.meta file in zip: {}", name_str);
+        if !name.ends_with(".meta") {
+            info!("Skipping non-.meta file in zip: {}", name);
             continue;
         }
-        let file_name = name_str;
-
-        let out_path = dir.join(&file_name);
-        if out_path.exists() {
-            info!("File already exists, skipping: {}", out_path.display());
+        // Entry names are relative paths; anything escaping the directory is refused
+        let Some(rel) = zip_file.enclosed_name() else {
+            warn!("Skipping entry with unsafe path in {}: {}", zip_path.display(), name);
             continue;
-        }
-
+        };
+        let path = out_dir.join(rel);
+        let (mtime, mode) = (entry_mtime(&zip_file), zip_file.unix_mode());
         let mut content = Vec::new();
         zip_file.read_to_end(&mut content)?;
-        let mut out_file = File::create(&out_path)?;
-        out_file.write_all(&content)?;
-        info!("Extracted: {}", out_path.display());
+        metas.push(ExtractedMeta { path, content, mtime, mode });
     }
+    Ok(Some((zip_path, metas)))
+}
 
-    // Optionally delete the zip file
-    if delete_zip {
-        fs::remove_file(&zip_path)?;
-        info!("Deleted zip: {}", zip_path.display());
+// Write extracted metas next to their zip, then delete the zip
+fn write_metas(metas: ZipMetas) -> Result<Written> {
+    let Some((zip_path, metas)) = metas else {
+        return Ok(Written::default());
+    };
+    let mut written = Written::default();
+    for meta in &metas {
+        if meta.path.exists() {
+            info!("File already exists, skipping: {}", meta.path.display());
+            continue;
+        }
+        if let Some(parent) = meta.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out_file = File::create(&meta.path)?;
+        out_file.write_all(&meta.content)?;
+        if let Some(mode) = meta.mode {
+            out_file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        if let Some(mtime) = meta.mtime {
+            out_file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime as u64))?;
+        }
+        written.metas.push(meta.path.clone());
+        written.bytes += meta.content.len() as u64;
     }
-
-    Ok(())
+    written.freed = fs::metadata(&zip_path)?.len();
+    fs::remove_file(&zip_path)?;
+    info!("Extracted {} metas from {}", metas.len(), zip_path.display());
+    Ok(written)
 }
 
 
-fn compress_process(dir: &Path) -> io::Result<()> {
-    let meta_files: Vec<PathBuf> = fs::read_dir(dir)?
+fn compress_process(dir: &Path) -> Result<()> {
+    let mut meta_files: Vec<PathBuf> = fs::read_dir(dir)?
         .filter_map(|entry| entry.ok())
         .filter(|entry| {
             entry
@@ -89,6 +477,8 @@ fn compress_process(dir: &Path) -> io::Result<()> {
         })
         .map(|entry| entry.path())
         .collect();
+    // Directory listing order varies between filesystems and runs
+    meta_files.sort();
 
     if !meta_files.is_empty() {
         create_zip(dir, &meta_files)?;
@@ -99,29 +489,95 @@ fn compress_process(dir: &Path) -> io::Result<()> {
     Ok(())
 }
 
-fn create_zip(dir: &Path, meta_files: &[PathBuf]) -> io::Result<()> {
-    let zip_path = dir.join(COMPRESS_FILE_NAME);
-    let file = fs::File::create(&zip_path)?;
-    let mut zip = ZipWriter::new(file);
-    let options = FileOptions::<()>::default();
+fn create_zip(dir: &Path, meta_files: &[PathBuf]) -> Result<()> {
+    let mut entries = Vec::with_capacity(meta_files.len());
+    for meta_file in meta_files {
+        let metadata = fs::metadata(meta_file)?;
+        entries.push(MetaEntry {
+            name: entry_name(dir, meta_file)?,
+            content: fs::read_to_string(meta_file)?,
+            mtime: Some(metadata.mtime()),
+            mode: Some(metadata.mode() & 0o7777),
+        });
+    }
+    write_zip_metas(&dir.join(COMPRESS_FILE_NAME), &entries)
+}
 
+fn delete_meta_files(meta_files: &[PathBuf]) -> Result<()> {
     for meta_file in meta_files {
-        let file_name = meta_file
-            .file_name()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid file name"))?
-            .to_string_lossy();
-        let content = fs::read(meta_file)?;
-        zip.start_file(file_name, options)?;
-        zip.write_all(&content)?;
+        fs::remove_file(meta_file)?;
     }
+    Ok(())
+}
 
-    zip.finish()?;
+
+// Rewrite the data pointer line of every .meta in a zip that references
+// checkpoint `old`, replacing the zip only when something changed.
+pub fn rewrite_zip_stored_in(zip_path: &Path, old: &str, new: &str) -> Result<()> {
+    update_zip_stored_in(zip_path, |_, current| (current == Some(old)).then(|| new.to_string()))
+}
+
+// Set the data pointer (fourth line) of .meta entries in a zip. `update` gets
+// each entry name and its current pointer and returns the new pointer, or
+// None to leave the entry untouched.
+pub fn update_zip_stored_in(
+    zip_path: &Path,
+    mut update: impl FnMut(&str, Option<&str>) -> Option<String>,
+) -> Result<()> {
+    let mut entries = read_zip_metas(zip_path)?;
+    let mut changed = false;
+    for entry in entries.iter_mut() {
+        let mut lines: Vec<String> = entry.content.lines().map(str::to_string).collect();
+        if let Some(pointer) = update(&entry.name, lines.get(3).map(String::as_str)) {
+            match lines.get_mut(3) {
+                Some(current) => *current = pointer,
+                None => {
+                    lines.truncate(3);
+                    lines.push(pointer);
+                }
+            }
+            entry.content = lines.join("\n") + "\n";
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+    write_zip_metas(zip_path, &entries)?;
+    info!("Updated data pointers in {}", zip_path.display());
     Ok(())
 }
 
-fn delete_meta_files(meta_files: &[PathBuf]) -> io::Result<()> {
-    for meta_file in meta_files {
-        fs::remove_file(meta_file)?;
+// Every entry of a meta zip
+pub fn read_zip_metas(zip_path: &Path) -> Result<Vec<MetaEntry>> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?).map_err(|e| Error::zip(zip_path, e))?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let mut zip_file = archive.by_index(i).map_err(|e| Error::zip(zip_path, e))?;
+        let mut content = String::new();
+        zip_file.read_to_string(&mut content)?;
+        entries.push(MetaEntry {
+            name: zip_file.name().to_string(),
+            content,
+            mtime: entry_mtime(&zip_file),
+            mode: zip_file.unix_mode().map(|mode| mode & 0o7777),
+        });
+    }
+    Ok(entries)
+}
+
+// Replace a meta zip with the given entries via a temporary file
+pub fn write_zip_metas(zip_path: &Path, entries: &[MetaEntry]) -> Result<()> {
+    let tmp = zip_path.with_extension("zip.tmp");
+    let mut zip = ZipWriter::new(File::create(&tmp)?);
+    let mut entries: Vec<&MetaEntry> = entries.iter().collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let options = meta_entry_options(entry).map_err(|e| Error::zip(zip_path, e))?;
+        zip.start_file(entry.name.as_str(), options).map_err(|e| Error::zip(zip_path, e))?;
+        zip.write_all(entry.content.as_bytes())?;
     }
+    zip.finish().map_err(|e| Error::zip(zip_path, e))?;
+    fs::rename(&tmp, zip_path)?;
     Ok(())
 }